@@ -0,0 +1,24 @@
+//! Runs arbitrary bytes as a ROM: the first 8 bytes seed the random number generator, the next 2
+//! are the keys held and the rest is the program
+
+#![no_main]
+
+use chipr_8::chip8::harness::check_program;
+use libfuzzer_sys::fuzz_target;
+
+const CYCLES: u64 = 10_000;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 10 {
+        return;
+    }
+    let (header, program) = data.split_at(10);
+    let mut seed = [0; 8];
+    seed.copy_from_slice(&header[..8]);
+    let seed = u64::from_le_bytes(seed);
+    let keys = u16::from_le_bytes([header[8], header[9]]);
+
+    let first = check_program(program, seed, keys, CYCLES).unwrap();
+    let second = check_program(program, seed, keys, CYCLES).unwrap();
+    assert!(first == second, "two runs with seed {} ended differently", seed);
+});
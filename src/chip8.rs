@@ -1,30 +1,71 @@
-use std::fs;
 use std::time;
 use std::time::Duration;
 
 use rand::prelude::*;
+use rand::rngs::StdRng;
 
+use cheats::Cheat;
+use debug::Stop;
+use coverage::Coverage;
+use observer::{Access, Observer};
+use profile::Profiler;
 use gfx::Gfx;
 use memory::Memory;
+pub use memory::MEM_SIZE;
+use quirks::Quirks;
 use register::Registers;
+use trace::{Entry, Tracer};
 
-use crate::multimedia::input::EventManager;
+use crate::multimedia::debugger::Snapshot;
+use crate::multimedia::input::{Command, EventManager};
+use crate::multimedia::osd::Status;
+use crate::multimedia::recorder::{RecordOptions, Recorder};
 use crate::multimedia::screen::Drawable;
 use crate::multimedia::sound::Beeper;
-use crate::multimedia::Multimedia;
 use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
 use std::rc::Rc;
 
+pub mod analysis;
+pub mod cheats;
+pub mod coverage;
+pub mod debug;
+pub mod decompile;
+pub mod disasm;
 mod font;
 pub mod gfx;
+pub mod harness;
 mod memory;
+pub mod observer;
+pub mod octo;
+pub mod quirks;
 mod register;
+#[cfg(test)]
+mod opcode_tests;
+pub mod profile;
+mod state;
+pub mod trace;
 
 const STACK_SIZE: usize = 16;
 
 const PC_START_ADDR: u16 = 0x200;
 
-const MIN_CYCLE_DURATION_MICROS: u64 = 1666;
+/// Addresses are 12 bits, PC and I stay within memory
+const MEMORY_MASK: u16 = 0x0FFF;
+
+/// Instructions per second
+pub const DEFAULT_SPEED: u32 = 600;
+
+/// How much faster than real time the emulator runs while fast-forwarding
+const FAST_FORWARD_FACTOR: u32 = 4;
+
+/// Frames a memory write stays highlighted in the debugger
+const RECENT_WRITE_FRAMES: u8 = 60;
+
+/// Writes kept between two frames for the debugger, more than the fastest ROMs make
+const WRITE_LOG_CAPACITY: usize = 4096;
 
 pub struct Chip8 {
     opcode: u16,
@@ -34,25 +75,57 @@ pub struct Chip8 {
     stack: [u16; STACK_SIZE],
     sp: usize,
     memory: Memory,
+    // Kept to reload it on reset
+    rom: Vec<u8>,
     gfx: Gfx,
     delay_timer: u8,
     sound_timer: u8,
     time: time::Instant,
     delta: Duration,
     timer_delta: Duration,
+    cycle_duration: Duration,
+    cycles_per_frame: usize,
+    cycles_in_frame: usize,
+    quirks: Quirks,
+    rng: StdRng,
     // This is here just to play a bit with Rc and RefCell
     // Looks ugly as hell.
     drawable: Rc<RefCell<dyn Drawable>>,
-    beeper: Rc<RefCell<dyn Beeper>>,
+    beepers: Vec<Rc<RefCell<dyn Beeper>>>,
     event_manager: Rc<RefCell<dyn EventManager>>,
+    recorder: Recorder,
+    /// Where the save and load state hotkeys keep the state
+    state_path: Option<PathBuf>,
     is_on: bool,
+    is_paused: bool,
+    is_advancing_frame: bool,
+    is_fast_forwarding: bool,
+    error: Option<String>,
+    // instructions and frames since `stats_time`, measured every second for the OSD
+    stats_time: time::Instant,
+    stats_steps: u32,
+    stats_ticks: u32,
+    status: Status,
+    breakpoints: BTreeSet<u16>,
+    // Set when resuming so the instruction at the breakpoint the machine stopped on runs
+    is_resuming: bool,
+    is_debugging: bool,
+    // Memory written in the last frames, by address, with the frames it stays highlighted
+    recent_writes: BTreeMap<u16, u8>,
+    // Code the program overwrote after running it, marked in the disassembly
+    self_modified: BTreeSet<u16>,
+    stop: Option<Stop>,
+    tracer: Option<Tracer>,
+    profiler: Option<Box<Profiler>>,
+    /// Values written back at the end of every frame
+    cheats: Vec<Cheat>,
 }
 
 const CLOCK_60_HZ: Duration = Duration::from_micros(16666);
 const DEFAULT_SLEEP_DURATION: Duration = Duration::from_micros(10);
 
 impl Chip8 {
-    pub fn new(multimedia: Multimedia) -> Chip8 {
+    pub fn new<M: Drawable + Beeper + EventManager + 'static>(multimedia: M) -> Chip8 {
         let mm = Rc::new(RefCell::new(multimedia));
         Chip8 {
             opcode: 0,
@@ -62,17 +135,42 @@ impl Chip8 {
             sp: 0,
             stack: [0; STACK_SIZE],
             memory: Memory::new(),
+            rom: Vec::new(),
             gfx: Gfx::new(),
             delay_timer: 0,
             sound_timer: 0,
             time: time::Instant::now(),
             delta: Duration::from_millis(0),
             timer_delta: Duration::from_millis(0),
+            cycle_duration: Duration::from_micros(1_000_000 / DEFAULT_SPEED as u64),
+            cycles_per_frame: (DEFAULT_SPEED / 60) as usize,
+            cycles_in_frame: 0,
+            quirks: Quirks::default(),
+            rng: StdRng::from_entropy(),
             // see comment in struct
             drawable: Rc::clone(&mm) as Rc<RefCell<dyn Drawable>>,
-            beeper: Rc::clone(&mm) as Rc<RefCell<dyn Beeper>>,
+            beepers: vec![Rc::clone(&mm) as Rc<RefCell<dyn Beeper>>],
             event_manager: mm as Rc<RefCell<dyn EventManager>>,
+            recorder: Recorder::new(RecordOptions::default()),
+            state_path: None,
             is_on: true,
+            is_paused: false,
+            is_advancing_frame: false,
+            is_fast_forwarding: false,
+            error: None,
+            stats_time: time::Instant::now(),
+            stats_steps: 0,
+            stats_ticks: 0,
+            status: Status::default(),
+            breakpoints: BTreeSet::new(),
+            is_resuming: false,
+            is_debugging: false,
+            recent_writes: BTreeMap::new(),
+            self_modified: BTreeSet::new(),
+            stop: None,
+            tracer: None,
+            profiler: None,
+            cheats: Vec::new(),
         }
     }
 
@@ -84,9 +182,149 @@ impl Chip8 {
         self.is_on
     }
 
-    pub fn load_rom(&mut self, file_path: &str) {
-        let file = fs::read(file_path).expect("Invalid ROM file");
-        self.memory.load_rom(&file);
+    /// Why the machine stopped on its own, if it did
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    pub fn gfx(&self) -> &Gfx {
+        &self.gfx
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.is_paused = paused;
+        self.is_resuming = !paused;
+        if !paused {
+            self.stop = None;
+        }
+        // the beeper stays quiet while paused, the sound timer picks up where it was on resume
+        if self.sound_timer > 0 {
+            self.switch_beep(!paused, self.frame_offset());
+        }
+    }
+
+    /// Sets the clock speed in instructions per second
+    pub fn set_speed(&mut self, instructions_per_second: u32) {
+        let speed = instructions_per_second.max(1);
+        self.cycle_duration = Duration::from_nanos(1_000_000_000 / speed as u64);
+        self.cycles_per_frame = ((speed + 30) / 60).max(1) as usize;
+    }
+
+    /// Instructions executed on every 60 Hz frame at the current speed
+    pub fn cycles_per_frame(&self) -> usize {
+        self.cycles_per_frame
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Makes CXNN reproducible
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = recorder;
+    }
+
+    /// Writes every instruction executed from now on
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Stops tracing, making sure everything traced so far made it to the file
+    pub fn finish_trace(&mut self) -> Result<(), String> {
+        match self.tracer.take() {
+            Some(mut tracer) => tracer.flush().map_err(|e| format!("Could not write trace {}: {}", tracer.path(), e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Records which bytes are executed, read and written from now on
+    pub fn start_coverage(&mut self) {
+        self.memory.observe().start_coverage();
+    }
+
+    /// Stops recording coverage, returning what was recorded
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        let coverage = self.memory.observer_mut().and_then(Observer::take_coverage);
+        self.memory.stop_observing_if_idle();
+        coverage
+    }
+
+    /// Counts the instructions executed from now on, per address and subroutine
+    pub fn start_profiling(&mut self) {
+        self.profiler = Some(Box::new(Profiler::new()));
+    }
+
+    /// Stops profiling, returning what was counted
+    pub fn take_profiler(&mut self) -> Option<Box<Profiler>> {
+        self.profiler.take()
+    }
+
+    pub fn set_state_path(&mut self, path: PathBuf) {
+        self.state_path = Some(path);
+    }
+
+    /// Sends the frontend a snapshot of the machine on every frame for its debugger
+    pub fn set_debugging(&mut self, debugging: bool) {
+        self.is_debugging = debugging;
+        if debugging {
+            self.memory.observe().start_log(&[Access::Write], WRITE_LOG_CAPACITY);
+        } else if let Some(observer) = self.memory.observer_mut() {
+            observer.stop_log();
+            self.memory.stop_observing_if_idle();
+        }
+    }
+
+    /// Sets a breakpoint at the address, or clears the one that is there. Returns whether it is set.
+    pub fn toggle_breakpoint(&mut self, address: u16) -> bool {
+        if self.breakpoints.remove(&address) {
+            return false;
+        }
+        self.breakpoints.insert(address);
+        true
+    }
+
+    /// Adds a beeper that follows the sound timer along with the frontend one
+    pub fn add_beeper(&mut self, beeper: Rc<RefCell<dyn Beeper>>) {
+        self.beepers.push(beeper);
+    }
+
+    /// The ROM last loaded
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn load_rom_bytes(&mut self, rom: &[u8]) -> Result<(), String> {
+        self.memory.load_rom(rom)?;
+        self.rom = rom.to_vec();
+        Ok(())
+    }
+
+    /// Starts the loaded ROM over, as if the machine was just turned on: registers, stack,
+    /// timers, display and memory are cleared and the font and ROM loaded again
+    pub fn reset(&mut self) {
+        if self.sound_timer > 0 && !self.is_paused {
+            self.switch_beep(false, self.frame_offset());
+        }
+        self.opcode = 0;
+        self.v = Registers::new();
+        self.i = 0;
+        self.pc = PC_START_ADDR;
+        self.stack = [0; STACK_SIZE];
+        self.sp = 0;
+        self.memory.clear();
+        self.self_modified.clear();
+        if !self.rom.is_empty() {
+            self.memory.load_rom(&self.rom).expect("the ROM loaded before");
+        }
+        self.gfx.clear();
+        self.delay_timer = 0;
+        self.sound_timer = 0;
+        self.cycles_in_frame = 0;
+        self.error = None;
     }
 
     pub fn dump_stack(&self) {
@@ -124,15 +362,141 @@ impl Chip8 {
         self.calculate_delta();
         self.run_multimedia();
 
-        if self.hit_min_delta_duration() {
-            self.fetch();
-            self.execute();
-            self.pc += 2;
+        if self.is_paused {
+            std::thread::sleep(DEFAULT_SLEEP_DURATION);
+        } else if self.hit_min_delta_duration() {
+            if !self.hit_breakpoint() {
+                self.step();
+                self.hit_watchpoint();
+            }
         } else {
             std::thread::sleep(DEFAULT_SLEEP_DURATION);
         }
     }
 
+    /// Runs `cycles` instructions followed by one 60 Hz tick, without looking at the clock
+    pub fn run_frame(&mut self, cycles: usize) {
+        if !self.is_paused {
+            self.run_cycles(cycles);
+        }
+        self.tick();
+    }
+
+    fn run_cycles(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            if !self.is_on || self.hit_breakpoint() {
+                return;
+            }
+            self.step();
+            if self.hit_watchpoint() {
+                return;
+            }
+        }
+    }
+
+    fn step(&mut self) {
+        let pc = self.pc;
+        self.fetch();
+        if self.profiler.is_some() {
+            self.profile();
+        }
+        self.execute();
+        // jumps leave PC 2 before their target, wrapping around memory like every other address
+        self.pc = self.pc.wrapping_add(2) & MEMORY_MASK;
+        if coverage::is_skip(self.opcode) {
+            if let Some(coverage) = self.memory.observer_mut().and_then(Observer::coverage_mut) {
+                coverage.record_branch(pc, self.pc == (pc + 4) & MEMORY_MASK);
+            }
+        }
+        self.cycles_in_frame += 1;
+        self.stats_steps += 1;
+        self.is_resuming = false;
+        if self.tracer.is_some() {
+            self.trace(pc);
+        }
+    }
+
+    /// Counts the instruction about to run, in the subroutines the stack says it is in
+    fn profile(&mut self) {
+        let profiler = match &mut self.profiler {
+            Some(profiler) => profiler,
+            None => return,
+        };
+        let memory = self.memory.bytes();
+        let subroutines = self.stack[..self.sp].iter().map(|&call| {
+            let opcode = (memory[call as usize % memory.len()] as u16) << 8 | memory[(call as usize + 1) % memory.len()] as u16;
+            opcode & 0x0FFF
+        });
+        profiler.record(self.pc, self.opcode, subroutines, self.delay_timer);
+    }
+
+    /// Writes the instruction that ran at `pc` with the registers it left behind
+    fn trace(&mut self, pc: u16) {
+        let tracer = match &mut self.tracer {
+            Some(tracer) => tracer,
+            None => return,
+        };
+        let entry = if tracer.is_traced(pc) {
+            Some(Entry {
+                cycle: 0,
+                pc,
+                opcode: self.opcode,
+                v: self.v.values(),
+                i: self.i,
+                delay_timer: self.delay_timer,
+                sound_timer: self.sound_timer,
+            })
+        } else {
+            None
+        };
+        if let Err(e) = tracer.record(entry) {
+            let message = format!("Trace {} stopped: {}", tracer.path(), e);
+            self.tracer = None;
+            self.notify(&message);
+        }
+    }
+
+    /// Pauses before the instruction at a breakpoint runs, unless the machine is resuming from it
+    fn hit_breakpoint(&mut self) -> bool {
+        if self.is_resuming || !self.breakpoints.contains(&self.pc) {
+            return false;
+        }
+        if !self.is_paused {
+            self.set_paused(true);
+        }
+        self.stop = Some(Stop::Breakpoint(self.pc));
+        self.notify(&format!("Breakpoint at 0x{:03X}", self.pc));
+        true
+    }
+
+    /// Pauses after an instruction that hit a watchpoint
+    fn hit_watchpoint(&mut self) -> bool {
+        let hit = match self.memory.observer_mut().and_then(Observer::take_hit) {
+            Some(hit) => hit,
+            None => return false,
+        };
+        if !self.is_paused {
+            self.set_paused(true);
+        }
+        self.stop = Some(Stop::Watchpoint(hit));
+        self.notify(&format!("{:?} watchpoint at 0x{:03X} by 0x{:03X}", hit.watchpoint.kind, hit.record.address, hit.record.pc));
+        true
+    }
+
+    /// Addresses past the end of memory wrap around to its start
+    fn read_memory(&mut self, address: usize) -> u8 {
+        self.memory.read(address % MEM_SIZE, self.pc)
+    }
+
+    fn write_memory(&mut self, address: usize, value: u8) {
+        self.memory.write(address % MEM_SIZE, value, self.pc);
+    }
+
+    /// Position of the current instruction inside the 60 Hz frame, from 0.0 to 1.0
+    fn frame_offset(&self) -> f32 {
+        (self.cycles_in_frame as f32 / self.cycles_per_frame as f32).min(1.0)
+    }
+
     fn execute(&mut self) {
         let op = self.opcode & 0xF000;
 
@@ -140,7 +504,7 @@ impl Chip8 {
             0x0000 => match self.opcode {
                 0x00E0 => self.clear_gfx(),
                 0x00EE => self.unstack(),
-                _ => panic!("code {:X} not implemented", self.opcode),
+                _ => self.unknown_opcode(),
             },
             0x1000 => self.goto(),
             0x2000 => self.subroutine(),
@@ -159,7 +523,7 @@ impl Chip8 {
                 0x6 => self.shift_r(),
                 0x7 => self.sub_vy_vx(),
                 0xE => self.shift_l(),
-                _ => panic!("code {:X} - {:X} not implemented", op, self.opcode),
+                _ => self.unknown_opcode(),
             },
             0x9000 => self.jmp_vx_neq_vy(),
             0xA000 => self.set_i_nnn(),
@@ -169,7 +533,7 @@ impl Chip8 {
             0xE000 => match self.opcode & 0x00FF {
                 0x9E => self.skip_if_pressed(),
                 0xA1 => self.skip_not_pressed(),
-                _ => panic!("code {:X} - {:X} not implemented", op, self.opcode),
+                _ => self.unknown_opcode(),
             },
             0xF000 => match self.opcode & 0x00FF {
                 0x07 => self.vx_get_delay(),
@@ -181,35 +545,30 @@ impl Chip8 {
                 0x33 => self.bin_dec_vx(),
                 0x55 => self.reg_dump(),
                 0x65 => self.reg_load(),
-                _ => panic!("code {:X} - {:X} not implemented", op, self.opcode),
+                _ => self.unknown_opcode(),
             },
 
-            _ => {
-                self.dump();
-                panic!("code {:X} - {:X} not implemented", op, self.opcode)
-            }
+            _ => self.unknown_opcode(),
         }
     }
 
+    fn unknown_opcode(&mut self) {
+        self.stop_with_error(format!("Unknown opcode 0x{:04X} at 0x{:03X}", self.opcode, self.pc));
+    }
+
+    /// Stops the machine instead of panicking, the error is kept for the frontend to report
+    fn stop_with_error(&mut self, error: String) {
+        self.error = Some(error);
+        self.shutdown();
+    }
+
     fn fetch(&mut self) {
-        self.opcode = self.memory.read_u16(self.pc as usize);
+        self.opcode = self.memory.fetch(self.pc);
     }
 
     fn run_multimedia(&mut self) {
         if self.timer_delta > CLOCK_60_HZ {
-            if self.delay_timer > 0 {
-                self.delay_timer -= 1;
-            }
-            if self.sound_timer > 0 {
-                if self.sound_timer > 1 {
-                    self.beeper.borrow_mut().start_beep();
-                } else {
-                    self.beeper.borrow_mut().stop_beep();
-                }
-                self.sound_timer -= 1;
-            }
-
-            self.draw_and_check_events();
+            self.tick();
 
             self.timer_delta = Duration::from_micros(
                 (self.timer_delta.as_micros() % CLOCK_60_HZ.as_micros()) as u64,
@@ -217,24 +576,213 @@ impl Chip8 {
         }
     }
 
+    fn tick(&mut self) {
+        // beepers render the frame that just ended, so they go before the timers
+        for beeper in &self.beepers {
+            beeper.borrow_mut().tick();
+        }
+        self.stats_ticks += 1;
+        self.draw_and_check_events();
+        self.record_frame();
+
+        // timers stand still while paused, except for the frame being advanced
+        if self.is_paused {
+            if !self.is_advancing_frame {
+                self.cycles_in_frame = 0;
+                return;
+            }
+            self.is_advancing_frame = false;
+            self.run_cycles(self.cycles_per_frame);
+        }
+
+        // highlights fade with emulated time, so they stay put while paused
+        self.recent_writes.retain(|_, frames| {
+            *frames -= 1;
+            *frames > 0
+        });
+        self.count_down_timers();
+        self.apply_cheats();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.end_frame();
+        }
+        self.cycles_in_frame = 0;
+    }
+
+    fn count_down_timers(&mut self) {
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1;
+        }
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
+            if self.sound_timer == 0 {
+                self.switch_beep(false, 0.0);
+            }
+        }
+    }
+
+    fn switch_beep(&mut self, on: bool, offset: f32) {
+        for beeper in &self.beepers {
+            if on {
+                beeper.borrow_mut().start_beep(offset);
+            } else {
+                beeper.borrow_mut().stop_beep(offset);
+            }
+        }
+        if on {
+            self.recorder.start_beep(offset);
+        } else {
+            self.recorder.stop_beep(offset);
+        }
+    }
+
     fn draw_and_check_events(&mut self) {
+        self.update_status();
+        self.update_debugger();
         self.drawable.borrow_mut().draw(&mut self.gfx);
         self.event_manager.borrow_mut().check_events();
         if self.event_manager.borrow_mut().is_quiting() {
             self.shutdown();
         }
+
+        let commands = self.event_manager.borrow().commands().to_vec();
+        self.is_fast_forwarding = commands.contains(&Command::FastForward);
+        for command in commands {
+            self.run_command(command);
+        }
+    }
+
+    fn update_status(&mut self) {
+        let elapsed = self.stats_time.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            let seconds = elapsed.as_secs_f32();
+            self.status.ips = (self.stats_steps as f32 / seconds).round() as u32;
+            self.status.fps = (self.stats_ticks as f32 / seconds).round() as u32;
+            self.stats_time = time::Instant::now();
+            self.stats_steps = 0;
+            self.stats_ticks = 0;
+        }
+        self.status.paused = self.is_paused;
+        for key in 0..16 {
+            self.status.keys[key] = self.event_manager.borrow().is_key_pressed(key as u8);
+        }
+        self.drawable.borrow_mut().update_status(&self.status);
+    }
+
+    fn update_debugger(&mut self) {
+        if !self.is_debugging {
+            return;
+        }
+        if let Some(observer) = self.memory.observer_mut() {
+            for record in observer.take_log() {
+                self.recent_writes.insert(record.address, RECENT_WRITE_FRAMES);
+                if observer.is_self_modifying(&record) {
+                    self.self_modified.insert(record.address);
+                }
+            }
+        }
+
+        let snapshot = Snapshot {
+            opcode: self.opcode,
+            v: self.v.values(),
+            i: self.i,
+            pc: self.pc,
+            sp: self.sp,
+            stack: self.stack,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            memory: self.memory.bytes().to_vec(),
+            recent_writes: self.recent_writes.keys().copied().collect(),
+            breakpoints: self.breakpoints.iter().copied().collect(),
+            self_modified: self.self_modified.iter().copied().collect(),
+            keys: self.status.keys,
+        };
+        self.drawable.borrow_mut().update_debugger(&snapshot);
+    }
+
+    /// Shows the message on the frontend and on stderr, which is left alone when the frontend
+    /// draws on the terminal itself. Stdout may carry `--dump-frames -` or `--dump-audio -`.
+    pub fn notify(&mut self, message: &str) {
+        let mut drawable = self.drawable.borrow_mut();
+        if !drawable.owns_terminal() {
+            eprintln!("{}", message);
+        }
+        drawable.show_message(message);
+    }
+
+    fn run_command(&mut self, command: Command) {
+        match command {
+            Command::TogglePause => self.set_paused(!self.is_paused),
+            Command::Reset => {
+                self.reset();
+                self.notify("Reset");
+            }
+            Command::FrameAdvance => {
+                self.is_advancing_frame = self.is_paused;
+                self.is_resuming = self.is_paused;
+            }
+            Command::FastForward => {}
+            Command::SpeedUp | Command::SpeedDown => {
+                let step = (self.cycles_per_frame / 10).max(1);
+                let cycles = if command == Command::SpeedUp {
+                    self.cycles_per_frame + step
+                } else {
+                    self.cycles_per_frame.saturating_sub(step).max(1)
+                };
+                self.set_speed(cycles as u32 * 60);
+                self.notify(&format!("Speed: {} instructions per second", cycles * 60));
+            }
+            Command::ToggleRecord => match self.recorder.toggle_gif() {
+                Ok(path) => self.notify(&format!("GIF recording toggled: {}", path)),
+                Err(e) => self.notify(&format!("Could not toggle GIF recording: {}", e)),
+            },
+            Command::ToggleOsd => self.drawable.borrow_mut().toggle_osd(),
+            Command::ToggleBreakpoint(address) => {
+                let message = if self.toggle_breakpoint(address) {
+                    format!("Breakpoint set at 0x{:03X}", address)
+                } else {
+                    format!("Breakpoint cleared at 0x{:03X}", address)
+                };
+                self.notify(&message);
+            }
+            Command::SaveState | Command::LoadState => {
+                let path = match &self.state_path {
+                    Some(path) => path.clone(),
+                    None => return,
+                };
+                let result = if command == Command::SaveState {
+                    fs::write(&path, self.save_state()).map_err(|e| e.to_string())
+                } else {
+                    fs::read(&path).map_err(|e| e.to_string()).and_then(|state| self.load_state(&state))
+                };
+                match result {
+                    Ok(()) if command == Command::SaveState => self.notify(&format!("State saved to {}", path.display())),
+                    Ok(()) => self.notify(&format!("State loaded from {}", path.display())),
+                    Err(e) => self.notify(&format!("Could not use save state {}: {}", path.display(), e)),
+                }
+            }
+        }
+    }
+
+    fn record_frame(&mut self) {
+        if let Err(e) = self.recorder.capture(&self.gfx) {
+            self.notify(&format!("Recording stopped: {}", e));
+            self.recorder = Recorder::new(RecordOptions::default());
+        }
     }
 
     fn calculate_delta(&mut self) {
         let now = time::Instant::now();
-        let delta = now.duration_since(self.time);
+        let mut delta = now.duration_since(self.time);
+        if self.is_fast_forwarding {
+            delta *= FAST_FORWARD_FACTOR;
+        }
         self.time = now;
         self.delta += delta;
         self.timer_delta += delta;
     }
 
     fn hit_min_delta_duration(&mut self) -> bool {
-        if self.delta > Duration::from_micros(MIN_CYCLE_DURATION_MICROS) {
+        if self.delta > self.cycle_duration {
             self.delta = Duration::from_nanos(0);
             return true;
         }
@@ -253,6 +801,10 @@ impl Chip8 {
     /// return<br>
     ///	Returns from a subroutine.
     fn unstack(&mut self) {
+        if self.sp == 0 {
+            self.stop_with_error(format!("Return with an empty stack at 0x{:03X}", self.pc));
+            return;
+        }
         self.sp -= 1;
         self.pc = self.stack[self.sp];
         self.stack[self.sp] = 0;
@@ -262,16 +814,20 @@ impl Chip8 {
     ///	goto NNN<br>
     /// Jumps to address NNN.
     fn goto(&mut self) {
-        self.pc = (self.opcode & 0x0FFF) - 2;
+        self.pc = (self.opcode & 0x0FFF).wrapping_sub(2);
     }
 
     /// 2NNN<br>
     ///	*(0xNNN)()<br>
     ///	Calls subroutine at NNN.
     fn subroutine(&mut self) {
+        if self.sp == STACK_SIZE {
+            self.stop_with_error(format!("Call with a full stack at 0x{:03X}", self.pc));
+            return;
+        }
         self.stack[self.sp] = self.pc;
         self.sp += 1;
-        self.pc = (self.opcode & 0x0FFF) - 2;
+        self.pc = (self.opcode & 0x0FFF).wrapping_sub(2);
     }
 
     /// 3XNN
@@ -353,6 +909,7 @@ impl Chip8 {
         let x = (self.opcode & 0x0F00) as usize >> 8;
         let y = (self.opcode & 0x00F0) as usize >> 4;
         self.v[x] |= self.v[y];
+        self.reset_vf_quirk();
     }
 
     /// 8XY2
@@ -364,6 +921,7 @@ impl Chip8 {
         let x = (self.opcode & 0x0F00) as usize >> 8;
         let y = (self.opcode & 0x00F0) as usize >> 4;
         self.v[x] &= self.v[y];
+        self.reset_vf_quirk();
     }
 
     /// 8XY3
@@ -375,6 +933,13 @@ impl Chip8 {
         let x = (self.opcode & 0x0F00) as usize >> 8;
         let y = (self.opcode & 0x00F0) as usize >> 4;
         self.v[x] ^= self.v[y];
+        self.reset_vf_quirk();
+    }
+
+    fn reset_vf_quirk(&mut self) {
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
     }
 
     /// 8XY4
@@ -385,9 +950,14 @@ impl Chip8 {
     fn add_vx_vy(&mut self) {
         let x = (self.opcode & 0x0F00) as usize >> 8;
         let y = (self.opcode & 0x00F0) as usize >> 4;
-        let result = self.v[x] as u16 + self.v[y] as u16;
-        self.v[x] = (result & 0xFF) as u8;
-        self.v[0xF] = (result >> 8) as u8;
+        let (result, carry) = self.v[x].overflowing_add(self.v[y]);
+        self.set_vx_then_vf(x, result, carry as u8);
+    }
+
+    /// VF is written last, so the flag wins when VF is also the result register
+    fn set_vx_then_vf(&mut self, x: usize, result: u8, flag: u8) {
+        self.v[x] = result;
+        self.v[0xF] = flag;
     }
 
     /// 8XY5
@@ -398,15 +968,8 @@ impl Chip8 {
     fn sub_vx_vy(&mut self) {
         let x = (self.opcode & 0x0F00) as usize >> 8;
         let y = (self.opcode & 0x00F0) as usize >> 4;
-
-        if self.v[x] >= self.v[y] {
-            self.v[x] -= self.v[y];
-            self.v[0xF] = 1;
-        } else {
-            let result = 256u16 + self.v[x] as u16 - self.v[y] as u16;
-            self.v[x] = result as u8;
-            self.v[0xF] = 0;
-        }
+        let (result, borrow) = self.v[x].overflowing_sub(self.v[y]);
+        self.set_vx_then_vf(x, result, !borrow as u8);
     }
 
     /// 8XY6
@@ -416,8 +979,9 @@ impl Chip8 {
     /// Stores the least significant bit of VX in VF and then shifts VX to the right by 1.
     fn shift_r(&mut self) {
         let register = ((self.opcode & 0x0F00) >> 8) as usize;
-        self.v[0xF] = self.v[register] & 0b1;
-        self.v[register] = self.v[register] >> 1;
+        self.shift_source_quirk();
+        let value = self.v[register];
+        self.set_vx_then_vf(register, value >> 1, value & 0b1);
     }
 
     /// 8XY7
@@ -428,15 +992,8 @@ impl Chip8 {
     fn sub_vy_vx(&mut self) {
         let x = (self.opcode & 0x0F00) as usize >> 8;
         let y = (self.opcode & 0x00F0) as usize >> 4;
-
-        if self.v[x] <= self.v[y] {
-            self.v[x] = self.v[y] - self.v[x];
-            self.v[0xF] = 1;
-        } else {
-            let result = 256u16 + self.v[y] as u16 - self.v[x] as u16;
-            self.v[x] = result as u8;
-            self.v[0xF] = 0;
-        }
+        let (result, borrow) = self.v[y].overflowing_sub(self.v[x]);
+        self.set_vx_then_vf(x, result, !borrow as u8);
     }
 
     /// 8XYE
@@ -446,8 +1003,18 @@ impl Chip8 {
     /// Stores the most significant bit of VX in VF and then shifts VX to the left by 1
     fn shift_l(&mut self) {
         let register = ((self.opcode & 0x0F00) >> 8) as usize;
-        self.v[0xF] = self.v[register] & 0b1;
-        self.v[register] = self.v[register] << 1;
+        self.shift_source_quirk();
+        let value = self.v[register];
+        self.set_vx_then_vf(register, value << 1, value >> 7);
+    }
+
+    /// With the shift quirk 8XY6/8XYE shift VY, so it is copied into VX first
+    fn shift_source_quirk(&mut self) {
+        if self.quirks.shift_uses_vy {
+            let x = (self.opcode & 0x0F00) as usize >> 8;
+            let y = (self.opcode & 0x00F0) as usize >> 4;
+            self.v[x] = self.v[y];
+        }
     }
 
     /// 9XY0
@@ -470,8 +1037,14 @@ impl Chip8 {
         self.i = self.opcode & 0x0FFF;
     }
 
+    /// BNNN
+    ///
+    /// PC=V0+NNN
+    ///
+    /// Jumps to the address NNN plus V0, or XNN plus VX with the jump quirk.
     fn jmp_nnn(&mut self) {
-        self.pc = (self.opcode & 0x0FFF) + self.v[0] as u16;
+        let r = if self.quirks.jump_uses_vx { (self.opcode & 0x0F00) as usize >> 8 } else { 0 };
+        self.pc = ((self.opcode & 0x0FFF) + self.v[r] as u16).wrapping_sub(2);
     }
 
     /// CXNN
@@ -482,7 +1055,7 @@ impl Chip8 {
     fn rand(&mut self) {
         let x = (self.opcode & 0x0F00) as usize >> 8;
         let nn = (self.opcode & 0x00FF) as u8;
-        let r: u8 = thread_rng().gen();
+        let r: u8 = self.rng.gen();
         self.v[x] = r & nn;
     }
 
@@ -501,11 +1074,17 @@ impl Chip8 {
         self.v[0xF] = 0;
 
         for line in 0..n {
-            let pixels = self.memory[self.i as usize + line];
+            let pixels = self.read_memory(self.i as usize + line);
             for col in 0..8 {
                 let pixel = (pixels & (0x80 >> col)) > 0;
                 if pixel {
-                    let index = (x + col as usize + (line + y) * gfx::GFX_COLS) % 2048;
+                    let px = x % gfx::GFX_COLS + col;
+                    let py = y % gfx::GFX_ROWS + line;
+                    if self.quirks.clip_sprites && (px >= gfx::GFX_COLS || py >= gfx::GFX_ROWS) {
+                        continue;
+                    }
+                    // without clipping, pixels past an edge wrap around to the opposite one
+                    let index = py % gfx::GFX_ROWS * gfx::GFX_COLS + px % gfx::GFX_COLS;
                     if self.v[0xF] == 0 && self.gfx[index] == pixel {
                         self.v[0xF] = 1;
                     }
@@ -520,7 +1099,7 @@ impl Chip8 {
     /// Skips the next instruction if the key stored in VX is pressed. (Usually the next instruction is a jump to skip a code block)
     fn skip_if_pressed(&mut self) {
         let x = (self.opcode & 0x0F00) as usize >> 8;
-        if self.event_manager.borrow().is_key_pressed(self.v[x]) {
+        if self.event_manager.borrow().is_key_pressed(self.v[x] & 0xF) {
             self.pc += 2;
         }
     }
@@ -530,7 +1109,7 @@ impl Chip8 {
     /// Skips the next instruction if the key stored in VX isn't pressed. (Usually the next instruction is a jump to skip a code block)
     fn skip_not_pressed(&mut self) {
         let x = (self.opcode & 0x0F00) as usize >> 8;
-        if !self.event_manager.borrow().is_key_pressed(self.v[x]) {
+        if !self.event_manager.borrow().is_key_pressed(self.v[x] & 0xF) {
             self.pc += 2;
         }
     }
@@ -548,25 +1127,14 @@ impl Chip8 {
     /// FX0A
     ///
     /// A key press is awaited, and then stored in VX.
-    /// (Blocking Operation. All instruction halted until next key event)
+    /// While no key is pressed the instruction runs again, so frames and timers keep going.
     ///
     fn get_key(&mut self) {
         let x = (self.opcode & 0x0F00) as usize >> 8;
-        let mut waiting = true;
-        while self.is_on && waiting {
-            self.draw_and_check_events();
-            for i in 0..0xF {
-                if self.event_manager.borrow().is_key_pressed(i) {
-                    self.v[x] = i;
-                    waiting = false;
-                    break;
-                }
-            }
-            if waiting {
-                std::thread::sleep(DEFAULT_SLEEP_DURATION);
-            }
+        match (0..=0xF).find(|key| self.event_manager.borrow().is_key_pressed(*key)) {
+            Some(key) => self.v[x] = key,
+            None => self.pc = self.pc.wrapping_sub(2) & MEMORY_MASK,
         }
-        self.v[x] = thread_rng().gen()
     }
 
     /// FX15
@@ -582,9 +1150,12 @@ impl Chip8 {
     /// FX18
     ///
     /// Sets the sound timer to VX.
+    ///
+    /// The beep sounds while the timer is above zero, starting at this instruction.
     fn set_sound_timer(&mut self) {
         let x = (self.opcode & 0x0F00) as usize >> 8;
         self.sound_timer = self.v[x];
+        self.switch_beep(self.sound_timer > 0, self.frame_offset());
     }
 
     /// FX1E
@@ -592,11 +1163,11 @@ impl Chip8 {
     /// I +=Vx
     /// Adds VX to I.
     /// VF is set to 1 when there is a range overflow (I+VX>0xFFF), and to 0 when there isn't.
+    /// I wraps around to stay in memory.
     fn add_vx_i(&mut self) {
-        self.i = self.v[(self.opcode & 0x0F00) as usize >> 8] as u16 + self.i;
-        if self.i > 0x0FFF {
-            self.v[0xF] = 1;
-        }
+        let result = self.v[(self.opcode & 0x0F00) as usize >> 8] as u16 + self.i;
+        self.i = result & 0x0FFF;
+        self.v[0xF] = (result > 0x0FFF) as u8;
     }
 
     /// FX29
@@ -622,9 +1193,9 @@ impl Chip8 {
         let d10 = vx % 100 / 10;
         let d1 = vx % 10;
 
-        self.memory[self.i as usize] = d100;
-        self.memory[self.i as usize + 1] = d10;
-        self.memory[self.i as usize + 2] = d1;
+        self.write_memory(self.i as usize, d100);
+        self.write_memory(self.i as usize + 1, d10);
+        self.write_memory(self.i as usize + 2, d1);
     }
 
     /// FX55
@@ -635,7 +1206,10 @@ impl Chip8 {
     fn reg_dump(&mut self) {
         let reg = ((self.opcode & 0x0F00) >> 8) as usize;
         for i in 0..reg + 1 {
-            self.memory[self.i as usize + i] = self.v[i];
+            self.write_memory(self.i as usize + i, self.v[i]);
+        }
+        if self.quirks.load_store_increments_i {
+            self.i = (self.i + reg as u16 + 1) & MEMORY_MASK;
         }
     }
 
@@ -648,7 +1222,205 @@ impl Chip8 {
     fn reg_load(&mut self) {
         let reg = ((self.opcode & 0x0F00) >> 8) as usize;
         for i in 0..reg + 1 {
-            self.v[i] = self.memory[self.i as usize + i];
+            self.v[i] = self.read_memory(self.i as usize + i);
+        }
+        if self.quirks.load_store_increments_i {
+            self.i = (self.i + reg as u16 + 1) & MEMORY_MASK;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::multimedia::headless::Headless;
+    use crate::multimedia::sound::AudioConfig;
+    use crate::multimedia::wav::WavBeeper;
+
+    type TestWav = Rc<RefCell<WavBeeper<Cursor<Vec<u8>>>>>;
+
+    const SAMPLES_PER_FRAME: u64 = 44100 / 60;
+
+    fn chip8_with_wav(rom: &[u8]) -> (Chip8, TestWav) {
+        let mut chip8 = Chip8::new(Headless);
+        chip8.load_rom_bytes(rom).unwrap();
+        let wav = WavBeeper::new(Cursor::new(Vec::new()), 44100, AudioConfig::default()).unwrap();
+        let wav = Rc::new(RefCell::new(wav));
+        chip8.add_beeper(Rc::clone(&wav) as Rc<RefCell<dyn Beeper>>);
+        (chip8, wav)
+    }
+
+    /// V0 = `value` in its own frame, then FX18 as the first instruction of the next one
+    fn sound_timer_rom(value: u8) -> Vec<u8> {
+        vec![0x60, value, 0xF0, 0x18, 0x12, 0x04]
+    }
+
+    #[test]
+    fn frames_and_write_highlights_count_ticks_not_instructions() {
+        // I = 0x300, [I] = V0, then waits for a key
+        let mut chip8 = Chip8::new(Headless);
+        chip8.load_rom_bytes(&[0xA3, 0x00, 0xF0, 0x55, 0xF0, 0x0A]).unwrap();
+        chip8.set_debugging(true);
+        chip8.run_frame(100);
+        assert_eq!(chip8.stats_ticks, 1);
+        assert!(chip8.recent_writes.contains_key(&0x300));
+        for _ in 1..RECENT_WRITE_FRAMES {
+            chip8.run_frame(100);
+        }
+        assert!(chip8.recent_writes.is_empty());
+    }
+
+    #[test]
+    fn fx18_beeps_for_as_many_frames_as_the_timer_value() {
+        let (mut chip8, wav) = chip8_with_wav(&sound_timer_rom(10));
+        chip8.run_frame(1);
+        for _ in 0..20 {
+            chip8.run_frame(chip8.cycles_per_frame());
+        }
+        assert_eq!(wav.borrow().beep_samples(), 10 * SAMPLES_PER_FRAME);
+    }
+
+    #[test]
+    fn fx18_with_one_beeps_for_one_frame() {
+        let (mut chip8, wav) = chip8_with_wav(&sound_timer_rom(1));
+        chip8.run_frame(1);
+        for _ in 0..5 {
+            chip8.run_frame(chip8.cycles_per_frame());
         }
+        assert_eq!(wav.borrow().beep_samples(), SAMPLES_PER_FRAME);
+    }
+
+    #[test]
+    fn fx18_starts_the_beep_at_its_position_in_the_frame() {
+        // FX18 is the second of ten instructions in the first frame
+        let (mut chip8, wav) = chip8_with_wav(&sound_timer_rom(10));
+        for _ in 0..20 {
+            chip8.run_frame(chip8.cycles_per_frame());
+        }
+        assert_eq!(wav.borrow().beep_samples(), 10 * SAMPLES_PER_FRAME - SAMPLES_PER_FRAME / 10);
+    }
+
+    #[test]
+    fn fx18_with_zero_stops_the_beep_right_away() {
+        // V0 = 10, ST = V0, V1 = 0, ST = V1
+        let rom = [0x60, 0x0A, 0xF0, 0x18, 0x61, 0x00, 0xF1, 0x18, 0x12, 0x08];
+        let (mut chip8, wav) = chip8_with_wav(&rom);
+        for _ in 0..20 {
+            chip8.run_frame(chip8.cycles_per_frame());
+        }
+        let start = SAMPLES_PER_FRAME / 10;
+        let stop = (0.3f32 * SAMPLES_PER_FRAME as f32) as u64;
+        assert_eq!(wav.borrow().beep_samples(), stop - start);
+    }
+
+    #[test]
+    fn sound_timer_counts_down_once_per_frame() {
+        let (mut chip8, _) = chip8_with_wav(&sound_timer_rom(3));
+        chip8.run_frame(2);
+        assert_eq!(chip8.sound_timer, 2);
+        chip8.run_frame(chip8.cycles_per_frame());
+        assert_eq!(chip8.sound_timer, 1);
+        chip8.run_frame(chip8.cycles_per_frame());
+        assert_eq!(chip8.sound_timer, 0);
+        chip8.run_frame(chip8.cycles_per_frame());
+        assert_eq!(chip8.sound_timer, 0);
+    }
+
+    #[test]
+    fn delay_timer_counts_down_once_per_frame() {
+        // V0 = 2, DT = V0, V1 = DT
+        let rom = [0x60, 0x02, 0xF0, 0x15, 0xF1, 0x07, 0x12, 0x04];
+        let (mut chip8, _) = chip8_with_wav(&rom);
+        chip8.run_frame(3);
+        assert_eq!(chip8.v[1], 2);
+        assert_eq!(chip8.delay_timer, 1);
+        chip8.run_frame(2);
+        assert_eq!(chip8.delay_timer, 0);
+        assert_eq!(chip8.v[1], 1);
+        chip8.run_frame(chip8.cycles_per_frame());
+        assert_eq!(chip8.delay_timer, 0);
+    }
+
+    #[test]
+    fn timers_stand_still_while_paused_until_a_frame_is_advanced() {
+        // V0 = 10, DT = V0, loop
+        let rom = [0x60, 0x0A, 0xF0, 0x15, 0x12, 0x04];
+        let (mut chip8, _) = chip8_with_wav(&rom);
+        chip8.run_frame(2);
+        assert_eq!(chip8.delay_timer, 9);
+
+        chip8.run_command(Command::TogglePause);
+        chip8.run_frame(chip8.cycles_per_frame());
+        chip8.run_frame(chip8.cycles_per_frame());
+        assert_eq!(chip8.delay_timer, 9);
+
+        chip8.run_command(Command::FrameAdvance);
+        chip8.run_frame(chip8.cycles_per_frame());
+        assert_eq!(chip8.delay_timer, 8);
+        chip8.run_frame(chip8.cycles_per_frame());
+        assert_eq!(chip8.delay_timer, 8);
+
+        chip8.run_command(Command::TogglePause);
+        chip8.run_frame(chip8.cycles_per_frame());
+        assert_eq!(chip8.delay_timer, 7);
+    }
+
+    #[test]
+    fn reset_starts_the_rom_over() {
+        // V0 = 10, ST = V0, V1 = 1, I = 0x300, [I] = V0, loop
+        let rom = [0x60, 0x0A, 0xF0, 0x18, 0x61, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x0A];
+        let (mut chip8, wav) = chip8_with_wav(&rom);
+        chip8.run_frame(chip8.cycles_per_frame());
+        assert_eq!(chip8.memory[0x300], 10);
+
+        chip8.run_command(Command::Reset);
+        assert_eq!(chip8.pc, PC_START_ADDR);
+        assert_eq!(chip8.v[0], 0);
+        assert_eq!(chip8.i, 0);
+        assert_eq!(chip8.sound_timer, 0);
+        assert_eq!(chip8.memory[0x300], 0);
+        assert_eq!(chip8.memory[0x200], 0x60);
+
+        // the beep stops with the reset, this frame only sets V0 again
+        let beeping = wav.borrow().beep_samples();
+        chip8.run_frame(1);
+        assert_eq!(wav.borrow().beep_samples(), beeping);
+    }
+
+    #[test]
+    fn breakpoints_pause_before_the_instruction_and_resume_past_it() {
+        // V0 = 1, loop: V0 += 1
+        let rom = [0x60, 0x01, 0x70, 0x01, 0x12, 0x02];
+        let mut chip8 = Chip8::new(Headless);
+        chip8.load_rom_bytes(&rom).unwrap();
+        chip8.run_command(Command::ToggleBreakpoint(0x202));
+
+        chip8.run_frame(10);
+        assert!(chip8.is_paused);
+        assert_eq!(chip8.pc, 0x202);
+        assert_eq!(chip8.v[0], 1);
+
+        chip8.run_command(Command::TogglePause);
+        chip8.run_frame(10);
+        assert!(chip8.is_paused);
+        assert_eq!(chip8.pc, 0x202);
+        assert_eq!(chip8.v[0], 2);
+
+        chip8.run_command(Command::ToggleBreakpoint(0x202));
+        chip8.run_command(Command::TogglePause);
+        chip8.run_frame(10);
+        assert!(!chip8.is_paused);
+    }
+
+    #[test]
+    fn speed_changes_in_steps_of_a_tenth() {
+        let mut chip8 = Chip8::new(Headless);
+        chip8.run_command(Command::SpeedUp);
+        assert_eq!(chip8.cycles_per_frame(), 11);
+        chip8.set_speed(60);
+        chip8.run_command(Command::SpeedDown);
+        assert_eq!(chip8.cycles_per_frame(), 1);
     }
 }
@@ -0,0 +1,412 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::io::Write;
+
+use super::coverage::is_skip;
+use super::disasm;
+use super::gfx::{GFX_COLS, GFX_ROWS};
+use super::quirks::Quirks;
+
+const ROM_START: u16 = 0x200;
+
+/// How control gets from one block to the next
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edge {
+    Next,
+    Jump,
+    Call,
+    /// From a call to the instruction after it, where the subroutine returns
+    Return,
+    /// Taken when a skip instruction skips
+    Skip,
+}
+
+/// Instructions that run one after the other, entered only at the first
+pub struct Block {
+    pub instructions: Vec<(u16, u16)>,
+    pub successors: Vec<(u16, Edge)>,
+    /// Ends in BNNN, whose target depends on a register
+    pub computed: bool,
+}
+
+/// An instruction whose behaviour depends on a quirk, `suggests` being the setting it hints at
+/// when it hints at one
+pub struct Hint {
+    pub address: u16,
+    pub opcode: u16,
+    pub quirk: &'static str,
+    pub suggests: Option<bool>,
+    pub reason: String,
+}
+
+/// FX55 or FX33 at `writer` overwriting instructions between `start` and `end`
+pub struct SelfModification {
+    pub writer: u16,
+    pub start: u16,
+    pub end: u16,
+}
+
+/// What can be told about a ROM without running it, from the instructions reachable from 0x200
+pub struct Analysis {
+    pub blocks: BTreeMap<u16, Block>,
+    /// Call targets with the instructions reachable in them until they return
+    pub subroutines: BTreeMap<u16, usize>,
+    pub self_modifications: Vec<SelfModification>,
+    pub hints: Vec<Hint>,
+    /// Reached addresses that do not hold an instruction this interpreter runs
+    pub unknown: Vec<u16>,
+}
+
+fn byte_at(rom: &[u8], address: u16) -> Option<u8> {
+    rom.get(address.checked_sub(ROM_START)? as usize).copied()
+}
+
+pub(super) fn opcode_at(rom: &[u8], address: u16) -> Option<u16> {
+    Some((byte_at(rom, address)? as u16) << 8 | byte_at(rom, address + 1)? as u16)
+}
+
+/// Where control can go after the instruction, calls first
+pub(super) fn successors(address: u16, opcode: u16) -> Vec<(u16, Edge)> {
+    let nnn = opcode & 0x0FFF;
+    match opcode & 0xF000 {
+        _ if !disasm::is_known(opcode) || opcode == 0x00EE => Vec::new(),
+        0x1000 => vec![(nnn, Edge::Jump)],
+        0x2000 => vec![(nnn, Edge::Call), (address + 2, Edge::Return)],
+        0xB000 => Vec::new(),
+        _ if is_skip(opcode) => vec![(address + 2, Edge::Next), (address + 4, Edge::Skip)],
+        _ => vec![(address + 2, Edge::Next)],
+    }
+}
+
+/// Columns and rows of the sprite at `i` that have a pixel set
+fn sprite_size(rom: &[u8], i: Option<u16>, rows: u16) -> (usize, usize) {
+    let bytes = match i {
+        Some(i) => (i..i + rows).map(|address| byte_at(rom, address)).collect::<Option<Vec<_>>>(),
+        None => None,
+    };
+    match bytes {
+        Some(bytes) => {
+            let columns = bytes.iter().fold(0, |columns, byte| columns | byte);
+            let width = if columns == 0 { 0 } else { 8 - columns.trailing_zeros() as usize };
+            (width, bytes.iter().rposition(|byte| *byte != 0).map_or(0, |row| row + 1))
+        }
+        None => (8, rows as usize),
+    }
+}
+
+impl Analysis {
+    pub fn new(rom: &[u8]) -> Analysis {
+        let mut instructions = BTreeMap::new();
+        let mut leaders = BTreeSet::new();
+        let mut unknown = Vec::new();
+        let mut pending = vec![ROM_START];
+        leaders.insert(ROM_START);
+        while let Some(address) = pending.pop() {
+            if instructions.contains_key(&address) {
+                continue;
+            }
+            let opcode = match opcode_at(rom, address) {
+                Some(opcode) => opcode,
+                None => continue,
+            };
+            instructions.insert(address, opcode);
+            if !disasm::is_known(opcode) {
+                unknown.push(address);
+            }
+            let next = successors(address, opcode);
+            let branches = next.len() != 1 || next[0].1 != Edge::Next;
+            for (target, _) in next {
+                if branches {
+                    leaders.insert(target);
+                }
+                pending.push(target);
+            }
+        }
+        unknown.sort_unstable();
+
+        let mut blocks = BTreeMap::new();
+        for leader in leaders.iter().filter(|leader| instructions.contains_key(leader)) {
+            let mut block = Block { instructions: Vec::new(), successors: Vec::new(), computed: false };
+            let mut address = *leader;
+            loop {
+                let opcode = instructions[&address];
+                block.instructions.push((address, opcode));
+                let next = successors(address, opcode);
+                match next[..] {
+                    [(next, Edge::Next)] if !leaders.contains(&next) && instructions.contains_key(&next) => address = next,
+                    _ => {
+                        block.computed = opcode & 0xF000 == 0xB000;
+                        block.successors = next;
+                        break;
+                    }
+                }
+            }
+            blocks.insert(*leader, block);
+        }
+
+        let mut analysis = Analysis { blocks, subroutines: BTreeMap::new(), self_modifications: Vec::new(), hints: Vec::new(), unknown };
+        analysis.find_subroutines(&instructions);
+        for block in analysis.blocks.values() {
+            analysis.hints.extend(block_hints(rom, block, &instructions, &mut analysis.self_modifications));
+        }
+        analysis
+    }
+
+    /// Counts the instructions of every call target, up to its returns and without the calls it makes
+    fn find_subroutines(&mut self, instructions: &BTreeMap<u16, u16>) {
+        let entries = self.blocks.values()
+            .flat_map(|block| block.successors.iter())
+            .filter(|(_, edge)| *edge == Edge::Call)
+            .map(|(target, _)| *target)
+            .collect::<BTreeSet<_>>();
+        for entry in entries {
+            let mut seen = BTreeSet::new();
+            let mut pending = vec![entry];
+            while let Some(address) = pending.pop() {
+                let opcode = match instructions.get(&address) {
+                    Some(opcode) if seen.insert(address) => *opcode,
+                    _ => continue,
+                };
+                pending.extend(successors(address, opcode).into_iter()
+                    .filter(|(_, edge)| *edge != Edge::Call)
+                    .map(|(target, _)| target));
+            }
+            self.subroutines.insert(entry, seen.len());
+        }
+    }
+
+    /// Quirks set the way the hints lean, the defaults where they do not
+    pub fn suggested_quirks(&self) -> Quirks {
+        let mut quirks = Quirks::default();
+        let mut votes: BTreeMap<&str, i32> = BTreeMap::new();
+        for hint in &self.hints {
+            if let Some(suggests) = hint.suggests {
+                *votes.entry(hint.quirk).or_insert(0) += if suggests { 1 } else { -1 };
+            }
+        }
+        for (quirk, votes) in votes {
+            if votes != 0 {
+                *quirks.flag_mut(quirk).expect("hints name known quirks") = votes > 0;
+            }
+        }
+        quirks
+    }
+
+    pub fn write_report(&self, out: &mut dyn Write) -> io::Result<()> {
+        let instructions = self.blocks.values().map(|block| block.instructions.len()).sum::<usize>();
+        let line = |address: u16, opcode: u16| format!("{:03X}  {:04X}  {}", address, opcode, disasm::disassemble(opcode));
+        writeln!(out, "{} instructions reachable from 0x200 in {} blocks", instructions, self.blocks.len())?;
+
+        let subroutines = self.subroutines.iter()
+            .map(|(address, instructions)| format!("{:03X}  {} instructions", address, instructions));
+        write_section(out, "Subroutines", subroutines)?;
+        let computed = self.blocks.values()
+            .filter(|block| block.computed)
+            .map(|block| block.instructions[block.instructions.len() - 1])
+            .map(|(address, opcode)| line(address, opcode));
+        write_section(out, "Computed jumps, their targets are not followed", computed)?;
+        let self_modifications = self.self_modifications.iter()
+            .map(|modification| format!("{:03X}  writes {:03X}-{:03X}, where instructions are", modification.writer, modification.start, modification.end));
+        write_section(out, "Self-modifying code", self_modifications)?;
+        let unknown = self.blocks.values()
+            .flat_map(|block| block.instructions.iter())
+            .filter(|(address, _)| self.unknown.contains(address))
+            .map(|(address, opcode)| line(*address, *opcode));
+        write_section(out, "Unknown opcodes reached", unknown)?;
+        let hints = self.hints.iter().map(|hint| {
+            let suggests = match hint.suggests {
+                Some(true) => format!(", suggests +{}", hint.quirk),
+                Some(false) => format!(", suggests -{}", hint.quirk),
+                None => String::new(),
+            };
+            format!("{}  ; {}: {}{}", line(hint.address, hint.opcode), hint.quirk, hint.reason, suggests)
+        });
+        write_section(out, "Quirk-sensitive instructions", hints)?;
+        writeln!(out, "\nSuggested quirks: {}", self.suggested_quirks().to_spec())
+    }
+
+    /// The control-flow graph in Graphviz DOT, one node per block
+    pub fn write_dot(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "digraph cfg {{")?;
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];")?;
+        for (start, block) in &self.blocks {
+            let mut label = String::new();
+            if self.subroutines.contains_key(start) {
+                label.push_str(&format!("sub_{:03X}:\\l", start));
+            }
+            for (address, opcode) in &block.instructions {
+                label.push_str(&format!("{:03X}  {:04X}  {}\\l", address, opcode, disasm::disassemble(*opcode)));
+            }
+            writeln!(out, "    \"{:03X}\" [label=\"{}\"];", start, label)?;
+            for (target, edge) in &block.successors {
+                if !self.blocks.contains_key(target) {
+                    writeln!(out, "    \"{:03X}\" [shape=plaintext, label=\"{:03X} outside the ROM\"];", target, target)?;
+                }
+                let attributes = match edge {
+                    Edge::Next => "",
+                    Edge::Jump => " [label=\"jump\"]",
+                    Edge::Call => " [label=\"call\", style=dashed]",
+                    Edge::Return => " [label=\"return\", style=dotted]",
+                    Edge::Skip => " [label=\"skip\"]",
+                };
+                writeln!(out, "    \"{:03X}\" -> \"{:03X}\"{};", start, target, attributes)?;
+            }
+            if block.computed {
+                writeln!(out, "    \"computed_{:03X}\" [shape=plaintext, label=\"?\"];", start)?;
+                writeln!(out, "    \"{:03X}\" -> \"computed_{:03X}\" [label=\"computed\", style=dashed];", start, start)?;
+            }
+        }
+        writeln!(out, "}}")
+    }
+}
+
+fn write_section(out: &mut dyn Write, title: &str, lines: impl Iterator<Item = String>) -> io::Result<()> {
+    writeln!(out, "\n{}:", title)?;
+    let mut empty = true;
+    for line in lines {
+        writeln!(out, "    {}", line)?;
+        empty = false;
+    }
+    if empty {
+        writeln!(out, "    none")?;
+    }
+    Ok(())
+}
+
+/// Follows the values of I and the V registers set by constants through the block, for the
+/// instructions whose behaviour depends on a quirk and the writes that land on code
+fn block_hints(rom: &[u8], block: &Block, instructions: &BTreeMap<u16, u16>, self_modifications: &mut Vec<SelfModification>) -> Vec<Hint> {
+    let mut hints = Vec::new();
+    let mut v: [Option<u8>; 16] = [None; 16];
+    let mut i: Option<u16> = None;
+    // FX55 or FX65 whose effect on I was not overwritten yet
+    let mut load_store: Option<(u16, u16)> = None;
+
+    for (address, opcode) in block.instructions.iter().copied() {
+        let x = (opcode >> 8 & 0xF) as usize;
+        let y = (opcode >> 4 & 0xF) as usize;
+        let n = opcode & 0xF;
+        let mut hint = |at: (u16, u16), quirk, suggests, reason: String| hints.push(Hint { address: at.0, opcode: at.1, quirk, suggests, reason });
+
+        if let Some(previous) = load_store {
+            match opcode & 0xF0FF {
+                0xF01E => hint(previous, "load_store_increments_i", Some(false), format!("I is moved on by FX1E at {:03X}", address)),
+                0xF033 | 0xF055 | 0xF065 => hint(previous, "load_store_increments_i", Some(true), format!("I is used again at {:03X} without being set", address)),
+                _ if opcode & 0xF000 == 0xD000 => hint(previous, "load_store_increments_i", None, format!("I is drawn from at {:03X} without being set", address)),
+                _ => {}
+            }
+            if opcode & 0xF000 == 0xA000 || matches!(opcode & 0xF0FF, 0xF01E | 0xF029 | 0xF033 | 0xF055 | 0xF065) || opcode & 0xF000 == 0xD000 {
+                load_store = None;
+            }
+        }
+
+        match opcode & 0xF000 {
+            0x6000 => v[x] = Some(opcode as u8),
+            0x7000 => v[x] = v[x].map(|value| value.wrapping_add(opcode as u8)),
+            0x8000 => {
+                if matches!(n, 0x6 | 0xE) && x != y {
+                    let (suggests, reason) = if y == 0 {
+                        (false, "written as SHR/SHL VX with an unused VY of 0")
+                    } else {
+                        (true, "shifts with X != Y, VY is the source on the COSMAC VIP")
+                    };
+                    hint((address, opcode), "shift_uses_vy", Some(suggests), reason.to_string());
+                }
+                v[x] = if n == 0 { v[y] } else { None };
+                v[0xF] = None;
+            }
+            0xA000 => i = Some(opcode & 0x0FFF),
+            0xB000 if x != 0 => hint((address, opcode), "jump_uses_vx", None, format!("jumps by V{:X} or V0 depending on the quirk", x)),
+            0xC000 => v[x] = None,
+            0xD000 => {
+                if let (Some(vx), Some(vy)) = (v[x], v[y]) {
+                    let (width, height) = sprite_size(rom, i, n);
+                    let (px, py) = (vx as usize % GFX_COLS, vy as usize % GFX_ROWS);
+                    if px + width > GFX_COLS || py + height > GFX_ROWS {
+                        hint((address, opcode), "clip_sprites", None, format!("sprite at {},{} crosses the screen edge", px, py));
+                    }
+                }
+                v[0xF] = None;
+            }
+            0xF000 => match opcode & 0x00FF {
+                0x07 | 0x0A => v[x] = None,
+                0x1E => i = i.zip(v[x]).map(|(i, vx)| (i + vx as u16) & 0x0FFF),
+                0x29 => i = v[x].map(|digit| (digit & 0xF) as u16 * 5),
+                0x33 | 0x55 | 0x65 => {
+                    let length = if opcode & 0xFF == 0x33 { 3 } else { x as u16 + 1 };
+                    if opcode & 0xFF != 0x65 {
+                        if let Some(start) = i {
+                            let end = start + length - 1;
+                            // an instruction starting just before the range has its second byte in it
+                            if instructions.range(start.saturating_sub(1)..=end).any(|(_, opcode)| disasm::is_known(*opcode)) {
+                                self_modifications.push(SelfModification { writer: address, start, end });
+                            }
+                        }
+                    }
+                    if opcode & 0xFF == 0x65 {
+                        v.iter_mut().take(x + 1).for_each(|value| *value = None);
+                    }
+                    if opcode & 0xFF != 0x33 {
+                        load_store = Some((address, opcode));
+                        i = None;
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    hints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 200: I = 0x216, V0 = 60, V1 = 0, call 20C
+    // 208: skip if V0 == 0, jump 200
+    // 20C: V2 >>= V3, draw 8x2 at V0,V1, [I] = V0-V1, FX1E, RET
+    // 214: sprite
+    const ROM: [u8; 24] = [
+        0xA2, 0x16, 0x60, 0x3C, 0x61, 0x00, 0x22, 0x0C,
+        0x30, 0x00, 0x12, 0x00,
+        0x82, 0x36, 0xD0, 0x12, 0xF1, 0x55, 0xF0, 0x1E, 0x00, 0xEE,
+        0xFF, 0x81,
+    ];
+
+    #[test]
+    fn builds_the_control_flow_graph() {
+        let analysis = Analysis::new(&ROM);
+        assert_eq!(analysis.blocks.keys().copied().collect::<Vec<_>>(), vec![0x200, 0x208, 0x20A, 0x20C]);
+        assert_eq!(analysis.blocks[&0x200].successors, vec![(0x20C, Edge::Call), (0x208, Edge::Return)]);
+        assert_eq!(analysis.blocks[&0x208].successors, vec![(0x20A, Edge::Next), (0x20C, Edge::Skip)]);
+        assert_eq!(analysis.subroutines.get(&0x20C), Some(&5));
+        assert!(analysis.unknown.is_empty());
+
+        let mut dot = Vec::new();
+        analysis.write_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.contains("\"20A\" -> \"200\" [label=\"jump\"];"));
+        assert!(dot.contains("label=\"sub_20C:\\l20C  8236  SHR V2, V3\\l"));
+    }
+
+    #[test]
+    fn hints_at_quirks() {
+        // I = 0x210, V0 = 60, V1 = 0, V2 >>= V3, draw 8x2 at V0,V1, [I] = V0-V1, FX1E, loop, sprite
+        let analysis = Analysis::new(&[
+            0xA2, 0x10, 0x60, 0x3C, 0x61, 0x00, 0x82, 0x36, 0xD0, 0x12, 0xF1, 0x55, 0xF0, 0x1E, 0x12, 0x0E, 0xFF, 0x81,
+        ]);
+        let hints = analysis.hints.iter().map(|hint| (hint.address, hint.quirk, hint.suggests)).collect::<Vec<_>>();
+        assert_eq!(hints, vec![
+            (0x206, "shift_uses_vy", Some(true)),
+            (0x208, "clip_sprites", None),
+            (0x20A, "load_store_increments_i", Some(false)),
+        ]);
+        assert!(analysis.self_modifications.is_empty());
+        assert_eq!(analysis.suggested_quirks().to_spec(), "default,+shift_uses_vy");
+
+        // I = 0x200, [I] = V0
+        let analysis = Analysis::new(&[0xA2, 0x00, 0xF0, 0x55, 0x12, 0x00]);
+        assert_eq!(analysis.self_modifications.len(), 1);
+    }
+}
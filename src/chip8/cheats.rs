@@ -0,0 +1,268 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::memory::MEM_SIZE;
+use super::Chip8;
+
+/// A value written to an address at the end of every frame, so the program can not change it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cheat {
+    pub address: u16,
+    pub value: u8,
+    pub description: String,
+}
+
+impl Cheat {
+    /// `ADDRESS=VALUE`, both in hex, e.g. `0x3F0=9`
+    pub fn parse(spec: &str) -> Result<Cheat, String> {
+        let invalid = || format!("Invalid cheat '{}', expected ADDRESS=VALUE in hex like 0x3F0=9", spec);
+        let (address, value) = spec.split_once('=').ok_or_else(invalid)?;
+        Ok(Cheat {
+            address: parse_address(address).ok_or_else(invalid)?,
+            value: parse_value(value).ok_or_else(invalid)?,
+            description: String::new(),
+        })
+    }
+}
+
+/// Hex address within memory, with or without 0x
+pub fn parse_address(address: &str) -> Option<u16> {
+    parse_hex(address).filter(|address| (*address as usize) < MEM_SIZE)
+}
+
+/// Hex byte, with or without 0x
+pub fn parse_value(value: &str) -> Option<u8> {
+    parse_hex(value).filter(|value| *value <= 0xFF).map(|value| value as u8)
+}
+
+fn parse_hex(hex: &str) -> Option<u16> {
+    u16::from_str_radix(hex.trim_start_matches("0x").trim_start_matches("0X"), 16).ok()
+}
+
+/// How a byte must have changed since the last snapshot to stay in a search
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Equal(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl Filter {
+    fn keeps(self, previous: u8, current: u8) -> bool {
+        match self {
+            Filter::Equal(value) => current == value,
+            Filter::Changed => current != previous,
+            Filter::Unchanged => current == previous,
+            Filter::Increased => current > previous,
+            Filter::Decreased => current < previous,
+        }
+    }
+}
+
+/// Narrows memory down to the addresses whose bytes change the way the player saw a counter
+/// change, e.g. decreased after losing a life, by comparing snapshots taken between filters
+pub struct Search {
+    snapshot: Vec<u8>,
+    candidates: Vec<u16>,
+}
+
+impl Search {
+    /// Starts with every address as a candidate
+    pub fn new(memory: &[u8]) -> Search {
+        Search {
+            snapshot: memory.to_vec(),
+            candidates: (0..memory.len() as u16).collect(),
+        }
+    }
+
+    /// Keeps the candidates that pass the filter against the last snapshot, then takes a new
+    /// one. Returns how many are left.
+    pub fn filter(&mut self, memory: &[u8], filter: Filter) -> usize {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|address| filter.keeps(snapshot[*address as usize], memory[*address as usize]));
+        self.snapshot = memory.to_vec();
+        self.candidates.len()
+    }
+
+    /// The candidates with their value in the last snapshot
+    pub fn candidates(&self) -> impl Iterator<Item = (u16, u8)> + '_ {
+        self.candidates.iter().map(move |address| (*address, self.snapshot[*address as usize]))
+    }
+}
+
+/// Cheat lists by ROM SHA-1, in TOML:
+///
+/// ```toml
+/// [[rom.0123456789abcdef0123456789abcdef01234567.cheat]]
+/// address = "0x3F0"
+/// value = 9
+/// description = "Lives"
+/// ```
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CheatFile {
+    #[serde(default)]
+    rom: BTreeMap<String, RomCheats>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct RomCheats {
+    #[serde(rename = "cheat", default)]
+    cheats: Vec<SavedCheat>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct SavedCheat {
+    /// In hex
+    address: String,
+    value: u8,
+    #[serde(default)]
+    description: String,
+}
+
+impl CheatFile {
+    /// Reads the cheat file, a missing one having no cheats
+    pub fn load(path: &Path) -> Result<CheatFile, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(CheatFile::default()),
+            Err(e) => return Err(format!("Could not read cheats {}: {}", path.display(), e)),
+        };
+        CheatFile::parse(&text).map_err(|e| format!("Invalid cheats {}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<CheatFile, String> {
+        let file: CheatFile = toml::from_str(text).map_err(|e| e.to_string())?;
+        let file = CheatFile {
+            rom: file.rom.into_iter().map(|(hash, cheats)| (hash.to_ascii_lowercase(), cheats)).collect(),
+        };
+        for (hash, cheats) in &file.rom {
+            for cheat in &cheats.cheats {
+                parse_address(&cheat.address)
+                    .ok_or_else(|| format!("[rom.{}] invalid address '{}', expected hex like 0x3F0", hash, cheat.address))?;
+            }
+        }
+        Ok(file)
+    }
+
+    /// Writes the file, creating its directory if needed
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = toml::to_string(self).map_err(|e| e.to_string())?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+        }
+        fs::write(path, text).map_err(|e| format!("Could not write cheats {}: {}", path.display(), e))
+    }
+
+    pub fn cheats_for(&self, sha1: &str) -> Vec<Cheat> {
+        self.rom.get(sha1).map_or_else(Vec::new, |rom| {
+            rom.cheats.iter()
+                .filter_map(|cheat| Some(Cheat {
+                    address: parse_address(&cheat.address)?,
+                    value: cheat.value,
+                    description: cheat.description.clone(),
+                }))
+                .collect()
+        })
+    }
+
+    /// Replaces the ROM's cheats, dropping its section when there are none left
+    pub fn set_cheats(&mut self, sha1: &str, cheats: &[Cheat]) {
+        if cheats.is_empty() {
+            self.rom.remove(sha1);
+            return;
+        }
+        let cheats = cheats.iter()
+            .map(|cheat| SavedCheat {
+                address: format!("0x{:03X}", cheat.address),
+                value: cheat.value,
+                description: cheat.description.clone(),
+            })
+            .collect();
+        self.rom.insert(sha1.to_string(), RomCheats { cheats });
+    }
+}
+
+impl Chip8 {
+    /// Memory as the program sees it, without triggering watchpoints
+    pub fn memory_bytes(&self) -> &[u8] {
+        self.memory.bytes()
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    /// Replaces the frozen values, writing them right away
+    pub fn set_cheats(&mut self, cheats: Vec<Cheat>) {
+        self.cheats = cheats;
+        self.apply_cheats();
+    }
+
+    pub(super) fn apply_cheats(&mut self) {
+        for cheat in &self.cheats {
+            self.memory[cheat.address as usize] = cheat.value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::harness::MachineBuilder;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn search_narrows_down_to_a_counter() {
+        let mut memory = vec![0u8; 8];
+        memory[3] = 3;
+        memory[5] = 3;
+        let mut search = Search::new(&memory);
+        assert_eq!(search.filter(&memory, Filter::Equal(3)), 2);
+
+        // the counter at 3 goes down, the byte at 5 goes up
+        memory[3] = 2;
+        memory[5] = 4;
+        memory[6] = 1;
+        assert_eq!(search.filter(&memory, Filter::Changed), 2);
+        assert_eq!(search.filter(&memory, Filter::Unchanged), 2);
+        memory[3] = 1;
+        memory[5] = 5;
+        assert_eq!(search.filter(&memory, Filter::Decreased), 1);
+        assert_eq!(search.candidates().collect::<Vec<_>>(), vec![(3, 1)]);
+        assert_eq!(search.filter(&memory, Filter::Increased), 0);
+    }
+
+    #[test]
+    fn saved_cheats_are_frozen_every_frame() {
+        let mut file = CheatFile::default();
+        let mut lives = Cheat::parse("0x300=9").unwrap();
+        lives.description = "Lives".to_string();
+        file.set_cheats(HASH, &[lives.clone()]);
+        let file = CheatFile::parse(&toml::to_string(&file).unwrap()).unwrap();
+        assert_eq!(file.cheats_for(HASH), vec![lives]);
+        assert!(file.cheats_for("other").is_empty());
+        assert!(Cheat::parse("0x1000=1").is_err());
+        assert!(Cheat::parse("0x300=100").is_err());
+
+        // I := 0x300, then decrement the byte there forever
+        let rom = [0xA3, 0x00, 0xF0, 0x65, 0x70, 0xFF, 0xF0, 0x55, 0x12, 0x02];
+        let mut chip8 = MachineBuilder::new(&rom).build().unwrap();
+        chip8.set_cheats(file.cheats_for(HASH));
+        assert_eq!(chip8.peek(0x300), Some(9));
+        chip8.run_frame(4);
+        assert_eq!(chip8.peek(0x300), Some(9));
+        chip8.set_cheats(Vec::new());
+        chip8.run_frame(4);
+        assert_eq!(chip8.peek(0x300), Some(8));
+    }
+}
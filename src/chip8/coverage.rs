@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::Write;
+
+use super::disasm;
+use super::memory::MEM_SIZE;
+use super::observer::{Access, AccessRecord};
+
+const ROM_START: usize = 0x200;
+
+const EXECUTED: u8 = 0b001;
+const READ: u8 = 0b010;
+const WRITTEN: u8 = 0b100;
+
+/// Instructions that skip the next one, the branches of CHIP-8
+pub fn is_skip(opcode: u16) -> bool {
+    match opcode & 0xF000 {
+        0x3000 | 0x4000 => true,
+        0x5000 | 0x9000 => opcode & 0x000F == 0,
+        0xE000 => matches!(opcode & 0x00FF, 0x9E | 0xA1),
+        _ => false,
+    }
+}
+
+/// What the machine did with every byte of memory
+pub struct Coverage {
+    flags: Box<[u8; MEM_SIZE]>,
+    /// Times an instruction started at the address
+    executions: Box<[u64; MEM_SIZE]>,
+    /// Skip instructions by address, with the times they skipped and did not
+    branches: BTreeMap<u16, (u64, u64)>,
+}
+
+/// Where the instructions of a ROM come from in its source, read from lines like
+/// `0x200 game.8o:12`, comments starting with `#`
+pub struct SourceMap {
+    lines: BTreeMap<u16, (String, u32)>,
+}
+
+impl SourceMap {
+    pub fn load(path: &str) -> Result<SourceMap, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Could not read source map {}: {}", path, e))?;
+        SourceMap::parse(&text).map_err(|e| format!("Invalid source map {}: {}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<SourceMap, String> {
+        let mut lines = BTreeMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || format!("line {}, expected ADDRESS FILE:LINE", number + 1);
+            let (address, location) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let address = u16::from_str_radix(address.trim_start_matches("0x"), 16).map_err(|_| invalid())?;
+            let (file, source_line) = location.trim().rsplit_once(':').ok_or_else(invalid)?;
+            let source_line = source_line.parse().map_err(|_| invalid())?;
+            lines.insert(address, (file.to_string(), source_line));
+        }
+        Ok(SourceMap { lines })
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            flags: Box::new([0; MEM_SIZE]),
+            executions: Box::new([0; MEM_SIZE]),
+            branches: BTreeMap::new(),
+        }
+    }
+
+    pub(super) fn record(&mut self, record: &AccessRecord) {
+        let address = record.address as usize % MEM_SIZE;
+        self.flags[address] |= match record.access {
+            Access::Execute => EXECUTED,
+            Access::Read => READ,
+            Access::Write => WRITTEN,
+        };
+        if record.access == Access::Execute && record.address == record.pc {
+            self.executions[address] += 1;
+        }
+    }
+
+    /// A skip instruction at `pc` ran, skipping the next one or not
+    pub fn record_branch(&mut self, pc: u16, skipped: bool) {
+        let (taken, not_taken) = self.branches.entry(pc).or_insert((0, 0));
+        if skipped {
+            *taken += 1;
+        } else {
+            *not_taken += 1;
+        }
+    }
+
+    fn flags(&self, address: usize) -> u8 {
+        self.flags.get(address).copied().unwrap_or(0)
+    }
+
+    /// Every word of the ROM, as `chipr-8 disasm` lists them, with what was done to its bytes,
+    /// how often it ran and which way its branch went
+    pub fn write_listing(&self, rom: &[u8], out: &mut dyn Write) -> io::Result<()> {
+        let words = rom.len().div_ceil(2);
+        let count = |flag: u8| (ROM_START..ROM_START + rom.len()).filter(|address| self.flags(*address) & flag != 0).count();
+        let code_words = (0..words).filter(|word| self.executions[ROM_START + word * 2] > 0).count();
+        let outcomes = self.branch_outcomes(rom);
+        writeln!(out, "{} of {} ROM words executed as instructions", code_words, words)?;
+        writeln!(out, "{} of {} ROM bytes executed, {} read as data, {} written", count(EXECUTED), rom.len(), count(READ), count(WRITTEN))?;
+        writeln!(out, "{} of {} branch outcomes taken", outcomes.iter().filter(|(_, count)| *count > 0).count(), outcomes.len())?;
+        writeln!(out, "\nADDR OPCODE XRW      COUNT")?;
+
+        for (word, bytes) in rom.chunks(2).enumerate() {
+            let address = ROM_START + word * 2;
+            let opcode = (bytes[0] as u16) << 8 | bytes.get(1).copied().unwrap_or(0) as u16;
+            let flags = self.flags(address) | self.flags(address + 1);
+            let flag = |bit: u8, c: char| if flags & bit != 0 { c } else { '-' };
+            let mut line = format!("{:03X}  {:04X}  {}{}{} {:>10}  {}", address, opcode, flag(EXECUTED, 'X'), flag(READ, 'R'),
+                                   flag(WRITTEN, 'W'), self.executions[address], disasm::disassemble(opcode));
+            if let Some((taken, not_taken)) = self.branches.get(&(address as u16)) {
+                line.push_str(&format!("  ; skipped {}, fell through {}", taken, not_taken));
+            } else if is_skip(opcode) && self.executions[address] == 0 && flags & (READ | WRITTEN) == 0 {
+                line.push_str("  ; never ran");
+            }
+            writeln!(out, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+
+    /// Both outcomes of every skip instruction in the ROM that ran or looks like code, with
+    /// how often each happened
+    fn branch_outcomes(&self, rom: &[u8]) -> Vec<((u16, bool), u64)> {
+        let mut outcomes = Vec::new();
+        for (word, bytes) in rom.chunks(2).enumerate() {
+            let address = (ROM_START + word * 2) as u16;
+            let opcode = (bytes[0] as u16) << 8 | bytes.get(1).copied().unwrap_or(0) as u16;
+            let (taken, not_taken) = match self.branches.get(&address) {
+                Some(counts) => *counts,
+                None if is_skip(opcode) && self.flags(address as usize) & (READ | WRITTEN) == 0 => (0, 0),
+                None => continue,
+            };
+            outcomes.push(((address, true), taken));
+            outcomes.push(((address, false), not_taken));
+        }
+        outcomes
+    }
+
+    /// An lcov tracefile, per source line through the source map or per line of
+    /// `chipr-8 disasm` under the ROM name without one. Words only read or written are data and left out.
+    pub fn write_lcov(&self, rom_path: &str, rom: &[u8], source_map: Option<&SourceMap>, out: &mut dyn Write) -> io::Result<()> {
+        // per file and line, the executions and the branch outcomes
+        let mut files: BTreeMap<String, BTreeMap<u32, (u64, Vec<u64>)>> = BTreeMap::new();
+        let outcomes = self.branch_outcomes(rom);
+        for word in 0..rom.len().div_ceil(2) {
+            let address = ROM_START + word * 2;
+            let executions = self.executions[address];
+            let flags = self.flags(address) | self.flags(address + 1);
+            if executions == 0 && flags & (READ | WRITTEN) != 0 {
+                continue;
+            }
+            let (file, line) = match source_map {
+                Some(map) => match map.lines.get(&(address as u16)) {
+                    Some((file, line)) => (file.clone(), *line),
+                    None => continue,
+                },
+                None => (rom_path.to_string(), word as u32 + 1),
+            };
+            let entry = files.entry(file).or_default().entry(line).or_insert((0, Vec::new()));
+            entry.0 = entry.0.max(executions);
+            for ((branch, _), count) in &outcomes {
+                if *branch as usize == address {
+                    entry.1.push(*count);
+                }
+            }
+        }
+
+        for (file, lines) in &files {
+            writeln!(out, "TN:")?;
+            writeln!(out, "SF:{}", file)?;
+            let (mut branches, mut branches_hit) = (0, 0);
+            for (line, (executions, outcomes)) in lines {
+                for (branch, count) in outcomes.iter().enumerate() {
+                    let taken = if *executions == 0 { "-".to_string() } else { count.to_string() };
+                    writeln!(out, "BRDA:{},0,{},{}", line, branch, taken)?;
+                    branches += 1;
+                    branches_hit += (*count > 0) as usize;
+                }
+            }
+            if branches > 0 {
+                writeln!(out, "BRF:{}", branches)?;
+                writeln!(out, "BRH:{}", branches_hit)?;
+            }
+            for (line, (executions, _)) in lines {
+                writeln!(out, "DA:{},{}", line, executions)?;
+            }
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(out, "LH:{}", lines.values().filter(|(executions, _)| *executions > 0).count())?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Chip8;
+    use crate::multimedia::headless::Headless;
+
+    // 200: I = 0x20E, V0 = [I], skip if V0 == 5, V1 = 1, [I] = V0, loop, data
+    const ROM: [u8; 16] = [0xA2, 0x0E, 0xF0, 0x65, 0x30, 0x05, 0x61, 0x01, 0xF0, 0x55, 0x12, 0x0A, 0x00, 0x00, 0x05, 0x00];
+
+    fn covered() -> Chip8 {
+        let mut chip8 = Chip8::new(Headless);
+        chip8.load_rom_bytes(&ROM).unwrap();
+        chip8.start_coverage();
+        chip8.run_frame(10);
+        chip8
+    }
+
+    fn lines(write: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> Vec<String> {
+        let mut out = Vec::new();
+        write(&mut out).unwrap();
+        String::from_utf8(out).unwrap().lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn listing_marks_code_data_and_branches() {
+        let mut chip8 = covered();
+        let coverage = chip8.take_coverage().unwrap();
+        let listing = lines(|out| coverage.write_listing(&ROM, out));
+        assert_eq!(listing[0], "5 of 8 ROM words executed as instructions");
+        assert_eq!(listing[2], "1 of 2 branch outcomes taken");
+        assert!(listing.contains(&"204  3005  X--          1  SE V0, 0x05  ; skipped 1, fell through 0".to_string()));
+        assert!(listing.contains(&"206  6101  ---          0  LD V1, 0x01".to_string()));
+        assert!(listing.contains(&"20E  0500  -RW          0  SYS 0x500".to_string()));
+        assert!(chip8.take_coverage().is_none());
+    }
+
+    #[test]
+    fn lcov_goes_through_the_source_map() {
+        let coverage = covered().take_coverage().unwrap();
+        let map = SourceMap::parse("# game\n0x200 game.8o:3\n202 game.8o:4\n204 game.8o:5\n206 game.8o:6\n").unwrap();
+        let lcov = lines(|out| coverage.write_lcov("game.ch8", &ROM, Some(&map), out));
+        assert_eq!(lcov, vec![
+            "TN:", "SF:game.8o", "BRDA:5,0,0,1", "BRDA:5,0,1,0", "BRF:2", "BRH:1",
+            "DA:3,1", "DA:4,1", "DA:5,1", "DA:6,0", "LF:4", "LH:3", "end_of_record",
+        ]);
+
+        let lcov = lines(|out| coverage.write_lcov("game.ch8", &ROM, None, out));
+        assert_eq!(lcov[1], "SF:game.ch8");
+        assert!(lcov.contains(&"DA:7,0".to_string()));
+        assert!(!lcov.contains(&"DA:8,0".to_string()));
+        assert!(SourceMap::parse("0x200 game.8o").is_err());
+    }
+}
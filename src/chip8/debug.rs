@@ -0,0 +1,197 @@
+use super::memory::MEM_SIZE;
+use super::observer::{Hit, Watchpoint};
+use super::Chip8;
+
+/// Registers in the order debuggers number them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    V(usize),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+pub const REGISTERS: [Register; 21] = [
+    Register::V(0x0), Register::V(0x1), Register::V(0x2), Register::V(0x3),
+    Register::V(0x4), Register::V(0x5), Register::V(0x6), Register::V(0x7),
+    Register::V(0x8), Register::V(0x9), Register::V(0xA), Register::V(0xB),
+    Register::V(0xC), Register::V(0xD), Register::V(0xE), Register::V(0xF),
+    Register::I, Register::Pc, Register::Sp, Register::Dt, Register::St,
+];
+
+/// Why the machine paused itself
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    Breakpoint(u16),
+    /// Stops after the instruction that made the access
+    Watchpoint(Hit),
+}
+
+impl Register {
+    /// Reads a register name, V0-VF, I, PC, SP, DT or ST in any case
+    pub fn parse(name: &str) -> Result<Register, String> {
+        let upper = name.to_ascii_uppercase();
+        match upper.as_str() {
+            "I" => Ok(Register::I),
+            "PC" => Ok(Register::Pc),
+            "SP" => Ok(Register::Sp),
+            "DT" => Ok(Register::Dt),
+            "ST" => Ok(Register::St),
+            _ => match upper.strip_prefix('V').filter(|x| x.len() == 1).and_then(|x| usize::from_str_radix(x, 16).ok()) {
+                Some(x) => Ok(Register::V(x)),
+                None => Err(format!("Unknown register '{}', expected V0-VF, I, PC, SP, DT or ST", name)),
+            },
+        }
+    }
+
+    /// Size in bytes
+    pub fn size(self) -> usize {
+        match self {
+            Register::I | Register::Pc => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl Chip8 {
+    pub fn is_paused(&self) -> bool {
+        self.is_paused
+    }
+
+    pub fn read_register(&self, register: Register) -> u16 {
+        match register {
+            Register::V(x) => self.v[x] as u16,
+            Register::I => self.i,
+            Register::Pc => self.pc,
+            Register::Sp => self.sp as u16,
+            Register::Dt => self.delay_timer as u16,
+            Register::St => self.sound_timer as u16,
+        }
+    }
+
+    /// Values are cut to what the register holds, SP to the stack size
+    pub fn write_register(&mut self, register: Register, value: u16) {
+        match register {
+            Register::V(x) => self.v[x] = value as u8,
+            Register::I => self.i = value & 0xFFF,
+            Register::Pc => self.pc = value & 0xFFF,
+            Register::Sp => self.sp = (value as usize).min(self.stack.len()),
+            Register::Dt => self.delay_timer = value as u8,
+            Register::St => self.sound_timer = value as u8,
+        }
+    }
+
+    /// Reads memory without triggering watchpoints, `None` outside of it
+    pub fn peek(&self, address: usize) -> Option<u8> {
+        if address < MEM_SIZE { Some(self.memory[address]) } else { None }
+    }
+
+    /// Writes memory without triggering watchpoints, returns whether the address exists
+    pub fn poke(&mut self, address: usize, value: u8) -> bool {
+        if address < MEM_SIZE {
+            self.memory[address] = value;
+        }
+        address < MEM_SIZE
+    }
+
+    /// Runs the instruction at PC even if the machine is paused or at a breakpoint
+    pub fn step_instruction(&mut self) {
+        if self.is_on {
+            self.step();
+            self.hit_watchpoint();
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.memory.observe().add_watchpoint(watchpoint);
+    }
+
+    /// Returns whether the watchpoint was set
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let removed = match self.memory.observer_mut() {
+            Some(observer) => observer.remove_watchpoint(watchpoint),
+            None => false,
+        };
+        self.memory.stop_observing_if_idle();
+        removed
+    }
+
+    /// Removes every breakpoint and watchpoint, when the debugger goes away
+    pub fn clear_breakpoints_and_watchpoints(&mut self) {
+        self.breakpoints.clear();
+        if let Some(observer) = self.memory.observer_mut() {
+            observer.clear_watchpoints();
+        }
+        self.memory.stop_observing_if_idle();
+    }
+
+    pub fn set_breakpoint(&mut self, address: u16, set: bool) {
+        if set {
+            self.breakpoints.insert(address);
+        } else {
+            self.breakpoints.remove(&address);
+        }
+    }
+
+    /// Why the machine last paused itself, if it did since the last call
+    pub fn take_stop(&mut self) -> Option<Stop> {
+        self.stop.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::observer::{Access, Condition, WatchKind};
+    use crate::multimedia::headless::Headless;
+
+    // I = 0x300, V0 = 7, [I] = V0, V0 = [I], loop
+    const ROM: [u8; 10] = [0xA3, 0x00, 0x60, 0x07, 0xF0, 0x55, 0xF0, 0x65, 0x12, 0x08];
+
+    #[test]
+    fn watchpoints_pause_after_the_access() {
+        let mut chip8 = Chip8::new(Headless);
+        chip8.load_rom_bytes(&ROM).unwrap();
+        chip8.add_watchpoint(Watchpoint { address: 0x300, len: 1, kind: WatchKind::Write, condition: Condition::Always });
+        chip8.add_watchpoint(Watchpoint { address: 0x2FF, len: 2, kind: WatchKind::Read, condition: Condition::Always });
+
+        chip8.run_frame(10);
+        assert!(chip8.is_paused());
+        let hit = match chip8.take_stop() {
+            Some(Stop::Watchpoint(hit)) => hit,
+            stop => panic!("stopped by {:?}", stop),
+        };
+        assert_eq!((hit.record.access, hit.record.address, hit.record.pc), (Access::Write, 0x300, 0x204));
+        assert_eq!(chip8.read_register(Register::Pc), 0x206);
+        assert_eq!(chip8.peek(0x300), Some(7));
+
+        chip8.set_paused(false);
+        chip8.run_frame(10);
+        assert!(matches!(chip8.take_stop(), Some(Stop::Watchpoint(hit)) if hit.watchpoint.kind == WatchKind::Read));
+        assert_eq!(chip8.read_register(Register::Pc), 0x208);
+    }
+
+    #[test]
+    fn registers_and_memory_can_be_changed() {
+        let mut chip8 = Chip8::new(Headless);
+        chip8.load_rom_bytes(&ROM).unwrap();
+        chip8.write_register(Register::V(3), 0x1FF);
+        chip8.write_register(Register::Sp, 100);
+        chip8.write_register(Register::I, 0xF300);
+        chip8.write_register(Register::Pc, 0x1204);
+        assert_eq!(chip8.read_register(Register::V(3)), 0xFF);
+        assert_eq!(chip8.read_register(Register::Sp), 16);
+        assert_eq!(chip8.read_register(Register::I), 0x300);
+        assert_eq!(chip8.read_register(Register::Pc), 0x204);
+        chip8.write_register(Register::Pc, 0x200);
+
+        assert!(chip8.poke(0x202, 0x61));
+        assert!(!chip8.poke(0x1000, 0));
+        assert_eq!(chip8.peek(0x1000), None);
+        chip8.step_instruction();
+        chip8.step_instruction();
+        assert_eq!(chip8.read_register(Register::V(1)), 7);
+    }
+}
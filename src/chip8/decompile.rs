@@ -0,0 +1,524 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::analysis::{self, Analysis, Edge};
+use super::coverage::is_skip;
+use super::disasm;
+
+const ROM_START: u16 = 0x200;
+
+/// What the decompiler writes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Syntax {
+    /// Octo's high-level assembly
+    Octo,
+    C,
+}
+
+impl Syntax {
+    pub fn parse(name: &str) -> Result<Syntax, String> {
+        match name {
+            "octo" => Ok(Syntax::Octo),
+            "c" => Ok(Syntax::C),
+            _ => Err(format!("Unknown syntax '{}', expected octo or c", name)),
+        }
+    }
+}
+
+/// A line of output, `address` being the instruction it starts at so labels can go before it
+struct Line {
+    address: Option<u16>,
+    depth: usize,
+    text: String,
+}
+
+/// Turns the code reachable from 0x200 into loops, ifs and named subroutines, falling back to
+/// labels and jumps where the control flow does not nest. Meant for reading: the output does not
+/// lay code and data out as the ROM does.
+pub struct Decompiler<'a> {
+    rom: &'a [u8],
+    syntax: Syntax,
+    instructions: BTreeMap<u16, u16>,
+    /// 0x200 and the call targets
+    functions: BTreeSet<u16>,
+    /// Branch targets with the instructions that branch there: jumps, calls, returns and both
+    /// ways out of skips
+    entries: BTreeMap<u16, BTreeSet<u16>>,
+    /// ANNN targets in the ROM outside of the code
+    data: BTreeSet<u16>,
+    lines: Vec<Line>,
+    labels: BTreeSet<u16>,
+    /// Instructions already in the output, folded ones included
+    written: BTreeSet<u16>,
+}
+
+impl<'a> Decompiler<'a> {
+    pub fn new(rom: &'a [u8], syntax: Syntax) -> Decompiler<'a> {
+        let analysis = Analysis::new(rom);
+        let instructions = analysis.blocks.values()
+            .flat_map(|block| block.instructions.iter().copied())
+            .collect::<BTreeMap<_, _>>();
+        let mut functions = analysis.subroutines.keys().copied().collect::<BTreeSet<_>>();
+        functions.insert(ROM_START);
+
+        let mut entries: BTreeMap<u16, BTreeSet<u16>> = BTreeMap::new();
+        for (address, opcode) in &instructions {
+            let next = analysis::successors(*address, *opcode);
+            for (target, edge) in &next {
+                if *edge != Edge::Next || next.len() > 1 {
+                    entries.entry(*target).or_default().insert(*address);
+                }
+            }
+        }
+        let code_end = |address: u16| instructions.contains_key(&address) || instructions.contains_key(&address.wrapping_sub(1));
+        let data = instructions.values()
+            .filter(|opcode| *opcode & 0xF000 == 0xA000)
+            .map(|opcode| opcode & 0x0FFF)
+            .filter(|address| *address >= ROM_START && ((address - ROM_START) as usize) < rom.len() && !code_end(*address))
+            .collect();
+
+        Decompiler { rom, syntax, instructions, functions, entries, data, lines: Vec::new(), labels: BTreeSet::new(), written: BTreeSet::new() }
+    }
+
+    pub fn decompile(mut self) -> String {
+        let mut functions = Vec::new();
+        for function in self.functions.clone() {
+            // code shared with a function already written is jumped to rather than repeated
+            let mut body = self.function_body(function);
+            body.retain(|address| *address == function || !self.written.contains(address));
+            let end = body.iter().next_back().map_or(function, |last| last + 2);
+            self.emit(&body, function, end, None, false, 1);
+            // code only jumped back to, written after the rest so the function starts at its entry
+            let first = body.iter().next().map_or(function, |first| *first);
+            self.emit(&body, first, function, None, false, 1);
+            functions.push((function, std::mem::take(&mut self.lines)));
+        }
+
+        let mut out = String::new();
+        let mut placed = BTreeSet::new();
+        for (function, lines) in functions {
+            out.push_str(&match self.syntax {
+                Syntax::Octo => format!(": {}\n", self.name(function)),
+                Syntax::C => format!("void {}() {{\n", self.name(function)),
+            });
+            for line in lines {
+                if let Some(address) = line.address {
+                    if self.labels.contains(&address) && address != function && placed.insert(address) {
+                        out.push_str(&match self.syntax {
+                            Syntax::Octo => format!(": {}\n", self.name(address)),
+                            Syntax::C => format!("{}:\n", self.name(address)),
+                        });
+                    }
+                }
+                out.push_str(&format!("{}{}\n", "    ".repeat(line.depth), line.text));
+            }
+            out.push_str(if self.syntax == Syntax::C { "}\n\n" } else { "\n" });
+        }
+        self.write_data(&mut out);
+        out
+    }
+
+    /// The instructions reachable from the entry without going into calls or other functions
+    fn function_body(&self, entry: u16) -> BTreeSet<u16> {
+        let mut body = BTreeSet::new();
+        let mut pending = vec![entry];
+        while let Some(address) = pending.pop() {
+            let opcode = match self.instructions.get(&address) {
+                Some(opcode) => *opcode,
+                None => continue,
+            };
+            if (address != entry && self.functions.contains(&address)) || !body.insert(address) {
+                continue;
+            }
+            for (target, edge) in analysis::successors(address, opcode) {
+                if edge != Edge::Call {
+                    pending.push(target);
+                }
+            }
+        }
+        body
+    }
+
+    /// Whether the instruction is only reached from `owner`, so it can be folded into its construct
+    fn only_entered_from(&self, address: u16, owner: u16) -> bool {
+        self.entries.get(&address).is_none_or(|sources| sources.iter().all(|source| *source == owner))
+            && !self.functions.contains(&address)
+    }
+
+    fn push(&mut self, address: Option<u16>, depth: usize, text: String) {
+        self.lines.push(Line { address, depth, text });
+    }
+
+    /// Writes the instructions of `body` in `[start, end)`, inside the loop `looping` (its head and
+    /// exit) if any. `in_loop_head` stops the loop starting at `start` from being found again.
+    fn emit(&mut self, body: &BTreeSet<u16>, start: u16, end: u16, looping: Option<(u16, u16)>, in_loop_head: bool, depth: usize) {
+        // the loop head is written by the instruction that starts the loop
+        let mut pos = match body.range(start..end.max(start)).find(|address| (in_loop_head && **address == start) || !self.written.contains(address)) {
+            Some(pos) => *pos,
+            None => return,
+        };
+        let mut first = true;
+        loop {
+            self.written.insert(pos);
+            let opcode = self.instructions[&pos];
+            let next = if !(first && in_loop_head) { self.find_loop(body, pos, end) } else { None };
+            first = false;
+            let after = if let Some(back) = next {
+                self.emit_loop(body, pos, back, depth)
+            } else if is_skip(opcode) {
+                self.emit_skip(body, pos, end, looping, depth)
+            } else {
+                self.push(Some(pos), depth, self.statement(opcode));
+                match opcode & 0xF000 {
+                    0x1000 => {
+                        self.labels.insert(opcode & 0x0FFF);
+                        None
+                    }
+                    0xB000 => None,
+                    _ if opcode == 0x00EE || !disasm::is_known(opcode) => None,
+                    _ => Some(pos + 2),
+                }
+            };
+
+            // falling through into what is not this function's code
+            let after = match after {
+                Some(after) if (after < end && !body.contains(&after)) || (after >= end && self.functions.contains(&after)) => {
+                    let text = match (self.functions.contains(&after), self.syntax) {
+                        (true, Syntax::Octo) => format!("jump {}", self.name(after)),
+                        (true, Syntax::C) => format!("goto {};", self.name(after)),
+                        (false, Syntax::Octo) => "# runs on into what is not code".to_string(),
+                        (false, Syntax::C) => "/* runs on into what is not code */".to_string(),
+                    };
+                    self.push(None, depth, text);
+                    None
+                }
+                after => after,
+            };
+            // instructions overlapping the ones just written are not passed over
+            let next = body.range(pos + 1..end.max(pos + 1)).find(|address| !self.written.contains(address)).copied();
+            if let Some(after) = after.filter(|after| body.contains(after) && next != Some(*after) && (*after < end || next.is_some())) {
+                self.labels.insert(after);
+                self.push(None, depth, match self.syntax {
+                    Syntax::Octo => format!("jump {}", self.name(after)),
+                    Syntax::C => format!("goto {};", self.name(after)),
+                });
+            }
+            pos = match next {
+                Some(pos) => pos,
+                None => return,
+            };
+        }
+    }
+
+    /// The last `JP head` in the range, the end of the loop starting at `head`
+    fn find_loop(&self, body: &BTreeSet<u16>, head: u16, end: u16) -> Option<u16> {
+        let back = body.range(head..end).rev()
+            .find(|address| self.instructions[address] == 0x1000 | head)
+            .copied()?;
+        let conditional = back >= 2 && body.contains(&(back - 2)) && is_skip(self.instructions[&(back - 2)]);
+        let owner = if conditional { back - 2 } else { back };
+        if back != head && !self.only_entered_from(back, owner) {
+            return None;
+        }
+        Some(back)
+    }
+
+    /// Returns where the code goes on after the loop, if it can be left
+    fn emit_loop(&mut self, body: &BTreeSet<u16>, head: u16, back: u16, depth: usize) -> Option<u16> {
+        let skip = back.checked_sub(2).filter(|skip| *skip > head && body.contains(skip) && is_skip(self.instructions[skip]));
+        self.push(Some(head), depth, match self.syntax {
+            Syntax::Octo => "loop".to_string(),
+            Syntax::C => "for (;;) {".to_string(),
+        });
+        self.emit(body, head, skip.unwrap_or(back), Some((head, back + 2)), true, depth + 1);
+        self.written.insert(back);
+        if let Some(skip) = skip {
+            self.written.insert(skip);
+            // skipping the jump back leaves the loop
+            let opcode = self.instructions[&skip];
+            let text = match self.syntax {
+                Syntax::Octo => format!("while {}", self.condition(opcode, false)),
+                Syntax::C => format!("if ({}) break;", self.condition(opcode, true)),
+            };
+            self.push(Some(skip), depth + 1, text);
+        }
+        self.push(None, depth, match self.syntax {
+            Syntax::Octo => "again".to_string(),
+            Syntax::C => "}".to_string(),
+        });
+        let exit = back + 2;
+        if skip.is_some() || body.range(head..back).any(|address| self.instructions[address] == 0x1000 | exit) {
+            Some(exit)
+        } else {
+            None
+        }
+    }
+
+    /// Returns where the code goes on after the construct the skip starts
+    fn emit_skip(&mut self, body: &BTreeSet<u16>, skip: u16, end: u16, looping: Option<(u16, u16)>, depth: usize) -> Option<u16> {
+        let opcode = self.instructions[&skip];
+        let next = skip + 2;
+        let folds = body.contains(&next) && self.only_entered_from(next, skip);
+        let next_opcode = self.instructions.get(&next).copied().unwrap_or(0);
+
+        if folds && next_opcode & 0xF000 == 0x1000 {
+            let target = next_opcode & 0x0FFF;
+            if matches!(looping, Some((_, exit)) if exit == target) {
+                let text = match self.syntax {
+                    Syntax::Octo => format!("while {}", self.condition(opcode, true)),
+                    Syntax::C => format!("if ({}) break;", self.condition(opcode, false)),
+                };
+                self.push(Some(skip), depth, text);
+                self.written.insert(next);
+                return Some(skip + 4);
+            }
+            if target > skip + 4 && target <= end {
+                return Some(self.emit_if(body, skip, target, depth));
+            }
+        }
+        if folds && !is_skip(next_opcode) {
+            let statement = self.statement(next_opcode);
+            let text = match self.syntax {
+                Syntax::Octo => format!("if {} then {}", self.condition(opcode, false), statement),
+                Syntax::C => format!("if ({}) {}", self.condition(opcode, false), statement),
+            };
+            self.push(Some(skip), depth, text);
+            self.written.insert(next);
+            if next_opcode & 0xF000 == 0x1000 {
+                self.labels.insert(next_opcode & 0x0FFF);
+            }
+            return Some(skip + 4);
+        }
+
+        self.labels.insert(skip + 4);
+        let text = match self.syntax {
+            Syntax::Octo => format!("if {} then jump {}", self.condition(opcode, true), self.name(skip + 4)),
+            Syntax::C => format!("if ({}) goto {};", self.condition(opcode, true), self.name(skip + 4)),
+        };
+        self.push(Some(skip), depth, text);
+        Some(next)
+    }
+
+    /// A skip over a forward jump: the code up to the jump target runs when the skip skips, and
+    /// code the jump target starts with runs otherwise when the first part ends in a jump past it.
+    /// Returns where both ways join.
+    fn emit_if(&mut self, body: &BTreeSet<u16>, skip: u16, target: u16, depth: usize) -> u16 {
+        let opcode = self.instructions[&skip];
+        let else_jump = target - 2;
+        let else_end = match self.instructions.get(&else_jump) {
+            Some(jump) if else_jump > skip + 4 && body.contains(&else_jump) && jump & 0xF000 == 0x1000
+                && jump & 0x0FFF > target && self.only_entered_from(else_jump, else_jump) => Some(jump & 0x0FFF),
+            _ => None,
+        };
+        self.written.insert(skip + 2);
+        if else_end.is_some() {
+            self.written.insert(else_jump);
+        }
+        self.push(Some(skip), depth, match self.syntax {
+            Syntax::Octo => format!("if {} begin", self.condition(opcode, true)),
+            Syntax::C => format!("if ({}) {{", self.condition(opcode, true)),
+        });
+        self.emit(body, skip + 4, else_end.map_or(target, |_| else_jump), None, false, depth + 1);
+        if let Some(else_end) = else_end {
+            self.push(None, depth, match self.syntax {
+                Syntax::Octo => "else".to_string(),
+                Syntax::C => "} else {".to_string(),
+            });
+            self.emit(body, target, else_end, None, false, depth + 1);
+        }
+        self.push(None, depth, match self.syntax {
+            Syntax::Octo => "end".to_string(),
+            Syntax::C => "}".to_string(),
+        });
+        else_end.unwrap_or(target)
+    }
+
+    fn name(&self, address: u16) -> String {
+        if address == ROM_START {
+            "main".to_string()
+        } else if self.functions.contains(&address) {
+            format!("sub_{:03X}", address)
+        } else if self.data.contains(&address) {
+            format!("data_{:03X}", address)
+        } else if self.instructions.contains_key(&address) {
+            format!("label_{:03X}", address)
+        } else {
+            format!("0x{:03X}", address)
+        }
+    }
+
+    /// The skip's condition when `skips`, or the one under which the next instruction runs
+    fn condition(&self, opcode: u16, skips: bool) -> String {
+        let x = opcode >> 8 & 0xF;
+        let y = opcode >> 4 & 0xF;
+        let nn = opcode & 0xFF;
+        // whether the skip skips on equality, or on a key being pressed
+        let on_equal = matches!(opcode & 0xF000, 0x3000 | 0x5000) || opcode & 0xF0FF == 0xE09E;
+        let equal = on_equal == skips;
+        match (opcode & 0xF000, self.syntax) {
+            (0xE000, Syntax::Octo) => format!("v{:x} {}", x, if equal { "key" } else { "-key" }),
+            (0xE000, Syntax::C) => format!("{}key_pressed(v{:x})", if equal { "" } else { "!" }, x),
+            (0x3000, _) | (0x4000, _) => format!("v{:x} {} 0x{:02X}", x, if equal { "==" } else { "!=" }, nn),
+            _ => format!("v{:x} {} v{:x}", x, if equal { "==" } else { "!=" }, y),
+        }
+    }
+
+    fn statement(&self, opcode: u16) -> String {
+        let x = opcode >> 8 & 0xF;
+        let y = opcode >> 4 & 0xF;
+        let n = opcode & 0xF;
+        let nn = opcode & 0xFF;
+        let nnn = opcode & 0x0FFF;
+        let target = |address: u16| {
+            if self.functions.contains(&address) || self.data.contains(&address) || self.instructions.contains_key(&address) {
+                self.name(address)
+            } else {
+                format!("0x{:03X}", address)
+            }
+        };
+        let octo = self.syntax == Syntax::Octo;
+        let text = match opcode & 0xF000 {
+            _ if !disasm::is_known(opcode) => return if octo {
+                format!("0x{:02X} 0x{:02X} # unknown opcode", opcode >> 8, opcode & 0xFF)
+            } else {
+                format!("/* DW 0x{:04X} */", opcode)
+            },
+            0x0000 if opcode == 0x00E0 => if octo { "clear".to_string() } else { "cls()".to_string() },
+            0x0000 => "return".to_string(),
+            0x1000 => if octo { format!("jump {}", target(nnn)) } else { format!("goto {}", target(nnn)) },
+            0x2000 => if octo { target(nnn) } else { format!("{}()", target(nnn)) },
+            0x6000 => if octo { format!("v{:x} := 0x{:02X}", x, nn) } else { format!("v{:x} = 0x{:02X}", x, nn) },
+            0x7000 => format!("v{:x} += 0x{:02X}", x, nn),
+            0x8000 => {
+                let operator = match (n, octo) {
+                    (0x0, true) => ":=",
+                    (0x0, false) => "=",
+                    (0x1, _) => "|=",
+                    (0x2, _) => "&=",
+                    (0x3, _) => "^=",
+                    (0x4, _) => "+=",
+                    (0x5, _) => "-=",
+                    (0x6, true) => ">>=",
+                    (0x7, true) => "=-",
+                    (0xE, true) => "<<=",
+                    (0x7, false) => return format!("v{:x} = v{:x} - v{:x};", x, y, x),
+                    (_, false) if x == y => return format!("v{:x} {}= 1;", x, if n == 0x6 { ">>" } else { "<<" }),
+                    (_, false) => return format!("v{:x} = {}(v{:x}, v{:x});", x, if n == 0x6 { "shr" } else { "shl" }, x, y),
+                    _ => unreachable!("8XY{:X} is not known", n),
+                };
+                format!("v{:x} {} v{:x}", x, operator, y)
+            }
+            0xA000 => if octo { format!("i := {}", target(nnn)) } else { format!("i = {}", target(nnn)) },
+            0xB000 => if octo { format!("jump0 {}", target(nnn)) } else { format!("goto *({} + v0)", target(nnn)) },
+            0xC000 => if octo { format!("v{:x} := random 0x{:02X}", x, nn) } else { format!("v{:x} = rand() & 0x{:02X}", x, nn) },
+            0xD000 => if octo { format!("sprite v{:x} v{:x} {}", x, y, n) } else { format!("draw(v{:x}, v{:x}, {})", x, y, n) },
+            _ => match (nn, octo) {
+                (0x07, true) => format!("v{:x} := delay", x),
+                (0x0A, true) => format!("v{:x} := key", x),
+                (0x15, true) => format!("delay := v{:x}", x),
+                (0x18, true) => format!("buzzer := v{:x}", x),
+                (0x1E, true) => format!("i += v{:x}", x),
+                (0x29, true) => format!("i := hex v{:x}", x),
+                (0x33, true) => format!("bcd v{:x}", x),
+                (0x55, true) => format!("save v{:x}", x),
+                (0x65, true) => format!("load v{:x}", x),
+                (0x07, false) => format!("v{:x} = delay", x),
+                (0x0A, false) => format!("v{:x} = wait_key()", x),
+                (0x15, false) => format!("delay = v{:x}", x),
+                (0x18, false) => format!("sound = v{:x}", x),
+                (0x1E, false) => format!("i += v{:x}", x),
+                (0x29, false) => format!("i = font(v{:x})", x),
+                (0x33, false) => format!("bcd(v{:x})", x),
+                (0x55, false) => format!("save(v{:x})", x),
+                _ => format!("load(v{:x})", x),
+            },
+        };
+        if octo { text } else { format!("{};", text) }
+    }
+
+    /// The bytes of every data label, up to the next label or instruction
+    fn write_data(&self, out: &mut String) {
+        let rom_end = ROM_START as usize + self.rom.len();
+        for address in &self.data {
+            let end = self.data.range(address + 1..).next().map_or(rom_end, |next| *next as usize)
+                .min(self.instructions.range(address..).next().map_or(rom_end, |(next, _)| *next as usize));
+            let bytes = &self.rom[(*address - ROM_START) as usize..end - ROM_START as usize];
+            let hex = bytes.chunks(8)
+                .map(|chunk| chunk.iter().map(|byte| format!("0x{:02X}", byte)).collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+            match self.syntax {
+                Syntax::Octo => {
+                    out.push_str(&format!(": {}\n", self.name(*address)));
+                    for line in hex {
+                        out.push_str(&format!("    {}\n", line.join(" ")));
+                    }
+                }
+                Syntax::C => {
+                    out.push_str(&format!("const uint8_t {}[] = {{\n", self.name(*address)));
+                    for line in hex {
+                        out.push_str(&format!("    {},\n", line.join(", ")));
+                    }
+                    out.push_str("};\n");
+                }
+            }
+            out.push('\n');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 200: v0 = 0, I = 21C
+    // 204: loop: draw, v0 += 8, if v0 == 0x40 then v1 = 1 else v1 = 2, loop while v0 != 0x40
+    // 216: call 21A, halt
+    // 21A: return
+    // 21C: sprite
+    const ROM: [u8; 30] = [
+        0x60, 0x00, 0xA2, 0x1C,
+        0xD0, 0x05, 0x70, 0x08, 0x30, 0x40, 0x12, 0x10, 0x61, 0x01, 0x12, 0x12, 0x61, 0x02,
+        0x30, 0x40, 0x12, 0x04,
+        0x22, 0x1A, 0x12, 0x18, 0x00, 0xEE,
+        0xF0, 0x90,
+    ];
+
+    #[test]
+    fn recovers_loops_ifs_and_subroutines_in_octo() {
+        let octo = Decompiler::new(&ROM, Syntax::Octo).decompile();
+        assert_eq!(octo, "\
+: main
+    v0 := 0x00
+    i := data_21C
+    loop
+        sprite v0 v0 5
+        v0 += 0x08
+        if v0 == 0x40 begin
+            v1 := 0x01
+        else
+            v1 := 0x02
+        end
+        while v0 != 0x40
+    again
+    sub_21A
+    loop
+    again
+
+: sub_21A
+    return
+
+: data_21C
+    0xF0 0x90
+
+");
+    }
+
+    #[test]
+    fn writes_c_like_code() {
+        let c = Decompiler::new(&ROM, Syntax::C).decompile();
+        assert!(c.starts_with("void main() {\n    v0 = 0x00;\n    i = data_21C;\n    for (;;) {\n"));
+        assert!(c.contains("\n        if (v0 == 0x40) {\n            v1 = 0x01;\n        } else {\n            v1 = 0x02;\n        }\n"));
+        assert!(c.contains("        if (v0 == 0x40) break;\n    }\n    sub_21A();\n    for (;;) {\n    }\n}\n"));
+        assert!(c.contains("const uint8_t data_21C[] = {\n    0xF0, 0x90,\n};\n"));
+        assert!(Syntax::parse("basic").is_err());
+    }
+}
@@ -26,6 +26,12 @@ impl IndexMut<usize> for Gfx {
 }
 
 
+impl Default for Gfx {
+    fn default() -> Gfx {
+        Gfx::new()
+    }
+}
+
 impl Gfx {
     pub fn new() -> Gfx {
         Gfx {
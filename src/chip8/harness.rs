@@ -0,0 +1,428 @@
+//! Runs ROM subroutines on their own to test them: a machine is set up with `MachineBuilder`,
+//! a subroutine called with `Chip8::run_until_return` and the state it leaves checked.
+//! `TestFile` does the same from a TOML file, for `chipr-8 test`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use super::debug::Register;
+use super::gfx::{Gfx, GFX_COLS, GFX_ROWS};
+use super::memory::MEM_SIZE;
+use super::quirks::Quirks;
+use super::{Chip8, STACK_SIZE};
+use crate::multimedia::input::{Command, EventManager};
+use crate::multimedia::screen::Drawable;
+use crate::multimedia::sound::Beeper;
+
+/// Instructions a subroutine may run before it is considered stuck, when not told otherwise
+pub const DEFAULT_MAX_CYCLES: u64 = 100_000;
+
+/// Frontend of the machines built here and in the opcode tests: nothing shown or heard, keys
+/// held for the whole run. Keys are looked up like the real frontends do, so a key past 0xF
+/// panics here too.
+pub(super) struct HeldKeys(pub(super) [bool; 16]);
+
+impl Drawable for HeldKeys {
+    fn draw(&mut self, gfx: &mut Gfx) {
+        gfx.set_needs_redraw(false);
+    }
+}
+
+impl Beeper for HeldKeys {
+    fn start_beep(&mut self, _offset: f32) {}
+
+    fn stop_beep(&mut self, _offset: f32) {}
+}
+
+impl EventManager for HeldKeys {
+    fn check_events(&mut self) {}
+
+    fn is_quiting(&self) -> bool {
+        false
+    }
+
+    fn is_key_pressed(&self, key: u8) -> bool {
+        self.0[key as usize]
+    }
+
+    fn commands(&self) -> &[Command] {
+        &[]
+    }
+}
+
+/// A machine with the ROM loaded and the registers, memory and keys a test needs.
+/// The random number generator is seeded with 0 unless told otherwise, so runs repeat.
+pub struct MachineBuilder {
+    rom: Vec<u8>,
+    quirks: Quirks,
+    seed: u64,
+    registers: Vec<(Register, u16)>,
+    memory: Vec<(u16, Vec<u8>)>,
+    keys: [bool; 16],
+}
+
+impl MachineBuilder {
+    pub fn new(rom: &[u8]) -> MachineBuilder {
+        MachineBuilder {
+            rom: rom.to_vec(),
+            quirks: Quirks::default(),
+            seed: 0,
+            registers: Vec::new(),
+            memory: Vec::new(),
+            keys: [false; 16],
+        }
+    }
+
+    pub fn quirks(mut self, quirks: Quirks) -> MachineBuilder {
+        self.quirks = quirks;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> MachineBuilder {
+        self.seed = seed;
+        self
+    }
+
+    pub fn register(mut self, register: Register, value: u16) -> MachineBuilder {
+        self.registers.push((register, value));
+        self
+    }
+
+    /// Writes the bytes from the address on, after the ROM is loaded
+    pub fn memory(mut self, address: u16, bytes: &[u8]) -> MachineBuilder {
+        self.memory.push((address, bytes.to_vec()));
+        self
+    }
+
+    /// Holds the key down for the whole run
+    pub fn key(mut self, key: u8) -> MachineBuilder {
+        if let Some(held) = self.keys.get_mut(key as usize) {
+            *held = true;
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<Chip8, String> {
+        let mut chip8 = Chip8::new(HeldKeys(self.keys));
+        chip8.load_rom_bytes(&self.rom)?;
+        chip8.set_quirks(self.quirks);
+        chip8.set_seed(self.seed);
+        for (register, value) in self.registers {
+            chip8.write_register(register, value);
+        }
+        for (address, bytes) in self.memory {
+            for (offset, byte) in bytes.iter().enumerate() {
+                if !chip8.poke(address as usize + offset, *byte) {
+                    return Err(format!("0x{:03X} is outside of memory", address as usize + offset));
+                }
+            }
+        }
+        Ok(chip8)
+    }
+}
+
+impl Chip8 {
+    /// Calls the subroutine at `address` as `2NNN` would and runs it until its `00EE`, at most
+    /// `max_cycles` instructions. The timers count down once per frame's worth of instructions
+    /// and PC is back where it was afterwards. Returns the instructions run.
+    pub fn run_until_return(&mut self, address: u16, max_cycles: u64) -> Result<u64, String> {
+        if self.sp >= STACK_SIZE {
+            return Err("the stack is full, the subroutine can not be called".to_string());
+        }
+        let caller = self.pc;
+        let depth = self.sp;
+        self.stack[self.sp] = caller;
+        self.sp += 1;
+        self.pc = address;
+
+        let mut cycles = 0;
+        while self.sp > depth {
+            if cycles == max_cycles {
+                return Err(format!("0x{:03X} did not return within {} cycles, stopped at 0x{:03X}", address, max_cycles, self.pc));
+            }
+            self.step_checked(cycles)?;
+            cycles += 1;
+        }
+        self.pc = caller;
+        Ok(cycles)
+    }
+
+    /// Runs the next instruction, `cycles` being the ones run before it, and counts the timers
+    /// down when a frame's worth has run. Fails when the machine stops.
+    fn step_checked(&mut self, cycles: u64) -> Result<(), String> {
+        self.step();
+        if let Some(error) = &self.error {
+            return Err(error.clone());
+        }
+        if (cycles + 1).is_multiple_of(self.cycles_per_frame.max(1) as u64) {
+            self.count_down_timers();
+        }
+        Ok(())
+    }
+
+    /// What no program may break: PC and I within memory and SP within the stack
+    fn check_invariants(&self) -> Result<(), String> {
+        if self.pc as usize >= MEM_SIZE {
+            return Err(format!("PC 0x{:04X} is outside of memory", self.pc));
+        }
+        if self.i as usize >= MEM_SIZE {
+            return Err(format!("I 0x{:04X} is outside of memory", self.i));
+        }
+        if self.sp > STACK_SIZE {
+            return Err(format!("SP {} is past the stack", self.sp));
+        }
+        Ok(())
+    }
+}
+
+/// Runs arbitrary bytes as a ROM for at most `max_cycles` instructions, with the keys set in
+/// `keys` held, checking the machine after every instruction. The program may stop the machine,
+/// that is not a failure. Returns the save state it ended in, which every run with the same
+/// seed must end in too.
+pub fn check_program(program: &[u8], seed: u64, keys: u16, max_cycles: u64) -> Result<Vec<u8>, String> {
+    let mut builder = MachineBuilder::new(program).seed(seed);
+    for key in (0..16).filter(|key| keys & 1 << key != 0) {
+        builder = builder.key(key);
+    }
+    let mut chip8 = match builder.build() {
+        Ok(chip8) => chip8,
+        // empty or too big for memory, which loading reports
+        Err(_) => return Ok(Vec::new()),
+    };
+    for cycles in 0..max_cycles {
+        let stopped = chip8.step_checked(cycles).is_err();
+        chip8.check_invariants().map_err(|e| format!("after {} instructions: {}", cycles + 1, e))?;
+        if stopped {
+            break;
+        }
+    }
+    Ok(chip8.save_state())
+}
+
+/// The display as rows of `#` for lit pixels and `.` for the others
+pub fn display_rows(gfx: &Gfx) -> Vec<String> {
+    (0..GFX_ROWS)
+        .map(|row| (0..GFX_COLS).map(|col| if gfx[row * GFX_COLS + col] { '#' } else { '.' }).collect())
+        .collect()
+}
+
+/// Tests of a ROM's subroutines, read from TOML:
+///
+/// ```toml
+/// rom = "game.ch8"
+///
+/// [[test]]
+/// name = "adds the score"
+/// call = 0x2A0
+/// registers = { V0 = 5, I = 0x300 }
+/// memory = { "0x300" = [1, 2] }
+///
+/// [test.expect]
+/// registers = { V0 = 7, VF = 0 }
+/// display = { x = 0, y = 0, rows = ["#.#", ".#."] }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestFile {
+    /// Relative to the test file
+    pub rom: String,
+    pub quirks: Option<String>,
+    pub seed: Option<u64>,
+    /// Default for the tests that do not give theirs
+    pub max_cycles: Option<u64>,
+    #[serde(rename = "test", default)]
+    pub tests: Vec<TestCase>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestCase {
+    pub name: String,
+    /// Subroutine address
+    pub call: u16,
+    pub max_cycles: Option<u64>,
+    /// Keys held down
+    #[serde(default)]
+    pub keys: Vec<u8>,
+    /// Register values by name, V0-VF, I, PC, SP, DT or ST
+    #[serde(default)]
+    pub registers: BTreeMap<String, u16>,
+    /// Bytes by address, in hex
+    #[serde(default)]
+    pub memory: BTreeMap<String, Vec<u8>>,
+    #[serde(default)]
+    pub expect: Expect,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expect {
+    #[serde(default)]
+    pub registers: BTreeMap<String, u16>,
+    #[serde(default)]
+    pub memory: BTreeMap<String, Vec<u8>>,
+    pub display: Option<DisplayRegion>,
+}
+
+/// Part of the display, `#` lit and `.` unlit, from its top left corner
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DisplayRegion {
+    #[serde(default)]
+    pub x: usize,
+    #[serde(default)]
+    pub y: usize,
+    pub rows: Vec<String>,
+}
+
+/// A test's name and what failed in it, if anything
+pub type Outcome = (String, Result<(), String>);
+
+fn parse_address(address: &str) -> Result<u16, String> {
+    u16::from_str_radix(address.trim_start_matches("0x").trim_start_matches("0X"), 16)
+        .map_err(|_| format!("Invalid address '{}', expected hex like 0x300", address))
+}
+
+impl TestFile {
+    /// Reads the test file and the ROM it tests
+    pub fn load(path: &str) -> Result<(TestFile, Vec<u8>), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Could not read test file {}: {}", path, e))?;
+        let file: TestFile = toml::from_str(&text).map_err(|e| format!("Invalid test file {}: {}", path, e))?;
+        let rom_path = Path::new(path).parent().unwrap_or_else(|| Path::new("")).join(&file.rom);
+        let rom = crate::rom::read(&rom_path.to_string_lossy())?;
+        Ok((file, rom))
+    }
+
+    /// Runs every test on a fresh machine, with what failed in each
+    pub fn run(&self, rom: &[u8]) -> Result<Vec<Outcome>, String> {
+        let quirks = match &self.quirks {
+            Some(quirks) => Quirks::parse(quirks)?,
+            None => Quirks::default(),
+        };
+        self.tests.iter()
+            .map(|test| Ok((test.name.clone(), test.run(rom, quirks, self.seed.unwrap_or(0), self.max_cycles)?)))
+            .collect()
+    }
+}
+
+impl TestCase {
+    /// `Err` for a test that can not run at all, `Ok(Err)` for one that fails
+    fn run(&self, rom: &[u8], quirks: Quirks, seed: u64, max_cycles: Option<u64>) -> Result<Result<(), String>, String> {
+        let context = |e: String| format!("Test '{}': {}", self.name, e);
+        let mut builder = MachineBuilder::new(rom).quirks(quirks).seed(seed);
+        for (name, value) in &self.registers {
+            builder = builder.register(Register::parse(name).map_err(context)?, *value);
+        }
+        for (address, bytes) in &self.memory {
+            builder = builder.memory(parse_address(address).map_err(context)?, bytes);
+        }
+        for key in &self.keys {
+            builder = builder.key(*key);
+        }
+        let mut chip8 = builder.build().map_err(context)?;
+
+        let max_cycles = self.max_cycles.or(max_cycles).unwrap_or(DEFAULT_MAX_CYCLES);
+        if let Err(e) = chip8.run_until_return(self.call, max_cycles) {
+            return Ok(Err(e));
+        }
+        let failures = self.expect.check(&chip8).map_err(context)?;
+        Ok(if failures.is_empty() { Ok(()) } else { Err(failures.join("\n")) })
+    }
+}
+
+impl Expect {
+    /// What differs from the expectations
+    fn check(&self, chip8: &Chip8) -> Result<Vec<String>, String> {
+        let mut failures = Vec::new();
+        for (name, expected) in &self.registers {
+            let actual = chip8.read_register(Register::parse(name)?);
+            if actual != *expected {
+                failures.push(format!("{} is 0x{:02X}, expected 0x{:02X}", name, actual, expected));
+            }
+        }
+        for (address, expected) in &self.memory {
+            let address = parse_address(address)?;
+            let actual = (0..expected.len()).map(|offset| chip8.peek(address as usize + offset)).collect::<Option<Vec<u8>>>();
+            if actual.as_ref() != Some(expected) {
+                failures.push(format!("memory at 0x{:03X} is {:02X?}, expected {:02X?}", address, actual.unwrap_or_default(), expected));
+            }
+        }
+        if let Some(display) = &self.display {
+            let rows = display_rows(chip8.gfx());
+            for (offset, expected) in display.rows.iter().enumerate() {
+                let actual = rows.get(display.y + offset)
+                    .map(|row| row.chars().skip(display.x).take(expected.chars().count()).collect::<String>())
+                    .unwrap_or_default();
+                if actual != *expected {
+                    failures.push(format!("display row {} from column {} is '{}', expected '{}'", display.y + offset, display.x, actual, expected));
+                }
+            }
+        }
+        Ok(failures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 200: JP 200
+    // 202: V0 += V1 with carry in VF, [I] = V0, RET
+    // 208: I = font V2, draw it at V3, V3, RET
+    // 210: wait for DT to run out, RET
+    const ROM: [u8; 24] = [
+        0x12, 0x00,
+        0x80, 0x14, 0xF0, 0x55, 0x00, 0xEE,
+        0xF2, 0x29, 0xD3, 0x35, 0x00, 0xEE, 0x00, 0x00,
+        0xF4, 0x07, 0x34, 0x00, 0x12, 0x10, 0x00, 0xEE,
+    ];
+
+    #[test]
+    fn calls_a_subroutine_until_it_returns() {
+        let mut chip8 = MachineBuilder::new(&ROM)
+            .register(Register::V(0), 0xF0)
+            .register(Register::V(1), 0x20)
+            .register(Register::I, 0x300)
+            .build()
+            .unwrap();
+        assert_eq!(chip8.run_until_return(0x202, 100), Ok(3));
+        assert_eq!(chip8.read_register(Register::V(0)), 0x10);
+        assert_eq!(chip8.read_register(Register::V(0xF)), 1);
+        assert_eq!(chip8.peek(0x300), Some(0x10));
+        assert_eq!((chip8.read_register(Register::Pc), chip8.read_register(Register::Sp)), (0x200, 0));
+
+        let mut chip8 = MachineBuilder::new(&ROM).register(Register::Dt, 3).build().unwrap();
+        assert!(chip8.run_until_return(0x210, 1000).unwrap() > 2 * chip8.cycles_per_frame() as u64);
+        assert!(chip8.run_until_return(0x200, 50).unwrap_err().contains("did not return within 50 cycles"));
+    }
+
+    #[test]
+    fn runs_test_files() {
+        let file: TestFile = toml::from_str(r#"
+            rom = "unused.ch8"
+
+            [[test]]
+            name = "draws a zero"
+            call = 0x208
+            registers = { V2 = 0, V3 = 1 }
+            [test.expect]
+            display = { x = 1, y = 1, rows = ['####', '#..#', '#..#', '#..#', '####'] }
+
+            [[test]]
+            name = "adds"
+            call = 0x202
+            registers = { V0 = 1, V1 = 2, I = 0x300 }
+            memory = { "0x300" = [9, 9] }
+            [test.expect]
+            registers = { V0 = 4 }
+            memory = { "0x300" = [3, 9] }
+        "#).unwrap();
+
+        let results = file.run(&ROM).unwrap();
+        assert_eq!(results[0], ("draws a zero".to_string(), Ok(())));
+        assert_eq!(results[1], ("adds".to_string(), Err("V0 is 0x03, expected 0x04".to_string())));
+    }
+}
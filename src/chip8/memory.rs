@@ -1,11 +1,17 @@
 use std::ops::{Index, IndexMut};
 
 use super::font::FONT_SET;
+use super::observer::{Access, AccessRecord, Observer};
 
 pub const MEM_SIZE: usize = 4096;
 
+pub const MAX_ROM_SIZE: usize = MEM_SIZE - 0x200;
+
+/// The 4 KB address space. Indexing reads and writes it as is, the machine goes through
+/// `read`, `write` and `fetch` so an observer can see what it does.
 pub struct Memory {
     memory: [u8; MEM_SIZE],
+    observer: Option<Box<Observer>>,
 }
 
 
@@ -28,17 +34,76 @@ impl Memory {
     pub fn new() -> Memory {
         Memory {
             memory: [0; MEM_SIZE],
+            observer: None,
+        }
+    }
+
+    /// Zeroes the memory, the observer keeps watching
+    pub fn clear(&mut self) {
+        self.memory = [0; MEM_SIZE];
+        if let Some(observer) = &mut self.observer {
+            observer.forget_code();
+        }
+    }
+
+    /// The observer, created on first use
+    pub fn observe(&mut self) -> &mut Observer {
+        self.observer.get_or_insert_with(|| Box::new(Observer::new()))
+    }
+
+    pub fn observer_mut(&mut self) -> Option<&mut Observer> {
+        self.observer.as_deref_mut()
+    }
+
+    /// Drops the observer when it neither watches nor logs anything, so accesses cost nothing again
+    pub fn stop_observing_if_idle(&mut self) {
+        if matches!(&self.observer, Some(observer) if observer.is_idle()) {
+            self.observer = None;
+        }
+    }
+
+    /// A read by the instruction at `pc`
+    pub fn read(&mut self, address: usize, pc: u16) -> u8 {
+        let value = self[address];
+        if let Some(observer) = &mut self.observer {
+            observer.record(AccessRecord { access: Access::Read, address: address as u16, pc, value, previous: value });
+        }
+        value
+    }
+
+    /// A write by the instruction at `pc`
+    pub fn write(&mut self, address: usize, value: u8, pc: u16) {
+        let previous = self[address];
+        self.memory[address] = value;
+        if let Some(observer) = &mut self.observer {
+            observer.record(AccessRecord { access: Access::Write, address: address as u16, pc, value, previous });
+        }
+    }
+
+    /// The opcode at `pc`, both of its bytes count as executed
+    pub fn fetch(&mut self, pc: u16) -> u16 {
+        let opcode = self.read_u16(pc as usize);
+        if let Some(observer) = &mut self.observer {
+            let next = ((pc as usize + 1) % MEM_SIZE) as u16;
+            for (address, value) in [(pc, (opcode >> 8) as u8), (next, opcode as u8)].iter().copied() {
+                observer.record(AccessRecord { access: Access::Execute, address, pc, value, previous: value });
+            }
         }
+        opcode
     }
 
-    pub fn load_rom(&mut self, vec: &Vec<u8>) {
-        if vec.len() > (0xFFF - 0x200) {
-            panic!("Invalid ROM size {}.", vec.len());
+    pub fn load_rom(&mut self, vec: &[u8]) -> Result<(), String> {
+        if vec.is_empty() {
+            return Err("the ROM is empty".to_string());
+        }
+        if vec.len() > MAX_ROM_SIZE {
+            return Err(format!("the ROM has {} bytes, at most {} fit in memory", vec.len(), MAX_ROM_SIZE));
         }
         for i in 0..vec.len() {
             self.memory[0x200 + i] = vec[i];
         }
         self.load_font();
+        Ok(())
     }
 
     pub fn dump(&self) {
@@ -63,9 +128,14 @@ impl Memory {
         }
     }
 
+    pub fn bytes(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// The big-endian word at `addr`, its second byte wrapping around to address 0 at the end of memory
     pub fn read_u16(&self, addr: usize) -> u16 {
         self.check_valid_addr(addr);
-        ((self.memory[addr] as u16) << 8) | self.memory[addr + 1] as u16
+        ((self.memory[addr] as u16) << 8) | self.memory[(addr + 1) % MEM_SIZE] as u16
     }
 
     fn check_valid_addr(&self, addr: usize) {
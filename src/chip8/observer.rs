@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+
+use super::coverage::Coverage;
+use super::memory::MEM_SIZE;
+
+/// How the machine touched memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Fetched as an instruction
+    Execute,
+}
+
+/// One memory access, `previous` is what a write replaced and the value itself otherwise
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccessRecord {
+    pub access: Access,
+    pub address: u16,
+    /// The instruction that made the access
+    pub pc: u16,
+    pub value: u8,
+    pub previous: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Reads and writes
+    Access,
+    Execute,
+}
+
+/// When a watchpoint triggers besides being accessed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Condition {
+    Always,
+    /// The value read, written or executed is this one
+    Equals(u8),
+    /// A write stores a different value than the one there
+    Changes,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub address: u16,
+    pub len: u16,
+    pub kind: WatchKind,
+    pub condition: Condition,
+}
+
+/// A watchpoint that triggered and the access that did it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hit {
+    pub watchpoint: Watchpoint,
+    pub record: AccessRecord,
+}
+
+/// Watches the accesses `Memory` reports to it. Memory only has one while something
+/// observes it, so there is nothing to pay for otherwise.
+pub struct Observer {
+    watchpoints: Vec<Watchpoint>,
+    hit: Option<Hit>,
+    log: Option<Log>,
+    /// Addresses fetched as instructions so far, to tell self-modifying writes apart
+    executed: Box<[bool; MEM_SIZE]>,
+    coverage: Option<Coverage>,
+}
+
+/// The last `capacity` accesses of the kinds asked for
+struct Log {
+    kinds: Vec<Access>,
+    capacity: usize,
+    records: VecDeque<AccessRecord>,
+}
+
+impl Watchpoint {
+    /// Parses `KIND:ADDRESS[,LENGTH][=VALUE|!]`, kind being `r`, `w`, `rw` or `x`, numbers in hex.
+    /// `=VALUE` triggers only on that value, `!` only on writes that change memory.
+    pub fn parse(spec: &str) -> Result<Watchpoint, String> {
+        let invalid = || format!("Invalid watchpoint '{}', expected KIND:ADDRESS[,LENGTH][=VALUE|!] like w:0x300", spec);
+
+        let (kind, rest) = spec.split_once(':').ok_or_else(invalid)?;
+        let kind = match kind {
+            "r" => WatchKind::Read,
+            "w" => WatchKind::Write,
+            "rw" => WatchKind::Access,
+            "x" => WatchKind::Execute,
+            _ => return Err(invalid()),
+        };
+        let (range, condition) = if let Some(range) = rest.strip_suffix('!') {
+            (range, Condition::Changes)
+        } else if let Some((range, value)) = rest.split_once('=') {
+            (range, Condition::Equals(parse_hex(value).filter(|v| *v <= 0xFF).ok_or_else(invalid)? as u8))
+        } else {
+            (rest, Condition::Always)
+        };
+        let (address, len) = match range.split_once(',') {
+            Some((address, len)) => (parse_hex(address), parse_hex(len)),
+            None => (parse_hex(range), Some(1)),
+        };
+        match (address, len) {
+            (Some(address), Some(len)) if address < MEM_SIZE as u32 && (1..=0xFFFF).contains(&len) => Ok(Watchpoint {
+                address: address as u16,
+                len: len as u16,
+                kind,
+                condition,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn triggers(&self, record: &AccessRecord) -> bool {
+        let kind_matches = match self.kind {
+            WatchKind::Read => record.access == Access::Read,
+            WatchKind::Write => record.access == Access::Write,
+            WatchKind::Access => record.access != Access::Execute,
+            WatchKind::Execute => record.access == Access::Execute,
+        };
+        let address = record.address as usize;
+        let in_range = address >= self.address as usize && address < self.address as usize + self.len.max(1) as usize;
+        let condition = match self.condition {
+            Condition::Always => true,
+            Condition::Equals(value) => record.value == value,
+            Condition::Changes => record.value != record.previous,
+        };
+        kind_matches && in_range && condition
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<u32> {
+    let hex = hex.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(hex, 16).ok()
+}
+
+impl Default for Observer {
+    fn default() -> Observer {
+        Observer::new()
+    }
+}
+
+impl Observer {
+    pub fn new() -> Observer {
+        Observer {
+            watchpoints: Vec::new(),
+            hit: None,
+            log: None,
+            executed: Box::new([false; MEM_SIZE]),
+            coverage: None,
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    /// Returns whether the watchpoint was set
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+        self.hit = None;
+    }
+
+    /// The first watchpoint that triggered since the last call
+    pub fn take_hit(&mut self) -> Option<Hit> {
+        self.hit.take()
+    }
+
+    /// Starts keeping the last `capacity` accesses of the given kinds, dropping the ones kept so far
+    pub fn start_log(&mut self, kinds: &[Access], capacity: usize) {
+        self.log = Some(Log {
+            kinds: kinds.to_vec(),
+            capacity,
+            records: VecDeque::with_capacity(capacity.min(1024)),
+        });
+    }
+
+    pub fn stop_log(&mut self) {
+        self.log = None;
+    }
+
+    /// The accesses logged since the last call, oldest first
+    pub fn take_log(&mut self) -> Vec<AccessRecord> {
+        match &mut self.log {
+            Some(log) => log.records.drain(..).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Starts recording what every byte is used for, dropping what was recorded so far
+    pub fn start_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    pub fn coverage_mut(&mut self) -> Option<&mut Coverage> {
+        self.coverage.as_mut()
+    }
+
+    /// Stops recording coverage, returning what was recorded
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    /// Whether the address was fetched as an instruction
+    pub fn is_code(&self, address: u16) -> bool {
+        self.executed.get(address as usize).copied().unwrap_or(false)
+    }
+
+    /// Forgets what was executed, when the memory is cleared
+    pub fn forget_code(&mut self) {
+        self.executed.fill(false);
+    }
+
+    /// Whether the write changed code the machine already ran
+    pub fn is_self_modifying(&self, record: &AccessRecord) -> bool {
+        record.access == Access::Write && record.value != record.previous && self.is_code(record.address)
+    }
+
+    /// Whether nothing would be lost by not observing anymore
+    pub fn is_idle(&self) -> bool {
+        self.watchpoints.is_empty() && self.log.is_none() && self.coverage.is_none()
+    }
+
+    pub(super) fn record(&mut self, record: AccessRecord) {
+        if record.access == Access::Execute {
+            self.executed[record.address as usize] = true;
+        }
+        if self.hit.is_none() {
+            if let Some(watchpoint) = self.watchpoints.iter().find(|w| w.triggers(&record)) {
+                self.hit = Some(Hit { watchpoint: *watchpoint, record });
+            }
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(&record);
+        }
+        if let Some(log) = &mut self.log {
+            if log.kinds.contains(&record.access) && log.capacity > 0 {
+                if log.records.len() == log.capacity {
+                    log.records.pop_front();
+                }
+                log.records.push_back(record);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::memory::Memory;
+
+    fn watchpoint(kind: WatchKind, condition: Condition) -> Watchpoint {
+        Watchpoint { address: 0x300, len: 2, kind, condition }
+    }
+
+    #[test]
+    fn watchpoints_trigger_on_their_kind_range_and_condition() {
+        let mut memory = Memory::new();
+        memory.observe().add_watchpoint(watchpoint(WatchKind::Write, Condition::Changes));
+        memory.write(0x300, 0, 0x200);
+        memory.read(0x300, 0x202);
+        memory.write(0x302, 1, 0x204);
+        assert_eq!(memory.observe().take_hit(), None);
+
+        memory.write(0x301, 5, 0x206);
+        let hit = memory.observe().take_hit().unwrap();
+        assert_eq!(hit.record, AccessRecord { access: Access::Write, address: 0x301, pc: 0x206, value: 5, previous: 0 });
+
+        let observer = memory.observe();
+        observer.clear_watchpoints();
+        observer.add_watchpoint(watchpoint(WatchKind::Access, Condition::Equals(5)));
+        memory.read(0x300, 0x208);
+        assert_eq!(memory.observe().take_hit(), None);
+        memory.read(0x301, 0x20A);
+        assert_eq!(memory.observe().take_hit().unwrap().record.pc, 0x20A);
+    }
+
+    #[test]
+    fn write_log_keeps_the_last_writes_and_spots_self_modifying_code() {
+        let mut memory = Memory::new();
+        memory.load_rom(&[0x60, 0x01, 0x12, 0x00]).unwrap();
+        memory.observe().start_log(&[Access::Write], 2);
+
+        memory.fetch(0x200);
+        memory.read(0x300, 0x200);
+        memory.write(0x300, 1, 0x202);
+        memory.write(0x301, 2, 0x202);
+        memory.write(0x201, 7, 0x202);
+
+        let observer = memory.observe();
+        let log = observer.take_log();
+        assert_eq!(log.iter().map(|record| record.address).collect::<Vec<_>>(), vec![0x301, 0x201]);
+        assert!(!observer.is_self_modifying(&log[0]));
+        assert!(observer.is_self_modifying(&log[1]));
+        assert!(observer.take_log().is_empty());
+
+        observer.stop_log();
+        assert!(observer.is_idle());
+        memory.stop_observing_if_idle();
+        assert!(memory.observer_mut().is_none());
+    }
+
+    #[test]
+    fn parses_watchpoints() {
+        assert_eq!(Watchpoint::parse("w:0x300").unwrap(), Watchpoint { address: 0x300, len: 1, kind: WatchKind::Write, condition: Condition::Always });
+        assert_eq!(Watchpoint::parse("rw:2A0,10=ff").unwrap(), Watchpoint { address: 0x2A0, len: 0x10, kind: WatchKind::Access, condition: Condition::Equals(0xFF) });
+        assert_eq!(Watchpoint::parse("x:0x208!").unwrap().condition, Condition::Changes);
+        for invalid in &["0x300", "q:0x300", "w:0x1000", "w:0x300,0", "w:0x300=100", "w:"] {
+            assert!(Watchpoint::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
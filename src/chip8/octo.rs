@@ -0,0 +1,719 @@
+//! Assembles Octo, the language of the Octo IDE and of the programs its cartridges carry.
+//!
+//! Covers the CHIP-8 instructions with Octo's names for them, its structured control flow
+//! (`if ... then`, `if ... begin`/`else`/`end`, `loop`/`while`/`again`, and the `<`, `>`, `<=`
+//! and `>=` comparisons through VF), labels, `:const`, `:alias`, `:org`, `:next`, `:unpack`,
+//! `:byte`, `:call`, `:macro` and `:calc`. SCHIP and XO-CHIP instructions are errors, the
+//! emulator does not run them.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use super::memory::MEM_SIZE;
+
+const ROM_START: usize = 0x200;
+
+/// Macro expansions allowed in one program, so a macro expanding to itself ends
+const MAX_EXPANSIONS: usize = 100_000;
+
+/// Assembles the program, erroring with the line of the first problem
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut assembler = Assembler {
+        tokens: tokenize(source),
+        line: 1,
+        memory: vec![0; MEM_SIZE],
+        here: ROM_START,
+        end: ROM_START,
+        labels: BTreeMap::new(),
+        constants: BTreeMap::new(),
+        aliases: BTreeMap::new(),
+        macros: BTreeMap::new(),
+        expansions: 0,
+        fixups: Vec::new(),
+        flow: Vec::new(),
+    };
+    while let Some(token) = assembler.next_token()? {
+        assembler.statement(&token).map_err(|e| format!("line {}: {}", assembler.line, e))?;
+    }
+    assembler.finish()
+}
+
+struct Token {
+    text: String,
+    line: usize,
+}
+
+/// Words separated by whitespace, `#` starting a comment up to the end of the line
+fn tokenize(source: &str) -> VecDeque<Token> {
+    source.lines().enumerate()
+        .flat_map(|(line, text)| {
+            let code = text.split('#').next().unwrap_or("");
+            code.split_whitespace().map(move |word| Token { text: word.to_string(), line: line + 1 })
+        })
+        .collect()
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<String>,
+}
+
+/// Where a name used before its label gets filled in
+enum Fixup {
+    /// The low 12 bits of the instruction at the address
+    Address(usize),
+    /// The byte at the address gets the label's top 4 bits, under the nibble
+    UnpackHigh(usize, u8),
+    /// The byte at the address gets the label's low byte
+    UnpackLow(usize),
+}
+
+/// An open control flow construct, with the jumps left to point past it
+enum Flow {
+    Loop { start: usize, breaks: Vec<usize> },
+    Begin { jump: usize },
+    Else { jump: usize },
+}
+
+/// A condition as the skips that skip when it holds and when it does not, with what sets VF
+/// up for the comparisons that go through it
+struct Condition {
+    setup: Vec<u16>,
+    skip_if_true: u16,
+    skip_if_false: u16,
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    line: usize,
+    memory: Vec<u8>,
+    here: usize,
+    /// One past the last byte written
+    end: usize,
+    labels: BTreeMap<String, usize>,
+    constants: BTreeMap<String, i64>,
+    aliases: BTreeMap<String, u16>,
+    macros: BTreeMap<String, Macro>,
+    expansions: usize,
+    fixups: Vec<(Fixup, String, usize)>,
+    flow: Vec<(Flow, usize)>,
+}
+
+impl Assembler {
+    /// The next token, with macros expanded
+    fn next_token(&mut self) -> Result<Option<String>, String> {
+        loop {
+            let token = match self.tokens.pop_front() {
+                Some(token) => token,
+                None => return Ok(None),
+            };
+            self.line = token.line;
+            let expansion = match self.macros.get(&token.text) {
+                Some(expansion) => expansion,
+                None => return Ok(Some(token.text)),
+            };
+            self.expansions += 1;
+            if self.expansions > MAX_EXPANSIONS {
+                return Err(format!("line {}: macro '{}' expands forever", token.line, token.text));
+            }
+            let mut arguments = BTreeMap::new();
+            for parameter in &expansion.parameters {
+                let argument = self.tokens.pop_front()
+                    .ok_or_else(|| format!("line {}: macro '{}' needs {} arguments", token.line, token.text, expansion.parameters.len()))?;
+                arguments.insert(parameter.clone(), argument.text);
+            }
+            for word in expansion.body.iter().rev() {
+                let text = arguments.get(word).unwrap_or(word).clone();
+                self.tokens.push_front(Token { text, line: token.line });
+            }
+        }
+    }
+
+    fn expect_token(&mut self) -> Result<String, String> {
+        self.next_token()?.ok_or_else(|| "the program ends in the middle of a statement".to_string())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.expect_token()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("expected '{}', found '{}'", expected, token)),
+        }
+    }
+
+    fn statement(&mut self, token: &str) -> Result<(), String> {
+        match token {
+            ":" => {
+                let name = self.expect_token()?;
+                self.define(name, self.here)?;
+            }
+            ":const" => {
+                let name = self.expect_token()?;
+                let value = self.token_value()?;
+                self.constants.insert(name, value);
+            }
+            ":calc" => {
+                let name = self.expect_token()?;
+                let value = self.braced_expression()?;
+                self.constants.insert(name, value);
+            }
+            ":alias" => {
+                let name = self.expect_token()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+            }
+            ":org" => {
+                let address = self.token_value()?;
+                if !(0..MEM_SIZE as i64).contains(&address) {
+                    return Err(format!(":org 0x{:X} is outside of memory", address));
+                }
+                self.here = address as usize;
+            }
+            ":next" => {
+                let name = self.expect_token()?;
+                self.define(name, self.here + 1)?;
+            }
+            ":unpack" => {
+                let nibble = self.token_value()? as u8 & 0xF;
+                let name = self.expect_token()?;
+                match self.known_value(&name) {
+                    Some(address) => {
+                        self.emit(0x6000 | (nibble as u16) << 4 | (address as u16 >> 8 & 0xF))?;
+                        self.emit(0x6100 | (address as u16 & 0xFF))?;
+                    }
+                    None => {
+                        self.fixups.push((Fixup::UnpackHigh(self.here + 1, nibble), name.clone(), self.line));
+                        self.emit(0x6000)?;
+                        self.fixups.push((Fixup::UnpackLow(self.here + 1), name, self.line));
+                        self.emit(0x6100)?;
+                    }
+                }
+            }
+            ":byte" => {
+                let value = if self.tokens.front().map(|token| token.text.as_str()) == Some("{") {
+                    self.braced_expression()?
+                } else {
+                    self.token_value()?
+                };
+                self.emit_byte(value as u8)?;
+            }
+            ":call" => self.address_instruction(0x2000)?,
+            ":macro" => self.define_macro()?,
+            ":breakpoint" => {
+                self.expect_token()?;
+            }
+            ":monitor" => {
+                self.expect_token()?;
+                self.expect_token()?;
+            }
+            "return" | ";" => self.emit(0x00EE)?,
+            "clear" => self.emit(0x00E0)?,
+            "bcd" => self.register_instruction(0xF033)?,
+            "save" => self.register_instruction(0xF055)?,
+            "load" => self.register_instruction(0xF065)?,
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.token_value()?;
+                if !(0..=15).contains(&n) {
+                    return Err(format!("sprites are 0 to 15 rows high, not {}", n));
+                }
+                self.emit(0xD000 | x << 8 | y << 4 | n as u16)?;
+            }
+            "jump" => self.address_instruction(0x1000)?,
+            "jump0" => self.address_instruction(0xB000)?,
+            "native" => self.address_instruction(0x0000)?,
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.emit(if token == "delay" { 0xF015 } else { 0xF018 } | x << 8)?;
+            }
+            "i" => self.i_statement()?,
+            "if" => self.if_statement()?,
+            "else" => match self.flow.pop() {
+                Some((Flow::Begin { jump }, _)) => {
+                    let skip_else = self.here;
+                    self.emit(0x1000)?;
+                    self.patch_jump(jump);
+                    self.flow.push((Flow::Else { jump: skip_else }, self.line));
+                }
+                _ => return Err("'else' without 'if ... begin'".to_string()),
+            },
+            "end" => match self.flow.pop() {
+                Some((Flow::Begin { jump }, _)) | Some((Flow::Else { jump }, _)) => self.patch_jump(jump),
+                _ => return Err("'end' without 'if ... begin'".to_string()),
+            },
+            "loop" => self.flow.push((Flow::Loop { start: self.here, breaks: Vec::new() }, self.line)),
+            "while" => {
+                let condition = self.condition()?;
+                let jump = self.here + 2 * (condition.setup.len() + 1);
+                match self.flow.iter_mut().rev().find_map(|(flow, _)| match flow {
+                    Flow::Loop { breaks, .. } => Some(breaks),
+                    _ => None,
+                }) {
+                    Some(breaks) => breaks.push(jump),
+                    None => return Err("'while' outside of a loop".to_string()),
+                }
+                self.emit_condition(&condition, condition.skip_if_true)?;
+                self.emit(0x1000)?;
+            }
+            "again" => match self.flow.pop() {
+                Some((Flow::Loop { start, breaks }, _)) => {
+                    self.emit(0x1000 | start as u16)?;
+                    for jump in breaks {
+                        self.patch_jump(jump);
+                    }
+                }
+                _ => return Err("'again' without 'loop'".to_string()),
+            },
+            "hires" | "lores" | "scroll-down" | "scroll-up" | "scroll-left" | "scroll-right" | "exit"
+            | "saveflags" | "loadflags" | "plane" | "audio" | "pitch" =>
+                return Err(format!("'{}' is an SCHIP or XO-CHIP instruction, only CHIP-8 ones run here", token)),
+            ":assert" | ":stringmode" | ":pointer" | ":proto" => return Err(format!("{} is not supported", token)),
+            _ if self.register_named(token).is_some() => self.register_statement(token)?,
+            _ => match parse_number(token).or_else(|| self.constants.get(token).copied()) {
+                Some(value) => self.emit_byte(value as u8)?,
+                None if token.starts_with(':') => return Err(format!("unknown directive '{}'", token)),
+                // a name on its own calls it
+                None => self.address_operand(0x2000, token.to_string())?,
+            },
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: String, address: usize) -> Result<(), String> {
+        if self.labels.insert(name.clone(), address).is_some() {
+            return Err(format!("label '{}' is defined twice", name));
+        }
+        Ok(())
+    }
+
+    /// `:macro NAME PARAMETERS... { BODY }`
+    fn define_macro(&mut self) -> Result<(), String> {
+        let name = self.expect_token()?;
+        let mut parameters = Vec::new();
+        loop {
+            let token = self.tokens.pop_front().ok_or("':macro' without '{'")?;
+            if token.text == "{" {
+                break;
+            }
+            parameters.push(token.text);
+        }
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.tokens.pop_front().ok_or("':macro' without '}'")?;
+            depth += match token.text.as_str() {
+                "{" => 1,
+                "}" => -1,
+                _ => 0,
+            };
+            if depth == 0 {
+                break;
+            }
+            body.push(token.text);
+        }
+        self.macros.insert(name, Macro { parameters, body });
+        Ok(())
+    }
+
+    fn register_named(&self, name: &str) -> Option<u16> {
+        let lower = name.to_ascii_lowercase();
+        match lower.strip_prefix('v') {
+            Some(digit) if digit.len() == 1 => u16::from_str_radix(digit, 16).ok(),
+            _ => self.aliases.get(name).copied(),
+        }
+    }
+
+    fn register(&mut self) -> Result<u16, String> {
+        let token = self.expect_token()?;
+        self.register_named(&token).ok_or_else(|| format!("expected a register, found '{}'", token))
+    }
+
+    fn register_instruction(&mut self, opcode: u16) -> Result<(), String> {
+        let x = self.register()?;
+        if self.tokens.front().map(|token| token.text.as_str()) == Some("-") {
+            return Err("saving and loading register ranges is XO-CHIP, only CHIP-8 instructions run here".to_string());
+        }
+        self.emit(opcode | x << 8)
+    }
+
+    /// A number, constant or known label
+    fn known_value(&self, token: &str) -> Option<i64> {
+        parse_number(token)
+            .or_else(|| self.constants.get(token).copied())
+            .or_else(|| self.labels.get(token).map(|address| *address as i64))
+    }
+
+    fn token_value(&mut self) -> Result<i64, String> {
+        let token = self.expect_token()?;
+        self.known_value(&token).ok_or_else(|| format!("unknown value '{}'", token))
+    }
+
+    fn byte_value(&mut self) -> Result<u16, String> {
+        let value = self.token_value()?;
+        if !(-128..=255).contains(&value) {
+            return Err(format!("{} does not fit in a byte", value));
+        }
+        Ok(value as u16 & 0xFF)
+    }
+
+    fn address_instruction(&mut self, opcode: u16) -> Result<(), String> {
+        let token = self.expect_token()?;
+        self.address_operand(opcode, token)
+    }
+
+    /// The instruction with an address, filled in later for labels not defined yet
+    fn address_operand(&mut self, opcode: u16, token: String) -> Result<(), String> {
+        match self.known_value(&token) {
+            Some(address) if (0..MEM_SIZE as i64).contains(&address) => self.emit(opcode | address as u16),
+            Some(address) => Err(format!("0x{:X} is outside of memory", address)),
+            None => {
+                self.fixups.push((Fixup::Address(self.here), token, self.line));
+                self.emit(opcode)
+            }
+        }
+    }
+
+    fn i_statement(&mut self) -> Result<(), String> {
+        match self.expect_token()?.as_str() {
+            ":=" => {
+                let token = self.expect_token()?;
+                match token.as_str() {
+                    "hex" => {
+                        let x = self.register()?;
+                        self.emit(0xF029 | x << 8)
+                    }
+                    "bighex" | "long" => Err(format!("'i := {}' is SCHIP or XO-CHIP, only CHIP-8 instructions run here", token)),
+                    _ => self.address_operand(0xA000, token),
+                }
+            }
+            "+=" => {
+                let x = self.register()?;
+                self.emit(0xF01E | x << 8)
+            }
+            operator => Err(format!("expected ':=' or '+=' after i, found '{}'", operator)),
+        }
+    }
+
+    fn register_statement(&mut self, register: &str) -> Result<(), String> {
+        let x = self.register_named(register).unwrap_or(0) << 8;
+        let operator = self.expect_token()?;
+        let operand = self.expect_token()?;
+        let y = self.register_named(&operand).map(|y| y << 4);
+        let opcode = match (operator.as_str(), y) {
+            (":=", _) if operand == "delay" => 0xF007 | x,
+            (":=", _) if operand == "key" => 0xF00A | x,
+            (":=", _) if operand == "random" => 0xC000 | x | self.byte_value()?,
+            (":=", Some(y)) => 0x8000 | x | y,
+            ("|=", Some(y)) => 0x8001 | x | y,
+            ("&=", Some(y)) => 0x8002 | x | y,
+            ("^=", Some(y)) => 0x8003 | x | y,
+            ("+=", Some(y)) => 0x8004 | x | y,
+            ("-=", Some(y)) => 0x8005 | x | y,
+            (">>=", Some(y)) => 0x8006 | x | y,
+            ("=-", Some(y)) => 0x8007 | x | y,
+            ("<<=", Some(y)) => 0x800E | x | y,
+            (":=", None) | ("+=", None) | ("-=", None) => {
+                self.tokens.push_front(Token { text: operand, line: self.line });
+                let value = self.byte_value()?;
+                match operator.as_str() {
+                    ":=" => 0x6000 | x | value,
+                    "+=" => 0x7000 | x | value,
+                    _ => 0x7000 | x | (0x100 - value) & 0xFF,
+                }
+            }
+            _ => return Err(format!("can not do '{} {}' on a register", operator, operand)),
+        };
+        self.emit(opcode)
+    }
+
+    /// `REGISTER OPERATOR OPERAND` or `REGISTER key`/`REGISTER -key`
+    fn condition(&mut self) -> Result<Condition, String> {
+        let left = self.expect_token()?;
+        let operator = self.expect_token()?;
+        let x = self.register_named(&left);
+        if operator == "key" || operator == "-key" {
+            let x = x.ok_or_else(|| format!("expected a register before {}, found '{}'", operator, left))? << 8;
+            let (pressed, released) = (0xE09E | x, 0xE0A1 | x);
+            return Ok(if operator == "key" {
+                Condition { setup: Vec::new(), skip_if_true: pressed, skip_if_false: released }
+            } else {
+                Condition { setup: Vec::new(), skip_if_true: released, skip_if_false: pressed }
+            });
+        }
+        let right = self.expect_token()?;
+        let y = self.register_named(&right);
+        let value = |assembler: &Assembler, token: &str| -> Result<u16, String> {
+            match assembler.known_value(token) {
+                Some(value) if (-128..=255).contains(&value) => Ok(value as u16 & 0xFF),
+                _ => Err(format!("expected a register or a byte, found '{}'", token)),
+            }
+        };
+
+        match operator.as_str() {
+            "==" | "!=" => {
+                let x = x.ok_or_else(|| format!("expected a register, found '{}'", left))? << 8;
+                let (equal, different) = match y {
+                    Some(y) => (0x5000 | x | y << 4, 0x9000 | x | y << 4),
+                    None => {
+                        let nn = value(self, &right)?;
+                        (0x3000 | x | nn, 0x4000 | x | nn)
+                    }
+                };
+                Ok(if operator == "==" {
+                    Condition { setup: Vec::new(), skip_if_true: equal, skip_if_false: different }
+                } else {
+                    Condition { setup: Vec::new(), skip_if_true: different, skip_if_false: equal }
+                })
+            }
+            "<" | ">" | "<=" | ">=" => {
+                // VF gets the carry of a - b, 0 when a < b
+                let (a, a_register, b, b_register) = if operator == "<" || operator == ">=" {
+                    (&left, x, &right, y)
+                } else {
+                    (&right, y, &left, x)
+                };
+                let setup = match (a_register, b_register) {
+                    (Some(a), Some(b)) => vec![0x8F00 | a << 4, 0x8F05 | b << 4],
+                    (None, Some(b)) => vec![0x6F00 | value(self, a)?, 0x8F05 | b << 4],
+                    (Some(a), None) => vec![0x6F00 | value(self, b)?, 0x8F07 | a << 4],
+                    (None, None) => return Err(format!("comparing {} and {} needs a register", left, right)),
+                };
+                let (borrow, no_borrow) = (0x3F00, 0x4F00);
+                Ok(if operator == "<" || operator == ">" {
+                    Condition { setup, skip_if_true: borrow, skip_if_false: no_borrow }
+                } else {
+                    Condition { setup, skip_if_true: no_borrow, skip_if_false: borrow }
+                })
+            }
+            _ => Err(format!("unknown comparison '{}'", operator)),
+        }
+    }
+
+    fn emit_condition(&mut self, condition: &Condition, skip: u16) -> Result<(), String> {
+        for opcode in &condition.setup {
+            self.emit(*opcode)?;
+        }
+        self.emit(skip)
+    }
+
+    /// `if CONDITION then` skips the next statement when the condition does not hold,
+    /// `if CONDITION begin` jumps to its `else` or `end`
+    fn if_statement(&mut self) -> Result<(), String> {
+        let condition = self.condition()?;
+        match self.expect_token()?.as_str() {
+            "then" => self.emit_condition(&condition, condition.skip_if_false),
+            "begin" => {
+                self.emit_condition(&condition, condition.skip_if_true)?;
+                self.flow.push((Flow::Begin { jump: self.here }, self.line));
+                self.emit(0x1000)
+            }
+            token => Err(format!("expected 'then' or 'begin', found '{}'", token)),
+        }
+    }
+
+    /// `{ EXPRESSION }`, evaluated right to left without precedence like Octo does
+    fn braced_expression(&mut self) -> Result<i64, String> {
+        self.expect("{")?;
+        let mut tokens = Vec::new();
+        loop {
+            match self.expect_token()? {
+                token if token == "}" => break,
+                token => tokens.push(token),
+            }
+        }
+        let (value, rest) = self.expression(&tokens)?;
+        if !rest.is_empty() {
+            return Err(format!("unexpected '{}' in expression", rest[0]));
+        }
+        Ok(value)
+    }
+
+    fn expression<'t>(&self, tokens: &'t [String]) -> Result<(i64, &'t [String]), String> {
+        let (left, rest) = self.term(tokens)?;
+        let operator = match rest.first() {
+            Some(operator) if operator != ")" => operator.as_str(),
+            _ => return Ok((left, rest)),
+        };
+        let (right, rest) = self.expression(&rest[1..])?;
+        let value = match operator {
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => return Err("division by zero".to_string()),
+            "/" => left / right,
+            "%" => left % right,
+            "&" => left & right,
+            "|" => left | right,
+            "^" => left ^ right,
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as i64,
+            ">" => (left > right) as i64,
+            "<=" => (left <= right) as i64,
+            ">=" => (left >= right) as i64,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            _ => return Err(format!("unknown operator '{}'", operator)),
+        };
+        Ok((value, rest))
+    }
+
+    fn term<'t>(&self, tokens: &'t [String]) -> Result<(i64, &'t [String]), String> {
+        let token = tokens.first().ok_or("the expression ends too early")?;
+        match token.as_str() {
+            "(" => {
+                let (value, rest) = self.expression(&tokens[1..])?;
+                match rest.first() {
+                    Some(close) if close == ")" => Ok((value, &rest[1..])),
+                    _ => Err("'(' without ')'".to_string()),
+                }
+            }
+            "-" | "~" | "!" => {
+                let (value, rest) = self.term(&tokens[1..])?;
+                Ok((match token.as_str() {
+                    "-" => value.wrapping_neg(),
+                    "~" => !value,
+                    _ => (value == 0) as i64,
+                }, rest))
+            }
+            "HERE" => Ok((self.here as i64, &tokens[1..])),
+            _ => {
+                let value = self.known_value(token).ok_or_else(|| format!("unknown value '{}'", token))?;
+                Ok((value, &tokens[1..]))
+            }
+        }
+    }
+
+    fn emit(&mut self, opcode: u16) -> Result<(), String> {
+        self.emit_byte((opcode >> 8) as u8)?;
+        self.emit_byte(opcode as u8)
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), String> {
+        if self.here >= MEM_SIZE {
+            return Err("the program does not fit in memory".to_string());
+        }
+        self.memory[self.here] = byte;
+        self.here += 1;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    /// Points the jump at `address` to here
+    fn patch_jump(&mut self, address: usize) {
+        self.memory[address] = 0x10 | (self.here >> 8) as u8 & 0xF;
+        self.memory[address + 1] = self.here as u8;
+    }
+
+    /// Fills in the labels used before they were defined and returns the program
+    fn finish(mut self) -> Result<Vec<u8>, String> {
+        if let Some((flow, line)) = self.flow.last() {
+            let (opened, closer) = match flow {
+                Flow::Loop { .. } => ("loop", "again"),
+                _ => ("if ... begin", "end"),
+            };
+            return Err(format!("line {}: '{}' without '{}'", line, opened, closer));
+        }
+        for (fixup, name, line) in &self.fixups {
+            let address = *self.labels.get(name).ok_or_else(|| format!("line {}: unknown name '{}'", line, name))?;
+            match *fixup {
+                Fixup::Address(at) => {
+                    self.memory[at] |= (address >> 8) as u8 & 0xF;
+                    self.memory[at + 1] = address as u8;
+                }
+                Fixup::UnpackHigh(at, nibble) => self.memory[at] = nibble << 4 | (address >> 8) as u8 & 0xF,
+                Fixup::UnpackLow(at) => self.memory[at] = address as u8,
+            }
+        }
+        Ok(self.memory[ROM_START..self.end.max(ROM_START)].to_vec())
+    }
+}
+
+/// Decimal, `0x` hex or `0b` binary, negative with a leading `-`
+fn parse_number(token: &str) -> Option<i64> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, token),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::decompile::{Decompiler, Syntax};
+    use crate::chip8::harness::{display_rows, MachineBuilder};
+
+    #[test]
+    fn assembles_structured_code() {
+        let program = assemble("
+            :alias x v3
+            :const ROWS 5
+            :calc STRIDE { ROWS * 2 + 1 }
+            : main
+                x := 0
+                i := digit
+                loop
+                    sprite x x ROWS
+                    x += 8
+                    if x == 0x40 begin v1 := 1 else v1 := 2 end
+                    while x != 0x40
+                again
+                if x < v1 then draw
+                if v2 -key then return
+                :unpack 0xA digit
+                v2 -= STRIDE
+            : draw
+                ;
+            : digit 0xF0 0x90
+        ").unwrap();
+        let expected = [
+            0x63, 0x00, 0xA2, 0x2C,
+            // loop
+            0xD3, 0x35, 0x73, 0x08,
+            0x33, 0x40, 0x12, 0x10, 0x61, 0x01, 0x12, 0x12, 0x61, 0x02,
+            0x43, 0x40, 0x12, 0x18, 0x12, 0x04,
+            // if x < v1 then draw
+            0x8F, 0x30, 0x8F, 0x15, 0x4F, 0x00, 0x22, 0x2A,
+            0xE2, 0x9E, 0x00, 0xEE,
+            // :unpack, then v2 -= 5 * (2 + 1) as Octo evaluates right to left
+            0x60, 0xA2, 0x61, 0x2C, 0x72, 0xF1,
+            0x00, 0xEE, 0xF0, 0x90,
+        ];
+        assert_eq!(program, expected);
+
+        assert_eq!(assemble("jump nowhere").unwrap_err(), "line 1: unknown name 'nowhere'");
+        assert_eq!(assemble("\nloop v0 += 1").unwrap_err(), "line 2: 'loop' without 'again'");
+        assert!(assemble("hires").unwrap_err().contains("SCHIP"));
+    }
+
+    #[test]
+    fn decompiled_programs_assemble_to_the_same_behaviour() {
+        // draws 8 digits across the screen, with a subroutine call and data
+        let rom = [
+            0x60, 0x00, 0x61, 0x00, 0xA2, 0x1A, 0xD0, 0x15, 0x70, 0x08, 0x30, 0x40, 0x12, 0x06,
+            0x22, 0x14, 0x12, 0x12, 0x00, 0x00, 0x71, 0x06, 0x00, 0xEE, 0x00, 0x00, 0xF0, 0x90,
+            0x90, 0x90, 0xF0,
+        ];
+        let source = Decompiler::new(&rom, Syntax::Octo).decompile();
+        let assembled = assemble(&source).unwrap_or_else(|e| panic!("{}\n{}", e, source));
+
+        let run = |program: &[u8]| {
+            let mut chip8 = MachineBuilder::new(program).build().unwrap();
+            chip8.run_frame(200);
+            display_rows(chip8.gfx())
+        };
+        assert_eq!(run(&assembled), run(&rom), "{}", source);
+    }
+}
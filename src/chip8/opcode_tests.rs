@@ -0,0 +1,386 @@
+//! One test per instruction: a machine is set up, runs the instruction at 0x200 and the
+//! registers, memory and display it leaves behind are checked
+
+use super::gfx::GFX_COLS;
+use super::harness::HeldKeys;
+use super::quirks::Quirks;
+use super::{Chip8, PC_START_ADDR};
+
+fn execute_with_keys(keys: &[u8], opcode: u16, setup: impl FnOnce(&mut Chip8)) -> Chip8 {
+    let mut pressed = [false; 16];
+    for key in keys {
+        pressed[*key as usize] = true;
+    }
+    let mut chip8 = Chip8::new(HeldKeys(pressed));
+    chip8.load_rom_bytes(&opcode.to_be_bytes()).unwrap();
+    setup(&mut chip8);
+    chip8.step();
+    assert_eq!(chip8.error(), None);
+    chip8
+}
+
+/// Runs `opcode` at 0x200 once `setup` prepared the machine
+fn execute(opcode: u16, setup: impl FnOnce(&mut Chip8)) -> Chip8 {
+    execute_with_keys(&[], opcode, setup)
+}
+
+fn quirks(spec: &str) -> Quirks {
+    Quirks::parse(spec).unwrap()
+}
+
+/// The display as rows of `#` and `.`, only the first `cols` columns and `rows` rows
+fn screen(chip8: &Chip8, cols: usize, rows: usize) -> Vec<String> {
+    (0..rows)
+        .map(|row| (0..cols).map(|col| if chip8.gfx[row * GFX_COLS + col] { '#' } else { '.' }).collect())
+        .collect()
+}
+
+#[test]
+fn cls_00e0_clears_the_display() {
+    let chip8 = execute(0x00E0, |chip8| {
+        chip8.gfx[0] = true;
+        chip8.gfx[2047] = true;
+    });
+    assert!((0..2048).all(|pixel| !chip8.gfx[pixel]));
+    assert_eq!(chip8.pc, 0x202);
+}
+
+#[test]
+fn ret_00ee_returns_past_the_call() {
+    let chip8 = execute(0x00EE, |chip8| {
+        chip8.stack[0] = 0x2A0;
+        chip8.sp = 1;
+    });
+    assert_eq!((chip8.pc, chip8.sp, chip8.stack[0]), (0x2A2, 0, 0));
+}
+
+#[test]
+fn jp_1nnn_jumps() {
+    assert_eq!(execute(0x1345, |_| ()).pc, 0x345);
+}
+
+#[test]
+fn call_2nnn_pushes_the_return_address() {
+    let chip8 = execute(0x2345, |_| ());
+    assert_eq!((chip8.pc, chip8.sp, chip8.stack[0]), (0x345, 1, PC_START_ADDR));
+}
+
+#[test]
+fn se_3xnn_and_sne_4xnn_compare_with_a_byte() {
+    let v3 = |chip8: &mut Chip8| chip8.v[3] = 0x12;
+    assert_eq!(execute(0x3312, v3).pc, 0x204);
+    assert_eq!(execute(0x3313, v3).pc, 0x202);
+    assert_eq!(execute(0x4312, v3).pc, 0x202);
+    assert_eq!(execute(0x4313, v3).pc, 0x204);
+}
+
+#[test]
+fn se_5xy0_and_sne_9xy0_compare_registers() {
+    let equal = |chip8: &mut Chip8| {
+        chip8.v[1] = 7;
+        chip8.v[2] = 7;
+    };
+    assert_eq!(execute(0x5120, equal).pc, 0x204);
+    assert_eq!(execute(0x9120, equal).pc, 0x202);
+    assert_eq!(execute(0x5130, equal).pc, 0x202);
+    assert_eq!(execute(0x9130, equal).pc, 0x204);
+}
+
+#[test]
+fn ld_6xnn_loads_a_byte() {
+    assert_eq!(execute(0x6A42, |_| ()).v[0xA], 0x42);
+}
+
+#[test]
+fn add_7xnn_wraps_without_touching_vf() {
+    let chip8 = execute(0x7510, |chip8| {
+        chip8.v[5] = 0xF8;
+        chip8.v[0xF] = 0x33;
+    });
+    assert_eq!((chip8.v[5], chip8.v[0xF]), (0x08, 0x33));
+}
+
+#[test]
+fn ld_8xy0_copies_a_register() {
+    assert_eq!(execute(0x8120, |chip8| chip8.v[2] = 9).v[1], 9);
+}
+
+#[test]
+fn logic_8xy1_8xy2_8xy3_reset_vf_only_with_the_quirk() {
+    for (opcode, result) in &[(0x8121, 0b1110), (0x8122, 0b1000), (0x8123, 0b0110)] {
+        for (quirk, vf) in &[("default", 5), ("default,+vf_reset", 0)] {
+            let chip8 = execute(*opcode, |chip8| {
+                chip8.set_quirks(quirks(quirk));
+                chip8.v[1] = 0b1100;
+                chip8.v[2] = 0b1010;
+                chip8.v[0xF] = 5;
+            });
+            assert_eq!((chip8.v[1], chip8.v[0xF]), (*result, *vf), "{:04X} {}", opcode, quirk);
+        }
+    }
+}
+
+#[test]
+fn add_8xy4_sets_vf_to_the_carry() {
+    let add = |a, b| {
+        let chip8 = execute(0x8124, |chip8| {
+            chip8.v[1] = a;
+            chip8.v[2] = b;
+        });
+        (chip8.v[1], chip8.v[0xF])
+    };
+    assert_eq!(add(0x10, 0x20), (0x30, 0));
+    assert_eq!(add(0xF0, 0x20), (0x10, 1));
+}
+
+#[test]
+fn sub_8xy5_and_subn_8xy7_set_vf_when_there_is_no_borrow() {
+    let sub = |opcode, a, b| {
+        let chip8 = execute(opcode, |chip8| {
+            chip8.v[1] = a;
+            chip8.v[2] = b;
+        });
+        (chip8.v[1], chip8.v[0xF])
+    };
+    assert_eq!(sub(0x8125, 0x30, 0x10), (0x20, 1));
+    assert_eq!(sub(0x8125, 0x10, 0x10), (0x00, 1));
+    assert_eq!(sub(0x8125, 0x10, 0x30), (0xE0, 0));
+    assert_eq!(sub(0x8127, 0x10, 0x30), (0x20, 1));
+    assert_eq!(sub(0x8127, 0x30, 0x10), (0xE0, 0));
+}
+
+#[test]
+fn shr_8xy6_and_shl_8xye_set_vf_to_the_bit_shifted_out() {
+    let shift = |opcode, value| {
+        let chip8 = execute(opcode, |chip8| chip8.v[1] = value);
+        (chip8.v[1], chip8.v[0xF])
+    };
+    assert_eq!(shift(0x8106, 0b0000_0011), (0b0000_0001, 1));
+    assert_eq!(shift(0x8106, 0b1000_0010), (0b0100_0001, 0));
+    assert_eq!(shift(0x810E, 0b1000_0001), (0b0000_0010, 1));
+    assert_eq!(shift(0x810E, 0b0100_0001), (0b1000_0010, 0));
+}
+
+#[test]
+fn shifts_shift_vy_with_the_quirk() {
+    let chip8 = execute(0x812E, |chip8| {
+        chip8.set_quirks(quirks("default,+shift_uses_vy"));
+        chip8.v[1] = 0x01;
+        chip8.v[2] = 0x81;
+    });
+    assert_eq!((chip8.v[1], chip8.v[2], chip8.v[0xF]), (0x02, 0x81, 1));
+}
+
+#[test]
+fn arithmetic_on_vf_keeps_the_flag() {
+    let flag = |opcode, vf, vy| {
+        execute(opcode, |chip8| {
+            chip8.v[0xF] = vf;
+            chip8.v[1] = vy;
+        }).v[0xF]
+    };
+    assert_eq!(flag(0x8F14, 0xFF, 0x02), 1);
+    assert_eq!(flag(0x8F15, 0x01, 0x02), 0);
+    assert_eq!(flag(0x8F17, 0x01, 0x02), 1);
+    assert_eq!(flag(0x8F06, 0x02, 0), 0);
+    assert_eq!(flag(0x8F0E, 0x80, 0), 1);
+}
+
+#[test]
+fn ld_annn_sets_i() {
+    assert_eq!(execute(0xA123, |_| ()).i, 0x123);
+}
+
+#[test]
+fn jp_bnnn_adds_v0_or_vx_with_the_quirk() {
+    let registers = |chip8: &mut Chip8| {
+        chip8.v[0] = 0x10;
+        chip8.v[3] = 0x20;
+    };
+    assert_eq!(execute(0xB300, registers).pc, 0x310);
+    let chip8 = execute(0xB300, |chip8| {
+        registers(chip8);
+        chip8.set_quirks(quirks("default,+jump_uses_vx"));
+    });
+    assert_eq!(chip8.pc, 0x320);
+}
+
+#[test]
+fn rnd_cxnn_masks_the_random_byte() {
+    for seed in 0..20 {
+        let chip8 = execute(0xC10F, |chip8| chip8.set_seed(seed));
+        assert_eq!(chip8.v[1] & 0xF0, 0);
+    }
+    assert_eq!(execute(0xC100, |chip8| chip8.v[1] = 0xFF).v[1], 0);
+}
+
+#[test]
+fn drw_dxyn_xors_the_sprite_and_reports_collisions() {
+    let sprite = |x, y| {
+        move |chip8: &mut Chip8| {
+            chip8.i = 0x300;
+            chip8.memory[0x300] = 0b1100_0000;
+            chip8.memory[0x301] = 0b1010_0000;
+            chip8.v[1] = x;
+            chip8.v[2] = y;
+        }
+    };
+    let chip8 = execute(0xD122, sprite(1, 1));
+    assert_eq!(screen(&chip8, 4, 3), vec!["....", ".##.", ".#.#"]);
+    assert_eq!((chip8.v[0xF], chip8.i), (0, 0x300));
+
+    let chip8 = execute(0xD122, |chip8| {
+        sprite(1, 1)(chip8);
+        chip8.gfx[GFX_COLS + 2] = true;
+        chip8.gfx[0] = true;
+    });
+    assert_eq!(screen(&chip8, 4, 3), vec!["#...", ".#..", ".#.#"]);
+    assert_eq!(chip8.v[0xF], 1);
+}
+
+#[test]
+fn drw_dxyn_wraps_pixels_around_the_same_row_and_column() {
+    let chip8 = execute(0xD122, |chip8| {
+        chip8.i = 0x300;
+        chip8.memory[0x300] = 0b1110_0000;
+        chip8.memory[0x301] = 0b1110_0000;
+        chip8.v[1] = 62;
+        chip8.v[2] = 31;
+    });
+    let lit = (0..2048).filter(|pixel| chip8.gfx[*pixel]).collect::<Vec<_>>();
+    assert_eq!(lit, vec![0, 62, 63, 31 * GFX_COLS, 31 * GFX_COLS + 62, 31 * GFX_COLS + 63]);
+}
+
+#[test]
+fn drw_dxyn_clips_at_the_edges_with_the_quirk_but_wraps_the_start() {
+    let chip8 = execute(0xD122, |chip8| {
+        chip8.set_quirks(quirks("default,+clip_sprites"));
+        chip8.i = 0x300;
+        chip8.memory[0x300] = 0b1110_0000;
+        chip8.memory[0x301] = 0b1110_0000;
+        chip8.v[1] = 62 + 64;
+        chip8.v[2] = 31;
+    });
+    let lit = (0..2048).filter(|pixel| chip8.gfx[*pixel]).collect::<Vec<_>>();
+    assert_eq!(lit, vec![31 * GFX_COLS + 62, 31 * GFX_COLS + 63]);
+}
+
+#[test]
+fn skp_ex9e_and_sknp_exa1_check_the_key_in_vx() {
+    let key_c = |chip8: &mut Chip8| chip8.v[4] = 0xC;
+    assert_eq!(execute_with_keys(&[0xC], 0xE49E, key_c).pc, 0x204);
+    assert_eq!(execute_with_keys(&[0xB], 0xE49E, key_c).pc, 0x202);
+    assert_eq!(execute_with_keys(&[0xC], 0xE4A1, key_c).pc, 0x202);
+    assert_eq!(execute_with_keys(&[], 0xE4A1, key_c).pc, 0x204);
+}
+
+#[test]
+fn skp_ex9e_and_sknp_exa1_only_look_at_the_low_nibble_of_vx() {
+    let mut chip8 = Chip8::new(HeldKeys([false; 16]));
+    chip8.load_rom_bytes(&[0x64, 0x20, 0xE4, 0x9E, 0x64, 0xFC, 0xE4, 0xA1]).unwrap();
+    for _ in 0..4 {
+        chip8.step();
+    }
+    assert_eq!(chip8.error(), None);
+    assert_eq!(chip8.pc, 0x20A);
+}
+
+#[test]
+fn ld_fx07_reads_the_delay_timer() {
+    assert_eq!(execute(0xF307, |chip8| chip8.delay_timer = 42).v[3], 42);
+}
+
+#[test]
+fn ld_fx0a_stores_the_key_pressed() {
+    assert_eq!(execute_with_keys(&[0xF], 0xF30A, |_| ()).v[3], 0xF);
+    assert_eq!(execute_with_keys(&[0x7, 0x9], 0xF30A, |_| ()).v[3], 0x7);
+}
+
+#[test]
+fn ld_fx0a_waits_without_blocking_frames_or_timers() {
+    let mut chip8 = Chip8::new(HeldKeys([false; 16]));
+    chip8.load_rom_bytes(&[0xF3, 0x0A]).unwrap();
+    chip8.delay_timer = 10;
+    chip8.sound_timer = 10;
+    for _ in 0..4 {
+        chip8.run_frame(10);
+    }
+    assert_eq!(chip8.error(), None);
+    assert_eq!(chip8.pc, 0x200);
+    assert_eq!(chip8.delay_timer, 6);
+    assert_eq!(chip8.sound_timer, 6);
+}
+
+#[test]
+fn ld_fx15_and_fx18_set_the_timers() {
+    let chip8 = execute(0xF315, |chip8| chip8.v[3] = 30);
+    assert_eq!(chip8.delay_timer, 30);
+    let chip8 = execute(0xF318, |chip8| chip8.v[3] = 20);
+    assert_eq!(chip8.sound_timer, 20);
+}
+
+#[test]
+fn add_fx1e_sets_vf_on_overflow_and_keeps_i_in_memory() {
+    let add = |i, vx| {
+        let chip8 = execute(0xF31E, |chip8| {
+            chip8.i = i;
+            chip8.v[3] = vx;
+            chip8.v[0xF] = 1;
+        });
+        (chip8.i, chip8.v[0xF])
+    };
+    assert_eq!(add(0x300, 0x10), (0x310, 0));
+    assert_eq!(add(0xFFF, 0x01), (0x000, 1));
+    assert_eq!(add(0xFF8, 0x10), (0x008, 1));
+}
+
+#[test]
+fn ld_fx29_points_i_to_the_font() {
+    assert_eq!(execute(0xF329, |chip8| chip8.v[3] = 0xA).i, 0xA * 5);
+}
+
+#[test]
+fn ld_fx33_stores_the_decimal_digits() {
+    let chip8 = execute(0xF333, |chip8| {
+        chip8.v[3] = 254;
+        chip8.i = 0x300;
+    });
+    assert_eq!((chip8.memory[0x300], chip8.memory[0x301], chip8.memory[0x302]), (2, 5, 4));
+    assert_eq!(chip8.i, 0x300);
+}
+
+#[test]
+fn ld_fx55_and_fx65_copy_v0_to_vx() {
+    for (quirk, i) in &[("default", 0x300), ("default,+load_store_increments_i", 0x303)] {
+        let chip8 = execute(0xF255, |chip8| {
+            chip8.set_quirks(quirks(quirk));
+            chip8.v[0] = 1;
+            chip8.v[1] = 2;
+            chip8.v[2] = 3;
+            chip8.v[3] = 4;
+            chip8.i = 0x300;
+        });
+        assert_eq!(&chip8.memory.bytes()[0x300..0x304], &[1, 2, 3, 0]);
+        assert_eq!(chip8.i, *i);
+
+        let chip8 = execute(0xF265, |chip8| {
+            chip8.set_quirks(quirks(quirk));
+            chip8.memory[0x300] = 5;
+            chip8.memory[0x301] = 6;
+            chip8.memory[0x302] = 7;
+            chip8.memory[0x303] = 8;
+            chip8.i = 0x300;
+        });
+        assert_eq!(&chip8.v.values()[..4], &[5, 6, 7, 0]);
+        assert_eq!(chip8.i, *i);
+    }
+}
+
+#[test]
+fn unknown_opcodes_stop_the_machine() {
+    let mut chip8 = Chip8::new(HeldKeys([false; 16]));
+    chip8.load_rom_bytes(&[0x80, 0x1F]).unwrap();
+    chip8.step();
+    assert_eq!(chip8.error(), Some("Unknown opcode 0x801F at 0x200"));
+    assert!(!chip8.is_on());
+}
@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::io::Write;
+
+use super::disasm;
+use super::memory::MEM_SIZE;
+
+/// Delay timer reads in a frame, with the timer running, that make the frame a busy-waiting one
+const BUSY_WAIT_POLLS: u32 = 2;
+
+/// Time spent in a subroutine, `None` being the code outside of any
+#[derive(Debug, PartialEq)]
+pub struct Subroutine {
+    pub address: Option<u16>,
+    pub calls: u64,
+    /// Instructions run in the subroutine and the ones it called
+    pub inclusive: u64,
+    /// Instructions run in the subroutine itself
+    pub exclusive: u64,
+}
+
+impl Subroutine {
+    fn name(&self) -> String {
+        frame_name(self.address)
+    }
+}
+
+fn frame_name(address: Option<u16>) -> String {
+    match address {
+        Some(address) => format!("sub_{:03X}", address),
+        None => "main".to_string(),
+    }
+}
+
+/// Counts what the machine runs, instruction by instruction
+pub struct Profiler {
+    executions: Box<[u64; MEM_SIZE]>,
+    /// The last opcode run at every address, for the disassembly
+    opcodes: Box<[u16; MEM_SIZE]>,
+    /// 2NNN calls by subroutine address
+    calls: BTreeMap<u16, u64>,
+    /// Instructions run with these subroutines on the call stack, outermost first
+    stacks: HashMap<Vec<u16>, u64>,
+    /// Reused to look the current call stack up without allocating
+    path: Vec<u16>,
+    instructions: u64,
+    frames: u64,
+    /// FX07 run this frame while the delay timer was running
+    frame_polls: u32,
+    busy_frames: u64,
+    /// FX07 addresses read while the delay timer was running, the loops that wait on it
+    delay_polls: BTreeMap<u16, u64>,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            executions: Box::new([0; MEM_SIZE]),
+            opcodes: Box::new([0; MEM_SIZE]),
+            calls: BTreeMap::new(),
+            stacks: HashMap::new(),
+            path: Vec::new(),
+            instructions: 0,
+            frames: 0,
+            frame_polls: 0,
+            busy_frames: 0,
+            delay_polls: BTreeMap::new(),
+        }
+    }
+
+    /// Counts the instruction about to run at `pc`, `subroutines` being the ones the call
+    /// stack is in, outermost first
+    pub fn record(&mut self, pc: u16, opcode: u16, subroutines: impl Iterator<Item = u16>, delay_timer: u8) {
+        let address = pc as usize % MEM_SIZE;
+        self.executions[address] += 1;
+        self.opcodes[address] = opcode;
+        self.instructions += 1;
+
+        self.path.clear();
+        self.path.extend(subroutines);
+        match self.stacks.get_mut(&self.path[..]) {
+            Some(cycles) => *cycles += 1,
+            None => {
+                self.stacks.insert(self.path.clone(), 1);
+            }
+        }
+
+        if opcode & 0xF000 == 0x2000 {
+            *self.calls.entry(opcode & 0x0FFF).or_insert(0) += 1;
+        }
+        if opcode & 0xF0FF == 0xF007 && delay_timer > 0 {
+            *self.delay_polls.entry(pc).or_insert(0) += 1;
+            self.frame_polls += 1;
+        }
+    }
+
+    /// Called once per 60 Hz frame that ran
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+        if self.frame_polls >= BUSY_WAIT_POLLS {
+            self.busy_frames += 1;
+        }
+        self.frame_polls = 0;
+    }
+
+    /// Every subroutine run, the code outside of them first, then by inclusive time
+    pub fn subroutines(&self) -> Vec<Subroutine> {
+        let mut subroutines: BTreeMap<Option<u16>, Subroutine> = BTreeMap::new();
+        subroutines.insert(None, Subroutine { address: None, calls: 0, inclusive: self.instructions, exclusive: 0 });
+        for (address, calls) in &self.calls {
+            subroutines.insert(Some(*address), Subroutine { address: Some(*address), calls: *calls, inclusive: 0, exclusive: 0 });
+        }
+        for (stack, cycles) in &self.stacks {
+            let top = stack.last().copied();
+            let entry = |address| Subroutine { address, calls: 0, inclusive: 0, exclusive: 0 };
+            subroutines.entry(top).or_insert_with(|| entry(top)).exclusive += cycles;
+            // recursive calls count once
+            let mut seen = Vec::new();
+            for address in stack {
+                if !seen.contains(address) {
+                    seen.push(*address);
+                    subroutines.entry(Some(*address)).or_insert_with(|| entry(Some(*address))).inclusive += cycles;
+                }
+            }
+        }
+        let mut subroutines = subroutines.into_values().collect::<Vec<_>>();
+        subroutines.sort_by_key(|subroutine| (subroutine.address.is_some(), std::cmp::Reverse(subroutine.inclusive)));
+        subroutines
+    }
+
+    /// Subroutine times, delay timer busy-waiting and the disassembly of everything that
+    /// ran with how often it did
+    pub fn write_report(&self, out: &mut dyn Write) -> io::Result<()> {
+        let share = |count: u64| 100.0 * count as f64 / self.instructions.max(1) as f64;
+        writeln!(out, "{} instructions in {} frames", self.instructions, self.frames)?;
+        writeln!(out, "{} frames ({:.1}%) busy-waiting on the delay timer", self.busy_frames,
+                 100.0 * self.busy_frames as f64 / self.frames.max(1) as f64)?;
+        for (address, polls) in &self.delay_polls {
+            writeln!(out, "    {:03X}  {:04X}  read {} times while running", address, self.opcodes[*address as usize], polls)?;
+        }
+
+        writeln!(out, "\n{:<10} {:>8} {:>12} {:>7} {:>12} {:>7}", "SUBROUTINE", "CALLS", "INCLUSIVE", "%", "EXCLUSIVE", "%")?;
+        for subroutine in self.subroutines() {
+            writeln!(out, "{:<10} {:>8} {:>12} {:>6.2}% {:>12} {:>6.2}%", subroutine.name(), subroutine.calls,
+                     subroutine.inclusive, share(subroutine.inclusive), subroutine.exclusive, share(subroutine.exclusive))?;
+        }
+
+        writeln!(out, "\n{:>12} {:>7}  ADDR OPCODE", "COUNT", "%")?;
+        let mut previous: Option<usize> = None;
+        for address in (0..MEM_SIZE).filter(|address| self.executions[*address] > 0) {
+            if self.calls.contains_key(&(address as u16)) {
+                writeln!(out, "{}:", frame_name(Some(address as u16)))?;
+            } else if matches!(previous, Some(previous) if previous + 2 != address) {
+                writeln!(out, "{:>12}", "...")?;
+            }
+            let count = self.executions[address];
+            let opcode = self.opcodes[address];
+            writeln!(out, "{:>12} {:>6.2}%  {:03X}  {:04X}  {}", count, share(count), address, opcode, disasm::disassemble(opcode))?;
+            previous = Some(address);
+        }
+        Ok(())
+    }
+
+    /// One line per call stack with the instructions run in it, the format flame graph tools read
+    pub fn write_folded(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut lines = self.stacks.iter()
+            .map(|(stack, cycles)| {
+                let frames = std::iter::once(None).chain(stack.iter().map(|address| Some(*address)));
+                (frames.map(frame_name).collect::<Vec<_>>().join(";"), cycles)
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        for (stack, cycles) in lines {
+            writeln!(out, "{} {}", stack, cycles)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chip8::Chip8;
+    use crate::multimedia::headless::Headless;
+
+    // 200: DT = 5, call 20A, loop
+    // 20A: wait until DT is 0, return
+    const ROM: [u8; 18] = [
+        0x60, 0x05, 0xF0, 0x15, 0x22, 0x0A, 0x12, 0x00, 0x00, 0x00,
+        0xF1, 0x07, 0x31, 0x00, 0x12, 0x0A, 0x00, 0xEE,
+    ];
+
+    fn profiled(frames: usize) -> Chip8 {
+        let mut chip8 = Chip8::new(Headless);
+        chip8.load_rom_bytes(&ROM).unwrap();
+        chip8.start_profiling();
+        for _ in 0..frames {
+            chip8.run_frame(10);
+        }
+        chip8
+    }
+
+    #[test]
+    fn subroutine_time_is_split_into_inclusive_and_exclusive() {
+        let profiler = profiled(20).take_profiler().unwrap();
+        let executions = |addresses: &[usize]| addresses.iter().map(|address| profiler.executions[*address]).sum::<u64>();
+
+        let subroutines = profiler.subroutines();
+        assert_eq!(subroutines.len(), 2);
+        let (main, wait) = (&subroutines[0], &subroutines[1]);
+        assert_eq!(main.inclusive, 200);
+        assert_eq!(main.exclusive, executions(&[0x200, 0x202, 0x204, 0x206]));
+        assert_eq!(wait.address, Some(0x20A));
+        assert_eq!(wait.calls, profiler.executions[0x204]);
+        assert_eq!(wait.inclusive, executions(&[0x20A, 0x20C, 0x20E, 0x210]));
+        assert_eq!(wait.exclusive, wait.inclusive);
+        assert!(profiler.busy_frames >= 15);
+    }
+
+    #[test]
+    fn writes_a_report_and_folded_stacks() {
+        let profiler = profiled(3).take_profiler().unwrap();
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        let lines = folded.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("main "));
+        assert!(lines[1].starts_with("main;sub_20A "));
+
+        let mut report = Vec::new();
+        profiler.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("30 instructions in 3 frames\n"));
+        assert!(report.contains("\nsub_20A:\n"));
+        assert!(report.contains("20A  F107  LD V1, DT"));
+    }
+}
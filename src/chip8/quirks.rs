@@ -0,0 +1,144 @@
+/// Behaviours that differ between CHIP-8 implementations, ROMs written for
+/// one platform often depend on them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    /// 8XY1, 8XY2 and 8XY3 reset VF to 0
+    pub vf_reset: bool,
+    /// 8XY6 and 8XYE shift VY into VX instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    /// FX55 and FX65 leave I pointing after the last register accessed
+    pub load_store_increments_i: bool,
+    /// BNNN jumps to XNN + VX instead of NNN + V0
+    pub jump_uses_vx: bool,
+    /// Sprites are clipped at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+}
+
+pub const PRESETS: [&str; 4] = ["default", "chip8", "schip", "xochip"];
+
+pub const NAMES: [&str; 5] = ["vf_reset", "shift_uses_vy", "load_store_increments_i", "jump_uses_vx", "clip_sprites"];
+
+impl Default for Quirks {
+    /// The behaviour Chipr-8 always had
+    fn default() -> Quirks {
+        Quirks {
+            vf_reset: false,
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: false,
+            clip_sprites: false,
+        }
+    }
+}
+
+impl Quirks {
+    pub fn preset(name: &str) -> Result<Quirks, String> {
+        match name {
+            "default" => Ok(Quirks::default()),
+            // original COSMAC VIP interpreter
+            "chip8" | "vip" => Ok(Quirks {
+                vf_reset: true,
+                shift_uses_vy: true,
+                load_store_increments_i: true,
+                jump_uses_vx: false,
+                clip_sprites: true,
+            }),
+            "schip" => Ok(Quirks {
+                vf_reset: false,
+                shift_uses_vy: false,
+                load_store_increments_i: false,
+                jump_uses_vx: true,
+                clip_sprites: true,
+            }),
+            "xochip" => Ok(Quirks {
+                vf_reset: false,
+                shift_uses_vy: true,
+                load_store_increments_i: true,
+                jump_uses_vx: false,
+                clip_sprites: false,
+            }),
+            _ => Err(format!("Unknown quirks preset '{}', expected one of {}", name, PRESETS.join(", "))),
+        }
+    }
+
+    /// Parses a preset optionally followed by quirks to turn on or off,
+    /// e.g. `schip,+vf_reset,-clip_sprites`
+    pub fn parse(spec: &str) -> Result<Quirks, String> {
+        let mut quirks = Quirks::default();
+        for (i, part) in spec.split(',').map(str::trim).enumerate() {
+            if let Some(name) = part.strip_prefix('+') {
+                *quirks.flag_mut(name)? = true;
+            } else if let Some(name) = part.strip_prefix('-') {
+                *quirks.flag_mut(name)? = false;
+            } else if i == 0 {
+                quirks = Quirks::preset(part)?;
+            } else {
+                return Err(format!("Invalid quirk '{}', expected +name or -name", part));
+            }
+        }
+        Ok(quirks)
+    }
+
+    /// The shortest spec `parse` reads back as these quirks
+    pub fn to_spec(self) -> String {
+        let base = PRESETS.iter()
+            .map(|name| (*name, Quirks::preset(name).unwrap()))
+            .min_by_key(|(_, preset)| self.differences(preset).len())
+            .unwrap();
+
+        let mut spec = base.0.to_string();
+        for (name, on) in self.differences(&base.1) {
+            spec.push(',');
+            spec.push(if on { '+' } else { '-' });
+            spec.push_str(name);
+        }
+        spec
+    }
+
+    fn flags(&self) -> [bool; 5] {
+        [self.vf_reset, self.shift_uses_vy, self.load_store_increments_i, self.jump_uses_vx, self.clip_sprites]
+    }
+
+    pub(super) fn flag_mut(&mut self, name: &str) -> Result<&mut bool, String> {
+        match name {
+            "vf_reset" => Ok(&mut self.vf_reset),
+            "shift_uses_vy" => Ok(&mut self.shift_uses_vy),
+            "load_store_increments_i" => Ok(&mut self.load_store_increments_i),
+            "jump_uses_vx" => Ok(&mut self.jump_uses_vx),
+            "clip_sprites" => Ok(&mut self.clip_sprites),
+            _ => Err(format!("Unknown quirk '{}', expected one of {}", name, NAMES.join(", "))),
+        }
+    }
+
+    /// Quirks set differently here than in `other`, with their value here
+    fn differences(&self, other: &Quirks) -> Vec<(&'static str, bool)> {
+        let (mine, theirs) = (self.flags(), other.flags());
+        (0..NAMES.len())
+            .filter(|i| mine[*i] != theirs[*i])
+            .map(|i| (NAMES[i], mine[i]))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_modifiers() {
+        let quirks = Quirks::parse("schip,+vf_reset,-clip_sprites").unwrap();
+        assert!(quirks.vf_reset && quirks.jump_uses_vx && !quirks.clip_sprites);
+        assert!(Quirks::parse("+jump_uses_vx").unwrap().jump_uses_vx);
+        assert!(Quirks::parse("schip,vf_reset").is_err());
+        assert!(Quirks::parse("schip,+wrap").is_err());
+    }
+
+    #[test]
+    fn spec_reads_back() {
+        for preset in PRESETS.iter() {
+            assert_eq!(Quirks::preset(preset).unwrap().to_spec(), *preset);
+        }
+        let quirks = Quirks::parse("chip8,-vf_reset,+jump_uses_vx").unwrap();
+        assert_eq!(Quirks::parse(&quirks.to_spec()).unwrap(), quirks);
+    }
+}
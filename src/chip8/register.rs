@@ -27,6 +27,10 @@ impl Registers {
         }
     }
 
+    pub fn values(&self) -> [u8; REGISTERS_SIZE] {
+        self.v
+    }
+
     pub fn dump(&self) {
         for i in 0..2 {
             print!("  V{:X}: 0x{:02X}", i * 8 + 0, self.v[i * 8 + 0]);
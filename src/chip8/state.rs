@@ -0,0 +1,140 @@
+use super::gfx::GFX_MEM_SIZE;
+use super::memory::MEM_SIZE;
+use super::{Chip8, STACK_SIZE};
+
+const MAGIC: &[u8; 8] = b"CHIPR8ST";
+const VERSION: u8 = 1;
+
+/// magic, version, opcode, V0-VF, I, PC, stack, SP, DT, ST, memory, one byte per pixel
+const STATE_SIZE: usize = 8 + 1 + 2 + 16 + 2 + 2 + STACK_SIZE * 2 + 1 + 1 + 1 + MEM_SIZE + GFX_MEM_SIZE;
+
+impl Chip8 {
+    /// Snapshot of the whole machine, registers, stack, timers, memory and display
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(STATE_SIZE);
+        state.extend_from_slice(MAGIC);
+        state.push(VERSION);
+        state.extend_from_slice(&self.opcode.to_be_bytes());
+        for i in 0..16 {
+            state.push(self.v[i]);
+        }
+        state.extend_from_slice(&self.i.to_be_bytes());
+        state.extend_from_slice(&self.pc.to_be_bytes());
+        for address in self.stack.iter() {
+            state.extend_from_slice(&address.to_be_bytes());
+        }
+        state.push(self.sp as u8);
+        state.push(self.delay_timer);
+        state.push(self.sound_timer);
+        for address in 0..MEM_SIZE {
+            state.push(self.memory[address]);
+        }
+        for pixel in 0..GFX_MEM_SIZE {
+            state.push(self.gfx[pixel] as u8);
+        }
+        state
+    }
+
+    /// Restores a snapshot taken with `save_state`, the machine is left untouched if it is not valid
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        if state.len() != STATE_SIZE || &state[..8] != MAGIC {
+            return Err("not a Chipr-8 save state".to_string());
+        }
+        if state[8] != VERSION {
+            return Err(format!("unsupported save state version {}", state[8]));
+        }
+        let i = u16::from_be_bytes([state[9 + 2 + 16], state[9 + 2 + 16 + 1]]);
+        if i as usize >= MEM_SIZE {
+            return Err(format!("invalid I 0x{:04X}", i));
+        }
+        let pc = u16::from_be_bytes([state[9 + 2 + 16 + 2], state[9 + 2 + 16 + 3]]);
+        if pc as usize >= MEM_SIZE {
+            return Err(format!("invalid program counter 0x{:04X}", pc));
+        }
+        let sp = state[9 + 2 + 16 + 4 + STACK_SIZE * 2] as usize;
+        if sp > STACK_SIZE {
+            return Err(format!("invalid stack pointer {}", sp));
+        }
+
+        let was_beeping = self.sound_timer > 0;
+        let mut bytes = state[9..].iter().copied();
+        self.opcode = next_u16(&mut bytes);
+        for i in 0..16 {
+            self.v[i] = bytes.next().unwrap();
+        }
+        self.i = next_u16(&mut bytes);
+        self.pc = next_u16(&mut bytes);
+        for i in 0..STACK_SIZE {
+            self.stack[i] = next_u16(&mut bytes);
+        }
+        self.sp = bytes.next().unwrap() as usize;
+        self.delay_timer = bytes.next().unwrap();
+        self.sound_timer = bytes.next().unwrap();
+        for address in 0..MEM_SIZE {
+            self.memory[address] = bytes.next().unwrap();
+        }
+        for pixel in 0..GFX_MEM_SIZE {
+            self.gfx[pixel] = bytes.next().unwrap() != 0;
+        }
+        self.cycles_in_frame = 0;
+        self.error = None;
+
+        let is_beeping = self.sound_timer > 0;
+        if was_beeping != is_beeping && !self.is_paused {
+            self.switch_beep(is_beeping, 0.0);
+        }
+        Ok(())
+    }
+}
+
+fn next_u16(bytes: &mut impl Iterator<Item = u8>) -> u16 {
+    u16::from_be_bytes([bytes.next().unwrap(), bytes.next().unwrap()])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chip8::Chip8;
+    use crate::multimedia::headless::Headless;
+
+    // V0 = 5, DT = V0, I = 0x300, [I] = V0, CALL 0x20C, (CLS), I = font 5, draws it at V0, V0
+    const ROM: [u8; 16] = [0x60, 0x05, 0xF0, 0x15, 0xA3, 0x00, 0xF0, 0x55, 0x22, 0x0C, 0x00, 0xE0, 0xF0, 0x29, 0xD0, 0x05];
+
+    #[test]
+    fn state_round_trips() {
+        let mut chip8 = Chip8::new(Headless);
+        chip8.load_rom_bytes(&ROM).unwrap();
+        chip8.run_frame(7);
+        let state = chip8.save_state();
+
+        let mut other = Chip8::new(Headless);
+        other.load_rom_bytes(&[0x12, 0x00]).unwrap();
+        other.load_state(&state).unwrap();
+        assert_eq!(other.save_state(), state);
+        assert_eq!(other.pc, chip8.pc);
+        assert_eq!(other.sp, 1);
+        assert_eq!(other.delay_timer, 4);
+        assert_eq!(other.memory[0x300], 5);
+        assert!(other.gfx[5 * 64 + 5]);
+    }
+
+    #[test]
+    fn invalid_states_leave_the_machine_alone() {
+        let mut chip8 = Chip8::new(Headless);
+        chip8.load_rom_bytes(&ROM).unwrap();
+        let mut state = chip8.save_state();
+        chip8.run_frame(3);
+
+        assert!(chip8.load_state(&state[1..]).is_err());
+        let mut corrupted = state.clone();
+        corrupted[29] = 0x12;
+        assert_eq!(chip8.load_state(&corrupted), Err("invalid program counter 0x1200".to_string()));
+        let mut corrupted = state.clone();
+        corrupted[27] = 0xF3;
+        assert_eq!(chip8.load_state(&corrupted), Err("invalid I 0xF300".to_string()));
+        state[8] = 2;
+        assert!(chip8.load_state(&state).is_err());
+        assert_eq!(chip8.v[0], 5);
+        chip8.run_frame(3);
+        assert_eq!(chip8.error(), None);
+    }
+}
@@ -0,0 +1,376 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+use super::disasm;
+
+const MAGIC: &[u8; 8] = b"CHIPR8TR";
+const VERSION: u8 = 1;
+
+/// cycle, PC, opcode, V0-VF, I, DT, ST, all little endian
+const RECORD_SIZE: usize = 8 + 2 + 2 + 16 + 2 + 1 + 1;
+
+const TEXT_HEADER: &str = "# cycle pc opcode v0-vf i dt st disassembly";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    /// One line per instruction, numbers in hex but the cycle
+    Text,
+    /// Fixed size records after a header, about a third of the text size
+    Binary,
+}
+
+impl TraceFormat {
+    pub fn parse(format: &str) -> Result<TraceFormat, String> {
+        match format {
+            "text" => Ok(TraceFormat::Text),
+            "binary" => Ok(TraceFormat::Binary),
+            _ => Err(format!("Invalid trace format '{}', expected text or binary", format)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceOptions {
+    pub path: String,
+    pub format: TraceFormat,
+    /// Only instructions at these addresses, both ends included, are written. Empty means all.
+    pub ranges: Vec<(u16, u16)>,
+}
+
+/// Parses an address range like `200-2FF` or a single address, in hex
+pub fn parse_range(range: &str) -> Result<(u16, u16), String> {
+    let invalid = || format!("Invalid address range '{}', expected START-END in hex like 200-2FF", range);
+    let parse = |address: &str| {
+        let address = address.trim_start_matches("0x").trim_start_matches("0X");
+        u16::from_str_radix(address, 16).map_err(|_| invalid())
+    };
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse(start)?, parse(end)?),
+        None => (parse(range)?, parse(range)?),
+    };
+    if start > end {
+        return Err(invalid());
+    }
+    Ok((start, end))
+}
+
+/// One executed instruction and the registers it left behind
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Entry {
+    /// Instructions executed before this one since the machine started
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+impl Entry {
+    fn from_text(line: &str) -> Option<Entry> {
+        let fields = line.split_whitespace().take(7).collect::<Vec<_>>();
+        if fields.len() != 7 || fields[3].len() != 32 {
+            return None;
+        }
+        let mut v = [0; 16];
+        for (x, v) in v.iter_mut().enumerate() {
+            *v = u8::from_str_radix(fields[3].get(x * 2..x * 2 + 2)?, 16).ok()?;
+        }
+        Some(Entry {
+            cycle: fields[0].parse().ok()?,
+            pc: u16::from_str_radix(fields[1], 16).ok()?,
+            opcode: u16::from_str_radix(fields[2], 16).ok()?,
+            v,
+            i: u16::from_str_radix(fields[4], 16).ok()?,
+            delay_timer: u8::from_str_radix(fields[5], 16).ok()?,
+            sound_timer: u8::from_str_radix(fields[6], 16).ok()?,
+        })
+    }
+
+    fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[0..8].copy_from_slice(&self.cycle.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.pc.to_le_bytes());
+        bytes[10..12].copy_from_slice(&self.opcode.to_le_bytes());
+        bytes[12..28].copy_from_slice(&self.v);
+        bytes[28..30].copy_from_slice(&self.i.to_le_bytes());
+        bytes[30] = self.delay_timer;
+        bytes[31] = self.sound_timer;
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; RECORD_SIZE]) -> Entry {
+        let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let mut cycle = [0; 8];
+        cycle.copy_from_slice(&bytes[0..8]);
+        let mut v = [0; 16];
+        v.copy_from_slice(&bytes[12..28]);
+        Entry {
+            cycle: u64::from_le_bytes(cycle),
+            pc: u16_at(8),
+            opcode: u16_at(10),
+            v,
+            i: u16_at(28),
+            delay_timer: bytes[30],
+            sound_timer: bytes[31],
+        }
+    }
+
+    /// Names of the fields that differ
+    pub fn differences(&self, other: &Entry) -> Vec<String> {
+        let mut differences = Vec::new();
+        if self.cycle != other.cycle {
+            differences.push("cycle".to_string());
+        }
+        if self.pc != other.pc {
+            differences.push("PC".to_string());
+        }
+        if self.opcode != other.opcode {
+            differences.push("opcode".to_string());
+        }
+        for x in 0..16 {
+            if self.v[x] != other.v[x] {
+                differences.push(format!("V{:X}", x));
+            }
+        }
+        if self.i != other.i {
+            differences.push("I".to_string());
+        }
+        if self.delay_timer != other.delay_timer {
+            differences.push("DT".to_string());
+        }
+        if self.sound_timer != other.sound_timer {
+            differences.push("ST".to_string());
+        }
+        differences
+    }
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let v = self.v.iter().map(|v| format!("{:02X}", v)).collect::<String>();
+        write!(f, "{} {:03X} {:04X} {} {:04X} {:02X} {:02X} {}", self.cycle, self.pc, self.opcode, v,
+               self.i, self.delay_timer, self.sound_timer, disasm::disassemble(self.opcode))
+    }
+}
+
+/// Writes the instructions the machine executes, those outside the ranges only count cycles
+pub struct Tracer {
+    options: TraceOptions,
+    output: Box<dyn Write>,
+    cycle: u64,
+}
+
+impl Tracer {
+    pub fn create(options: TraceOptions) -> io::Result<Tracer> {
+        let output = BufWriter::new(File::create(&options.path)?);
+        Tracer::new(options, Box::new(output))
+    }
+
+    fn new(options: TraceOptions, mut output: Box<dyn Write>) -> io::Result<Tracer> {
+        match options.format {
+            TraceFormat::Text => writeln!(output, "{}", TEXT_HEADER)?,
+            TraceFormat::Binary => {
+                output.write_all(MAGIC)?;
+                output.write_all(&[VERSION])?;
+            }
+        }
+        Ok(Tracer { options, output, cycle: 0 })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.options.path
+    }
+
+    /// Whether the instruction at the address is written, it still takes a cycle otherwise
+    pub fn is_traced(&self, pc: u16) -> bool {
+        self.options.ranges.is_empty() || self.options.ranges.iter().any(|(start, end)| (*start..=*end).contains(&pc))
+    }
+
+    /// Counts an instruction that ran, writing it when `entry` is given, its cycle is filled in
+    pub fn record(&mut self, entry: Option<Entry>) -> io::Result<()> {
+        let cycle = self.cycle;
+        self.cycle += 1;
+        let entry = match entry {
+            Some(entry) => Entry { cycle, ..entry },
+            None => return Ok(()),
+        };
+        match self.options.format {
+            TraceFormat::Text => writeln!(self.output, "{}", entry),
+            TraceFormat::Binary => self.output.write_all(&entry.to_bytes()),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Reads a trace in either format, telling them apart by the header
+pub struct TraceReader {
+    input: Box<dyn BufRead>,
+    format: TraceFormat,
+    line: usize,
+}
+
+impl TraceReader {
+    pub fn open(path: &str) -> Result<TraceReader, String> {
+        let file = File::open(path).map_err(|e| format!("Could not open trace {}: {}", path, e))?;
+        TraceReader::new(Box::new(BufReader::new(file))).map_err(|e| format!("Invalid trace {}: {}", path, e))
+    }
+
+    fn new(mut input: Box<dyn BufRead>) -> Result<TraceReader, String> {
+        let is_binary = input.fill_buf().map_err(|e| e.to_string())?.starts_with(MAGIC);
+        if !is_binary {
+            return Ok(TraceReader { input, format: TraceFormat::Text, line: 0 });
+        }
+        let mut header = [0; 9];
+        input.read_exact(&mut header).map_err(|e| e.to_string())?;
+        if header[8] != VERSION {
+            return Err(format!("unsupported trace version {}", header[8]));
+        }
+        Ok(TraceReader { input, format: TraceFormat::Binary, line: 0 })
+    }
+
+    fn next_text(&mut self) -> Option<Result<Entry, String>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            self.line += 1;
+            match self.input.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) if line.starts_with('#') || line.trim().is_empty() => continue,
+                Ok(_) => break,
+                Err(e) => return Some(Err(e.to_string())),
+            }
+        }
+        Some(Entry::from_text(&line).ok_or_else(|| format!("invalid line {}", self.line)))
+    }
+
+    fn next_binary(&mut self) -> Option<Result<Entry, String>> {
+        let mut record = [0; RECORD_SIZE];
+        let mut read = 0;
+        while read < RECORD_SIZE {
+            match self.input.read(&mut record[read..]) {
+                Ok(0) if read == 0 => return None,
+                Ok(0) => return Some(Err("truncated record at the end".to_string())),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Some(Err(e.to_string())),
+            }
+        }
+        Some(Ok(Entry::from_bytes(&record)))
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = Result<Entry, String>;
+
+    fn next(&mut self) -> Option<Result<Entry, String>> {
+        match self.format {
+            TraceFormat::Text => self.next_text(),
+            TraceFormat::Binary => self.next_binary(),
+        }
+    }
+}
+
+/// Where two traces stop agreeing, `None` on the side that ended first
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    /// Entries both traces have in common before it
+    pub index: u64,
+    pub a: Option<Entry>,
+    pub b: Option<Entry>,
+}
+
+/// The first entry the traces disagree on, `None` if they are the same
+pub fn first_divergence<A, B>(a: A, b: B) -> Result<Option<Divergence>, String>
+    where A: Iterator<Item = Result<Entry, String>>, B: Iterator<Item = Result<Entry, String>> {
+    let (mut a, mut b) = (a.fuse(), b.fuse());
+    let mut index = 0;
+    loop {
+        let (entry_a, entry_b) = (a.next().transpose()?, b.next().transpose()?);
+        match (entry_a, entry_b) {
+            (None, None) => return Ok(None),
+            (Some(x), Some(y)) if x == y => index += 1,
+            (entry_a, entry_b) => return Ok(Some(Divergence { index, a: entry_a, b: entry_b })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    /// Keeps what is written where the test can still read it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn entry(pc: u16, opcode: u16) -> Entry {
+        let mut v = [0; 16];
+        v[0xA] = 0x42;
+        Entry { cycle: 0, pc, opcode, v, i: 0x300, delay_timer: 3, sound_timer: 0 }
+    }
+
+    fn trace(format: TraceFormat, ranges: Vec<(u16, u16)>, entries: &[Entry]) -> Vec<Entry> {
+        let output = Shared::default();
+        let options = TraceOptions { path: String::new(), format, ranges };
+        let mut tracer = Tracer::new(options, Box::new(output.clone())).unwrap();
+        for entry in entries {
+            let traced = tracer.is_traced(entry.pc);
+            tracer.record(if traced { Some(*entry) } else { None }).unwrap();
+        }
+        let bytes = output.0.borrow().clone();
+        TraceReader::new(Box::new(Cursor::new(bytes))).unwrap().collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn both_formats_read_back_what_was_written() {
+        let entries = [entry(0x200, 0x6A42), entry(0x202, 0xA300), entry(0x300, 0x1200)];
+        for format in &[TraceFormat::Text, TraceFormat::Binary] {
+            let read = trace(*format, Vec::new(), &entries);
+            assert_eq!(read.len(), 3);
+            assert_eq!(read[2], Entry { cycle: 2, ..entries[2] });
+        }
+        assert!(entry(0x200, 0x6A42).to_string().ends_with("0300 03 00 LD VA, 0x42"));
+    }
+
+    #[test]
+    fn ranges_filter_entries_but_cycles_keep_counting() {
+        let entries = [entry(0x200, 0x6A42), entry(0x202, 0xA300), entry(0x300, 0x1200)];
+        let read = trace(TraceFormat::Binary, vec![parse_range("202").unwrap(), parse_range("0x2FF-0x3FF").unwrap()], &entries);
+        assert_eq!(read.iter().map(|entry| (entry.cycle, entry.pc)).collect::<Vec<_>>(), vec![(1, 0x202), (2, 0x300)]);
+        assert!(parse_range("300-200").is_err());
+        assert!(parse_range("x").is_err());
+    }
+
+    #[test]
+    fn finds_the_first_divergence() {
+        let a = vec![entry(0x200, 0x6A42), entry(0x202, 0xA300)];
+        let mut b = a.clone();
+        b[1].v[3] = 1;
+        b[1].i = 0;
+        let divergence = first_divergence(a.clone().into_iter().map(Ok), b.into_iter().map(Ok)).unwrap().unwrap();
+        assert_eq!(divergence.index, 1);
+        assert_eq!(divergence.a.unwrap().differences(&divergence.b.unwrap()), vec!["V3", "I"]);
+
+        let shorter = first_divergence(a.clone().into_iter().map(Ok), a[..1].iter().copied().map(Ok)).unwrap().unwrap();
+        assert_eq!((shorter.index, shorter.b), (1, None));
+        assert_eq!(first_divergence(a.clone().into_iter().map(Ok), a.into_iter().map(Ok)).unwrap(), None);
+    }
+}
@@ -0,0 +1,565 @@
+use crate::chip8::cheats::Cheat;
+use crate::chip8::decompile::Syntax;
+use crate::chip8::observer::Watchpoint;
+use crate::chip8::quirks::Quirks;
+use crate::chip8::trace::{self, TraceFormat, TraceOptions};
+use crate::config::Settings;
+use crate::multimedia::palette::Palette;
+use crate::multimedia::recorder::{RecordOptions, GIF_SCALES};
+use crate::multimedia::sound::Waveform;
+use crate::multimedia::wav::SAMPLE_RATES;
+use crate::rom;
+use crate::terminal::screen::Charset;
+
+/// Frames `chipr-8 test` runs when `--frames` is not given, ten seconds of emulated time
+const DEFAULT_TEST_FRAMES: u64 = 600;
+
+pub const USAGE: &str = "\
+Chipr-8 - CHIP-8 Emulator
+
+USAGE:
+    chipr-8 [run] [OPTIONS] <ROM>
+    chipr-8 disasm <ROM>
+    chipr-8 info [--database <FILE>] <ROM>
+    chipr-8 analyze [--dot <FILE>] <ROM>
+    chipr-8 decompile [--syntax <SYNTAX>] <ROM>
+    chipr-8 test [OPTIONS] <ROM>
+    chipr-8 test <TESTS.toml>
+    chipr-8 config dump [OPTIONS] [ROM]
+    chipr-8 trace-diff <TRACE> <TRACE>
+    chipr-8 patch create <ORIGINAL> <MODIFIED> <PATCH>
+
+COMMANDS:
+    run         Runs the ROM, the default when no command is given
+    disasm      Prints the ROM as CHIP-8 assembly
+    info        Prints the ROM size, what its instructions look like and what the
+                ROM database knows about it
+    analyze     Follows the ROM's control flow from 0x200 without running it, listing
+                subroutines, computed jumps, self-modifying code and the instructions
+                that depend on quirks, with the quirks they suggest
+    decompile   Prints the code found by analyze as loops, ifs and named subroutines
+    test        Runs the ROM headless for a number of frames and prints the display,
+                or runs the subroutine tests of a .toml test file
+    config      'config dump' prints the settings in effect, for the ROM if one is given
+    trace-diff  Prints the first instruction two --trace files disagree on
+    patch       'patch create' writes the changes from one ROM to another as a
+                patch, BPS if PATCH ends in .bps and IPS otherwise
+
+ROMS:
+    Raw binaries, hex listings and Octo cartridge GIFs, also inside .zip and .gz
+    archives. - reads the ROM from the standard input.
+
+OPTIONS:
+    -s, --speed <IPS>             Instructions per second [default: 600]
+        --quirks <QUIRKS>         Quirks preset: default, chip8, schip or xochip,
+                                  optionally followed by +quirk or -quirk, e.g.
+                                  schip,+vf_reset
+        --scale <N>               Window pixels per CHIP-8 pixel [default: 12]
+        --palette <PALETTE>       green, amber, white, gameboy or RRGGBB,RRGGBB
+        --seed <N>                Seeds the random number generator
+        --paused                  Starts paused, P toggles pause
+        --watch                   Reloads the ROM whenever it changes on disk
+        --debug                   Shows the debugger panels around the game, click
+                                  a disassembly line to toggle a breakpoint
+        --watchpoint <SPEC>       Pauses after an access to memory, SPEC being
+                                  KIND:ADDRESS[,LENGTH][=VALUE|!] with KIND r, w,
+                                  rw or x, e.g. w:0x300=ff, can be repeated
+        --trace <FILE>            Writes every instruction executed with the
+                                  registers after it to FILE
+        --trace-format <FORMAT>   text or binary [default: text]
+        --trace-range <RANGE>     Only traces instructions at START-END, in hex,
+                                  e.g. 200-2FF, can be repeated
+        --profile <FILE>          Writes how often every address ran, the time
+                                  spent in each subroutine and the frames spent
+                                  waiting on the delay timer to FILE on exit
+        --profile-folded <FILE>   Writes the time per call stack to FILE on exit,
+                                  in the folded format of flame graph tools
+        --coverage <FILE>         Writes the ROM listing with the bytes executed,
+                                  read and written and the skips taken to FILE
+                                  on exit
+        --coverage-lcov <FILE>    Writes the coverage as an lcov tracefile on exit
+        --source-map <FILE>       Maps addresses to source lines in the lcov file
+                                  [default: <ROM>.map when it exists]
+        --gdb <PORT>              Waits for GDB on localhost:PORT and runs under
+                                  its control, 0 picks a free port, not with
+                                  --debug
+        --patch <FILE>            Applies an IPS or BPS patch to the ROM when
+                                  loading it, can be repeated
+        --entry <NAME>            The ROM to load from a zip archive holding
+                                  several, asked for at the terminal otherwise
+        --cheats                  Enables the cheats saved for the ROM
+        --cheat <ADDRESS=VALUE>   Writes VALUE to ADDRESS every frame, both in hex,
+                                  e.g. 0x3F0=9, can be repeated
+        --cheat-search            Reads cheat search commands on the standard input
+                                  while the ROM runs, 'help' lists them
+        --cheat-file <FILE>       Where cheats are saved, by ROM SHA-1
+                                  [default: ~/.config/chipr-8/cheats.toml]
+        --restore <FILE>          Restores a save state after loading the ROM, and
+                                  after every reload with --watch
+        --state <FILE>            Save state file for F6 (save) and F7 (load)
+                                  [default: <ROM>.state]
+        --config <FILE>           Reads the settings from FILE instead of
+                                  ~/.config/chipr-8/config.toml
+        --database <FILE>         Looks ROMs up in FILE, a programs.json from the
+                                  CHIP-8 database, instead of the bundled one
+        --headless                Runs without window, sound or input
+        --frames <N>              Runs N frames as fast as possible and exits
+        --dot <FILE>              Writes the control-flow graph found by analyze
+                                  to FILE in Graphviz DOT
+        --syntax <SYNTAX>         Output of decompile: octo or c [default: octo]
+        --tui                     Runs in the terminal instead of a window
+        --tui-charset <CHARSET>   halfblock or braille
+        --no-bell                 Does not ring the terminal bell
+        --beep-frequency <HZ>     Beeper pitch [default: 440]
+        --waveform <WAVEFORM>     square, triangle, sawtooth, sine or noise
+        --volume <0-1>            Beeper volume [default: 0.25]
+        --mute                    Starts muted
+        --audio-out <FILE>        Writes the beeper to a WAV file
+        --sample-rate <HZ>        Sample rate of --audio-out, 8000 to 192000
+                                  [default: 44100]
+        --record-gif <FILE>       Records the display to an animated GIF
+        --gif-scale <N>           GIF pixels per CHIP-8 pixel, 1 to 64 [default: 4]
+        --gif-palette <PALETTE>   GIF palette, --palette by default
+        --dump-frames <FILE>      Dumps every frame as raw RGB24
+        --dump-audio <FILE>       Dumps the beeper as raw s16le 44100 Hz
+    -h, --help                    Prints this help
+    -V, --version                 Prints the version
+";
+
+pub enum Command {
+    Run(RunOptions),
+    Disasm(String),
+    Info {
+        rom: String,
+        database: Option<String>,
+    },
+    Analyze {
+        rom: String,
+        /// Graphviz file the control-flow graph goes to
+        dot: Option<String>,
+    },
+    Decompile {
+        rom: String,
+        syntax: Syntax,
+    },
+    TraceDiff(String, String),
+    PatchCreate {
+        original: String,
+        modified: String,
+        patch: String,
+    },
+    Test(RunOptions),
+    ConfigDump {
+        config: Option<String>,
+        database: Option<String>,
+        rom: Option<String>,
+        settings: Settings,
+    },
+    Help,
+    Version,
+}
+
+pub enum Frontend {
+    Window,
+    Terminal(TuiOptions),
+    Headless,
+}
+
+pub struct TuiOptions {
+    pub charset: Charset,
+    pub bell: bool,
+}
+
+pub struct AudioOutOptions {
+    pub path: Option<String>,
+    pub sample_rate: u32,
+}
+
+pub struct ProfileOptions {
+    pub report: Option<String>,
+    /// Folded stacks for flame graphs
+    pub folded: Option<String>,
+}
+
+pub struct CoverageOptions {
+    pub listing: Option<String>,
+    pub lcov: Option<String>,
+    pub source_map: Option<String>,
+}
+
+pub struct CheatOptions {
+    /// Enables the cheats saved for the ROM
+    pub saved: bool,
+    pub freeze: Vec<Cheat>,
+    /// Reads cheat search commands on standard input
+    pub search: bool,
+    /// Where cheats are saved, next to the config file by default
+    pub file: Option<String>,
+}
+
+pub struct RunOptions {
+    pub rom: String,
+    /// IPS or BPS patches applied to the ROM in order
+    pub patches: Vec<String>,
+    /// The file to load when the ROM is a zip archive of several
+    pub entry: Option<String>,
+    /// Only what was given on the command line, merged over the config file later
+    pub settings: Settings,
+    pub seed: Option<u64>,
+    pub paused: bool,
+    /// Reloads the ROM when it changes on disk
+    pub watch: bool,
+    /// Shows the debugger panels, window only
+    pub debug: bool,
+    pub watchpoints: Vec<Watchpoint>,
+    pub trace: Option<TraceOptions>,
+    /// Profiles the run when either output is given
+    pub profile: ProfileOptions,
+    /// Records coverage when either output is given
+    pub coverage: CoverageOptions,
+    /// Port the GDB stub listens on
+    pub gdb: Option<u16>,
+    /// Save state restored after loading the ROM, and after every reload
+    pub restore: Option<String>,
+    /// Where the save and load state hotkeys keep the state, `<ROM>.state` by default
+    pub state: Option<String>,
+    pub config: Option<String>,
+    /// ROM database to use instead of the bundled one
+    pub database: Option<String>,
+    pub frontend: Frontend,
+    pub record: RecordOptions,
+    /// GIF palette when it differs from the display one
+    pub gif_palette: Option<Palette>,
+    pub audio_out: AudioOutOptions,
+    /// Runs this many frames as fast as possible instead of in real time
+    pub frames: Option<u64>,
+    pub cheats: CheatOptions,
+}
+
+/// What `config dump` takes, the options that go into the settings
+const CONFIG_OPTIONS: [&str; 11] = ["-s", "--speed", "--quirks", "--scale", "--palette", "--beep-frequency", "--waveform",
+    "--volume", "--mute", "--config", "--database"];
+
+/// Parses the arguments without the program name, errors are usage errors
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let (command, args) = match args.first().map(String::as_str) {
+        Some("run") | Some("disasm") | Some("info") | Some("analyze") | Some("decompile") | Some("test") => (args[0].as_str(), &args[1..]),
+        Some("config") => match args.get(1).map(String::as_str) {
+            Some("dump") => ("config", &args[2..]),
+            _ => return Err("Expected 'config dump'".to_string()),
+        },
+        Some("trace-diff") => return match &args[1..] {
+            [a, b] if !a.starts_with('-') && !b.starts_with('-') => Ok(Command::TraceDiff(a.clone(), b.clone())),
+            _ => Err("Expected 'trace-diff <TRACE> <TRACE>'".to_string()),
+        },
+        Some("patch") => return match &args[1..] {
+            [create, original, modified, patch] if create == "create"
+                && [original, modified, patch].iter().all(|arg| !arg.starts_with('-')) => Ok(Command::PatchCreate {
+                original: original.clone(),
+                modified: modified.clone(),
+                patch: patch.clone(),
+            }),
+            _ => Err("Expected 'patch create <ORIGINAL> <MODIFIED> <PATCH>'".to_string()),
+        },
+        _ => ("run", args),
+    };
+
+    let mut rom: Option<String> = None;
+    let mut patches = Vec::new();
+    let mut entry: Option<String> = None;
+    let mut settings = Settings::default();
+    let mut gif_palette: Option<Palette> = None;
+    let mut seed: Option<u64> = None;
+    let mut paused = false;
+    let mut watch = false;
+    let mut debug = false;
+    let mut watchpoints = Vec::new();
+    let mut trace_path: Option<String> = None;
+    let mut trace_format = TraceFormat::Text;
+    let mut trace_ranges = Vec::new();
+    let mut profile = ProfileOptions { report: None, folded: None };
+    let mut coverage = CoverageOptions { listing: None, lcov: None, source_map: None };
+    let mut gdb: Option<u16> = None;
+    let mut restore: Option<String> = None;
+    let mut state: Option<String> = None;
+    let mut config: Option<String> = None;
+    let mut database: Option<String> = None;
+    let mut record = RecordOptions::default();
+    let mut use_tui = false;
+    let mut headless = false;
+    let mut tui = TuiOptions { charset: Charset::HalfBlock, bell: true };
+    let mut audio_out = AudioOutOptions { path: None, sample_rate: 44100 };
+    let mut frames: Option<u64> = None;
+    let mut dot: Option<String> = None;
+    let mut syntax: Option<Syntax> = None;
+    let mut cheats = CheatOptions { saved: false, freeze: Vec::new(), search: false, file: None };
+    let mut given = Vec::new();
+
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        let is_flag = matches!(arg, "-h" | "--help" | "-V" | "--version" | "--tui" | "--headless" | "--no-bell"
+            | "--mute" | "--paused" | "--watch" | "--debug"
+            | "--cheats" | "--cheat-search");
+        let needs_value = arg.starts_with('-') && arg != "-" && !is_flag;
+        if needs_value && i + 1 >= args.len() {
+            return Err(format!("Missing value for {}", arg));
+        }
+        let value = if needs_value { args[i + 1].as_str() } else { "" };
+        if arg.starts_with('-') && arg != "-" {
+            given.push(arg);
+        }
+        match arg {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "-s" | "--speed" => settings.speed = Some(parse_number(arg, value).and_then(|ips| match ips {
+                0 => Err("Invalid --speed, expected at least 1 instruction per second".to_string()),
+                ips => Ok(ips),
+            })?),
+            "--quirks" => settings.quirks = Quirks::parse(value).map(|_| Some(value.to_string()))?,
+            "--scale" => settings.window.scale = Some(parse_number(arg, value)?),
+            "--palette" => settings.palette = Palette::parse(value).map(|_| Some(value.to_string()))?,
+            "--seed" => seed = Some(parse_number(arg, value)?),
+            "--paused" => paused = true,
+            "--watch" => watch = true,
+            "--debug" => debug = true,
+            "--watchpoint" => watchpoints.push(Watchpoint::parse(value)?),
+            "--trace" => trace_path = Some(value.to_string()),
+            "--trace-format" => trace_format = TraceFormat::parse(value)?,
+            "--trace-range" => trace_ranges.push(trace::parse_range(value)?),
+            "--profile" => profile.report = Some(value.to_string()),
+            "--profile-folded" => profile.folded = Some(value.to_string()),
+            "--coverage" => coverage.listing = Some(value.to_string()),
+            "--coverage-lcov" => coverage.lcov = Some(value.to_string()),
+            "--source-map" => coverage.source_map = Some(value.to_string()),
+            "--gdb" => gdb = Some(parse_number(arg, value)?),
+            "--patch" => patches.push(value.to_string()),
+            "--entry" => entry = Some(value.to_string()),
+            "--cheats" => cheats.saved = true,
+            "--cheat" => cheats.freeze.push(Cheat::parse(value)?),
+            "--cheat-search" => cheats.search = true,
+            "--cheat-file" => cheats.file = Some(value.to_string()),
+            "--restore" => restore = Some(value.to_string()),
+            "--state" => state = Some(value.to_string()),
+            "--config" => config = Some(value.to_string()),
+            "--database" => database = Some(value.to_string()),
+            "--record-gif" => record.gif_path = Some(value.to_string()),
+            "--gif-scale" => record.scale = parse_number(arg, value).and_then(|scale| match scale {
+                scale if GIF_SCALES.contains(&scale) => Ok(scale),
+                _ => Err(format!("Invalid --gif-scale, expected {} to {}", GIF_SCALES.start(), GIF_SCALES.end())),
+            })?,
+            "--gif-palette" => gif_palette = Some(Palette::parse(value)?),
+            "--dump-frames" => record.frames_path = Some(value.to_string()),
+            "--dump-audio" => record.audio_path = Some(value.to_string()),
+            "--tui" => use_tui = true,
+            "--tui-charset" => tui.charset = Charset::parse(value)?,
+            "--no-bell" => tui.bell = false,
+            "--beep-frequency" => settings.audio.frequency = Some(parse_number(arg, value)?),
+            "--waveform" => settings.audio.waveform = Waveform::parse(value).map(|_| Some(value.to_string()))?,
+            "--volume" => settings.audio.volume = Some(parse_number::<f32>(arg, value)?.clamp(0.0, 1.0)),
+            "--mute" => settings.audio.muted = Some(true),
+            "--headless" => headless = true,
+            "--audio-out" => audio_out.path = Some(value.to_string()),
+            "--frames" => frames = Some(parse_number(arg, value)?),
+            "--dot" => dot = Some(value.to_string()),
+            "--syntax" => syntax = Some(Syntax::parse(value)?),
+            "--sample-rate" => audio_out.sample_rate = parse_number(arg, value).and_then(|rate| match rate {
+                rate if SAMPLE_RATES.contains(&rate) => Ok(rate),
+                _ => Err(format!("Invalid --sample-rate, expected {} to {} Hz", SAMPLE_RATES.start(), SAMPLE_RATES.end())),
+            })?,
+            _ if needs_value => return Err(format!("Unknown option {}", arg)),
+            _ if rom.is_some() => return Err(format!("Unexpected argument {}", arg)),
+            _ => rom = Some(arg.to_string()),
+        }
+        i += if needs_value { 2 } else { 1 };
+    }
+
+    if dot.is_some() && command != "analyze" {
+        return Err("--dot is an analyze option".to_string());
+    }
+    if syntax.is_some() && command != "decompile" {
+        return Err("--syntax is a decompile option".to_string());
+    }
+    if command != "run" && command != "test" {
+        let takes: &[&str] = match command {
+            "config" => &CONFIG_OPTIONS,
+            "info" => &["--database"],
+            "analyze" => &["--dot"],
+            "decompile" => &["--syntax"],
+            _ => &[],
+        };
+        if let Some(option) = given.iter().find(|option| !takes.contains(option)) {
+            return Err(format!("{} is a run and test option", option));
+        }
+    }
+
+    if command == "config" {
+        return Ok(Command::ConfigDump { config, database, rom, settings });
+    }
+
+    let rom = rom.ok_or_else(|| "Missing ROM file".to_string())?;
+
+    let frontend = if headless || command == "test" {
+        Frontend::Headless
+    } else if use_tui {
+        Frontend::Terminal(tui)
+    } else {
+        Frontend::Window
+    };
+    if debug && !matches!(frontend, Frontend::Window) {
+        return Err("--debug needs the window, it can not be used with --tui, --headless or test".to_string());
+    }
+    let trace = match trace_path {
+        Some(path) => Some(TraceOptions { path, format: trace_format, ranges: trace_ranges }),
+        None if !trace_ranges.is_empty() => return Err("--trace-range needs --trace".to_string()),
+        None => None,
+    };
+    if gdb.is_some() && debug {
+        return Err("--gdb and --debug both step the machine, use one of them".to_string());
+    }
+    if gdb.is_some() && (frames.is_some() || command == "test") {
+        return Err("--gdb runs in real time, it can not be used with --frames or test".to_string());
+    }
+    if cheats.search && (use_tui || gdb.is_some() || frames.is_some() || command == "test") {
+        return Err("--cheat-search reads the standard input in real time, it can not be used with --tui, --gdb, --frames or test".to_string());
+    }
+    let dumps_to_stdout = [&record.frames_path, &record.audio_path].iter().filter(|path| path.as_deref() == Some("-")).count();
+    if dumps_to_stdout > 1 {
+        return Err("Only one of --dump-frames and --dump-audio can write to the standard output".to_string());
+    }
+    if dumps_to_stdout > 0 && (use_tui || cheats.search || command == "test") {
+        return Err("--tui, --cheat-search and test print to the standard output, dumps can not go there too".to_string());
+    }
+    if cheats.file.is_some() && !cheats.saved && !cheats.search {
+        return Err("--cheat-file needs --cheats or --cheat-search".to_string());
+    }
+    if rom == rom::STDIN && watch {
+        return Err("--watch can not reload a ROM read from the standard input".to_string());
+    }
+    if rom == rom::STDIN && cheats.search {
+        return Err("--cheat-search reads commands from the standard input, the ROM can not come from it too".to_string());
+    }
+
+    let options = RunOptions {
+        rom,
+        patches,
+        entry,
+        settings,
+        seed,
+        paused,
+        watch,
+        debug,
+        watchpoints,
+        trace,
+        profile,
+        coverage,
+        gdb,
+        restore,
+        state,
+        config,
+        database,
+        frontend,
+        record,
+        gif_palette,
+        audio_out,
+        frames,
+        cheats,
+    };
+
+    Ok(match command {
+        "disasm" => Command::Disasm(options.rom),
+        "info" => Command::Info { rom: options.rom, database: options.database },
+        "analyze" => Command::Analyze { rom: options.rom, dot },
+        "decompile" => Command::Decompile { rom: options.rom, syntax: syntax.unwrap_or(Syntax::Octo) },
+        "test" => Command::Test(RunOptions { frames: options.frames.or(Some(DEFAULT_TEST_FRAMES)), ..options }),
+        _ => Command::Run(options),
+    })
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid {} '{}', expected a number", option, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(line: &str) -> Result<Command, String> {
+        parse(&line.split_whitespace().map(str::to_string).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn parses_commands_and_their_options() {
+        match parse_line("--speed 1000 --quirks schip --paused --watchpoint w:300 --patch fix.ips game.ch8").unwrap() {
+            Command::Run(options) => {
+                assert_eq!(options.rom, "game.ch8");
+                assert_eq!(options.settings.speed, Some(1000));
+                assert_eq!(options.settings.quirks.as_deref(), Some("schip"));
+                assert_eq!(options.patches, ["fix.ips"]);
+                assert_eq!(options.watchpoints.len(), 1);
+                assert!(options.paused);
+                assert!(matches!(options.frontend, Frontend::Window));
+            }
+            _ => panic!("expected a run"),
+        }
+        match parse_line("test game.ch8 --frames 60").unwrap() {
+            Command::Test(options) => assert!(matches!(options.frontend, Frontend::Headless)),
+            _ => panic!("expected a test"),
+        }
+        assert!(matches!(parse_line("disasm game.ch8").unwrap(), Command::Disasm(rom) if rom == "game.ch8"));
+        assert!(matches!(parse_line("info --database db.json game.ch8").unwrap(),
+                         Command::Info { database: Some(database), .. } if database == "db.json"));
+        assert!(matches!(parse_line("config dump --scale 8 --database db.json").unwrap(),
+                         Command::ConfigDump { rom: None, settings, .. } if settings.window.scale == Some(8)));
+        assert!(matches!(parse_line("trace-diff a.trace b.trace").unwrap(), Command::TraceDiff(..)));
+        assert!(matches!(parse_line("game.ch8 --help").unwrap(), Command::Help));
+
+        assert_eq!(parse_line("game.ch8 --speed").err().unwrap(), "Missing value for --speed");
+        assert_eq!(parse_line("game.ch8 --speed 0").err().unwrap(), "Invalid --speed, expected at least 1 instruction per second");
+        assert_eq!(parse_line("game.ch8 --bogus 1").err().unwrap(), "Unknown option --bogus");
+        assert_eq!(parse_line("game.ch8 other.ch8").err().unwrap(), "Unexpected argument other.ch8");
+        assert_eq!(parse_line("--paused").err().unwrap(), "Missing ROM file");
+        assert!(matches!(parse_line("game.ch8 --sample-rate 192000").unwrap(), Command::Run(options) if options.audio_out.sample_rate == 192000));
+        assert!(matches!(parse_line("game.ch8 --gif-scale 64").unwrap(), Command::Run(options) if options.record.scale == 64));
+        for scale in ["0", "65", "1024"].iter() {
+            assert_eq!(parse_line(&format!("game.ch8 --gif-scale {}", scale)).err().unwrap(), "Invalid --gif-scale, expected 1 to 64");
+        }
+        for rate in ["0", "7999", "192001", "4294967295"].iter() {
+            assert_eq!(parse_line(&format!("game.ch8 --sample-rate {}", rate)).err().unwrap(), "Invalid --sample-rate, expected 8000 to 192000 Hz");
+        }
+    }
+
+    #[test]
+    fn rejects_options_that_do_not_apply() {
+        for line in ["disasm --quirks schip game.ch8", "disasm --gdb 1234 game.ch8", "info --scale 8 game.ch8",
+                     "info --dump-frames - game.ch8", "analyze --paused game.ch8", "decompile --patch fix.ips game.ch8",
+                     "config dump --tui", "config dump --entry game.ch8"].iter() {
+            let option = line.split_whitespace().find(|arg| arg.starts_with("--")).unwrap();
+            assert_eq!(parse_line(line).err().unwrap(), format!("{} is a run and test option", option), "{}", line);
+        }
+        assert_eq!(parse_line("disasm --dot cfg.dot game.ch8").err().unwrap(), "--dot is an analyze option");
+        assert_eq!(parse_line("analyze --syntax c game.ch8").err().unwrap(), "--syntax is a decompile option");
+
+        assert!(parse_line("game.ch8 --gdb 1234 --debug").err().unwrap().contains("--gdb and --debug"));
+        assert!(parse_line("game.ch8 --gdb 1234 --frames 10").err().unwrap().contains("--gdb runs in real time"));
+        assert!(parse_line("game.ch8 --tui --debug").err().unwrap().contains("--debug needs the window"));
+        assert!(parse_line("- --watch").err().unwrap().contains("--watch can not reload"));
+        assert!(parse_line("- --cheat-search").err().unwrap().contains("the ROM can not come from it too"));
+        assert!(matches!(parse_line("- --headless").unwrap(), Command::Run(options) if options.rom == rom::STDIN));
+    }
+
+    #[test]
+    fn dumps_to_stdout_must_have_it_to_themselves() {
+        match parse_line("rom.ch8 --dump-frames - --dump-audio audio.pcm").unwrap() {
+            Command::Run(options) => {
+                assert_eq!(options.record.frames_path.as_deref(), Some("-"));
+                assert_eq!(options.record.audio_path.as_deref(), Some("audio.pcm"));
+            }
+            _ => panic!("expected a run"),
+        }
+        assert!(parse_line("rom.ch8 --dump-frames - --dump-audio -").is_err());
+        assert!(parse_line("rom.ch8 --tui --dump-audio -").is_err());
+        assert!(parse_line("rom.ch8 --cheat-search --dump-frames -").is_err());
+        assert!(parse_line("test rom.ch8 --dump-frames -").is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::chip8::DEFAULT_SPEED;
+use crate::chip8::quirks::Quirks;
+use crate::multimedia::keymap::Keymap;
+use crate::multimedia::palette::Palette;
+use crate::multimedia::screen::DEFAULT_SCALE;
+use crate::multimedia::sound::{AudioConfig, Waveform};
+
+/// Settings that can come from the config file, a `[rom.<sha1>]` section of it or
+/// the command line, each one overriding the previous. Unset values fall back to
+/// the built-in defaults.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub speed: Option<u32>,
+    pub quirks: Option<String>,
+    pub palette: Option<String>,
+    pub window: WindowSettings,
+    pub audio: AudioSettings,
+    /// Keypad key (hex digit) to keyboard key
+    pub keys: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowSettings {
+    pub scale: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioSettings {
+    pub frequency: Option<f32>,
+    pub waveform: Option<String>,
+    pub volume: Option<f32>,
+    pub muted: Option<bool>,
+}
+
+/// The config file, defaults at the top level and per ROM overrides in
+/// `[rom.<sha1>]` sections
+#[derive(Default)]
+pub struct Config {
+    defaults: Settings,
+    roms: BTreeMap<String, Settings>,
+}
+
+impl Settings {
+    /// Overrides the settings with the ones set in `other`
+    pub fn merge(&mut self, other: &Settings) {
+        self.speed = other.speed.or(self.speed);
+        self.quirks = other.quirks.clone().or_else(|| self.quirks.take());
+        self.palette = other.palette.clone().or_else(|| self.palette.take());
+        self.window.scale = other.window.scale.or(self.window.scale);
+        self.audio.frequency = other.audio.frequency.or(self.audio.frequency);
+        self.audio.waveform = other.audio.waveform.clone().or_else(|| self.audio.waveform.take());
+        self.audio.volume = other.audio.volume.or(self.audio.volume);
+        self.audio.muted = other.audio.muted.or(self.audio.muted);
+        for (key, binding) in &other.keys {
+            self.keys.insert(key.clone(), binding.clone());
+        }
+    }
+
+    pub fn speed(&self) -> u32 {
+        self.speed.unwrap_or(DEFAULT_SPEED).max(1)
+    }
+
+    pub fn quirks(&self) -> Result<Quirks, String> {
+        Quirks::parse(self.quirks.as_deref().unwrap_or("default"))
+    }
+
+    pub fn palette(&self) -> Result<Palette, String> {
+        self.palette.as_deref().map_or(Ok(Palette::default()), Palette::parse)
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.window.scale.unwrap_or(DEFAULT_SCALE).max(1)
+    }
+
+    pub fn audio(&self) -> Result<AudioConfig, String> {
+        let mut audio = AudioConfig::default();
+        audio.frequency = self.audio.frequency.unwrap_or(audio.frequency);
+        if let Some(waveform) = &self.audio.waveform {
+            audio.waveform = Waveform::parse(waveform)?;
+        }
+        audio.volume = self.audio.volume.unwrap_or(audio.volume).clamp(0.0, 1.0);
+        audio.muted = self.audio.muted.unwrap_or(audio.muted);
+        Ok(audio)
+    }
+
+    pub fn keymap(&self) -> Result<Keymap, String> {
+        let mut keymap = Keymap::default();
+        for (key, binding) in &self.keys {
+            keymap.bind(key, binding)?;
+        }
+        Ok(keymap)
+    }
+
+    /// Every setting with the value that will be used, for `config dump`
+    pub fn effective(&self) -> Result<Settings, String> {
+        let audio = self.audio()?;
+        let keymap = self.keymap()?;
+        self.quirks()?;
+        self.palette()?;
+
+        Ok(Settings {
+            speed: Some(self.speed()),
+            quirks: Some(self.quirks.clone().unwrap_or_else(|| "default".to_string())),
+            palette: Some(self.palette.clone().unwrap_or_else(|| "green".to_string())),
+            window: WindowSettings {
+                scale: Some(self.scale()),
+            },
+            audio: AudioSettings {
+                frequency: Some(audio.frequency),
+                waveform: Some(self.audio.waveform.clone().unwrap_or_else(|| "square".to_string())),
+                volume: Some(audio.volume),
+                muted: Some(audio.muted),
+            },
+            keys: (0..16).map(|key| (format!("{:X}", key), keymap.binding(key).to_string())).collect(),
+        })
+    }
+
+    pub fn to_toml(&self) -> Result<String, String> {
+        toml::to_string(self).map_err(|e| format!("Could not write settings: {}", e))
+    }
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/chipr-8/config.toml`, or `~/.config/chipr-8/config.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("chipr-8").join("config.toml"))
+    }
+
+    /// Loads the given config file, or the default one if it exists
+    pub fn load(path: Option<&str>) -> Result<Config, String> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => match Config::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read config {}: {}", path.display(), e))?;
+        Config::parse(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut table: toml::value::Table = toml::from_str(text).map_err(|e| e.to_string())?;
+
+        let mut roms = BTreeMap::new();
+        if let Some(sections) = table.remove("rom") {
+            let sections: BTreeMap<String, Settings> = sections.try_into().map_err(|e| format!("[rom] {}", e))?;
+            for (hash, settings) in sections {
+                settings.effective().map_err(|e| format!("[rom.{}] {}", hash, e))?;
+                roms.insert(hash.to_ascii_lowercase(), settings);
+            }
+        }
+
+        let defaults: Settings = toml::Value::Table(table).try_into().map_err(|e| e.to_string())?;
+        defaults.effective()?;
+
+        Ok(Config { defaults, roms })
+    }
+
+    /// The defaults, then what was detected for the ROM (from the ROM database)
+    /// and finally the overrides for the ROM with the given SHA-1
+    pub fn settings_for(&self, sha1: Option<&str>, detected: Option<&Settings>) -> Settings {
+        let mut settings = self.defaults.clone();
+        if let Some(detected) = detected {
+            settings.merge(detected);
+        }
+        if let Some(rom) = sha1.and_then(|sha1| self.roms.get(sha1)) {
+            settings.merge(rom);
+        }
+        settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn rom_section_overrides_defaults() {
+        let config = Config::parse(&format!(r#"
+            speed = 700
+            palette = "amber"
+            [keys]
+            5 = "i"
+            [rom.{}]
+            speed = 1000
+            quirks = "chip8"
+        "#, HASH.to_uppercase())).unwrap();
+
+        let settings = config.settings_for(Some(HASH), None);
+        assert_eq!(settings.speed(), 1000);
+        assert_eq!(settings.quirks().unwrap(), Quirks::preset("chip8").unwrap());
+        assert_eq!(settings.palette().unwrap(), Palette::parse("amber").unwrap());
+        assert_eq!(settings.keymap().unwrap().keypad_key('i'), Some(5));
+
+        let settings = config.settings_for(None, None);
+        assert_eq!(settings.speed(), 700);
+        assert_eq!(settings.quirks().unwrap(), Quirks::default());
+    }
+
+    #[test]
+    fn rom_section_overrides_detected() {
+        let config = Config::parse(&format!("speed = 700\n[rom.{}]\nquirks = \"schip\"", HASH)).unwrap();
+        let detected = Settings {
+            speed: Some(900),
+            quirks: Some("chip8".to_string()),
+            ..Settings::default()
+        };
+
+        let settings = config.settings_for(Some(HASH), Some(&detected));
+        assert_eq!(settings.speed(), 900);
+        assert_eq!(settings.quirks().unwrap(), Quirks::preset("schip").unwrap());
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let config = Config::parse("speed = 700\n[audio]\nvolume = 0.5").unwrap();
+        let cli = Settings { speed: Some(900), ..Settings::default() };
+
+        let mut settings = config.settings_for(None, None);
+        settings.merge(&cli);
+        assert_eq!(settings.speed(), 900);
+        assert_eq!(settings.audio().unwrap().volume, 0.5);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert!(Config::parse("sped = 700").is_err());
+        assert!(Config::parse("quirks = \"amiga\"").is_err());
+        assert!(Config::parse(&format!("[rom.{}.keys]\nG = \"x\"", HASH)).is_err());
+    }
+
+    #[test]
+    fn dump_reads_back() {
+        let dumped = Settings::default().effective().unwrap().to_toml().unwrap();
+        let config = Config::parse(&dumped).unwrap();
+        assert_eq!(config.settings_for(None, None).speed(), DEFAULT_SPEED);
+        assert_eq!(config.settings_for(None, None).keymap().unwrap(), Keymap::default());
+    }
+}
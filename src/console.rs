@@ -0,0 +1,144 @@
+//! The cheat search console, commands typed on standard input while the ROM runs to find
+//! counters in memory and freeze them, see `HELP`
+
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::chip8::cheats::{self, Cheat, CheatFile, Filter, Search};
+use crate::chip8::Chip8;
+
+/// Candidates listed after a filter when there are no more than this
+const MAX_LISTED: usize = 20;
+
+const HELP: &str = "\
+Cheat search, addresses and values in hex:
+    search                          starts a search over all of memory
+    eq <VALUE>                      keeps the bytes equal to VALUE
+    changed, unchanged              keeps the bytes that changed, or not, since the last filter
+    inc, dec                        keeps the bytes that went up, or down, since the last filter
+    list                            lists what is left of the search
+    freeze <ADDRESS> <VALUE> [TEXT] writes VALUE to ADDRESS every frame
+    unfreeze <ADDRESS>              stops freezing ADDRESS
+    cheats                          lists the frozen addresses
+    save                            saves them as the cheats of this ROM
+    help                            prints this help";
+
+pub struct CheatConsole {
+    lines: Receiver<String>,
+    search: Option<Search>,
+    /// Where `save` writes to
+    file: PathBuf,
+    sha1: String,
+}
+
+impl CheatConsole {
+    /// Starts reading commands on a thread of its own, so the emulator never waits for input
+    pub fn start(file: PathBuf, sha1: String) -> CheatConsole {
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    return;
+                }
+            }
+        });
+        println!("{}", HELP);
+        CheatConsole { lines, search: None, file, sha1 }
+    }
+
+    /// Runs the commands typed since the last call
+    pub fn poll(&mut self, chip8: &mut Chip8) {
+        loop {
+            match self.lines.try_recv() {
+                Ok(line) => match self.run(line.trim(), chip8) {
+                    Ok(output) if output.is_empty() => {}
+                    Ok(output) => println!("{}", output),
+                    Err(e) => eprintln!("{}", e),
+                },
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return,
+            }
+        }
+    }
+
+    fn run(&mut self, line: &str, chip8: &mut Chip8) -> Result<String, String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(String::new()),
+        };
+        let filter = match command {
+            "search" => {
+                self.search = Some(Search::new(chip8.memory_bytes()));
+                return Ok(format!("{} candidates", chip8.memory_bytes().len()));
+            }
+            "eq" => {
+                let value = words.next().ok_or("Expected 'eq <VALUE>'")?;
+                Filter::Equal(cheats::parse_value(value).ok_or_else(|| format!("Invalid value '{}'", value))?)
+            }
+            "changed" => Filter::Changed,
+            "unchanged" => Filter::Unchanged,
+            "inc" => Filter::Increased,
+            "dec" => Filter::Decreased,
+            "list" => return self.search.as_ref().map(list).ok_or_else(no_search),
+            "freeze" => return self.freeze(words.collect(), chip8),
+            "unfreeze" => {
+                let address = words.next().and_then(cheats::parse_address).ok_or("Expected 'unfreeze <ADDRESS>'")?;
+                let mut frozen = chip8.cheats().to_vec();
+                frozen.retain(|cheat| cheat.address != address);
+                chip8.set_cheats(frozen);
+                return Ok(String::new());
+            }
+            "cheats" => return Ok(chip8.cheats().iter().map(describe).collect::<Vec<_>>().join("\n")),
+            "save" => {
+                let mut file = CheatFile::load(&self.file)?;
+                file.set_cheats(&self.sha1, chip8.cheats());
+                file.save(&self.file)?;
+                return Ok(format!("Saved {} cheats to {}", chip8.cheats().len(), self.file.display()));
+            }
+            "help" => return Ok(HELP.to_string()),
+            _ => return Err(format!("Unknown command '{}', 'help' lists them", command)),
+        };
+
+        let search = self.search.as_mut().ok_or_else(no_search)?;
+        let left = search.filter(chip8.memory_bytes(), filter);
+        if left <= MAX_LISTED {
+            Ok(list(search))
+        } else {
+            Ok(format!("{} candidates", left))
+        }
+    }
+
+    /// `freeze <ADDRESS> <VALUE> [TEXT]`, replacing what the address was frozen to
+    fn freeze(&mut self, words: Vec<&str>, chip8: &mut Chip8) -> Result<String, String> {
+        let usage = || "Expected 'freeze <ADDRESS> <VALUE> [TEXT]'".to_string();
+        let cheat = Cheat {
+            address: words.first().and_then(|address| cheats::parse_address(address)).ok_or_else(usage)?,
+            value: words.get(1).and_then(|value| cheats::parse_value(value)).ok_or_else(usage)?,
+            description: words.get(2..).unwrap_or_default().join(" "),
+        };
+        let mut frozen = chip8.cheats().to_vec();
+        frozen.retain(|frozen| frozen.address != cheat.address);
+        frozen.push(cheat);
+        chip8.set_cheats(frozen);
+        Ok(String::new())
+    }
+}
+
+fn no_search() -> String {
+    "No search, 'search' starts one".to_string()
+}
+
+fn list(search: &Search) -> String {
+    let candidates = search.candidates().map(|(address, value)| format!("0x{:03X} = 0x{:02X}", address, value)).collect::<Vec<_>>();
+    if candidates.is_empty() {
+        "No candidates left".to_string()
+    } else {
+        candidates.join("\n")
+    }
+}
+
+fn describe(cheat: &Cheat) -> String {
+    format!("0x{:03X} = 0x{:02X} {}", cheat.address, cheat.value, cheat.description).trim_end().to_string()
+}
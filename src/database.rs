@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use serde::Deserialize;
+
+use crate::chip8::quirks::Quirks;
+use crate::config::Settings;
+use crate::multimedia::palette::Palette;
+
+/// `programs.json` from the CHIP-8 database (https://github.com/chip-8/chip-8-database)
+const BUNDLED: &str = include_str!("../database/programs.json");
+
+/// What the database knows about one ROM
+pub struct Entry {
+    pub title: String,
+    /// First platform listed for the ROM that Chipr-8 can emulate
+    pub platform: Option<String>,
+    /// Instructions per 60 Hz frame
+    pub tickrate: Option<u32>,
+    pub quirks: Option<Quirks>,
+    pub palette: Option<Palette>,
+    /// What the game uses keypad keys for, e.g. `up` -> 5
+    pub keys: BTreeMap<String, u8>,
+}
+
+/// ROMs known by SHA-1
+pub struct Database {
+    entries: HashMap<String, Entry>,
+}
+
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    roms: BTreeMap<String, Rom>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Rom {
+    #[serde(default)]
+    platforms: Vec<String>,
+    tickrate: Option<u32>,
+    colors: Option<Colors>,
+    #[serde(default)]
+    keys: BTreeMap<String, u8>,
+    #[serde(default)]
+    quirky_platforms: BTreeMap<String, QuirkOverrides>,
+}
+
+#[derive(Deserialize)]
+struct Colors {
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+/// Quirks a ROM needs set differently than its platform has them
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuirkOverrides {
+    shift: Option<bool>,
+    memory_increment_by_x: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    logic: Option<bool>,
+}
+
+impl Database {
+    pub fn bundled() -> Database {
+        Database::parse(BUNDLED).expect("bundled ROM database is valid")
+    }
+
+    pub fn load(path: &str) -> Result<Database, String> {
+        let json = fs::read_to_string(path).map_err(|e| format!("Could not read database {}: {}", path, e))?;
+        Database::parse(&json).map_err(|e| format!("Invalid database {}: {}", path, e))
+    }
+
+    pub fn parse(json: &str) -> Result<Database, String> {
+        let programs: Vec<Program> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+
+        let mut entries = HashMap::new();
+        for program in programs {
+            for (sha1, rom) in program.roms {
+                entries.insert(sha1.to_ascii_lowercase(), Entry::new(&program.title, rom));
+            }
+        }
+        Ok(Database { entries })
+    }
+
+    pub fn lookup(&self, sha1: &str) -> Option<&Entry> {
+        self.entries.get(sha1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Entry {
+    fn new(title: &str, rom: Rom) -> Entry {
+        let platform = rom.platforms.iter().find(|platform| preset_for(platform).is_some()).cloned();
+        let quirks = platform.as_ref().map(|platform| {
+            let mut quirks = Quirks::preset(preset_for(platform).unwrap()).unwrap();
+            if let Some(overrides) = rom.quirky_platforms.get(platform) {
+                overrides.apply(&mut quirks);
+            }
+            quirks
+        });
+        let palette = rom.colors
+            .filter(|colors| colors.pixels.len() >= 2)
+            .and_then(|colors| Palette::parse(&format!("{},{}", colors.pixels[0], colors.pixels[1])).ok());
+
+        Entry {
+            title: title.to_string(),
+            platform,
+            tickrate: rom.tickrate,
+            quirks,
+            palette,
+            keys: rom.keys,
+        }
+    }
+
+    /// The settings the database asks for, the config file and command line still override them
+    pub fn settings(&self) -> Settings {
+        Settings {
+            speed: self.tickrate.map(|tickrate| tickrate.saturating_mul(60)),
+            quirks: self.quirks.map(|quirks| quirks.to_spec()),
+            palette: self.palette.map(|palette| palette.to_spec()),
+            ..Settings::default()
+        }
+    }
+}
+
+impl QuirkOverrides {
+    fn apply(&self, quirks: &mut Quirks) {
+        if let Some(shift) = self.shift {
+            quirks.shift_uses_vy = !shift;
+        }
+        if self.memory_increment_by_x == Some(true) {
+            quirks.load_store_increments_i = true;
+        }
+        if let Some(unchanged) = self.memory_leave_i_unchanged {
+            quirks.load_store_increments_i = !unchanged;
+        }
+        if let Some(wrap) = self.wrap {
+            quirks.clip_sprites = !wrap;
+        }
+        if let Some(jump) = self.jump {
+            quirks.jump_uses_vx = jump;
+        }
+        if let Some(logic) = self.logic {
+            quirks.vf_reset = logic;
+        }
+    }
+}
+
+/// The quirks preset closest to a database platform, `None` for platforms Chipr-8 can not run
+fn preset_for(platform: &str) -> Option<&'static str> {
+    match platform {
+        "originalChip8" | "hybridVIP" | "chip8x" => Some("chip8"),
+        "modernChip8" => Some("default"),
+        "chip48" | "superchip1" | "superchip" => Some("schip"),
+        "xochip" => Some("xochip"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAMS: &str = r##"[
+        {
+            "title": "Some Game",
+            "roms": {
+                "0123456789ABCDEF0123456789ABCDEF01234567": {
+                    "file": "game.ch8",
+                    "platforms": ["megachip8", "superchip"],
+                    "tickrate": 30,
+                    "colors": { "pixels": ["#000000", "#ff8000"] },
+                    "keys": { "up": 5, "down": 8 },
+                    "quirkyPlatforms": { "superchip": { "logic": true, "wrap": true } }
+                }
+            }
+        },
+        { "title": "Unsupported", "roms": { "ffffffffffffffffffffffffffffffffffffffff": { "platforms": ["megachip8"] } } }
+    ]"##;
+
+    #[test]
+    fn looks_up_roms_by_sha1() {
+        let database = Database::parse(PROGRAMS).unwrap();
+        assert_eq!(database.len(), 2);
+
+        let entry = database.lookup("0123456789abcdef0123456789abcdef01234567").unwrap();
+        assert_eq!(entry.title, "Some Game");
+        assert_eq!(entry.platform.as_deref(), Some("superchip"));
+        assert_eq!(entry.tickrate, Some(30));
+        assert_eq!(entry.palette, Some(Palette::parse("000000,FF8000").unwrap()));
+        assert_eq!(entry.keys.get("up"), Some(&5));
+
+        let mut quirks = Quirks::preset("schip").unwrap();
+        quirks.vf_reset = true;
+        quirks.clip_sprites = false;
+        assert_eq!(entry.quirks, Some(quirks));
+
+        let settings = entry.settings();
+        assert_eq!(settings.speed(), 1800);
+        assert_eq!(settings.quirks().unwrap(), quirks);
+
+        let fast = Database::parse(&PROGRAMS.replace("\"tickrate\": 30", "\"tickrate\": 4294967295")).unwrap();
+        assert_eq!(fast.lookup("0123456789abcdef0123456789abcdef01234567").unwrap().settings().speed(), u32::MAX);
+
+        let unsupported = database.lookup("ffffffffffffffffffffffffffffffffffffffff").unwrap();
+        assert_eq!(unsupported.platform, None);
+        assert_eq!(unsupported.settings().quirks, None);
+    }
+
+    #[test]
+    fn bundled_database_parses() {
+        Database::bundled();
+    }
+
+    #[test]
+    fn bundled_database_detects_the_ibm_logo() {
+        let ibm_logo = [
+            0x00, 0xE0, 0xA2, 0x2A, 0x60, 0x0C, 0x61, 0x08, 0xD0, 0x1F, 0x70, 0x09, 0xA2, 0x39, 0xD0, 0x1F,
+            0xA2, 0x48, 0x70, 0x08, 0xD0, 0x1F, 0x70, 0x04, 0xA2, 0x57, 0xD0, 0x1F, 0x70, 0x08, 0xA2, 0x66,
+            0xD0, 0x1F, 0x70, 0x08, 0xA2, 0x75, 0xD0, 0x1F, 0x12, 0x28, 0xFF, 0x00, 0xFF, 0x00, 0x3C, 0x00,
+            0x3C, 0x00, 0x3C, 0x00, 0x3C, 0x00, 0xFF, 0x00, 0xFF, 0xFF, 0x00, 0xFF, 0x00, 0x38, 0x00, 0x3F,
+            0x00, 0x3F, 0x00, 0x38, 0x00, 0xFF, 0x00, 0xFF, 0x80, 0x00, 0xE0, 0x00, 0xE0, 0x00, 0x80, 0x00,
+            0x80, 0x00, 0xE0, 0x00, 0xE0, 0x00, 0x80, 0xF8, 0x00, 0xFC, 0x00, 0x3E, 0x00, 0x3F, 0x00, 0x3B,
+            0x00, 0x39, 0x00, 0xF8, 0x00, 0xF8, 0x03, 0x00, 0x07, 0x00, 0x0F, 0x00, 0xBF, 0x00, 0xFB, 0x00,
+            0xF3, 0x00, 0xE3, 0x00, 0x43, 0xE0, 0x00, 0xE0, 0x00, 0x80, 0x00, 0x80, 0x00, 0x80, 0x00, 0x80,
+            0x00, 0xE0, 0x00, 0xE0,
+        ];
+        let database = Database::bundled();
+        let entry = database.lookup(&crate::rom::sha1(&ibm_logo)).unwrap();
+        assert_eq!(entry.title, "IBM Logo");
+        assert_eq!(entry.platform.as_deref(), Some("originalChip8"));
+        assert_eq!(entry.settings().quirks().unwrap(), Quirks::preset("chip8").unwrap());
+    }
+}
@@ -0,0 +1,505 @@
+//! A GDB remote serial protocol stub, so GDB or any RSP client can drive the emulator.
+//!
+//! Registers are V0-VF, I, PC, SP, DT and ST, numbered in that order and sent big-endian
+//! like CHIP-8 memory. The client gets them described in a target description, memory is
+//! the 4 KB address space. Besides reading and writing both it can continue, step, set
+//! software breakpoints and watchpoints, and interrupt the running machine.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::chip8::debug::{Register, Stop, REGISTERS};
+use crate::chip8::observer::{Condition, WatchKind, Watchpoint};
+use crate::chip8::{Chip8, MEM_SIZE};
+
+/// Sent by the client to interrupt the running machine
+const INTERRUPT: u8 = 0x03;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chipr8.cpu">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+/// Waits for a client on localhost, then runs the machine under its control until it is
+/// turned off. Clients can detach and attach again, the machine runs freely in between.
+pub fn serve(chip8: &mut Chip8, port: u16) -> Result<(), String> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| format!("Could not listen on port {}: {}", port, e))?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+    eprintln!("Waiting for GDB on {}", address);
+
+    let (stream, _) = listener.accept().map_err(|e| format!("Could not accept GDB: {}", e))?;
+    let mut client = Some(Client::new(stream, chip8).map_err(|e| format!("Could not set up GDB connection: {}", e))?);
+    listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+
+    while chip8.is_on() {
+        if client.is_none() {
+            if let Ok((stream, _)) = listener.accept() {
+                client = Client::new(stream, chip8).ok();
+            }
+        }
+        if let Some(connected) = &mut client {
+            if !connected.poll(chip8).unwrap_or(false) {
+                client = None;
+                chip8.clear_breakpoints_and_watchpoints();
+                chip8.set_paused(false);
+            }
+        }
+        chip8.execute_cycle();
+    }
+
+    if let Some(mut client) = client {
+        let reply = match chip8.error() {
+            Some(_) => format!("X{:02x}", SIGILL),
+            None => "W00".to_string(),
+        };
+        // the machine is gone either way
+        client.send(&reply).ok();
+    }
+    Ok(())
+}
+
+struct Client {
+    stream: TcpStream,
+    input: Vec<u8>,
+    no_ack: bool,
+    /// The client let the machine run and waits for it to stop
+    running: bool,
+}
+
+impl Client {
+    /// The machine stops while a client is attached, until it tells it to continue
+    fn new(stream: TcpStream, chip8: &mut Chip8) -> io::Result<Client> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        chip8.set_paused(true);
+        chip8.take_stop();
+        Ok(Client {
+            stream,
+            input: Vec::new(),
+            no_ack: false,
+            running: false,
+        })
+    }
+
+    /// Handles whatever the client sent and reports the machine stopping, returns
+    /// whether the client is still attached
+    fn poll(&mut self, chip8: &mut Chip8) -> io::Result<bool> {
+        let mut buffer = [0; 4096];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        while let Some(packet) = self.next_packet()? {
+            match packet {
+                Packet::Interrupt => {
+                    if self.running {
+                        chip8.set_paused(true);
+                    }
+                }
+                Packet::Command(command) => {
+                    if !self.handle(chip8, &command)? {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+
+        if self.running && chip8.is_paused() {
+            self.running = false;
+            let reply = stop_reply(chip8.take_stop(), SIGINT);
+            self.send(&reply)?;
+        }
+        Ok(true)
+    }
+
+    /// Takes the next packet out of the input, acknowledging it
+    fn next_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            match self.input.first() {
+                None => return Ok(None),
+                Some(&INTERRUPT) => {
+                    self.input.remove(0);
+                    return Ok(Some(Packet::Interrupt));
+                }
+                Some(b'$') => break,
+                // acks, and anything else between packets
+                Some(_) => {
+                    self.input.remove(0);
+                }
+            }
+        }
+
+        let end = match self.input.iter().position(|&b| b == b'#') {
+            Some(end) if self.input.len() >= end + 3 => end,
+            _ => return Ok(None),
+        };
+        let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+        let data = &packet[1..end];
+        let checksum = std::str::from_utf8(&packet[end + 1..]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if checksum != Some(checksum_of(data)) {
+            if !self.no_ack {
+                self.write(b"-")?;
+            }
+            return self.next_packet();
+        }
+        if !self.no_ack {
+            self.write(b"+")?;
+        }
+        Ok(Some(Packet::Command(unescape(data))))
+    }
+
+    /// Runs a command and answers it, returns false when the client is done
+    fn handle(&mut self, chip8: &mut Chip8, command: &[u8]) -> io::Result<bool> {
+        let command = String::from_utf8_lossy(command).to_string();
+        let (name, args) = command.split_at(1.min(command.len()));
+
+        let reply = match name {
+            "?" => stop_reply(None, SIGTRAP),
+            "g" => REGISTERS.iter().map(|&register| encode_register(chip8, register)).collect(),
+            "G" => write_registers(chip8, args),
+            "p" => match parse_hex(args).and_then(|n| REGISTERS.get(n as usize)) {
+                Some(&register) => encode_register(chip8, register),
+                None => error(),
+            },
+            "P" => write_register(chip8, args),
+            "m" => read_memory(chip8, args),
+            "M" => write_memory(chip8, args),
+            "c" | "s" => {
+                if let Some(address) = parse_hex(args) {
+                    chip8.write_register(Register::Pc, address as u16);
+                }
+                if name == "s" {
+                    chip8.step_instruction();
+                    if !chip8.is_on() {
+                        // the instruction stopped the machine, the exit is reported next
+                        return Ok(true);
+                    }
+                    stop_reply(chip8.take_stop(), SIGTRAP)
+                } else {
+                    chip8.set_paused(false);
+                    self.running = true;
+                    return Ok(true);
+                }
+            }
+            "Z" | "z" => set_point(chip8, args, name == "Z"),
+            "k" => {
+                chip8.shutdown();
+                return Ok(false);
+            }
+            "D" => {
+                self.send("OK")?;
+                return Ok(false);
+            }
+            "H" | "T" => "OK".to_string(),
+            "q" | "Q" => self.query(&command),
+            _ => String::new(),
+        };
+        self.send(&reply)?;
+        Ok(true)
+    }
+
+    fn query(&mut self, query: &str) -> String {
+        if query.starts_with("qSupported") {
+            return "PacketSize=4000;QStartNoAckMode+;qXfer:features:read+;swbreak+".to_string();
+        }
+        if let Some(args) = query.strip_prefix("qXfer:features:read:target.xml:") {
+            return read_target_xml(args);
+        }
+        match query {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let data = escape(data.as_bytes());
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(&data);
+        packet.extend_from_slice(format!("#{:02x}", checksum_of(&data)).as_bytes());
+        self.write(&packet)
+    }
+
+    /// Writes all of it even though the socket does not block
+    fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            match self.stream.write(data) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => data = &data[n..],
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Packet {
+    Interrupt,
+    Command(Vec<u8>),
+}
+
+/// Why the machine stopped, `signal` when it did not stop by itself
+fn stop_reply(stop: Option<Stop>, signal: u8) -> String {
+    match stop {
+        Some(Stop::Breakpoint(_)) => format!("T{:02x}swbreak:;", SIGTRAP),
+        Some(Stop::Watchpoint(hit)) => {
+            let name = match hit.watchpoint.kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access | WatchKind::Execute => "awatch",
+            };
+            format!("T{:02x}{}:{:x};", SIGTRAP, name, hit.record.address)
+        }
+        None => format!("S{:02x}", signal),
+    }
+}
+
+fn error() -> String {
+    "E01".to_string()
+}
+
+fn encode_register(chip8: &Chip8, register: Register) -> String {
+    let value = chip8.read_register(register);
+    match register.size() {
+        2 => format!("{:04x}", value),
+        _ => format!("{:02x}", value),
+    }
+}
+
+/// `G` with every register, in the order and sizes `g` sends them
+fn write_registers(chip8: &mut Chip8, hex: &str) -> String {
+    let size: usize = REGISTERS.iter().map(|register| register.size() * 2).sum();
+    if hex.len() != size || !hex.is_ascii() {
+        return error();
+    }
+    let mut offset = 0;
+    for &register in REGISTERS.iter() {
+        let digits = register.size() * 2;
+        match u16::from_str_radix(&hex[offset..offset + digits], 16) {
+            Ok(value) => chip8.write_register(register, value),
+            Err(_) => return error(),
+        }
+        offset += digits;
+    }
+    "OK".to_string()
+}
+
+/// `P n=value`
+fn write_register(chip8: &mut Chip8, args: &str) -> String {
+    let mut parts = args.splitn(2, '=');
+    let register = parts.next().and_then(parse_hex).and_then(|n| REGISTERS.get(n as usize));
+    let value = parts.next().and_then(parse_hex);
+    match (register, value) {
+        (Some(&register), Some(value)) => {
+            chip8.write_register(register, value as u16);
+            "OK".to_string()
+        }
+        _ => error(),
+    }
+}
+
+/// `m address,length`
+fn read_memory(chip8: &Chip8, args: &str) -> String {
+    // a read past the end of memory is cut short, one starting there is an error
+    let (address, len) = match parse_range(args) {
+        Some((address, len)) if address < MEM_SIZE => (address, len.min(MEM_SIZE - address)),
+        _ => return error(),
+    };
+    (address..address + len).filter_map(|address| chip8.peek(address)).map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `M address,length:bytes`
+fn write_memory(chip8: &mut Chip8, args: &str) -> String {
+    let mut parts = args.splitn(2, ':');
+    let range = parts.next().and_then(parse_range);
+    let bytes = parts.next().and_then(decode_hex);
+    match (range, bytes) {
+        (Some((address, len)), Some(bytes)) if bytes.len() == len => {
+            for (offset, byte) in bytes.into_iter().enumerate() {
+                if !chip8.poke(address + offset, byte) {
+                    return error();
+                }
+            }
+            "OK".to_string()
+        }
+        _ => error(),
+    }
+}
+
+/// `Z type,address,kind` sets and `z type,address,kind` clears a breakpoint or watchpoint
+fn set_point(chip8: &mut Chip8, args: &str, set: bool) -> String {
+    let mut parts = args.split(',');
+    let kind = parts.next();
+    let address = parts.next().and_then(parse_hex);
+    let len = parts.next().and_then(parse_hex);
+    let (address, len) = match (address, len) {
+        (Some(address), Some(len)) if address <= 0xFFF => (address as u16, len as u16),
+        _ => return error(),
+    };
+
+    let watch_kind = match kind {
+        // software and hardware breakpoints are the same thing here
+        Some("0") | Some("1") => {
+            chip8.set_breakpoint(address, set);
+            return "OK".to_string();
+        }
+        Some("2") => WatchKind::Write,
+        Some("3") => WatchKind::Read,
+        Some("4") => WatchKind::Access,
+        _ => return String::new(),
+    };
+    let watchpoint = Watchpoint { address, len: len.max(1), kind: watch_kind, condition: Condition::Always };
+    if set {
+        chip8.add_watchpoint(watchpoint);
+    } else {
+        chip8.remove_watchpoint(watchpoint);
+    }
+    "OK".to_string()
+}
+
+/// `offset,length` of the target description
+fn read_target_xml(args: &str) -> String {
+    let (offset, len) = match parse_range(args) {
+        Some(range) => range,
+        None => return error(),
+    };
+    let xml = TARGET_XML.as_bytes();
+    let start = offset.min(xml.len());
+    let end = (start + len).min(xml.len());
+    let prefix = if end == xml.len() { "l" } else { "m" };
+    format!("{}{}", prefix, String::from_utf8_lossy(&xml[start..end]))
+}
+
+fn parse_hex(hex: &str) -> Option<u32> {
+    u32::from_str_radix(hex, 16).ok()
+}
+
+fn parse_range(args: &str) -> Option<(usize, usize)> {
+    let mut parts = args.splitn(2, ',');
+    let address = parse_hex(parts.next()?)?;
+    let len = parse_hex(parts.next()?)?;
+    Some((address as usize, len as usize))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// `}` escapes the byte that follows XORed with 0x20
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &b in data {
+        if matches!(b, b'$' | b'#' | b'}' | b'*') {
+            escaped.push(b'}');
+            escaped.push(b ^ 0x20);
+        } else {
+            escaped.push(b);
+        }
+    }
+    escaped
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            b'}' => match bytes.next() {
+                Some(&escaped) => unescaped.push(escaped ^ 0x20),
+                None => break,
+            },
+            _ => unescaped.push(b),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::multimedia::headless::Headless;
+
+    #[test]
+    fn escapes_round_trip() {
+        let data = b"a$b#c}d*e";
+        assert_eq!(unescape(&escape(data)), data.to_vec());
+        assert_eq!(checksum_of(b"OK"), 0x9a);
+    }
+
+    #[test]
+    fn registers_are_sent_big_endian_in_order() {
+        let mut chip8 = Chip8::new(Headless);
+        chip8.write_register(Register::V(0), 0x12);
+        chip8.write_register(Register::I, 0x345);
+        let registers: String = REGISTERS.iter().map(|&register| encode_register(&chip8, register)).collect();
+        assert_eq!(registers.len(), 46);
+        assert!(registers.starts_with("12000000"));
+        assert_eq!(&registers[32..40], "03450200");
+
+        assert_eq!(write_registers(&mut chip8, &registers.replace("0345", "0abc")), "OK");
+        assert_eq!(chip8.read_register(Register::I), 0xABC);
+        assert_eq!(write_registers(&mut chip8, "12"), error());
+    }
+
+    #[test]
+    fn memory_reads_stop_at_the_end_of_memory() {
+        let mut chip8 = Chip8::new(Headless);
+        chip8.poke(0xFFF, 0xAB);
+        assert_eq!(read_memory(&chip8, "ffe,2"), "00ab");
+        assert_eq!(read_memory(&chip8, "ffe,ffffffff"), "00ab");
+        assert_eq!(read_memory(&chip8, "0,ffffffff").len(), MEM_SIZE * 2);
+        assert_eq!(read_memory(&chip8, "1000,1"), error());
+        assert_eq!(read_memory(&chip8, "ffffffff,2"), error());
+    }
+}
@@ -0,0 +1,14 @@
+//! The emulator behind the `chipr-8` binary, usable from Rust to drive a machine directly,
+//! e.g. to test ROM subroutines with `chip8::harness`
+
+pub mod chip8;
+pub mod cli;
+pub mod config;
+pub mod console;
+pub mod database;
+pub mod gdb;
+pub mod multimedia;
+pub mod patch;
+pub mod rom;
+pub mod terminal;
+pub mod watch;
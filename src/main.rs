@@ -1,38 +1,459 @@
-mod chip8;
-mod multimedia;
+use chipr_8::{chip8, cli, config, console, database, gdb, multimedia, patch, rom, terminal, watch};
 
 use chip8::Chip8;
+use chip8::analysis::Analysis;
+use chip8::cheats::CheatFile;
+use chip8::disasm;
+use chip8::trace::{self, TraceReader, Tracer};
+use chip8::coverage::SourceMap;
+use chip8::decompile::Decompiler;
+use chip8::harness::TestFile;
+use cli::{CheatOptions, Command, CoverageOptions, Frontend, ProfileOptions, RunOptions};
+use config::{Config, Settings};
+use console::CheatConsole;
+use database::Database;
 use multimedia::Multimedia;
+use multimedia::headless::Headless;
+use multimedia::recorder::Recorder;
+use multimedia::sound::Beeper;
+use multimedia::wav::WavBeeper;
+use patch::Format;
+use rom::RomFile;
+use std::cell::RefCell;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use terminal::Terminal;
+use watch::FileWatcher;
+
+/// The ROM could not be loaded or the emulator stopped with an error
+const EXIT_FAILURE: i32 = 1;
+/// The command line could not be parsed
+const EXIT_USAGE: i32 = 2;
+
+const ROM_START_ADDR: usize = 0x200;
 
 fn main() {
-    let args = &std::env::args().collect::<Vec<String>>();
-
-    let rom_file:Option<&str> =
-        if args.len() > 1 {
-            let rom_file = &args[1];
-            Some(rom_file)
-        } else if cfg!(debug_assertions) {
-            Some("roms/rom.ch8")
-        } else {
-            None
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+
+    let command = cli::parse(&args).unwrap_or_else(|e| {
+        eprintln!("{}\n\nRun 'chipr-8 --help' for usage", e);
+        std::process::exit(EXIT_USAGE);
+    });
+
+    let result = match command {
+        Command::Run(options) => run_chip8(options),
+        Command::Test(options) => test_rom(options),
+        Command::Disasm(rom) => disasm_rom(&rom),
+        Command::Info { rom, database } => print_info(&rom, database.as_deref()),
+        Command::Analyze { rom, dot } => analyze_rom(&rom, dot.as_deref()),
+        Command::Decompile { rom, syntax } => {
+            rom::read(&rom).map(|rom| print!("{}", Decompiler::new(&rom, syntax).decompile()))
+        }
+        Command::TraceDiff(a, b) => diff_traces(&a, &b),
+        Command::PatchCreate { original, modified, patch } => create_patch(&original, &modified, &patch),
+        Command::ConfigDump { config, database, rom, settings } =>
+            dump_config(config.as_deref(), database.as_deref(), rom.as_deref(), &settings),
+        Command::Help => {
+            print!("{}", cli::USAGE);
+            Ok(())
+        }
+        Command::Version => {
+            println!("chipr-8 {}", env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+    };
+
+    if let Err(e) = result {
+        exit_with_error(&e);
+    }
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(EXIT_FAILURE);
+}
+
+fn load_database(path: Option<&str>) -> Result<Database, String> {
+    match path {
+        Some(path) => Database::load(path),
+        None => Ok(Database::bundled()),
+    }
+}
+
+/// The config file settings, what the database knows for the ROM and what its file asks for,
+/// with the command line ones on top
+fn merged_settings(config: Option<&str>, database: Option<&str>, rom: Option<&RomFile>,
+                   cli_settings: &Settings) -> Result<Settings, String> {
+    let config = Config::load(config)?;
+    let database = load_database(database)?;
+    let sha1 = rom.map(|rom| rom::sha1(&rom.bytes));
+    let mut detected = sha1.as_deref().and_then(|sha1| database.lookup(sha1)).map(|entry| entry.settings()).unwrap_or_default();
+    if let Some(rom) = rom {
+        detected.merge(&rom.settings);
+    }
+    let mut settings = config.settings_for(sha1.as_deref(), Some(&detected));
+    settings.merge(cli_settings);
+    Ok(settings)
+}
+
+fn build_chip8(options: &RunOptions, rom: &[u8], settings: &Settings) -> Result<Chip8, String> {
+    let palette = settings.palette()?;
+    let audio = settings.audio()?;
+    let keymap = settings.keymap()?;
+
+    let mut record = options.record.clone();
+    record.palette = options.gif_palette.unwrap_or(palette);
+    record.audio = audio;
+    let mut recorder = Recorder::new(record);
+    recorder.start().map_err(|e| format!("Could not start recording: {}", e))?;
+
+    let mut chip8 = match &options.frontend {
+        Frontend::Window => Chip8::new(Multimedia::new(settings.scale(), palette, audio, keymap, options.debug)),
+        Frontend::Terminal(tui) => {
+            let terminal = Terminal::new(tui.charset, palette, tui.bell && !audio.muted, keymap)
+                .map_err(|e| format!("Could not set up the terminal: {}", e))?;
+            Chip8::new(terminal)
+        }
+        Frontend::Headless => Chip8::new(Headless),
+    };
+    chip8.set_recorder(recorder);
+    chip8.set_speed(settings.speed());
+    chip8.set_quirks(settings.quirks()?);
+    chip8.set_paused(options.paused);
+    chip8.set_debugging(options.debug);
+    for watchpoint in &options.watchpoints {
+        chip8.add_watchpoint(*watchpoint);
+    }
+    if let Some(seed) = options.seed {
+        chip8.set_seed(seed);
+    }
+    chip8.set_state_path(options.state.clone().unwrap_or_else(|| format!("{}.state", options.rom)).into());
+    chip8.load_rom_bytes(rom)
+        .map_err(|e| format!("Invalid ROM {}: {}", options.rom, e))?;
+    if let Some(state) = &options.restore {
+        restore_state(&mut chip8, state, rom)?;
+    }
+    let mut cheats = if options.cheats.saved {
+        CheatFile::load(&cheat_file(&options.cheats)?)?.cheats_for(&rom::sha1(rom))
+    } else {
+        Vec::new()
+    };
+    cheats.extend(options.cheats.freeze.iter().cloned());
+    chip8.set_cheats(cheats);
+    if let Some(trace) = &options.trace {
+        let tracer = Tracer::create(trace.clone()).map_err(|e| format!("Could not create trace {}: {}", trace.path, e))?;
+        chip8.set_tracer(tracer);
+    }
+    if options.profile.report.is_some() || options.profile.folded.is_some() {
+        chip8.start_profiling();
+    }
+    if options.coverage.listing.is_some() || options.coverage.lcov.is_some() {
+        chip8.start_coverage();
+    }
+    Ok(chip8)
+}
+
+/// `--cheat-file`, or `cheats.toml` next to the default config file
+fn cheat_file(options: &CheatOptions) -> Result<PathBuf, String> {
+    match &options.file {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Config::default_path()
+            .map(|config| config.with_file_name("cheats.toml"))
+            .ok_or_else(|| "No home directory for the cheat file, give one with --cheat-file".to_string()),
+    }
+}
+
+/// Restores the save state, but with the program code of `rom` so edits to the ROM take effect
+fn restore_state(chip8: &mut Chip8, path: &str, rom: &[u8]) -> Result<(), String> {
+    let state = fs::read(path).map_err(|e| format!("Could not read save state {}: {}", path, e))?;
+    chip8.load_state(&state).map_err(|e| format!("Invalid save state {}: {}", path, e))?;
+    chip8.load_rom_bytes(rom)
+}
+
+/// Loads the ROM again from disk and starts it over
+fn reload_rom(chip8: &mut Chip8, options: &RunOptions) -> Result<(), String> {
+    let rom = rom::load(&options.rom, options.entry.as_deref(), &options.patches)?.bytes;
+    chip8.load_rom_bytes(&rom)
+        .map_err(|e| format!("Invalid ROM {}: {}", options.rom, e))?;
+    chip8.reset();
+    if let Some(state) = &options.restore {
+        restore_state(chip8, state, &rom)?;
+    }
+    Ok(())
+}
+
+fn run_chip8(options: RunOptions) -> Result<(), String> {
+    let rom = rom::load(&options.rom, options.entry.as_deref(), &options.patches)?;
+    let settings = merged_settings(options.config.as_deref(), options.database.as_deref(), Some(&rom), &options.settings)?;
+    let rom = rom.bytes;
+    let mut chip8 = build_chip8(&options, &rom, &settings)?;
+    let audio = settings.audio()?;
+
+    let audio_out = &options.audio_out;
+    let wav = match &audio_out.path {
+        Some(path) => {
+            let wav = File::create(path)
+                .and_then(|file| WavBeeper::new(BufWriter::new(file), audio_out.sample_rate, audio))
+                .map_err(|e| format!("Could not create {}: {}", path, e))?;
+            Some(Rc::new(RefCell::new(wav)))
+        }
+        None => None,
+    };
+    if let Some(wav) = &wav {
+        chip8.add_beeper(Rc::clone(wav) as Rc<RefCell<dyn Beeper>>);
+    }
+
+    if let Some(port) = options.gdb {
+        gdb::serve(&mut chip8, port)?;
+    } else {
+        match options.frames {
+            Some(frames) => {
+                for _ in 0..frames {
+                    chip8.run_frame(chip8.cycles_per_frame());
+                }
+            }
+            None => {
+                let mut watcher = if options.watch { Some(FileWatcher::new(&options.rom)) } else { None };
+                let mut console = if options.cheats.search {
+                    Some(CheatConsole::start(cheat_file(&options.cheats)?, rom::sha1(&rom)))
+                } else {
+                    None
+                };
+                while chip8.is_on() {
+                    chip8.execute_cycle();
+                    if let Some(console) = &mut console {
+                        console.poll(&mut chip8);
+                    }
+                    if let Some(watcher) = &mut watcher {
+                        if watcher.changed() {
+                            let message = match reload_rom(&mut chip8, &options) {
+                                Ok(()) => format!("Reloaded {}", options.rom),
+                                Err(e) => format!("Could not reload: {}", e),
+                            };
+                            chip8.notify(&message);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(wav) = wav {
+        let wav = wav.borrow();
+        eprintln!("Wrote {} audio samples, {} of them beeping", wav.samples(), wav.beep_samples());
+    }
+    chip8.finish_trace()?;
+    write_profile(&mut chip8, &options.profile)?;
+    write_coverage(&mut chip8, &options.rom, &options.coverage)?;
+
+    match chip8.error() {
+        Some(e) => Err(e.to_string()),
+        None => Ok(()),
+    }
+}
+
+/// Writes what the profiler counted to the files asked for
+fn write_profile(chip8: &mut Chip8, options: &ProfileOptions) -> Result<(), String> {
+    let profiler = match chip8.take_profiler() {
+        Some(profiler) => profiler,
+        None => return Ok(()),
+    };
+    if let Some(path) = &options.report {
+        write_report(path, &|out| profiler.write_report(out))?;
+        eprintln!("Wrote profile to {}", path);
+    }
+    if let Some(path) = &options.folded {
+        write_report(path, &|out| profiler.write_folded(out))?;
+        eprintln!("Wrote folded stacks to {}", path);
+    }
+    Ok(())
+}
+
+/// Writes the coverage listing and lcov file asked for, the lcov one through the source map if there is one
+fn write_coverage(chip8: &mut Chip8, rom_path: &str, options: &CoverageOptions) -> Result<(), String> {
+    let coverage = match chip8.take_coverage() {
+        Some(coverage) => coverage,
+        None => return Ok(()),
+    };
+    let rom = chip8.rom().to_vec();
+    if let Some(path) = &options.listing {
+        write_report(path, &|out| coverage.write_listing(&rom, out))?;
+        eprintln!("Wrote coverage to {}", path);
+    }
+    if let Some(path) = &options.lcov {
+        let default_map = format!("{}.map", rom_path);
+        let source_map = match &options.source_map {
+            Some(map) => Some(SourceMap::load(map)?),
+            None if Path::new(&default_map).exists() => Some(SourceMap::load(&default_map)?),
+            None => None,
         };
+        write_report(path, &|out| coverage.write_lcov(rom_path, &rom, source_map.as_ref(), out))?;
+        eprintln!("Wrote lcov coverage to {}", path);
+    }
+    Ok(())
+}
 
-    match rom_file {
-        Some(file) => run_chip8(file),
+fn write_report(path: &str, write: &dyn Fn(&mut dyn Write) -> io::Result<()>) -> Result<(), String> {
+    File::create(path)
+        .and_then(|file| {
+            let mut out = BufWriter::new(file);
+            write(&mut out)?;
+            out.flush()
+        })
+        .map_err(|e| format!("Could not write {}: {}", path, e))
+}
+
+/// Runs the ROM headless and prints the display it ends up with
+fn test_rom(options: RunOptions) -> Result<(), String> {
+    if options.rom.ends_with(".toml") {
+        return run_test_file(&options.rom);
+    }
+    let rom = rom::load(&options.rom, options.entry.as_deref(), &options.patches)?;
+    let settings = merged_settings(options.config.as_deref(), options.database.as_deref(), Some(&rom), &options.settings)?;
+    let rom = rom.bytes;
+    let mut chip8 = build_chip8(&options, &rom, &settings)?;
+    for _ in 0..options.frames.unwrap_or(0) {
+        chip8.run_frame(chip8.cycles_per_frame());
+    }
+    chip8.finish_trace()?;
+    write_profile(&mut chip8, &options.profile)?;
+    write_coverage(&mut chip8, &options.rom, &options.coverage)?;
+    match chip8.error() {
+        Some(e) => {
+            chip8.dump();
+            Err(e.to_string())
+        }
         None => {
-            eprintln!("Chipr-8 needs a ROM to run, please run: \nchipr-8 rom_file.ch8");
-            std::process::exit(1);
+            chip8.gfx().dump();
+            Ok(())
         }
     }
+}
 
+/// Runs the subroutine tests of a test file, failing if any does
+fn run_test_file(path: &str) -> Result<(), String> {
+    let (file, rom) = TestFile::load(path)?;
+    let results = file.run(&rom)?;
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    for (name, result) in &results {
+        match result {
+            Ok(()) => println!("test {} ... ok", name),
+            Err(e) => println!("test {} ... FAILED\n    {}", name, e.replace('\n', "\n    ")),
+        }
+    }
+    println!("\n{} passed, {} failed", results.len() - failed, failed);
+    if failed > 0 {
+        return Err(format!("{} of {} tests failed", failed, results.len()));
+    }
+    Ok(())
 }
 
-fn run_chip8(rom_file: &str) {
-    let multimedia = Multimedia::new();
-    let mut chip8 = Chip8::new(multimedia);
-    chip8.load_rom(rom_file);
+fn disasm_rom(rom_file: &str) -> Result<(), String> {
+    let rom = rom::read(rom_file)?;
+    for (i, word) in rom.chunks(2).enumerate() {
+        let opcode = match word {
+            [high, low] => (*high as u16) << 8 | *low as u16,
+            [high] => (*high as u16) << 8,
+            _ => unreachable!(),
+        };
+        println!("{:03X}  {:04X}  {}", ROM_START_ADDR + i * 2, opcode, disasm::disassemble(opcode));
+    }
+    Ok(())
+}
+
+/// Prints what the analyzer finds in the ROM, writing its control-flow graph when asked
+fn analyze_rom(rom_file: &str, dot: Option<&str>) -> Result<(), String> {
+    let analysis = Analysis::new(&rom::read(rom_file)?);
+    analysis.write_report(&mut io::stdout()).map_err(|e| format!("Could not print the analysis: {}", e))?;
+    match dot {
+        Some(path) => write_report(path, &|out| analysis.write_dot(out)),
+        None => Ok(()),
+    }
+}
+
+/// Writes the patch from one ROM to the other, BPS or IPS depending on the patch file name
+fn create_patch(original: &str, modified: &str, path: &str) -> Result<(), String> {
+    let patch = patch::create(Format::from_path(path), &rom::read(original)?, &rom::read(modified)?)?;
+    fs::write(path, &patch).map_err(|e| format!("Could not write {}: {}", path, e))?;
+    println!("Wrote {}, {} bytes", path, patch.len());
+    Ok(())
+}
+
+/// Prints where two traces stop agreeing, failing if they do
+fn diff_traces(a: &str, b: &str) -> Result<(), String> {
+    let divergence = match trace::first_divergence(TraceReader::open(a)?, TraceReader::open(b)?)? {
+        Some(divergence) => divergence,
+        None => {
+            println!("The traces match");
+            return Ok(());
+        }
+    };
+    println!("The traces diverge after {} matching instructions", divergence.index);
+    for (path, entry) in [(a, &divergence.a), (b, &divergence.b)].iter() {
+        match entry {
+            Some(entry) => println!("{}: {}", path, entry),
+            None => println!("{}: ends here", path),
+        }
+    }
+    if let (Some(entry_a), Some(entry_b)) = (&divergence.a, &divergence.b) {
+        println!("Differs in: {}", entry_a.differences(entry_b).join(", "));
+    }
+    Err("The traces differ".to_string())
+}
+
+fn print_info(rom_file: &str, database: Option<&str>) -> Result<(), String> {
+    let rom = rom::read(rom_file)?;
+    let opcodes: Vec<u16> = rom.chunks(2)
+        .filter(|word| word.len() == 2)
+        .map(|word| (word[0] as u16) << 8 | word[1] as u16)
+        .collect();
+    let unknown = opcodes.iter().filter(|opcode| !disasm::is_known(**opcode)).count();
+
+    println!("File:         {}", rom_file);
+    println!("Size:         {} bytes", rom.len());
+    println!("Words:        {}", opcodes.len());
+    println!("Instructions: {}", opcodes.len() - unknown);
+    // sprites and other data show up here too, a high count alone does not mean a broken ROM
+    println!("Unknown:      {}", unknown);
+
+    let sha1 = rom::sha1(&rom);
+    println!("SHA-1:        {}", sha1);
+    let database = load_database(database)?;
+    let entry = match database.lookup(&sha1) {
+        Some(entry) => entry,
+        None => {
+            println!("Database:     not found among {} known ROMs", database.len());
+            return Ok(());
+        }
+    };
+    println!("Title:        {}", entry.title);
+    println!("Platform:     {}", entry.platform.as_deref().unwrap_or("unsupported"));
+    if let Some(quirks) = entry.quirks {
+        println!("Quirks:       {}", quirks.to_spec());
+    }
+    if let Some(tickrate) = entry.tickrate {
+        println!("Speed:        {} instructions per frame ({} per second)", tickrate, tickrate.saturating_mul(60));
+    }
+    if let Some(palette) = entry.palette {
+        println!("Palette:      {}", palette.to_spec());
+    }
+    for (action, key) in &entry.keys {
+        println!("Key:          {} -> {:X}", action, key);
+    }
+    Ok(())
+}
 
-    while chip8.is_on() {
-        chip8.execute_cycle();
+fn dump_config(config: Option<&str>, database: Option<&str>, rom_file: Option<&str>,
+               cli_settings: &Settings) -> Result<(), String> {
+    let rom = rom_file.map(|path| rom::load(path, None, &[])).transpose()?;
+    let settings = merged_settings(config, database, rom.as_ref(), cli_settings)?;
+    if let (Some(rom_file), Some(rom)) = (rom_file, &rom) {
+        println!("# {}, overridden in [rom.{}]\n", rom_file, rom::sha1(&rom.bytes));
     }
+    print!("{}", settings.effective()?.to_toml()?);
+    Ok(())
 }
@@ -1,38 +1,92 @@
 
 use crate::chip8::gfx::Gfx;
-use crate::multimedia::input::{EventManager, Input};
+use crate::multimedia::debugger::Snapshot;
+use crate::multimedia::input::{Command, EventManager, Input};
+use crate::multimedia::keymap::Keymap;
+use crate::multimedia::osd::Status;
+use crate::multimedia::palette::Palette;
 use crate::multimedia::screen::{Drawable, Screen};
-use crate::multimedia::sound::{Beeper, Sound};
+use crate::multimedia::sound::{AudioConfig, Beeper, Sound};
+use sdl2::keyboard::Keycode;
 
 pub mod screen;
+pub mod debugger;
 pub mod sound;
 pub mod input;
+pub mod keymap;
+pub mod osd;
+pub mod palette;
+pub mod recorder;
+pub mod headless;
+pub mod wav;
+pub mod text;
 
 
 pub struct Multimedia {
     screen: Screen,
     sound: Sound,
-    input: Input
+    input: Input,
+    // The input commands plus breakpoints toggled by clicking the debugger
+    commands: Vec<Command>,
 }
 
 impl Drawable for Multimedia {
     fn draw(&mut self, gfx: &mut Gfx) {
         self.screen.draw(gfx);
     }
+
+    fn show_message(&mut self, message: &str) {
+        self.screen.show_message(message);
+    }
+
+    fn update_status(&mut self, status: &Status) {
+        self.screen.update_status(status);
+    }
+
+    fn toggle_osd(&mut self) {
+        self.screen.toggle_osd();
+    }
+
+    fn update_debugger(&mut self, snapshot: &Snapshot) {
+        self.screen.update_debugger(snapshot);
+    }
 }
 impl Beeper for Multimedia {
-    fn start_beep(&mut self) {
-        self.sound.start_beep();
+    fn start_beep(&mut self, offset: f32) {
+        self.sound.start_beep(offset);
     }
 
-    fn stop_beep(&mut self) {
-        self.sound.stop_beep();
+    fn stop_beep(&mut self, offset: f32) {
+        self.sound.stop_beep(offset);
+    }
+
+    fn tick(&mut self) {
+        self.sound.tick();
     }
 }
 
 impl EventManager for Multimedia {
     fn check_events(&mut self) {
         self.input.check_events();
+        for key in self.input.audio_keys() {
+            match key {
+                Keycode::M => self.sound.toggle_mute(),
+                Keycode::Minus => self.sound.change_volume(-0.05),
+                Keycode::Equals => self.sound.change_volume(0.05),
+                Keycode::LeftBracket => self.sound.change_pitch(-1.0),
+                Keycode::RightBracket => self.sound.change_pitch(1.0),
+                Keycode::Backslash => self.sound.next_waveform(),
+                _ => {}
+            }
+        }
+
+        self.commands.clear();
+        self.commands.extend_from_slice(self.input.commands());
+        for &(x, y) in self.input.clicks() {
+            if let Some(address) = self.screen.breakpoint_at(x, y) {
+                self.commands.push(Command::ToggleBreakpoint(address));
+            }
+        }
     }
 
     fn is_quiting(&self) -> bool {
@@ -42,18 +96,23 @@ impl EventManager for Multimedia {
     fn is_key_pressed(&self, key: u8) -> bool {
         self.input.is_key_pressed(key)
     }
+
+    fn commands(&self) -> &[Command] {
+        &self.commands
+    }
 }
 impl Multimedia {
-    pub fn new() -> Multimedia {
+    pub fn new(scale: u32, palette: Palette, audio_config: AudioConfig, keymap: Keymap, debug: bool) -> Multimedia {
         let sdl_context = sdl2::init().unwrap();
-        let screen = Screen::new(&sdl_context);
-        let sound = Sound::new(&sdl_context);
-        let input = Input::new(&sdl_context);
+        let screen = Screen::new(&sdl_context, scale, palette, debug);
+        let sound = Sound::new(&sdl_context, audio_config);
+        let input = Input::new(&sdl_context, keymap);
 
         Multimedia {
             screen,
             sound,
-            input
+            input,
+            commands: Vec::new(),
         }
     }
 
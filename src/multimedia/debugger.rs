@@ -0,0 +1,300 @@
+use crate::chip8::disasm;
+use crate::multimedia::screen::KEYPAD_LAYOUT;
+use crate::multimedia::text::{GLYPH_HEIGHT, GLYPH_WIDTH};
+
+/// Debugger pixel size, the panels hold a lot of text so it stays small whatever the scale
+pub const PANEL_UNIT: u32 = 2;
+
+const CELL_WIDTH: i32 = ((GLYPH_WIDTH + 1) * PANEL_UNIT) as i32;
+const LINE_HEIGHT: i32 = ((GLYPH_HEIGHT + 2) * PANEL_UNIT) as i32;
+
+/// Characters in a disassembly line, breakpoint and self-modified code markers included
+const DISASM_COLUMNS: i32 = 26;
+/// Disassembly lines shown when the game view is shorter than that
+const MIN_DISASM_LINES: i32 = 19;
+/// Title and eight rows of memory, the tallest panel under the game
+const BOTTOM_LINES: i32 = 9;
+const MEMORY_ROWS: usize = 8;
+const MEMORY_COLUMNS: usize = 8;
+/// Characters taken by the registers, stack, memory and keypad panels, spacing included
+const BOTTOM_COLUMNS: [i32; 4] = [24, 29, 30, 10];
+
+/// The machine as the debugger panels show it, taken on every frame
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub opcode: u16,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub sp: usize,
+    pub stack: [u16; 16],
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub memory: Vec<u8>,
+    /// Addresses written in the last second, highlighted in the memory view
+    pub recent_writes: Vec<u16>,
+    pub breakpoints: Vec<u16>,
+    /// Code overwritten after it ran, marked in the disassembly
+    pub self_modified: Vec<u16>,
+    pub keys: [bool; 16],
+}
+
+/// Adds the labels of a panel whose top left corner is at x, y
+type Panel = fn(&Snapshot, i32, i32, &mut Vec<Label>);
+
+/// Text to draw at a window position, inverted labels swap the colours
+#[derive(Debug, PartialEq)]
+pub struct Label {
+    pub x: i32,
+    pub y: i32,
+    pub text: String,
+    pub inverted: bool,
+}
+
+/// Where the panels go around the game view: the disassembly on its right, registers,
+/// stack, memory and keypad under it
+pub struct Debugger {
+    disasm_x: i32,
+    disasm_lines: i32,
+    bottom_y: i32,
+    width: u32,
+    height: u32,
+    snapshot: Option<Snapshot>,
+    changed: bool,
+}
+
+impl Debugger {
+    pub fn new(game_width: u32, game_height: u32) -> Debugger {
+        let disasm_x = game_width as i32 + CELL_WIDTH;
+        // a line for the title
+        let disasm_lines = (game_height as i32 / LINE_HEIGHT - 1).max(MIN_DISASM_LINES);
+        let bottom_y = (game_height as i32).max((disasm_lines + 1) * LINE_HEIGHT) + LINE_HEIGHT / 2;
+        let right = disasm_x + DISASM_COLUMNS * CELL_WIDTH + CELL_WIDTH;
+        let bottom_width = BOTTOM_COLUMNS.iter().sum::<i32>() * CELL_WIDTH;
+
+        Debugger {
+            disasm_x,
+            disasm_lines,
+            bottom_y,
+            width: right.max(bottom_width) as u32,
+            height: (bottom_y + BOTTOM_LINES * LINE_HEIGHT + LINE_HEIGHT / 2) as u32,
+            snapshot: None,
+            changed: false,
+        }
+    }
+
+    /// Window size that fits the game and every panel
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn set_snapshot(&mut self, snapshot: &Snapshot) {
+        if self.snapshot.as_ref() != Some(snapshot) {
+            self.snapshot = Some(snapshot.clone());
+            self.changed = true;
+        }
+    }
+
+    /// Whether the panels have to be drawn again since the last call
+    pub fn take_changed(&mut self) -> bool {
+        let changed = self.changed;
+        self.changed = false;
+        changed
+    }
+
+    /// The address of the disassembly line at the window position, to toggle a breakpoint on
+    pub fn address_at(&self, x: i32, y: i32) -> Option<u16> {
+        let snapshot = self.snapshot.as_ref()?;
+        if x < self.disasm_x || x >= self.disasm_x + DISASM_COLUMNS * CELL_WIDTH || y < LINE_HEIGHT {
+            return None;
+        }
+        let line = y / LINE_HEIGHT - 1;
+        self.disasm_addresses(snapshot.pc).nth(line as usize)
+    }
+
+    /// Everything the panels show, in window coordinates
+    pub fn labels(&self) -> Vec<Label> {
+        let snapshot = match &self.snapshot {
+            Some(snapshot) => snapshot,
+            None => return Vec::new(),
+        };
+
+        let mut labels = Vec::new();
+        self.disassembly(snapshot, &mut labels);
+
+        let panels: [Panel; 4] = [registers, stack, memory, keypad];
+        let mut x = 0;
+        for (panel, width) in panels.iter().zip(BOTTOM_COLUMNS.iter()) {
+            panel(snapshot, x, self.bottom_y, &mut labels);
+            x += width * CELL_WIDTH;
+        }
+        labels
+    }
+
+    /// Addresses of the disassembly lines, PC in the middle
+    fn disasm_addresses(&self, pc: u16) -> impl Iterator<Item = u16> {
+        let lines = self.disasm_lines as u16;
+        let last = 0xFFE - (lines - 1) * 2;
+        let first = pc.saturating_sub(lines / 2 * 2).min(last);
+        (0..lines).map(move |line| first + line * 2)
+    }
+
+    fn disassembly(&self, snapshot: &Snapshot, labels: &mut Vec<Label>) {
+        labels.push(title(self.disasm_x, 0, "DISASSEMBLY"));
+        for (line, address) in self.disasm_addresses(snapshot.pc).enumerate() {
+            let opcode = (snapshot.memory[address as usize] as u16) << 8 | snapshot.memory[address as usize + 1] as u16;
+            let breakpoint = if snapshot.breakpoints.contains(&address) { '*' } else { ' ' };
+            let modified = [address, address + 1].iter().any(|a| snapshot.self_modified.contains(a));
+            let modified = if modified { '!' } else { ' ' };
+            let mut text = format!("{}{:03X}{}{:04X} {}", breakpoint, address, modified, opcode, disasm::disassemble(opcode));
+            text.truncate(DISASM_COLUMNS as usize);
+            labels.push(Label {
+                x: self.disasm_x,
+                y: (line as i32 + 1) * LINE_HEIGHT,
+                text,
+                inverted: address == snapshot.pc,
+            });
+        }
+    }
+}
+
+fn title(x: i32, y: i32, text: &str) -> Label {
+    Label { x, y, text: text.to_string(), inverted: true }
+}
+
+fn line(x: i32, y: i32, line: i32, text: String) -> Label {
+    Label { x, y: y + line * LINE_HEIGHT, text, inverted: false }
+}
+
+fn registers(snapshot: &Snapshot, x: i32, y: i32, labels: &mut Vec<Label>) {
+    labels.push(title(x, y, "REGISTERS"));
+    for row in 0..4 {
+        let text = (0..4)
+            .map(|col| row * 4 + col)
+            .map(|i| format!("V{:X} {:02X}", i, snapshot.v[i]))
+            .collect::<Vec<_>>()
+            .join(" ");
+        labels.push(line(x, y, row as i32 + 1, text));
+    }
+    labels.push(line(x, y, 5, format!("PC {:03X}  I {:03X}", snapshot.pc, snapshot.i)));
+    labels.push(line(x, y, 6, format!("OP {:04X} SP {:X}", snapshot.opcode, snapshot.sp)));
+    labels.push(line(x, y, 7, format!("DT {:02X}  ST {:02X}", snapshot.delay_timer, snapshot.sound_timer)));
+}
+
+/// Only the entries below SP are in use, the rest are shown as dashes
+fn stack(snapshot: &Snapshot, x: i32, y: i32, labels: &mut Vec<Label>) {
+    labels.push(title(x, y, "STACK"));
+    for row in 0..4 {
+        let text = (0..4)
+            .map(|col| row * 4 + col)
+            .map(|i| if i < snapshot.sp {
+                format!("S{:X} {:03X}", i, snapshot.stack[i])
+            } else {
+                format!("S{:X} ---", i)
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        labels.push(line(x, y, row as i32 + 1, text));
+    }
+}
+
+/// Eight rows around I, one label per byte so recent writes can be highlighted
+fn memory(snapshot: &Snapshot, x: i32, y: i32, labels: &mut Vec<Label>) {
+    labels.push(title(x, y, "MEMORY AT I"));
+    let size = MEMORY_ROWS * MEMORY_COLUMNS;
+    let start = (snapshot.i as usize & !(MEMORY_COLUMNS - 1)).saturating_sub(MEMORY_COLUMNS * 2).min(snapshot.memory.len() - size);
+    for row in 0..MEMORY_ROWS {
+        let address = start + row * MEMORY_COLUMNS;
+        let y = y + (row as i32 + 1) * LINE_HEIGHT;
+        labels.push(Label { x, y, text: format!("{:03X}", address), inverted: false });
+        for col in 0..MEMORY_COLUMNS {
+            let address = address + col;
+            labels.push(Label {
+                x: x + (4 + col as i32 * 3) * CELL_WIDTH,
+                y,
+                text: format!("{:02X}", snapshot.memory[address]),
+                inverted: snapshot.recent_writes.contains(&(address as u16)),
+            });
+        }
+    }
+}
+
+fn keypad(snapshot: &Snapshot, x: i32, y: i32, labels: &mut Vec<Label>) {
+    labels.push(title(x, y, "KEYPAD"));
+    for (row, keys) in KEYPAD_LAYOUT.iter().enumerate() {
+        for (col, key) in keys.iter().enumerate() {
+            labels.push(Label {
+                x: x + col as i32 * 2 * CELL_WIDTH,
+                y: y + (row as i32 + 1) * LINE_HEIGHT,
+                text: format!("{:X}", key),
+                inverted: snapshot.keys[*key],
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        let mut memory = vec![0; 4096];
+        memory[0x200..0x206].copy_from_slice(&[0x60, 0x05, 0xA3, 0x00, 0xF0, 0x55]);
+        Snapshot {
+            opcode: 0xA300,
+            v: [0; 16],
+            i: 0x300,
+            pc: 0x204,
+            sp: 1,
+            stack: [0x20A; 16],
+            delay_timer: 0,
+            sound_timer: 0,
+            memory,
+            recent_writes: vec![0x300],
+            breakpoints: vec![0x202],
+            self_modified: vec![0x205],
+            keys: [false; 16],
+        }
+    }
+
+    fn find<'a>(labels: &'a [Label], text: &str) -> &'a Label {
+        labels.iter().find(|label| label.text.starts_with(text)).unwrap()
+    }
+
+    #[test]
+    fn disassembly_is_centered_on_pc_with_breakpoints_and_modified_code_marked() {
+        let mut debugger = Debugger::new(64 * 12, 32 * 12);
+        debugger.set_snapshot(&snapshot());
+        let labels = debugger.labels();
+
+        let current = find(&labels, " 204!F055 LD [I], V0");
+        assert!(current.inverted);
+        let breakpoint = find(&labels, "*202 A300 LD I, 0x300");
+        assert!(!breakpoint.inverted);
+        assert_eq!(current.y - breakpoint.y, LINE_HEIGHT);
+
+        let x = breakpoint.x + CELL_WIDTH;
+        assert_eq!(debugger.address_at(x, breakpoint.y + 1), Some(0x202));
+        assert_eq!(debugger.address_at(x, 0), None);
+        assert_eq!(debugger.address_at(0, breakpoint.y), None);
+    }
+
+    #[test]
+    fn panels_show_registers_stack_and_recent_writes() {
+        let mut debugger = Debugger::new(64 * 4, 32 * 4);
+        debugger.set_snapshot(&snapshot());
+        assert!(debugger.take_changed());
+        let labels = debugger.labels();
+
+        find(&labels, "PC 204  I 300");
+        find(&labels, "S0 20A S1 ---");
+        let row = find(&labels, "300");
+        let written = labels.iter().find(|label| label.y == row.y && label.x > row.x).unwrap();
+        assert!(written.inverted);
+
+        let (width, height) = debugger.size();
+        assert!(labels.iter().all(|label| label.x >= 0 && label.y >= 0 && (label.x as u32) < width && (label.y as u32) < height));
+        debugger.set_snapshot(&snapshot());
+        assert!(!debugger.take_changed());
+    }
+}
@@ -0,0 +1,35 @@
+use crate::chip8::gfx::Gfx;
+use crate::multimedia::input::{Command, EventManager};
+use crate::multimedia::screen::Drawable;
+use crate::multimedia::sound::Beeper;
+
+/// Frontend without display, sound or input, for tests and batch runs.
+pub struct Headless;
+
+impl Drawable for Headless {
+    fn draw(&mut self, gfx: &mut Gfx) {
+        gfx.set_needs_redraw(false);
+    }
+}
+
+impl Beeper for Headless {
+    fn start_beep(&mut self, _offset: f32) {}
+
+    fn stop_beep(&mut self, _offset: f32) {}
+}
+
+impl EventManager for Headless {
+    fn check_events(&mut self) {}
+
+    fn is_quiting(&self) -> bool {
+        false
+    }
+
+    fn is_key_pressed(&self, _key: u8) -> bool {
+        false
+    }
+
+    fn commands(&self) -> &[Command] {
+        &[]
+    }
+}
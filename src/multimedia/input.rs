@@ -1,26 +1,77 @@
 use sdl2::{EventPump, Sdl};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
+
+use crate::multimedia::keymap::Keymap;
+
+/// Emulator controls, triggered by hotkeys outside the keypad
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    TogglePause,
+    /// Reloads the ROM and starts over
+    Reset,
+    /// Runs a single frame while paused
+    FrameAdvance,
+    /// Held, runs faster than real time while reported
+    FastForward,
+    SpeedUp,
+    SpeedDown,
+    ToggleRecord,
+    SaveState,
+    LoadState,
+    /// Shows or hides the on-screen display
+    ToggleOsd,
+    /// Sets or clears a breakpoint at the address
+    ToggleBreakpoint(u16),
+}
 
 pub struct Input {
     event_pump: EventPump,
+    keymap: Keymap,
     keys: [bool; 16],
     is_quiting: bool,
+    commands: Vec<Command>,
+    // Audio hotkeys pressed since the last check, handled by the frontend itself
+    audio_keys: Vec<Keycode>,
+    // Left clicks since the last check, in window coordinates
+    clicks: Vec<(i32, i32)>,
 }
 
 pub trait EventManager {
     fn check_events(&mut self);
     fn is_quiting(&self) -> bool;
     fn is_key_pressed(&self, key: u8) -> bool;
+    /// Commands issued since the last check, held ones are reported on every check while held
+    fn commands(&self) -> &[Command];
 }
 
 impl EventManager for Input {
     fn check_events(&mut self) {
         self.keys = [false; 16];
+        self.commands.clear();
+        self.audio_keys.clear();
+        self.clicks.clear();
 
         for event in self.event_pump.poll_iter() {
             match event {
                 Event::Quit { .. } => self.is_quiting = true,
+                Event::MouseButtonDown { mouse_btn: MouseButton::Left, x, y, .. } => self.clicks.push((x, y)),
+                Event::KeyDown { keycode: Some(Keycode::F9), repeat: false, .. } => self.commands.push(Command::ToggleRecord),
+                Event::KeyDown { keycode: Some(Keycode::P), repeat: false, .. } => self.commands.push(Command::TogglePause),
+                Event::KeyDown { keycode: Some(Keycode::F1), repeat: false, .. } => self.commands.push(Command::ToggleOsd),
+                Event::KeyDown { keycode: Some(Keycode::F5), repeat: false, .. } => self.commands.push(Command::Reset),
+                Event::KeyDown { keycode: Some(Keycode::F6), repeat: false, .. } => self.commands.push(Command::SaveState),
+                Event::KeyDown { keycode: Some(Keycode::F7), repeat: false, .. } => self.commands.push(Command::LoadState),
+                Event::KeyDown { keycode: Some(Keycode::N), .. } => self.commands.push(Command::FrameAdvance),
+                Event::KeyDown { keycode: Some(Keycode::PageUp), .. } => self.commands.push(Command::SpeedUp),
+                Event::KeyDown { keycode: Some(Keycode::PageDown), .. } => self.commands.push(Command::SpeedDown),
+                Event::KeyDown { keycode: Some(key @ Keycode::M), repeat: false, .. }
+                | Event::KeyDown { keycode: Some(key @ Keycode::Backslash), repeat: false, .. }
+                | Event::KeyDown { keycode: Some(key @ Keycode::Minus), .. }
+                | Event::KeyDown { keycode: Some(key @ Keycode::Equals), .. }
+                | Event::KeyDown { keycode: Some(key @ Keycode::LeftBracket), .. }
+                | Event::KeyDown { keycode: Some(key @ Keycode::RightBracket), .. } => self.audio_keys.push(key),
                 _ => {}
             }
         }
@@ -28,23 +79,15 @@ impl EventManager for Input {
         let keys: Vec<Keycode> = self.event_pump.keyboard_state().pressed_scancodes().filter_map(Keycode::from_scancode).collect();
 
         for key in keys {
-            let op_i = match key {
-                Keycode::Num1 => Some(0x1),
-                Keycode::Num2 => Some(0x2),
-                Keycode::Num3 => Some(0x3),
-                Keycode::Num4 => Some(0xc),
-                Keycode::Q => Some(0x4),
-                Keycode::W => Some(0x5),
-                Keycode::E => Some(0x6),
-                Keycode::R => Some(0xd),
-                Keycode::A => Some(0x7),
-                Keycode::S => Some(0x8),
-                Keycode::D => Some(0x9),
-                Keycode::F => Some(0xe),
-                Keycode::Z => Some(0xa),
-                Keycode::X => Some(0x0),
-                Keycode::C => Some(0xb),
-                Keycode::V => Some(0xf),
+            if key == Keycode::Tab {
+                self.commands.push(Command::FastForward);
+                continue;
+            }
+
+            // printable keys have their (lowercase) ASCII code as keycode
+            let code = key as i32;
+            let op_i = match code {
+                0..=0x7F => self.keymap.keypad_key(code as u8 as char),
                 _ => None,
             };
 
@@ -61,15 +104,31 @@ impl EventManager for Input {
     fn is_key_pressed(&self, key: u8) -> bool {
         self.keys[key as usize]
     }
+
+    fn commands(&self) -> &[Command] {
+        &self.commands
+    }
 }
 
 impl Input {
-    pub fn new(sdl_context: &Sdl) -> Input {
+    pub fn new(sdl_context: &Sdl, keymap: Keymap) -> Input {
         let event_pump = sdl_context.event_pump().unwrap();
         Input {
             event_pump,
+            keymap,
             keys: [false; 16],
             is_quiting: false,
+            commands: Vec::new(),
+            audio_keys: Vec::new(),
+            clicks: Vec::new(),
         }
     }
+
+    pub fn audio_keys(&self) -> &[Keycode] {
+        &self.audio_keys
+    }
+
+    pub fn clicks(&self) -> &[(i32, i32)] {
+        &self.clicks
+    }
 }
\ No newline at end of file
@@ -0,0 +1,72 @@
+/// Keys the window or the terminal already use for pausing, frame advance and the sound
+pub const HOTKEYS: [char; 8] = ['p', 'n', 'm', '-', '=', '[', ']', '\\'];
+
+/// Which keyboard key drives each of the 16 keypad keys. Keys are printable ASCII
+/// characters so the same binding works for the window and the terminal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keymap {
+    keys: [char; 16],
+}
+
+impl Default for Keymap {
+    /// The left side of a QWERTY keyboard, laid out like the COSMAC VIP keypad
+    fn default() -> Keymap {
+        Keymap {
+            keys: ['x', '1', '2', '3', 'q', 'w', 'e', 'a', 's', 'd', 'z', 'c', '4', 'r', 'f', 'v'],
+        }
+    }
+}
+
+impl Keymap {
+    /// Binds keypad key `key` (a hex digit) to the keyboard key `binding` (one character)
+    pub fn bind(&mut self, key: &str, binding: &str) -> Result<(), String> {
+        let index = match key.len() {
+            1 => usize::from_str_radix(key, 16).ok(),
+            _ => None,
+        }.ok_or_else(|| format!("Invalid keypad key '{}', expected a hex digit", key))?;
+
+        let mut chars = binding.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if HOTKEYS.contains(&c.to_ascii_lowercase()) => {
+                Err(format!("Invalid binding '{}' for key {}, it is a hotkey", binding, key))
+            }
+            (Some(c), None) if c.is_ascii_graphic() => {
+                self.keys[index] = c.to_ascii_lowercase();
+                Ok(())
+            }
+            _ => Err(format!("Invalid binding '{}' for key {}, expected a single character", binding, key)),
+        }
+    }
+
+    pub fn binding(&self, key: usize) -> char {
+        self.keys[key]
+    }
+
+    /// The keypad key bound to the character, case insensitive
+    pub fn keypad_key(&self, c: char) -> Option<usize> {
+        let c = c.to_ascii_lowercase();
+        self.keys.iter().position(|key| *key == c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binds_keys_but_not_hotkeys() {
+        let mut keymap = Keymap::default();
+        keymap.bind("a", "K").unwrap();
+        assert_eq!(keymap.binding(0xA), 'k');
+        assert_eq!(keymap.keypad_key('K'), Some(0xA));
+
+        for hotkey in HOTKEYS.iter() {
+            let error = keymap.bind("5", &hotkey.to_string()).unwrap_err();
+            assert!(error.contains("hotkey"), "{}", error);
+        }
+        assert!(keymap.bind("5", "P").is_err());
+        assert_eq!(keymap, Keymap { keys: ['x', '1', '2', '3', 'q', 'w', 'e', 'a', 's', 'd', 'k', 'c', '4', 'r', 'f', 'v'] });
+        assert!(keymap.bind("g", "k").is_err());
+        assert!(keymap.bind("5", "ab").is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use std::time::{Duration, Instant};
+
+/// How long a message stays on screen
+const MESSAGE_DURATION: Duration = Duration::from_secs(2);
+
+/// What the emulator reports to the on-screen display on every frame
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Status {
+    /// Instructions executed in the last second
+    pub ips: u32,
+    /// Frames shown in the last second
+    pub fps: u32,
+    pub paused: bool,
+    pub keys: [bool; 16],
+}
+
+/// On-screen display state shared by the frontends, which decide how to draw it
+pub struct Osd {
+    visible: bool,
+    message: Option<(String, Instant)>,
+    status: Status,
+    changed: bool,
+}
+
+impl Default for Osd {
+    fn default() -> Osd {
+        Osd::new()
+    }
+}
+
+impl Osd {
+    pub fn new() -> Osd {
+        Osd {
+            visible: true,
+            message: None,
+            status: Status::default(),
+            changed: false,
+        }
+    }
+
+    pub fn show_message(&mut self, message: &str) {
+        self.message = Some((message.to_string(), Instant::now()));
+        self.changed |= self.visible;
+    }
+
+    pub fn set_status(&mut self, status: &Status) {
+        if *status != self.status {
+            self.status = *status;
+            self.changed |= self.visible;
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+        self.changed = true;
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_ref().map(|(message, _)| message.as_str())
+    }
+
+    pub fn status(&self) -> &Status {
+        &self.status
+    }
+
+    /// Whether the display has to be drawn again since the last call, expires old messages
+    pub fn take_changed(&mut self) -> bool {
+        if let Some((_, shown)) = &self.message {
+            if shown.elapsed() > MESSAGE_DURATION {
+                self.message = None;
+                self.changed |= self.visible;
+            }
+        }
+        let changed = self.changed;
+        self.changed = false;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_only_count_while_visible() {
+        let mut osd = Osd::new();
+        osd.show_message("Reset");
+        assert!(osd.take_changed());
+        assert!(!osd.take_changed());
+
+        osd.toggle();
+        assert!(osd.take_changed());
+        osd.set_status(&Status { paused: true, ..Status::default() });
+        osd.show_message("Speed: 600 instructions per second");
+        assert!(!osd.take_changed());
+        assert_eq!(osd.message(), Some("Speed: 600 instructions per second"));
+        assert!(osd.status().paused);
+    }
+}
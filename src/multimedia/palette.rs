@@ -0,0 +1,55 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    pub background: Rgb,
+    pub foreground: Rgb,
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette {
+            background: Rgb(0, 0, 0),
+            foreground: Rgb(65, 255, 0),
+        }
+    }
+}
+
+impl Palette {
+    /// Parses either a preset name (`green`, `amber`, `white`, `gameboy`)
+    /// or two hex colours as `background,foreground`, e.g. `000000,41FF00`.
+    pub fn parse(spec: &str) -> Result<Palette, String> {
+        match spec {
+            "green" => return Ok(Palette::default()),
+            "amber" => return Ok(Palette { background: Rgb(0, 0, 0), foreground: Rgb(255, 176, 0) }),
+            "white" => return Ok(Palette { background: Rgb(0, 0, 0), foreground: Rgb(255, 255, 255) }),
+            "gameboy" => return Ok(Palette { background: Rgb(155, 188, 15), foreground: Rgb(15, 56, 15) }),
+            _ => {}
+        }
+
+        let colors: Vec<&str> = spec.split(',').collect();
+        if colors.len() != 2 {
+            return Err(format!("Invalid palette '{}', expected a preset or BG,FG hex colours", spec));
+        }
+        Ok(Palette {
+            background: parse_hex_color(colors[0])?,
+            foreground: parse_hex_color(colors[1])?,
+        })
+    }
+
+    /// The palette as `BG,FG` hex colours, which `parse` reads back
+    pub fn to_spec(self) -> String {
+        let (bg, fg) = (self.background, self.foreground);
+        format!("{:02X}{:02X}{:02X},{:02X}{:02X}{:02X}", bg.0, bg.1, bg.2, fg.0, fg.1, fg.2)
+    }
+}
+
+fn parse_hex_color(color: &str) -> Result<Rgb, String> {
+    let color = color.trim().trim_start_matches('#');
+    let value = u32::from_str_radix(color, 16).map_err(|_| format!("Invalid colour '{}'", color))?;
+    if color.len() != 6 {
+        return Err(format!("Invalid colour '{}', expected RRGGBB", color));
+    }
+    Ok(Rgb((value >> 16) as u8, (value >> 8) as u8, value as u8))
+}
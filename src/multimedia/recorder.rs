@@ -0,0 +1,276 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use gif::{Encoder, Frame, Repeat};
+
+use crate::chip8::gfx::{Gfx, GFX_COLS, GFX_MEM_SIZE, GFX_ROWS};
+use crate::multimedia::palette::Palette;
+use crate::multimedia::sound::{AudioConfig, Beeper, FrameSynth};
+
+const TICKS_PER_SECOND: u64 = 60;
+const AUDIO_SAMPLE_RATE: u32 = 44100;
+
+/// GIF pixels per CHIP-8 pixel `--gif-scale` accepts, the largest still fits the GIF's 16 bit width
+pub const GIF_SCALES: RangeInclusive<u16> = 1..=64;
+
+#[derive(Clone)]
+pub struct RecordOptions {
+    /// GIF output, `None` means the GIF is only started by the hotkey.
+    pub gif_path: Option<String>,
+    pub scale: u16,
+    pub palette: Palette,
+    /// Raw RGB24 frames, one per tick. `-` writes to stdout.
+    pub frames_path: Option<String>,
+    /// Raw signed 16 bit little endian mono PCM at 44100 Hz. `-` writes to stdout.
+    pub audio_path: Option<String>,
+    pub audio: AudioConfig,
+}
+
+impl Default for RecordOptions {
+    fn default() -> RecordOptions {
+        RecordOptions {
+            gif_path: None,
+            scale: 4,
+            palette: Palette::default(),
+            frames_path: None,
+            audio_path: None,
+            audio: AudioConfig::default(),
+        }
+    }
+}
+
+/// Records the frames produced on every 60 Hz tick to an animated GIF
+/// and/or raw streams that can be piped into an external encoder.
+pub struct Recorder {
+    options: RecordOptions,
+    gif: Option<GifRecorder>,
+    frames: Option<Box<dyn Write>>,
+    audio: Option<AudioSynth>,
+}
+
+impl Recorder {
+    pub fn new(options: RecordOptions) -> Recorder {
+        Recorder {
+            options,
+            gif: None,
+            frames: None,
+            audio: None,
+        }
+    }
+
+    /// Opens every output configured in the options.
+    pub fn start(&mut self) -> io::Result<()> {
+        if let Some(path) = self.options.gif_path.clone() {
+            self.gif = Some(GifRecorder::create(&path, self.options.scale, self.options.palette)?);
+        }
+        if let Some(path) = &self.options.frames_path {
+            self.frames = Some(open_output(path)?);
+        }
+        if let Some(path) = &self.options.audio_path {
+            self.audio = Some(AudioSynth::new(open_output(path)?, self.options.audio));
+        }
+        Ok(())
+    }
+
+    /// Starts a GIF recording or finishes the current one.
+    /// Returns the path of the GIF being started or finished.
+    pub fn toggle_gif(&mut self) -> io::Result<String> {
+        match self.gif.take() {
+            Some(mut gif) => {
+                gif.finish()?;
+                Ok(gif.path)
+            }
+            None => {
+                let path = self.options.gif_path.clone().unwrap_or_else(default_gif_path);
+                let gif = GifRecorder::create(&path, self.options.scale, self.options.palette)?;
+                self.gif = Some(gif);
+                Ok(path)
+            }
+        }
+    }
+
+    pub fn capture(&mut self, gfx: &Gfx) -> io::Result<()> {
+        if let Some(gif) = &mut self.gif {
+            gif.capture(gfx)?;
+        }
+        if let Some(frames) = &mut self.frames {
+            write_rgb_frame(frames, gfx, self.options.palette)?;
+        }
+        if let Some(audio) = &mut self.audio {
+            audio.write_frame()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(mut gif) = self.gif.take() {
+            gif.finish()?;
+        }
+        if let Some(frames) = &mut self.frames {
+            frames.flush()?;
+        }
+        if let Some(audio) = &mut self.audio {
+            audio.out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// The raw audio follows the sound timer like any other beeper
+impl Beeper for Recorder {
+    fn start_beep(&mut self, offset: f32) {
+        if let Some(audio) = &mut self.audio {
+            audio.synth.start(offset);
+        }
+    }
+
+    fn stop_beep(&mut self, offset: f32) {
+        if let Some(audio) = &mut self.audio {
+            audio.synth.stop(offset);
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("Could not finish recording: {}", e);
+        }
+    }
+}
+
+struct GifRecorder {
+    path: String,
+    encoder: Encoder<BufWriter<File>>,
+    scale: usize,
+    // Identical consecutive frames are merged into a single GIF frame
+    pending: Option<Vec<u8>>,
+    pending_ticks: u64,
+    written_ticks: u64,
+}
+
+impl GifRecorder {
+    fn create(path: &str, scale: u16, palette: Palette) -> io::Result<GifRecorder> {
+        let scale = scale.max(1);
+        let file = BufWriter::new(File::create(path)?);
+        let global_palette = [
+            palette.background.0, palette.background.1, palette.background.2,
+            palette.foreground.0, palette.foreground.1, palette.foreground.2,
+        ];
+        let mut encoder = Encoder::new(file, GFX_COLS as u16 * scale, GFX_ROWS as u16 * scale, &global_palette)
+            .map_err(to_io_error)?;
+        encoder.set_repeat(Repeat::Infinite).map_err(to_io_error)?;
+
+        Ok(GifRecorder {
+            path: path.to_string(),
+            encoder,
+            scale: scale as usize,
+            pending: None,
+            pending_ticks: 0,
+            written_ticks: 0,
+        })
+    }
+
+    fn capture(&mut self, gfx: &Gfx) -> io::Result<()> {
+        let mut pixels = vec![0u8; GFX_MEM_SIZE];
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = gfx[i] as u8;
+        }
+
+        if self.pending.as_ref() == Some(&pixels) {
+            self.pending_ticks += 1;
+            return Ok(());
+        }
+        self.write_pending()?;
+        self.pending = Some(pixels);
+        self.pending_ticks = 1;
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        let pixels = match self.pending.take() {
+            Some(pixels) => pixels,
+            None => return Ok(()),
+        };
+
+        let width = GFX_COLS * self.scale;
+        let height = GFX_ROWS * self.scale;
+        let mut scaled = vec![0u8; width * height];
+        for (i, pixel) in scaled.iter_mut().enumerate() {
+            let x = (i % width) / self.scale;
+            let y = (i / width) / self.scale;
+            *pixel = pixels[y * GFX_COLS + x];
+        }
+
+        // GIF delays are in hundredths of a second, so the delay is computed
+        // from the total elapsed time to keep 60 Hz from drifting.
+        let start = centiseconds(self.written_ticks);
+        self.written_ticks += self.pending_ticks;
+        let delay = centiseconds(self.written_ticks) - start;
+
+        let mut frame = Frame::from_indexed_pixels(width as u16, height as u16, &scaled, None);
+        frame.delay = delay.min(u16::MAX as u64) as u16;
+        self.encoder.write_frame(&frame).map_err(to_io_error)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.encoder.get_mut().flush()
+    }
+}
+
+/// Synthesizes the same tone played by `Sound`, one tick at a time.
+struct AudioSynth {
+    out: Box<dyn Write>,
+    synth: FrameSynth,
+}
+
+impl AudioSynth {
+    fn new(out: Box<dyn Write>, config: AudioConfig) -> AudioSynth {
+        AudioSynth {
+            out,
+            synth: FrameSynth::new(config, AUDIO_SAMPLE_RATE),
+        }
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        let mut data = Vec::new();
+        for sample in self.synth.render_frame() {
+            data.extend_from_slice(&((sample * i16::MAX as f32) as i16).to_le_bytes());
+        }
+        self.out.write_all(&data)
+    }
+}
+
+fn write_rgb_frame(out: &mut Box<dyn Write>, gfx: &Gfx, palette: Palette) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(GFX_MEM_SIZE * 3);
+    for i in 0..GFX_MEM_SIZE {
+        let color = if gfx[i] { palette.foreground } else { palette.background };
+        frame.extend_from_slice(&[color.0, color.1, color.2]);
+    }
+    out.write_all(&frame)
+}
+
+fn open_output(path: &str) -> io::Result<Box<dyn Write>> {
+    if path == "-" {
+        Ok(Box::new(io::stdout()))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+fn default_gif_path() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    format!("chipr-8-{}.gif", secs)
+}
+
+fn centiseconds(ticks: u64) -> u64 {
+    (ticks * 100 + TICKS_PER_SECOND / 2) / TICKS_PER_SECOND
+}
+
+fn to_io_error(e: gif::EncodingError) -> io::Error {
+    io::Error::other(e.to_string())
+}
@@ -4,57 +4,208 @@ use sdl2::render::Canvas;
 use sdl2::video::Window;
 
 use crate::chip8::gfx::{Gfx, GFX_COLS, GFX_MEM_SIZE, GFX_ROWS};
+use crate::multimedia::debugger::{Debugger, Snapshot, PANEL_UNIT};
+use crate::multimedia::osd::{Osd, Status};
+use crate::multimedia::palette::{Palette, Rgb};
+use crate::multimedia::text::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
 use sdl2::Sdl;
 
-const SCALE: u32 = 12;
+pub const DEFAULT_SCALE: u32 = 12;
+
+/// Keypad keys as they are laid out on the COSMAC VIP
+pub const KEYPAD_LAYOUT: [[usize; 4]; 4] = [[0x1, 0x2, 0x3, 0xC], [0x4, 0x5, 0x6, 0xD], [0x7, 0x8, 0x9, 0xE], [0xA, 0x0, 0xB, 0xF]];
 
 pub struct Screen {
     canvas: Canvas<Window>,
+    scale: u32,
+    palette: Palette,
+    osd: Osd,
+    /// Panels around the game view, only with `--debug`
+    debugger: Option<Debugger>,
 }
 
 pub trait Drawable {
     fn draw(&mut self, gfx: &mut Gfx);
+
+    /// Shows a short message on the on-screen display, if the frontend has one
+    fn show_message(&mut self, _message: &str) {}
+
+    /// Whether the frontend draws on the terminal, where anything else printed would scribble over it
+    fn owns_terminal(&self) -> bool {
+        false
+    }
+
+    /// Called before every draw with what the on-screen display shows besides messages
+    fn update_status(&mut self, _status: &Status) {}
+
+    fn toggle_osd(&mut self) {}
+
+    /// Called before every draw with the machine state when the debugger is enabled
+    fn update_debugger(&mut self, _snapshot: &Snapshot) {}
 }
 
 impl Drawable for Screen {
     fn draw(&mut self, gfx: &mut Gfx) {
-        if gfx.needs_redraw() {
+        let osd_changed = self.osd.take_changed();
+        let debugger_changed = match &mut self.debugger {
+            Some(debugger) => debugger.take_changed(),
+            None => false,
+        };
+        if gfx.needs_redraw() || osd_changed || debugger_changed {
+            let scale = self.scale;
             let canvas = &mut self.canvas;
-            canvas.set_draw_color(Color::RGB(0, 0, 0));
+            canvas.set_draw_color(to_color(self.palette.background));
             canvas.clear();
-            canvas.set_draw_color(Color::RGB(65, 255, 0));
+            canvas.set_draw_color(to_color(self.palette.foreground));
 
-            let mut rect = Rect::new(0, 0, SCALE as u32, SCALE as u32);
+            let mut rect = Rect::new(0, 0, scale, scale);
             for i in 0..GFX_MEM_SIZE {
                 if gfx[i] {
-                    let x = (i % GFX_COLS) as i32 * SCALE as i32;
-                    let y = (i / GFX_COLS) as i32 * SCALE as i32;
+                    let x = (i % GFX_COLS) as i32 * scale as i32;
+                    let y = (i / GFX_COLS) as i32 * scale as i32;
                     rect.set_x(x);
                     rect.set_y(y);
                     canvas.fill_rect(rect).expect("Could not draw rect");
                 }
             }
-            canvas.present();
+            if self.osd.is_visible() {
+                self.draw_osd();
+            }
+            self.draw_debugger();
+            self.canvas.present();
             gfx.set_needs_redraw(false);
         }
     }
+
+    fn show_message(&mut self, message: &str) {
+        self.osd.show_message(message);
+    }
+
+    fn update_status(&mut self, status: &Status) {
+        self.osd.set_status(status);
+    }
+
+    fn toggle_osd(&mut self) {
+        self.osd.toggle();
+    }
+
+    fn update_debugger(&mut self, snapshot: &Snapshot) {
+        if let Some(debugger) = &mut self.debugger {
+            debugger.set_snapshot(snapshot);
+        }
+    }
 }
 
 impl Screen {
-    pub fn new(sdl_context: &Sdl) -> Screen {
+    pub fn new(sdl_context: &Sdl, scale: u32, palette: Palette, debug: bool) -> Screen {
         let video_subsystem = sdl_context.video().unwrap();
+        let scale = scale.max(1);
+        let game_size = (GFX_COLS as u32 * scale, GFX_ROWS as u32 * scale);
+        let debugger = if debug { Some(Debugger::new(game_size.0, game_size.1)) } else { None };
+        let (width, height) = debugger.as_ref().map_or(game_size, Debugger::size);
 
-        let window = video_subsystem.window("Chipr-8 - CHIP-8 Emulator", GFX_COLS as u32 * SCALE, GFX_ROWS as u32 * SCALE)
+        let window = video_subsystem.window("Chipr-8 - CHIP-8 Emulator", width, height)
             .position_centered()
             .build()
             .unwrap();
 
         let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+        canvas.set_draw_color(to_color(palette.background));
         canvas.clear();
         canvas.present();
 
         Screen {
             canvas,
+            scale,
+            palette,
+            osd: Osd::new(),
+            debugger,
         }
     }
+
+    /// The address of the disassembly line at the window position, if the debugger shows one there
+    pub fn breakpoint_at(&self, x: i32, y: i32) -> Option<u16> {
+        self.debugger.as_ref().and_then(|debugger| debugger.address_at(x, y))
+    }
+
+    /// OSD pixel size, a quarter of a CHIP-8 pixel so text stays small next to the game
+    fn osd_unit(&self) -> u32 {
+        (self.scale / 4).max(1)
+    }
+
+    fn draw_osd(&mut self) {
+        let unit = self.osd_unit();
+        let width = (GFX_COLS as u32 * self.scale) as i32;
+        let height = (GFX_ROWS as u32 * self.scale) as i32;
+        let label_height = ((GLYPH_HEIGHT + 2) * unit) as i32;
+        let status = *self.osd.status();
+
+        if let Some(message) = self.osd.message().map(str::to_string) {
+            self.draw_label(0, 0, unit, &message, false);
+        }
+
+        let stats = format!("{} IPS {} FPS", status.ips, status.fps);
+        self.draw_label(width - label_width(unit, &stats), 0, unit, &stats, false);
+
+        if status.paused {
+            self.draw_label(0, height - label_height, unit, "PAUSED", false);
+        }
+
+        let cell = label_width(unit, "0");
+        for (row, keys) in KEYPAD_LAYOUT.iter().enumerate() {
+            for (col, key) in keys.iter().enumerate() {
+                let x = width - (4 - col as i32) * cell;
+                let y = height - (4 - row as i32) * label_height;
+                self.draw_label(x, y, unit, &format!("{:X}", key), status.keys[*key]);
+            }
+        }
+    }
+
+    fn draw_debugger(&mut self) {
+        let labels = match &self.debugger {
+            Some(debugger) => debugger.labels(),
+            None => return,
+        };
+        for label in labels {
+            self.draw_label(label.x, label.y, PANEL_UNIT, &label.text, label.inverted);
+        }
+    }
+
+    /// Draws the text over a box one OSD pixel larger on every side, so it reads over any picture.
+    /// Inverted labels swap the foreground and background colours.
+    fn draw_label(&mut self, x: i32, y: i32, unit: u32, text: &str, inverted: bool) {
+        let (fg, bg) = if inverted {
+            (self.palette.background, self.palette.foreground)
+        } else {
+            (self.palette.foreground, self.palette.background)
+        };
+
+        let width = label_width(unit, text) as u32;
+        self.canvas.set_draw_color(to_color(bg));
+        self.canvas.fill_rect(Rect::new(x, y, width, (GLYPH_HEIGHT + 2) * unit)).expect("Could not draw rect");
+
+        self.canvas.set_draw_color(to_color(fg));
+        let mut pixel = Rect::new(0, 0, unit, unit);
+        for (i, c) in text.chars().enumerate() {
+            let glyph = text::glyph(c);
+            let left = x + ((i as u32 * (GLYPH_WIDTH + 1) + 1) * unit) as i32;
+            for gy in 0..GLYPH_HEIGHT {
+                for gx in 0..GLYPH_WIDTH {
+                    if text::is_set(&glyph, gx, gy) {
+                        pixel.set_x(left + (gx * unit) as i32);
+                        pixel.set_y(y + ((gy + 1) * unit) as i32);
+                        self.canvas.fill_rect(pixel).expect("Could not draw rect");
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn label_width(unit: u32, text: &str) -> i32 {
+    ((text.chars().count() as u32 * (GLYPH_WIDTH + 1) + 1) * unit) as i32
+}
+
+fn to_color(rgb: Rgb) -> Color {
+    Color::RGB(rgb.0, rgb.1, rgb.2)
 }
\ No newline at end of file
@@ -1,57 +1,340 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
 use sdl2::Sdl;
 use sdl2::audio::{AudioSpecDesired, AudioDevice, AudioCallback};
 
+/// Length of the attack and release ramps, avoids clicks when the beep starts or stops
+const ENVELOPE_SECONDS: f32 = 0.005;
+
+const FRAMES_PER_SECOND: u64 = 60;
+
+/// Frames the callback may fall behind the emulator before it catches up,
+/// bounds the latency added by following the emulated timeline.
+const MAX_QUEUED_FRAMES: usize = 3;
+
 pub struct Sound {
-    device: AudioDevice<Wave>,
+    // Kept only to keep the device open, it is always playing
+    _device: AudioDevice<Wave>,
+    controls: Sender<AudioControl>,
+    config: AudioConfig,
     playing: bool
 }
 
-struct Wave {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Sawtooth,
+    Noise,
+}
+
+impl Waveform {
+    pub fn parse(name: &str) -> Result<Waveform, String> {
+        match name {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "sawtooth" => Ok(Waveform::Sawtooth),
+            "noise" => Ok(Waveform::Noise),
+            _ => Err(format!("Invalid waveform '{}', expected square, sine, triangle, sawtooth or noise", name)),
+        }
+    }
+
+    fn next(self) -> Waveform {
+        match self {
+            Waveform::Square => Waveform::Sine,
+            Waveform::Sine => Waveform::Triangle,
+            Waveform::Triangle => Waveform::Sawtooth,
+            Waveform::Sawtooth => Waveform::Noise,
+            Waveform::Noise => Waveform::Square,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioConfig {
+    pub frequency: f32,
+    pub waveform: Waveform,
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl Default for AudioConfig {
+    fn default() -> AudioConfig {
+        AudioConfig {
+            frequency: 440.0,
+            waveform: Waveform::Square,
+            volume: 0.25,
+            muted: false,
+        }
+    }
+}
+
+/// Messages sent to the audio callback, which owns the oscillator.
+///
+/// `Start` and `Stop` carry their position inside the current 60 Hz frame,
+/// from 0.0 to 1.0, and `Frame` marks the end of each emulated frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioControl {
+    Start(f32),
+    Stop(f32),
+    Frame,
+    SetFrequency(f32),
+    SetWaveform(Waveform),
+    SetVolume(f32),
+    Mute(bool),
+}
+
+/// Tone generator shared by the SDL callback and the recorders.
+pub struct Oscillator {
+    config: AudioConfig,
+    sample_rate: f32,
     phase_inc: f32,
     phase: f32,
-    volume: f32
+    noise: u32,
+    noise_sample: f32,
+    gate: bool,
+    envelope: f32,
+    envelope_step: f32,
+}
+
+impl Oscillator {
+    pub fn new(config: AudioConfig, sample_rate: u32) -> Oscillator {
+        let sample_rate = sample_rate as f32;
+        Oscillator {
+            config,
+            sample_rate,
+            phase_inc: config.frequency / sample_rate,
+            phase: 0.0,
+            noise: 0x1234_5678,
+            noise_sample: 0.0,
+            gate: false,
+            envelope: 0.0,
+            envelope_step: 1.0 / (ENVELOPE_SECONDS * sample_rate).max(1.0),
+        }
+    }
+
+    pub fn control(&mut self, control: AudioControl) {
+        match control {
+            AudioControl::Start(_) => self.gate = true,
+            AudioControl::Stop(_) => self.gate = false,
+            AudioControl::Frame => {}
+            AudioControl::SetFrequency(frequency) => {
+                self.config.frequency = frequency;
+                self.phase_inc = frequency / self.sample_rate;
+            }
+            AudioControl::SetWaveform(waveform) => self.config.waveform = waveform,
+            AudioControl::SetVolume(volume) => self.config.volume = volume,
+            AudioControl::Mute(muted) => self.config.muted = muted,
+        }
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let target = if self.gate && !self.config.muted { 1.0 } else { 0.0 };
+        if self.envelope < target {
+            self.envelope = (self.envelope + self.envelope_step).min(target);
+        } else if self.envelope > target {
+            self.envelope = (self.envelope - self.envelope_step).max(target);
+        }
+        if self.envelope == 0.0 {
+            return 0.0;
+        }
+
+        let phase = self.phase;
+        let sample = match self.config.waveform {
+            Waveform::Square => if phase <= 0.5 { 1.0 } else { -1.0 },
+            Waveform::Sine => (phase * 2.0 * std::f32::consts::PI).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * phase - 1.0,
+            Waveform::Noise => self.noise_sample,
+        };
+
+        self.phase += self.phase_inc;
+        if self.phase >= 1.0 {
+            self.phase %= 1.0;
+            // a new random level every period, so the noise follows the frequency
+            self.noise ^= self.noise << 13;
+            self.noise ^= self.noise >> 17;
+            self.noise ^= self.noise << 5;
+            self.noise_sample = (self.noise as f32 / u32::MAX as f32) * 2.0 - 1.0;
+        }
+
+        sample * self.config.volume * self.envelope
+    }
+}
+
+/// Renders the tone one 60 Hz frame at a time, switching it on and off at
+/// the exact sample where the sound timer changed inside the frame.
+pub struct FrameSynth {
+    oscillator: Oscillator,
+    sample_rate: u64,
+    frames: u64,
+    samples: u64,
+    beep_samples: u64,
+    gate: bool,
+    events: Vec<(f32, bool)>,
+}
+
+impl FrameSynth {
+    pub fn new(config: AudioConfig, sample_rate: u32) -> FrameSynth {
+        FrameSynth {
+            oscillator: Oscillator::new(config, sample_rate),
+            sample_rate: sample_rate as u64,
+            frames: 0,
+            samples: 0,
+            beep_samples: 0,
+            gate: false,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn start(&mut self, offset: f32) {
+        self.events.push((offset, true));
+    }
+
+    pub fn stop(&mut self, offset: f32) {
+        self.events.push((offset, false));
+    }
+
+    /// Renders the samples of the frame that just ended
+    pub fn render_frame(&mut self) -> Vec<f32> {
+        self.frames += 1;
+        let end = self.frames * self.sample_rate / FRAMES_PER_SECOND;
+        let count = (end - self.samples) as usize;
+        let events: Vec<(f32, bool)> = self.events.drain(..).collect();
+
+        let mut out = Vec::with_capacity(count);
+        let mut next_event = 0;
+        for n in 0..count {
+            while next_event < events.len() && (events[next_event].0 * count as f32) as usize <= n {
+                self.set_gate(events[next_event].1);
+                next_event += 1;
+            }
+            out.push(self.oscillator.next_sample());
+            if self.gate {
+                self.beep_samples += 1;
+            }
+        }
+        // events at the very end of the frame
+        for &(_, gate) in &events[next_event..] {
+            self.set_gate(gate);
+        }
+
+        self.samples = end;
+        out
+    }
+
+    fn set_gate(&mut self, gate: bool) {
+        self.gate = gate;
+        self.oscillator.control(if gate { AudioControl::Start(0.0) } else { AudioControl::Stop(0.0) });
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// Number of samples rendered while the sound timer was active
+    pub fn beep_samples(&self) -> u64 {
+        self.beep_samples
+    }
+}
+
+/// Plays the emulated timeline: the beep is switched at the position of each
+/// `Start`/`Stop` inside its frame, and the callback waits for the emulator
+/// by holding the current state when it has no complete frame queued.
+struct Wave {
+    oscillator: Oscillator,
+    controls: Receiver<AudioControl>,
+    pending: VecDeque<AudioControl>,
+    frame_samples: f32,
+    position: f32,
+}
+
+impl Wave {
+    fn queued_frames(&self) -> usize {
+        self.pending.iter().filter(|control| **control == AudioControl::Frame).count()
+    }
+
+    /// Applies every control due at the current position
+    fn apply_due_controls(&mut self) {
+        while let Some(&control) = self.pending.front() {
+            match control {
+                AudioControl::Start(offset) | AudioControl::Stop(offset)
+                    if offset * self.frame_samples > self.position => return,
+                AudioControl::Frame if self.position < self.frame_samples => return,
+                AudioControl::Frame => self.position -= self.frame_samples,
+                _ => {}
+            }
+            self.oscillator.control(control);
+            self.pending.pop_front();
+        }
+    }
 }
 
 impl AudioCallback for Wave {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
+        while let Ok(control) = self.controls.try_recv() {
+            self.pending.push_back(control);
+        }
+        // too far behind, jump to the oldest frame we are allowed to lag
+        while self.queued_frames() > MAX_QUEUED_FRAMES {
+            while let Some(control) = self.pending.pop_front() {
+                if control == AudioControl::Frame {
+                    break;
+                }
+                self.oscillator.control(control);
+            }
+            self.position = 0.0;
+        }
+
         for x in out.iter_mut() {
-            if self.phase <= 0.5 {
-                *x = self.volume;
-            } else {
-                *x = -self.volume;
+            self.apply_due_controls();
+            *x = self.oscillator.next_sample();
+            // hold at the end of the frame until the emulator finishes it
+            if self.position < self.frame_samples {
+                self.position += 1.0;
             }
-            self.phase = (self.phase + self.phase_inc) % 1.0;
         }
     }
 }
 
 
+/// `offset` is the position of the change inside the current 60 Hz frame,
+/// from 0.0 at its start to 1.0 at its end.
 pub trait Beeper {
-    fn start_beep(&mut self);
-    fn stop_beep(&mut self);
+    fn start_beep(&mut self, offset: f32);
+    fn stop_beep(&mut self, offset: f32);
+    /// Called at the end of every 60 Hz frame.
+    /// Only beepers that keep their own timeline, like `WavBeeper`, need it.
+    fn tick(&mut self) {}
 }
 
 impl Beeper for Sound {
-    fn start_beep(&mut self) {
+    fn start_beep(&mut self, offset: f32) {
         if !self.playing {
-            self.device.resume();
+            self.send(AudioControl::Start(offset));
             self.playing = true;
         }
     }
 
-    fn stop_beep(&mut self) {
+    fn stop_beep(&mut self, offset: f32) {
         if self.playing {
-            self.device.pause();
+            self.send(AudioControl::Stop(offset));
             self.playing = false;
         }
     }
+
+    fn tick(&mut self) {
+        self.send(AudioControl::Frame);
+    }
 }
 
 impl Sound {
-    pub fn new(sdl_context: &Sdl) -> Sound {
+    pub fn new(sdl_context: &Sdl, config: AudioConfig) -> Sound {
         let audio_subsystem = sdl_context.audio().unwrap();
         let desired_spec = AudioSpecDesired {
             freq: Some(44100),
@@ -59,17 +342,52 @@ impl Sound {
             samples: None       // default sample size
         };
 
+        let (controls, receiver) = channel();
         let device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
             // initialize the audio callback
             Wave {
-                phase_inc: 440.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.25
+                oscillator: Oscillator::new(config, spec.freq as u32),
+                controls: receiver,
+                pending: VecDeque::new(),
+                frame_samples: spec.freq as f32 / FRAMES_PER_SECOND as f32,
+                position: 0.0,
             }
         }).unwrap();
+        // The device never pauses, beeps are shaped by the oscillator envelope
+        device.resume();
+
         Sound {
-            device,
+            _device: device,
+            controls,
+            config,
             playing: false
         }
     }
-}
\ No newline at end of file
+
+    pub fn toggle_mute(&mut self) {
+        self.config.muted = !self.config.muted;
+        self.send(AudioControl::Mute(self.config.muted));
+    }
+
+    pub fn change_volume(&mut self, delta: f32) {
+        self.config.volume = (self.config.volume + delta).clamp(0.0, 1.0);
+        self.send(AudioControl::SetVolume(self.config.volume));
+    }
+
+    /// Changes the frequency by a number of semitones
+    pub fn change_pitch(&mut self, semitones: f32) {
+        self.config.frequency = (self.config.frequency * 2f32.powf(semitones / 12.0)).clamp(20.0, 20000.0);
+        self.send(AudioControl::SetFrequency(self.config.frequency));
+    }
+
+    pub fn next_waveform(&mut self) {
+        self.config.waveform = self.config.waveform.next();
+        self.send(AudioControl::SetWaveform(self.config.waveform));
+    }
+
+    /// Sends a control message to the audio callback
+    fn send(&self, control: AudioControl) {
+        // the callback only goes away with the device
+        let _ = self.controls.send(control);
+    }
+}
@@ -0,0 +1,186 @@
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+
+use crate::multimedia::sound::{AudioConfig, Beeper, FrameSynth};
+
+const HEADER_SIZE: u32 = 44;
+const BYTES_PER_SAMPLE: u32 = 2;
+
+/// Sample rates `--sample-rate` accepts, from telephone quality to studio recordings
+pub const SAMPLE_RATES: RangeInclusive<u32> = 8000..=192000;
+
+/// Beeper that writes the tone to a 16 bit mono WAV file instead of playing it.
+///
+/// Samples are generated per 60 Hz tick from the sound timer state, so the
+/// output does not depend on the real time the emulator took to run.
+pub struct WavBeeper<W: Write + Seek> {
+    out: W,
+    synth: FrameSynth,
+    sample_rate: u32,
+    error: Option<io::Error>,
+}
+
+impl<W: Write + Seek> Beeper for WavBeeper<W> {
+    fn start_beep(&mut self, offset: f32) {
+        self.synth.start(offset);
+    }
+
+    fn stop_beep(&mut self, offset: f32) {
+        self.synth.stop(offset);
+    }
+
+    fn tick(&mut self) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.write_tick() {
+            eprintln!("Could not write audio: {}", e);
+            self.error = Some(e);
+        }
+    }
+}
+
+impl<W: Write + Seek> WavBeeper<W> {
+    pub fn new(mut out: W, sample_rate: u32, config: AudioConfig) -> io::Result<WavBeeper<W>> {
+        write_header(&mut out, sample_rate, 0)?;
+        Ok(WavBeeper {
+            out,
+            synth: FrameSynth::new(config, sample_rate),
+            sample_rate,
+            error: None,
+        })
+    }
+
+    /// Number of samples written while the sound timer was active
+    pub fn beep_samples(&self) -> u64 {
+        self.synth.beep_samples()
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.synth.samples()
+    }
+
+    fn write_tick(&mut self) -> io::Result<()> {
+        let frame = self.synth.render_frame();
+        let mut data = Vec::with_capacity(frame.len() * BYTES_PER_SAMPLE as usize);
+        for sample in frame {
+            data.extend_from_slice(&((sample * i16::MAX as f32) as i16).to_le_bytes());
+        }
+        self.out.write_all(&data)?;
+
+        // Sizes are updated every tick so the file stays valid if the process is killed
+        let position = self.out.stream_position()?;
+        let data_size = self.data_size();
+        write_header(&mut self.out, self.sample_rate, data_size)?;
+        self.out.seek(SeekFrom::Start(position))?;
+        Ok(())
+    }
+
+    fn data_size(&self) -> u32 {
+        (self.samples() * BYTES_PER_SAMPLE as u64).min((u32::MAX - HEADER_SIZE) as u64) as u32
+    }
+}
+
+impl<W: Write + Seek> Drop for WavBeeper<W> {
+    fn drop(&mut self) {
+        if self.error.is_none() {
+            if let Err(e) = self.out.flush() {
+                eprintln!("Could not write audio: {}", e);
+            }
+        }
+    }
+}
+
+fn write_header<W: Write + Seek>(out: &mut W, sample_rate: u32, data_size: u32) -> io::Result<()> {
+    let byte_rate = sample_rate.checked_mul(BYTES_PER_SAMPLE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("sample rate {} Hz is too high", sample_rate)))?;
+    out.seek(SeekFrom::Start(0))?;
+    let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(HEADER_SIZE - 8 + data_size).to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    header.extend_from_slice(&1u16.to_le_bytes()); // mono
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&byte_rate.to_le_bytes());
+    header.extend_from_slice(&(BYTES_PER_SAMPLE as u16).to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_size.to_le_bytes());
+    out.write_all(&header)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+    }
+
+    #[test]
+    fn writes_one_tick_of_samples_per_tick() {
+        let mut wav = WavBeeper::new(Cursor::new(Vec::new()), 44100, AudioConfig::default()).unwrap();
+        for _ in 0..60 {
+            wav.tick();
+        }
+        assert_eq!(wav.samples(), 44100);
+        assert_eq!(wav.beep_samples(), 0);
+    }
+
+    #[test]
+    fn counts_beep_samples_with_uneven_sample_rate() {
+        let mut wav = WavBeeper::new(Cursor::new(Vec::new()), 22050, AudioConfig::default()).unwrap();
+        wav.tick();
+        wav.start_beep(0.0);
+        for _ in 0..3 {
+            wav.tick();
+        }
+        wav.stop_beep(0.0);
+        wav.tick();
+
+        // 22050 / 60 = 367.5 samples per tick
+        assert_eq!(wav.samples(), 5 * 22050 / 60);
+        assert_eq!(wav.beep_samples(), 4 * 22050 / 60 - 22050 / 60);
+    }
+
+    #[test]
+    fn header_matches_written_data() {
+        let mut wav = WavBeeper::new(Cursor::new(Vec::new()), 8000, AudioConfig::default()).unwrap();
+        wav.start_beep(0.0);
+        for _ in 0..6 {
+            wav.tick();
+        }
+        let data = wav.out.get_ref().clone();
+
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(&data[8..12], b"WAVE");
+        assert_eq!(u32_at(&data, 24), 8000);
+        assert_eq!(u32_at(&data, 40), 800 * 2);
+        assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+        assert!(data[44..].iter().any(|&b| b != 0));
+    }
+
+    #[test]
+    fn rejects_sample_rates_the_header_can_not_hold() {
+        let error = WavBeeper::new(Cursor::new(Vec::new()), u32::MAX, AudioConfig::default()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(WavBeeper::new(Cursor::new(Vec::new()), *SAMPLE_RATES.end(), AudioConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn switches_beep_inside_the_frame() {
+        let mut wav = WavBeeper::new(Cursor::new(Vec::new()), 44100, AudioConfig::default()).unwrap();
+        wav.start_beep(0.25);
+        wav.stop_beep(0.75);
+        wav.tick();
+
+        assert_eq!(wav.samples(), 735);
+        assert_eq!(wav.beep_samples(), 551 - 183);
+    }
+}
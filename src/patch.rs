@@ -0,0 +1,329 @@
+//! IPS and BPS patches, the formats ROM translations and fixes are passed around in.
+//!
+//! IPS records bytes to write at offsets, with run-length records and an optional
+//! truncation after `EOF`. BPS describes the patched ROM as copies from the original,
+//! from itself and from the patch, and carries the CRC32 of the original, the patched
+//! ROM and the patch to check it is applied to the ROM it was made for.
+
+use std::convert::TryFrom;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+/// Offsets and lengths are 3 bytes
+const IPS_MAX_SIZE: usize = 0xFF_FFFF;
+/// Records hold at most this many bytes
+const IPS_MAX_RECORD: usize = 0xFFFF;
+
+const BPS_MAGIC: &[u8] = b"BPS1";
+/// The three CRC32 at the end
+const BPS_FOOTER: usize = 12;
+
+const SOURCE_READ: u64 = 0;
+const TARGET_READ: u64 = 1;
+const SOURCE_COPY: u64 = 2;
+const TARGET_COPY: u64 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Ips,
+    Bps,
+}
+
+impl Format {
+    /// BPS for `.bps` files, IPS otherwise
+    pub fn from_path(path: &str) -> Format {
+        if path.to_ascii_lowercase().ends_with(".bps") { Format::Bps } else { Format::Ips }
+    }
+}
+
+/// The ROM with the patch applied, its format told by its header
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, &patch[IPS_MAGIC.len()..])
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err("not an IPS or BPS patch".to_string())
+    }
+}
+
+/// A patch turning `original` into `modified`
+pub fn create(format: Format, original: &[u8], modified: &[u8]) -> Result<Vec<u8>, String> {
+    match format {
+        Format::Ips => create_ips(original, modified),
+        Format::Bps => Ok(create_bps(original, modified)),
+    }
+}
+
+/// Reads the patch bytes in order, every read failing past the end
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self.pos.checked_add(len)
+            .and_then(|end| self.bytes.get(self.pos..end))
+            .ok_or("the patch ends in the middle of a record")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn number(&mut self, len: usize) -> Result<usize, String> {
+        Ok(self.take(len)?.iter().fold(0, |number, byte| number << 8 | *byte as usize))
+    }
+
+    /// BPS numbers, 7 bits a byte with the high bit ending them and every byte but the last
+    /// counting one more, so each number has a single encoding
+    fn varint(&mut self) -> Result<u64, String> {
+        let too_big = || "the patch has a number too big".to_string();
+        let mut number = 0u64;
+        let mut shift = 1u64;
+        loop {
+            let byte = self.take(1)?[0] as u64;
+            number = (byte & 0x7F).checked_mul(shift).and_then(|value| number.checked_add(value)).ok_or_else(too_big)?;
+            if byte & 0x80 != 0 {
+                return Ok(number);
+            }
+            shift = shift.checked_mul(0x80).ok_or_else(too_big)?;
+            number = number.checked_add(shift).ok_or_else(too_big)?;
+        }
+    }
+}
+
+fn apply_ips(rom: &[u8], records: &[u8]) -> Result<Vec<u8>, String> {
+    let mut patched = rom.to_vec();
+    let mut reader = Reader { bytes: records, pos: 0 };
+    loop {
+        if reader.take(IPS_EOF.len())? == IPS_EOF {
+            break;
+        }
+        reader.pos -= IPS_EOF.len();
+        let offset = reader.number(3)?;
+        let (len, value) = match reader.number(2)? {
+            0 => (reader.number(2)?, Some(reader.take(1)?[0])),
+            len => (len, None),
+        };
+        if patched.len() < offset + len {
+            patched.resize(offset + len, 0);
+        }
+        match value {
+            Some(value) => patched[offset..offset + len].iter_mut().for_each(|byte| *byte = value),
+            None => patched[offset..offset + len].copy_from_slice(reader.take(len)?),
+        }
+    }
+    // the truncation extension
+    if reader.pos + 3 == records.len() {
+        let len = reader.number(3)?;
+        patched.truncate(len);
+    }
+    Ok(patched)
+}
+
+fn create_ips(original: &[u8], modified: &[u8]) -> Result<Vec<u8>, String> {
+    if modified.len() > IPS_MAX_SIZE {
+        return Err(format!("IPS patches only reach the first {} bytes", IPS_MAX_SIZE));
+    }
+    let mut patch = IPS_MAGIC.to_vec();
+    let differs = |i: usize| original.get(i) != Some(&modified[i]);
+    let mut start = 0;
+    while start < modified.len() {
+        if !differs(start) {
+            start += 1;
+            continue;
+        }
+        // an offset spelling EOF would end the patch, start a byte earlier
+        if start == 0x45_4F46 {
+            start -= 1;
+        }
+        let mut end = start + 1;
+        while end < modified.len() && end - start < IPS_MAX_RECORD && differs(end) {
+            end += 1;
+        }
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&modified[start..end]);
+        start = end;
+    }
+    patch.extend_from_slice(IPS_EOF);
+    if modified.len() < original.len() {
+        patch.extend_from_slice(&(modified.len() as u32).to_be_bytes()[1..]);
+    }
+    Ok(patch)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < BPS_MAGIC.len() + BPS_FOOTER {
+        return Err("the patch ends in the middle of a record".to_string());
+    }
+    let footer = patch.len() - BPS_FOOTER;
+    let crc = |at: usize| u32::from_le_bytes([patch[at], patch[at + 1], patch[at + 2], patch[at + 3]]);
+    let (source_crc, target_crc, patch_crc) = (crc(footer), crc(footer + 4), crc(footer + 8));
+    if crc32(&patch[..footer + 8]) != patch_crc {
+        return Err("the patch is damaged, its CRC32 does not match".to_string());
+    }
+    if crc32(rom) != source_crc {
+        return Err(format!("the patch is for a ROM with CRC32 {:08X}, this one has {:08X}", source_crc, crc32(rom)));
+    }
+
+    let mut reader = Reader { bytes: &patch[..footer], pos: BPS_MAGIC.len() };
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()? as usize;
+    let metadata = reader.varint()? as usize;
+    reader.take(metadata)?;
+    if source_size != rom.len() as u64 {
+        return Err(format!("the patch is for a ROM of {} bytes, this one has {}", source_size, rom.len()));
+    }
+
+    let mut target = Vec::new();
+    let mut source_offset = 0i64;
+    let mut target_offset = 0i64;
+    let out_of_bounds = || "the patch copies from outside of the ROM".to_string();
+    while reader.pos < footer {
+        let action = reader.varint()?;
+        let len = (action >> 2) as usize + 1;
+        if len > target_size - target.len() {
+            return Err("the patch writes past the size it gives".to_string());
+        }
+        match action & 3 {
+            SOURCE_READ => {
+                let at = target.len();
+                target.extend_from_slice(rom.get(at..at + len).ok_or_else(out_of_bounds)?);
+            }
+            TARGET_READ => target.extend_from_slice(reader.take(len)?),
+            SOURCE_COPY => {
+                source_offset = source_offset.checked_add(signed(reader.varint()?)).ok_or_else(out_of_bounds)?;
+                let at = usize::try_from(source_offset).map_err(|_| out_of_bounds())?;
+                target.extend_from_slice(rom.get(at..at.saturating_add(len)).ok_or_else(out_of_bounds)?);
+                source_offset += len as i64;
+            }
+            TARGET_COPY => {
+                target_offset = target_offset.checked_add(signed(reader.varint()?)).ok_or_else(out_of_bounds)?;
+                // copied a byte at a time, the copy can overlap what it writes
+                for _ in 0..len {
+                    let byte = usize::try_from(target_offset).ok().and_then(|at| target.get(at).copied()).ok_or_else(out_of_bounds)?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+    if target.len() != target_size {
+        return Err(format!("the patch gives {} bytes of the {} it promises", target.len(), target_size));
+    }
+    if crc32(&target) != target_crc {
+        return Err("the patched ROM does not have the CRC32 the patch gives".to_string());
+    }
+    Ok(target)
+}
+
+/// Copy offsets are relative, the low bit being the sign
+fn signed(offset: u64) -> i64 {
+    let magnitude = (offset >> 1) as i64;
+    if offset & 1 == 1 { -magnitude } else { magnitude }
+}
+
+/// Reads the bytes that did not change from the original and stores the others
+fn create_bps(original: &[u8], modified: &[u8]) -> Vec<u8> {
+    let mut patch = BPS_MAGIC.to_vec();
+    write_varint(&mut patch, original.len() as u64);
+    write_varint(&mut patch, modified.len() as u64);
+    write_varint(&mut patch, 0);
+
+    let same = |i: usize| original.get(i) == Some(&modified[i]);
+    let mut start = 0;
+    while start < modified.len() {
+        let kind = same(start);
+        let mut end = start + 1;
+        while end < modified.len() && same(end) == kind {
+            end += 1;
+        }
+        let action = if kind { SOURCE_READ } else { TARGET_READ };
+        write_varint(&mut patch, ((end - start - 1) as u64) << 2 | action);
+        if !kind {
+            patch.extend_from_slice(&modified[start..end]);
+        }
+        start = end;
+    }
+
+    patch.extend_from_slice(&crc32(original).to_le_bytes());
+    patch.extend_from_slice(&crc32(modified).to_le_bytes());
+    let patch_crc = crc32(&patch);
+    patch.extend_from_slice(&patch_crc.to_le_bytes());
+    patch
+}
+
+fn write_varint(out: &mut Vec<u8>, mut number: u64) {
+    loop {
+        let byte = (number & 0x7F) as u8;
+        number >>= 7;
+        if number == 0 {
+            out.push(0x80 | byte);
+            return;
+        }
+        out.push(byte);
+        number -= 1;
+    }
+}
+
+/// CRC-32 as zip and BPS compute it
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| if crc & 1 == 1 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &[u8] = &[0x60, 0x03, 0xA2, 0x0A, 0xD0, 0x15, 0x12, 0x06, 0x00, 0x00, 0xF0, 0x90];
+
+    #[test]
+    fn ips_patches_write_fill_and_truncate() {
+        // 2 bytes at 1, then 0x80 three times from 10, growing the ROM
+        let patch = b"PATCH\x00\x00\x01\x00\x02\x05\xA3\x00\x00\x0A\x00\x00\x00\x03\x80EOF";
+        let patched = apply(ORIGINAL, patch).unwrap();
+        assert_eq!(patched, [0x60, 0x05, 0xA3, 0x0A, 0xD0, 0x15, 0x12, 0x06, 0x00, 0x00, 0x80, 0x80, 0x80]);
+        assert_eq!(apply(ORIGINAL, b"PATCHEOF\x00\x00\x04").unwrap(), &ORIGINAL[..4]);
+        assert!(apply(ORIGINAL, b"PATCH\x00\x00\x01\x00\x02\x05").is_err());
+        assert!(apply(ORIGINAL, b"NOT A PATCH").is_err());
+
+        for modified in [&patched[..], &ORIGINAL[..5]].iter() {
+            let patch = create(Format::Ips, ORIGINAL, modified).unwrap();
+            assert_eq!(apply(ORIGINAL, &patch).unwrap(), *modified);
+        }
+    }
+
+    #[test]
+    fn bps_patches_check_the_roms_they_apply_to() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        let mut modified = ORIGINAL.to_vec();
+        modified[1] = 0x07;
+        modified.extend_from_slice(&[0xF0; 200]);
+        let patch = create(Format::Bps, ORIGINAL, &modified).unwrap();
+        assert_eq!(apply(ORIGINAL, &patch).unwrap(), modified);
+
+        let error = apply(&modified, &patch).unwrap_err();
+        assert!(error.starts_with("the patch is for a ROM with CRC32"), "{}", error);
+        let mut damaged = patch.clone();
+        damaged[6] ^= 1;
+        assert!(apply(ORIGINAL, &damaged).unwrap_err().contains("damaged"));
+
+        // target copies may overlap what they write: "AB" then 4 bytes copied from offset 0
+        let mut patch = b"BPS1".to_vec();
+        for number in [0, 6, 0, 1 << 2 | TARGET_READ].iter() {
+            write_varint(&mut patch, *number);
+        }
+        patch.extend_from_slice(b"AB");
+        write_varint(&mut patch, 3 << 2 | TARGET_COPY);
+        write_varint(&mut patch, 0);
+        patch.extend_from_slice(&crc32(b"").to_le_bytes());
+        patch.extend_from_slice(&crc32(b"ABABAB").to_le_bytes());
+        let patch_crc = crc32(&patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+        assert_eq!(apply(b"", &patch).unwrap(), b"ABABAB");
+    }
+}
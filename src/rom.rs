@@ -0,0 +1,61 @@
+mod archive;
+mod cartridge;
+mod listing;
+
+use std::fs;
+use std::io::{self, Read};
+
+use crate::config::Settings;
+use crate::patch;
+
+/// The ROM path that reads the ROM from standard input
+pub const STDIN: &str = "-";
+
+/// A ROM and the settings its file asks for, like the palette, speed and quirks of an Octo cartridge
+#[derive(Debug)]
+pub struct RomFile {
+    pub bytes: Vec<u8>,
+    pub settings: Settings,
+}
+
+pub fn read(path: &str) -> Result<Vec<u8>, String> {
+    load(path, None, &[]).map(|rom| rom.bytes)
+}
+
+/// Loads a raw binary, hex listing or Octo cartridge, possibly inside a zip or gzip archive,
+/// then applies the IPS or BPS patches in order. `entry` picks the file out of a zip archive
+/// holding several ROMs.
+pub fn load(path: &str, entry: Option<&str>, patches: &[String]) -> Result<RomFile, String> {
+    let bytes = if path == STDIN {
+        let mut bytes = Vec::new();
+        io::stdin().lock().read_to_end(&mut bytes).map(|_| bytes)
+    } else {
+        fs::read(path)
+    };
+    let bytes = bytes.map_err(|e| format!("Could not read ROM {}: {}", path, e))?;
+    let mut rom = decode(bytes, entry).map_err(|e| format!("Could not load ROM {}: {}", path, e))?;
+    for patch_path in patches {
+        let bytes = fs::read(patch_path).map_err(|e| format!("Could not read patch {}: {}", patch_path, e))?;
+        rom.bytes = patch::apply(&rom.bytes, &bytes).map_err(|e| format!("Could not apply patch {}: {}", patch_path, e))?;
+    }
+    Ok(rom)
+}
+
+/// Tells the formats apart by their first bytes, anything unknown being a raw binary
+fn decode(bytes: Vec<u8>, entry: Option<&str>) -> Result<RomFile, String> {
+    if bytes.starts_with(b"GIF8") {
+        cartridge::decode(&bytes)
+    } else if bytes.starts_with(&archive::GZIP_MAGIC) {
+        decode(archive::gunzip(&bytes)?, entry)
+    } else if bytes.starts_with(&archive::ZIP_MAGIC) {
+        decode(archive::unzip(&bytes, entry)?, None)
+    } else {
+        let bytes = listing::parse(&bytes).unwrap_or(bytes);
+        Ok(RomFile { bytes, settings: Settings::default() })
+    }
+}
+
+/// Lowercase hex SHA-1 of the ROM, the key ROMs are known by in the config file
+pub fn sha1(rom: &[u8]) -> String {
+    sha1_smol::Sha1::from(rom).digest().to_string()
+}
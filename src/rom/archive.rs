@@ -0,0 +1,244 @@
+//! ROMs inside gzip files and zip archives. Zip archives can only hold stored and deflated
+//! files, which is what every zip tool writes by default.
+
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Read, Write};
+
+use flate2::read::{DeflateDecoder, GzDecoder};
+
+use crate::patch;
+
+pub const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+pub const ZIP_MAGIC: [u8; 4] = *b"PK\x03\x04";
+
+const END_OF_DIRECTORY: [u8; 4] = *b"PK\x05\x06";
+const DIRECTORY_ENTRY: [u8; 4] = *b"PK\x01\x02";
+const END_OF_DIRECTORY_SIZE: usize = 22;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// Decompressed files larger than this are not ROMs
+const MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Archive files with these extensions are picked over the others
+const ROM_EXTENSIONS: [&str; 8] = ["ch8", "c8", "sc8", "xo8", "rom", "bin", "gif", "hex"];
+
+pub fn gunzip(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    inflate(GzDecoder::new(bytes)).map_err(|e| format!("invalid gzip file: {}", e))
+}
+
+fn inflate(decoder: impl Read) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    decoder.take(MAX_SIZE + 1).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > MAX_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("larger than {} bytes", MAX_SIZE)));
+    }
+    Ok(bytes)
+}
+
+/// A file listed in the central directory
+struct Entry {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed_size: usize,
+    header_offset: usize,
+}
+
+/// The ROM in the archive, the `entry` one when given. With several ROMs and no `entry`, asks
+/// which one to load if there is someone at the terminal.
+pub fn unzip(bytes: &[u8], entry: Option<&str>) -> Result<Vec<u8>, String> {
+    let entries = directory(bytes).map_err(|e| format!("invalid zip archive: {}", e))?;
+    let files = entries.iter().filter(|entry| !entry.name.ends_with('/')).collect::<Vec<_>>();
+    let roms = files.iter().copied().filter(|entry| is_rom_name(&entry.name)).collect::<Vec<_>>();
+    let candidates = if roms.is_empty() { &files } else { &roms };
+
+    let chosen = match entry {
+        Some(name) => files.iter()
+            .find(|entry| entry.name == name || entry.name.rsplit('/').next() == Some(name))
+            .copied()
+            .ok_or_else(|| format!("no file named '{}' in the archive, it has {}", name, names(&files)))?,
+        None => match candidates.as_slice() {
+            [] => return Err("the zip archive is empty".to_string()),
+            [only] => *only,
+            _ if is_terminal() => choose(candidates)?,
+            _ => return Err(format!("the zip archive holds several ROMs, pick one with --entry: {}", names(candidates))),
+        },
+    };
+    extract(bytes, chosen).map_err(|e| format!("could not extract {}: {}", chosen.name, e))
+}
+
+fn is_rom_name(name: &str) -> bool {
+    match name.rsplit_once('.') {
+        Some((_, extension)) => ROM_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()),
+        None => false,
+    }
+}
+
+fn names(entries: &[&Entry]) -> String {
+    entries.iter().map(|entry| entry.name.as_str()).collect::<Vec<_>>().join(", ")
+}
+
+fn is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 && libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+/// Lists the entries and reads the number of the one to load
+fn choose<'a>(entries: &[&'a Entry]) -> Result<&'a Entry, String> {
+    println!("The archive holds several ROMs:");
+    for (i, entry) in entries.iter().enumerate() {
+        println!("{:>3}  {}", i + 1, entry.name);
+    }
+    loop {
+        print!("Load which one? ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+            return Err("no ROM picked from the archive".to_string());
+        }
+        match line.trim().parse::<usize>() {
+            Ok(number) if (1..=entries.len()).contains(&number) => return Ok(entries[number - 1]),
+            _ => println!("Expected a number from 1 to {}", entries.len()),
+        }
+    }
+}
+
+/// The files of the central directory, found through the end of central directory record
+fn directory(bytes: &[u8]) -> Result<Vec<Entry>, String> {
+    let end = (0..=bytes.len().saturating_sub(END_OF_DIRECTORY_SIZE)).rev()
+        .find(|at| bytes[*at..].starts_with(&END_OF_DIRECTORY))
+        .ok_or("no central directory")?;
+    let count = u16_at(bytes, end + 10)? as usize;
+    let mut at = u32_at(bytes, end + 16)? as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if bytes.get(at..at + 4) != Some(&DIRECTORY_ENTRY[..]) {
+            return Err("broken central directory".to_string());
+        }
+        let name_length = u16_at(bytes, at + 28)? as usize;
+        let name = bytes.get(at + 46..at + 46 + name_length).ok_or("truncated central directory")?;
+        entries.push(Entry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: u16_at(bytes, at + 10)?,
+            crc: u32_at(bytes, at + 16)?,
+            compressed_size: u32_at(bytes, at + 20)? as usize,
+            header_offset: u32_at(bytes, at + 42)? as usize,
+        });
+        at += 46 + name_length + u16_at(bytes, at + 30)? as usize + u16_at(bytes, at + 32)? as usize;
+    }
+    Ok(entries)
+}
+
+fn extract(bytes: &[u8], entry: &Entry) -> Result<Vec<u8>, String> {
+    let header = entry.header_offset;
+    if bytes.get(header..header + 4) != Some(&ZIP_MAGIC[..]) {
+        return Err("broken local header".to_string());
+    }
+    let start = header + 30 + u16_at(bytes, header + 26)? as usize + u16_at(bytes, header + 28)? as usize;
+    let data = start.checked_add(entry.compressed_size)
+        .and_then(|end| bytes.get(start..end))
+        .ok_or("truncated file")?;
+    let contents = match entry.method {
+        STORED => data.to_vec(),
+        DEFLATED => inflate(DeflateDecoder::new(data)).map_err(|e| e.to_string())?,
+        method => return Err(format!("unsupported compression method {}", method)),
+    };
+    if patch::crc32(&contents) != entry.crc {
+        return Err("CRC mismatch".to_string());
+    }
+    Ok(contents)
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Result<u16, String> {
+    bytes.get(at..at + 2)
+        .and_then(|bytes| <[u8; 2]>::try_from(bytes).ok())
+        .map(u16::from_le_bytes)
+        .ok_or_else(|| "truncated archive".to_string())
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Result<u32, String> {
+    bytes.get(at..at + 4)
+        .and_then(|bytes| <[u8; 4]>::try_from(bytes).ok())
+        .map(u32::from_le_bytes)
+        .ok_or_else(|| "truncated archive".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{DeflateEncoder, GzEncoder};
+    use flate2::Compression;
+
+    /// A zip archive holding the files, deflated when `deflate` is set
+    fn zip(files: &[(&str, &[u8])], deflate: bool) -> Vec<u8> {
+        let (mut archive, mut directory) = (Vec::new(), Vec::new());
+        for (name, contents) in files {
+            let data = if deflate {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(contents).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                contents.to_vec()
+            };
+            let method = if deflate { DEFLATED } else { STORED };
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0; 4]);
+            fields.extend_from_slice(&patch::crc32(contents).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&[0; 2]);
+
+            directory.extend_from_slice(&DIRECTORY_ENTRY);
+            directory.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            directory.extend_from_slice(&fields);
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&(archive.len() as u32).to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+
+            archive.extend_from_slice(&ZIP_MAGIC);
+            archive.extend_from_slice(&[20, 0, 0, 0]);
+            archive.extend_from_slice(&fields);
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(&data);
+        }
+        let offset = archive.len() as u32;
+        archive.extend_from_slice(&directory);
+        archive.extend_from_slice(&END_OF_DIRECTORY);
+        archive.extend_from_slice(&[0; 4]);
+        archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&offset.to_le_bytes());
+        archive.extend_from_slice(&[0; 2]);
+        archive
+    }
+
+    #[test]
+    fn extracts_roms_from_archives() {
+        let rom: &[u8] = &[0x00, 0xE0, 0x12, 0x00];
+        let other: &[u8] = &[0x12, 0x00];
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(rom).unwrap();
+        assert_eq!(gunzip(&gzip.finish().unwrap()).unwrap(), rom);
+
+        for deflate in [false, true].iter() {
+            let archive = zip(&[("README.txt", b"Read me"), ("games/pong.ch8", rom)], *deflate);
+            assert_eq!(unzip(&archive, None).unwrap(), rom);
+
+            let archive = zip(&[("pong.ch8", rom), ("games/tetris.ch8", other)], *deflate);
+            assert_eq!(unzip(&archive, Some("tetris.ch8")).unwrap(), other);
+            assert_eq!(unzip(&archive, Some("pong.ch8")).unwrap(), rom);
+            assert!(unzip(&archive, Some("brix.ch8")).unwrap_err().contains("pong.ch8, games/tetris.ch8"));
+        }
+
+        let mut corrupted = zip(&[("pong.ch8", rom)], false);
+        corrupted[30 + "pong.ch8".len()] ^= 0xFF;
+        assert!(unzip(&corrupted, None).unwrap_err().contains("CRC mismatch"));
+        assert!(unzip(&ZIP_MAGIC, None).is_err());
+    }
+}
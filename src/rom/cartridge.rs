@@ -0,0 +1,146 @@
+//! Octo cartridges, GIFs whose pixels carry the Octo program and its options. The low two
+//! bits of each pixel's colour index, over all frames and four pixels to a byte with the
+//! high bits first, give a big endian 32 bit length followed by that much JSON:
+//!
+//! ```json
+//! {"program": ": main ...", "options": {"tickrate": 20, "fillColor": "#FFCC00", ...}}
+//! ```
+
+use std::convert::TryFrom;
+
+use serde::Deserialize;
+
+use super::RomFile;
+use crate::chip8::octo;
+use crate::chip8::quirks::Quirks;
+use crate::config::Settings;
+use crate::multimedia::palette::Palette;
+
+#[derive(Deserialize)]
+struct Payload {
+    program: String,
+    #[serde(default)]
+    options: Options,
+}
+
+/// The options Chipr-8 has settings for, the others are for Octo's own emulator
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Options {
+    tickrate: Option<u32>,
+    fill_color: Option<String>,
+    background_color: Option<String>,
+    logic_quirks: Option<bool>,
+    shift_quirks: Option<bool>,
+    load_store_quirks: Option<bool>,
+    jump_quirks: Option<bool>,
+    clip_quirks: Option<bool>,
+}
+
+/// Assembles the cartridge's program, with the settings its options ask for
+pub fn decode(gif: &[u8]) -> Result<RomFile, String> {
+    let payload = payload(gif)?;
+    let payload: Payload = serde_json::from_slice(&payload).map_err(|e| format!("invalid Octo cartridge: {}", e))?;
+    let bytes = octo::assemble(&payload.program).map_err(|e| format!("could not assemble the cartridge's program, {}", e))?;
+    Ok(RomFile { bytes, settings: payload.options.settings() })
+}
+
+/// The JSON hidden in the pixels
+fn payload(gif: &[u8]) -> Result<Vec<u8>, String> {
+    let invalid = |e: gif::DecodingError| format!("invalid GIF: {}", e);
+    let mut decoder = gif::DecodeOptions::new().read_info(gif).map_err(invalid)?;
+    let mut bytes = Vec::new();
+    let mut byte = 0u8;
+    let mut pairs = 0;
+    while let Some(frame) = decoder.read_next_frame().map_err(invalid)? {
+        for pixel in frame.buffer.iter() {
+            byte = byte << 2 | pixel & 3;
+            pairs += 1;
+            if pairs % 4 == 0 {
+                bytes.push(byte);
+            }
+        }
+    }
+
+    let length = bytes.get(..4)
+        .and_then(|length| <[u8; 4]>::try_from(length).ok())
+        .map(|length| u32::from_be_bytes(length) as usize)
+        .ok_or("not an Octo cartridge, the GIF is too small")?;
+    bytes.get(4..)
+        .and_then(|json| json.get(..length))
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| "not an Octo cartridge, the GIF holds less than the length it starts with".to_string())
+}
+
+impl Options {
+    fn settings(&self) -> Settings {
+        let mut quirks = Quirks::default();
+        quirks.vf_reset = self.logic_quirks.unwrap_or(quirks.vf_reset);
+        quirks.shift_uses_vy = self.shift_quirks.map_or(quirks.shift_uses_vy, |in_place| !in_place);
+        quirks.load_store_increments_i = self.load_store_quirks.map_or(quirks.load_store_increments_i, |unchanged| !unchanged);
+        quirks.jump_uses_vx = self.jump_quirks.unwrap_or(quirks.jump_uses_vx);
+        quirks.clip_sprites = self.clip_quirks.unwrap_or(quirks.clip_sprites);
+        let palette = match (&self.background_color, &self.fill_color) {
+            (Some(background), Some(fill)) => Palette::parse(&format!("{},{}", background, fill)).ok().map(Palette::to_spec),
+            _ => None,
+        };
+        Settings {
+            speed: self.tickrate.map(|tickrate| tickrate.saturating_mul(60)),
+            quirks: Some(quirks.to_spec()),
+            palette,
+            ..Settings::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gif::{Encoder, Frame};
+
+    /// A cartridge GIF, the payload spread over two frames
+    fn cartridge(json: &str) -> Vec<u8> {
+        let mut payload = (json.len() as u32).to_be_bytes().to_vec();
+        payload.extend_from_slice(json.as_bytes());
+        // the high bits are what the label drawn over the data looks like
+        let mut pixels = payload.iter()
+            .flat_map(|byte| (0..4).rev().map(move |pair| 0x0C | byte >> (pair * 2) & 3))
+            .collect::<Vec<u8>>();
+        pixels.resize(pixels.len().div_ceil(128) * 128, 0);
+        let half = pixels.len() / 2;
+
+        let mut gif = Vec::new();
+        {
+            let palette = (0..16).flat_map(|color| vec![color * 16; 3]).collect::<Vec<u8>>();
+            let mut encoder = Encoder::new(&mut gif, 64, (half / 64) as u16, &palette).unwrap();
+            for frame in pixels.chunks(half) {
+                encoder.write_frame(&Frame::from_indexed_pixels(64, (half / 64) as u16, frame, None)).unwrap();
+            }
+        }
+        gif
+    }
+
+    #[test]
+    fn loads_the_program_and_options() {
+        let gif = cartridge(r##"{
+            "program": ": main\n  i := digit\n  sprite v0 v0 2\n  loop again\n: digit 0xF0 0x90",
+            "options": {"tickrate": 20, "fillColor": "#FFCC00", "backgroundColor": "#996600",
+                        "shiftQuirks": true, "loadStoreQuirks": false, "jumpQuirks": false,
+                        "logicQuirks": true, "clipQuirks": true, "vBlankQuirks": true}
+        }"##);
+        let rom = decode(&gif).unwrap();
+        assert_eq!(rom.bytes, [0xA2, 0x06, 0xD0, 0x02, 0x12, 0x04, 0xF0, 0x90]);
+        assert_eq!(rom.settings.speed(), 1200);
+        assert_eq!(rom.settings.palette.as_deref(), Some("996600,FFCC00"));
+        assert_eq!(rom.settings.quirks().unwrap(), Quirks {
+            vf_reset: true,
+            shift_uses_vy: false,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            clip_sprites: true,
+        });
+
+        assert!(decode(&cartridge("{\"program\": \"hires\"}")).unwrap_err().contains("line 1"));
+        assert!(decode(&cartridge("[]")).is_err());
+    }
+}
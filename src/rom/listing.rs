@@ -0,0 +1,55 @@
+//! Hex listings of ROMs, bytes or words separated by whitespace or commas:
+//!
+//! ```text
+//! # IBM logo
+//! 200: 00E0 A22A 6000 610E
+//! 208: 0x60 0x0C, 0xD0 0x1F
+//! ```
+//!
+//! `#`, `;` and `//` start comments, and the `ADDRESS:` at the start of a line is skipped.
+
+/// The bytes of the listing, `None` if the text is not one
+pub fn parse(text: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(text).ok()?;
+    let mut bytes = Vec::new();
+    for line in text.lines() {
+        let code = ["#", ";", "//"].iter().fold(line, |code, comment| code.split(comment).next().unwrap_or(""));
+        let code = match code.split_once(':') {
+            Some((address, rest)) if is_hex(address.trim()) => rest,
+            _ => code,
+        };
+        for token in code.split(|c: char| c.is_whitespace() || c == ',').filter(|token| !token.is_empty()) {
+            let digits = token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")).unwrap_or(token);
+            if !is_hex(digits) || digits.len() % 2 != 0 {
+                return None;
+            }
+            for pair in digits.as_bytes().chunks(2) {
+                bytes.push(u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?);
+            }
+        }
+    }
+    if bytes.is_empty() {
+        None
+    } else {
+        Some(bytes)
+    }
+}
+
+fn is_hex(text: &str) -> bool {
+    !text.is_empty() && text.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_bytes_and_words() {
+        let listing = b"# IBM logo\n200: 00E0 A22A\n204: 0x60 0x0C, 0xd0 ; draw\n\n// end\n1F\n";
+        assert_eq!(parse(listing), Some(vec![0x00, 0xE0, 0xA2, 0x2A, 0x60, 0x0C, 0xD0, 0x1F]));
+
+        assert_eq!(parse(b"# only a comment\n"), None);
+        assert_eq!(parse(b"00E0 A2G0"), None);
+        assert_eq!(parse(&[0x00, 0xE0, 0x12, 0x00]), None);
+    }
+}
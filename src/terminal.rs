@@ -0,0 +1,139 @@
+use std::io::{self, Write};
+
+use crate::chip8::gfx::Gfx;
+use crate::multimedia::input::{Command, EventManager};
+use crate::multimedia::keymap::Keymap;
+use crate::multimedia::osd::Status;
+use crate::multimedia::palette::Palette;
+use crate::multimedia::screen::Drawable;
+use crate::multimedia::sound::Beeper;
+use crate::terminal::bell::Bell;
+use crate::terminal::input::Input;
+use crate::terminal::screen::{Charset, Screen};
+
+pub mod bell;
+pub mod input;
+pub mod screen;
+
+/// Console frontend, renders the display with Unicode characters and reads the
+/// keypad from the terminal in raw mode, so it works over SSH without a X server.
+pub struct Terminal {
+    screen: Screen,
+    bell: Bell,
+    input: Input,
+    raw_mode: RawMode,
+}
+
+impl Drawable for Terminal {
+    fn draw(&mut self, gfx: &mut Gfx) {
+        self.screen.draw(gfx);
+    }
+
+    fn show_message(&mut self, message: &str) {
+        self.screen.show_message(message);
+    }
+
+    fn owns_terminal(&self) -> bool {
+        true
+    }
+
+    fn update_status(&mut self, status: &Status) {
+        self.screen.update_status(status);
+    }
+
+    fn toggle_osd(&mut self) {
+        self.screen.toggle_osd();
+    }
+}
+
+impl Beeper for Terminal {
+    fn start_beep(&mut self, offset: f32) {
+        self.bell.start_beep(offset);
+    }
+
+    fn stop_beep(&mut self, offset: f32) {
+        self.bell.stop_beep(offset);
+    }
+}
+
+impl EventManager for Terminal {
+    fn check_events(&mut self) {
+        self.input.check_events();
+    }
+
+    fn is_quiting(&self) -> bool {
+        self.input.is_quiting()
+    }
+
+    fn is_key_pressed(&self, key: u8) -> bool {
+        self.input.is_key_pressed(key)
+    }
+
+    fn commands(&self) -> &[Command] {
+        self.input.commands()
+    }
+}
+
+impl Terminal {
+    pub fn new(charset: Charset, palette: Palette, bell: bool, keymap: Keymap) -> io::Result<Terminal> {
+        let raw_mode = RawMode::enable()?;
+        Ok(Terminal {
+            screen: Screen::new(charset, palette),
+            bell: Bell::new(bell),
+            input: Input::new(keymap),
+            raw_mode,
+        })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.raw_mode.disable();
+    }
+}
+
+/// Puts stdin in non-blocking raw mode and switches to the alternate screen,
+/// restoring both when disabled.
+struct RawMode {
+    original: libc::termios,
+    enabled: bool,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        unsafe {
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            raw.c_cc[libc::VMIN] = 0;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // alternate screen, hidden cursor, clear
+            print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+            io::stdout().flush()?;
+
+            Ok(RawMode {
+                original,
+                enabled: true,
+            })
+        }
+    }
+
+    fn disable(&mut self) {
+        if self.enabled {
+            print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+            let _ = io::stdout().flush();
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+            }
+            self.enabled = false;
+        }
+    }
+}
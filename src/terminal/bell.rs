@@ -0,0 +1,34 @@
+use std::io::{self, Write};
+
+use crate::multimedia::sound::Beeper;
+
+/// Rings the terminal bell once at the start of every beep, or stays silent.
+pub struct Bell {
+    enabled: bool,
+    playing: bool,
+}
+
+impl Beeper for Bell {
+    fn start_beep(&mut self, _offset: f32) {
+        if !self.playing {
+            if self.enabled {
+                print!("\x07");
+                let _ = io::stdout().flush();
+            }
+            self.playing = true;
+        }
+    }
+
+    fn stop_beep(&mut self, _offset: f32) {
+        self.playing = false;
+    }
+}
+
+impl Bell {
+    pub fn new(enabled: bool) -> Bell {
+        Bell {
+            enabled,
+            playing: false,
+        }
+    }
+}
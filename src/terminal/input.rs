@@ -0,0 +1,121 @@
+use crate::multimedia::input::{Command, EventManager};
+use crate::multimedia::keymap::Keymap;
+
+/// Terminals only report key presses (and auto repeat), never releases,
+/// so a key is considered held for this many checks after its last press.
+const HOLD_CHECKS: u8 = 10;
+
+const ESC: u8 = 0x1b;
+const CTRL_C: u8 = 0x03;
+const TAB: u8 = 0x09;
+
+pub struct Input {
+    keymap: Keymap,
+    keys: [u8; 16],
+    /// Checks left before fast-forward is considered released, like the keypad keys
+    fast_forward: u8,
+    is_quiting: bool,
+    commands: Vec<Command>,
+}
+
+impl EventManager for Input {
+    fn check_events(&mut self) {
+        self.commands.clear();
+        for key in self.keys.iter_mut() {
+            *key = key.saturating_sub(1);
+        }
+        self.fast_forward = self.fast_forward.saturating_sub(1);
+
+        let bytes = read_stdin();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                CTRL_C => self.is_quiting = true,
+                ESC if i + 1 == bytes.len() => self.is_quiting = true,
+                ESC => {
+                    // CSI sequence, ends at the first byte in 0x40..=0x7E
+                    let start = i;
+                    i += 2;
+                    while i < bytes.len() && !(0x40..=0x7E).contains(&bytes[i]) {
+                        i += 1;
+                    }
+                    let command = match bytes.get(start..=i) {
+                        Some(b"\x1bOP") => Some(Command::ToggleOsd),      // F1
+                        Some(b"\x1b[20~") => Some(Command::ToggleRecord), // F9
+                        Some(b"\x1b[15~") => Some(Command::Reset),        // F5
+                        Some(b"\x1b[17~") => Some(Command::SaveState),    // F6
+                        Some(b"\x1b[18~") => Some(Command::LoadState),    // F7
+                        Some(b"\x1b[5~") => Some(Command::SpeedUp),       // Page Up
+                        Some(b"\x1b[6~") => Some(Command::SpeedDown),     // Page Down
+                        _ => None,
+                    };
+                    self.commands.extend(command);
+                }
+                b'p' | b'P' => self.commands.push(Command::TogglePause),
+                b'n' | b'N' => self.commands.push(Command::FrameAdvance),
+                TAB => self.fast_forward = HOLD_CHECKS,
+                byte => {
+                    if let Some(key) = self.keymap.keypad_key(byte as char) {
+                        self.keys[key] = HOLD_CHECKS;
+                    }
+                }
+            }
+            i += 1;
+        }
+        if self.fast_forward > 0 {
+            self.commands.push(Command::FastForward);
+        }
+    }
+
+    fn is_quiting(&self) -> bool {
+        self.is_quiting
+    }
+
+    fn is_key_pressed(&self, key: u8) -> bool {
+        self.keys.get(key as usize).is_some_and(|&held| held > 0)
+    }
+
+    fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+}
+
+impl Input {
+    pub fn new(keymap: Keymap) -> Input {
+        Input {
+            keymap,
+            keys: [0; 16],
+            fast_forward: 0,
+            is_quiting: false,
+            commands: Vec::new(),
+        }
+    }
+}
+
+/// Reads whatever is pending on stdin, stdin is non-blocking in raw mode.
+fn read_stdin() -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut buffer = [0u8; 64];
+    loop {
+        let read = unsafe { libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+        if read <= 0 {
+            break;
+        }
+        bytes.extend_from_slice(&buffer[..read as usize]);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_past_the_keypad_are_never_pressed() {
+        let mut input = Input::new(Keymap::default());
+        input.keys = [1; 16];
+        assert!(input.is_key_pressed(0xF));
+        assert!(!input.is_key_pressed(0x10));
+        assert!(!input.is_key_pressed(0xFF));
+    }
+}
@@ -0,0 +1,134 @@
+use std::io::{self, Write};
+
+use crate::chip8::gfx::{Gfx, GFX_COLS, GFX_ROWS};
+use crate::multimedia::osd::{Osd, Status};
+use crate::multimedia::palette::Palette;
+use crate::multimedia::screen::Drawable;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Charset {
+    /// `▀▄█`, one character for 1x2 pixels (64x16 characters)
+    HalfBlock,
+    /// Braille patterns, one character for 2x4 pixels (32x8 characters)
+    Braille,
+}
+
+impl Charset {
+    pub fn parse(name: &str) -> Result<Charset, String> {
+        match name {
+            "halfblock" => Ok(Charset::HalfBlock),
+            "braille" => Ok(Charset::Braille),
+            _ => Err(format!("Invalid charset '{}', expected halfblock or braille", name)),
+        }
+    }
+}
+
+pub struct Screen {
+    charset: Charset,
+    palette: Palette,
+    osd: Osd,
+}
+
+impl Drawable for Screen {
+    fn draw(&mut self, gfx: &mut Gfx) {
+        let osd_changed = self.osd.take_changed();
+        if gfx.needs_redraw() || osd_changed {
+            let mut frame = match self.charset {
+                Charset::HalfBlock => self.render_half_block(gfx),
+                Charset::Braille => self.render_braille(gfx),
+            };
+            frame.push_str(&self.render_status_line());
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            out.write_all(frame.as_bytes()).expect("Could not draw to terminal");
+            out.flush().expect("Could not draw to terminal");
+            gfx.set_needs_redraw(false);
+        }
+    }
+
+    fn show_message(&mut self, message: &str) {
+        self.osd.show_message(message);
+    }
+
+    fn update_status(&mut self, status: &Status) {
+        self.osd.set_status(status);
+    }
+
+    fn toggle_osd(&mut self) {
+        self.osd.toggle();
+    }
+}
+
+impl Screen {
+    pub fn new(charset: Charset, palette: Palette) -> Screen {
+        Screen {
+            charset,
+            palette,
+            osd: Osd::new(),
+        }
+    }
+
+    /// The on-screen display as one line of text under the picture, cleared when hidden
+    fn render_status_line(&self) -> String {
+        if !self.osd.is_visible() {
+            return "\x1b[K".to_string();
+        }
+        let status = self.osd.status();
+        let keys: Vec<String> = (0..16).filter(|key| status.keys[*key]).map(|key| format!("{:X}", key)).collect();
+        format!("{:<32} {:>4} IPS {:>2} FPS {:<6} {}\x1b[K",
+                self.osd.message().unwrap_or(""),
+                status.ips,
+                status.fps,
+                if status.paused { "PAUSED" } else { "" },
+                keys.join(" "))
+    }
+
+    fn render_half_block(&self, gfx: &Gfx) -> String {
+        let mut frame = self.frame_start();
+        for row in (0..GFX_ROWS).step_by(2) {
+            for col in 0..GFX_COLS {
+                let top = gfx[row * GFX_COLS + col];
+                let bottom = gfx[(row + 1) * GFX_COLS + col];
+                frame.push(match (top, bottom) {
+                    (false, false) => ' ',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (true, true) => '█',
+                });
+            }
+            frame.push_str("\r\n");
+        }
+        frame.push_str("\x1b[0m");
+        frame
+    }
+
+    fn render_braille(&self, gfx: &Gfx) -> String {
+        // dot bit for each (x, y) position inside a 2x4 braille cell
+        const DOTS: [[u32; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+
+        let mut frame = self.frame_start();
+        for row in (0..GFX_ROWS).step_by(4) {
+            for col in (0..GFX_COLS).step_by(2) {
+                let mut cell = 0;
+                for (dx, dots) in DOTS.iter().enumerate() {
+                    for (dy, dot) in dots.iter().enumerate() {
+                        if gfx[(row + dy) * GFX_COLS + col + dx] {
+                            cell |= dot;
+                        }
+                    }
+                }
+                frame.push(std::char::from_u32(0x2800 + cell).unwrap_or(' '));
+            }
+            frame.push_str("\r\n");
+        }
+        frame.push_str("\x1b[0m");
+        frame
+    }
+
+    /// Moves the cursor home and selects the palette colours
+    fn frame_start(&self) -> String {
+        let bg = self.palette.background;
+        let fg = self.palette.foreground;
+        format!("\x1b[H\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m", fg.0, fg.1, fg.2, bg.0, bg.1, bg.2)
+    }
+}
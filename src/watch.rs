@@ -0,0 +1,43 @@
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the file is looked at, reading metadata on every cycle would slow the emulator down
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Notices when a file changes on disk by polling its modification time and size
+pub struct FileWatcher {
+    path: String,
+    last_seen: Option<(SystemTime, u64)>,
+    last_poll: Instant,
+}
+
+impl FileWatcher {
+    pub fn new(path: &str) -> FileWatcher {
+        FileWatcher {
+            path: path.to_string(),
+            last_seen: FileWatcher::stat(path),
+            last_poll: Instant::now(),
+        }
+    }
+
+    /// Whether the file changed since the last time this returned true
+    pub fn changed(&mut self) -> bool {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.last_poll = Instant::now();
+
+        let seen = FileWatcher::stat(&self.path);
+        // a missing file is usually one being rewritten, wait for it to come back
+        if seen.is_none() || seen == self.last_seen {
+            return false;
+        }
+        self.last_seen = seen;
+        true
+    }
+
+    fn stat(path: &str) -> Option<(SystemTime, u64)> {
+        let metadata = fs::metadata(path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+}
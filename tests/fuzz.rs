@@ -0,0 +1,66 @@
+//! Runs random programs, and ones that used to panic the emulator, through
+//! `harness::check_program`, the stable counterpart of the `fuzz/` target
+
+use chipr_8::chip8::harness::check_program;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const PROGRAMS: u64 = 500;
+const CYCLES: u64 = 2_000;
+
+/// A program of mostly valid instructions jumping within itself, so runs go further than the
+/// first unknown opcode
+fn random_program(rng: &mut StdRng) -> Vec<u8> {
+    let words = rng.gen_range(1, 64);
+    let mut program = Vec::with_capacity(words * 2);
+    for _ in 0..words {
+        let mut word: u16 = rng.gen();
+        match word >> 12 {
+            0x0 => word = if rng.gen_range(0, 4) == 0 { 0x00EE } else { 0x00E0 },
+            0x1 | 0x2 | 0xB => word = word & 0xF000 | (0x200 + 2 * rng.gen_range(0, words as u16)),
+            0x8 => word = word & 0xFFF0 | [0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0xE][rng.gen_range(0, 9)],
+            0xE => word = word & 0xFF00 | if rng.gen() { 0x9E } else { 0xA1 },
+            0xF => word = word & 0xFF00 | [0x07, 0x0A, 0x15, 0x18, 0x1E, 0x29, 0x33, 0x55, 0x65][rng.gen_range(0, 9)],
+            _ => {}
+        }
+        program.extend_from_slice(&word.to_be_bytes());
+    }
+    program
+}
+
+fn check_twice(program: &[u8], seed: u64, keys: u16) {
+    let first = check_program(program, seed, keys, CYCLES).unwrap_or_else(|e| panic!("{:02X?}: {}", program, e));
+    let second = check_program(program, seed, keys, CYCLES).unwrap();
+    assert!(first == second, "{:02X?} ran differently with seed {}", program, seed);
+}
+
+#[test]
+fn random_programs_keep_the_machine_valid_and_deterministic() {
+    let mut rng = StdRng::seed_from_u64(0xC8);
+    for _ in 0..PROGRAMS {
+        let program = random_program(&mut rng);
+        check_twice(&program, rng.gen(), rng.gen());
+    }
+}
+
+#[test]
+fn programs_that_used_to_panic() {
+    let programs: [&[u8]; 6] = [
+        // return with an empty stack
+        &[0x00, 0xEE],
+        // call itself until the stack is full
+        &[0x22, 0x00],
+        // jump to 0
+        &[0x10, 0x00],
+        // I = 0xFFF, store V0-VF past the end of memory, then load them back
+        &[0xAF, 0xFF, 0xFF, 0x55, 0xFF, 0x65, 0xFF, 0x33, 0xD0, 0x0F],
+        // jump to the last byte of memory and run across the end
+        &[0x1F, 0xFF],
+        // BNNN past the end of memory
+        &[0x60, 0xFF, 0xBF, 0xFF],
+    ];
+    for program in programs.iter() {
+        check_twice(program, 0, 0);
+        check_twice(program, 0, 0xFFFF);
+    }
+}
@@ -0,0 +1,166 @@
+//! Drives `chipr-8 --gdb` with a scripted client speaking the GDB remote serial protocol
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+// 200: I = 0x300
+// 202: V0 = 7
+// 204: V0 += 1
+// 206: [I] = V0
+// 208: JP 0x204
+const ROM: [u8; 10] = [0xA3, 0x00, 0x60, 0x07, 0x70, 0x01, 0xF0, 0x55, 0x12, 0x04];
+
+struct Session {
+    emulator: Child,
+    stream: TcpStream,
+    rom: PathBuf,
+}
+
+impl Session {
+    fn start(name: &str, program: &[u8]) -> Session {
+        let rom = env::temp_dir().join(format!("chipr-8-gdb-{}-{}.ch8", name, std::process::id()));
+        fs::write(&rom, program).unwrap();
+        let mut emulator = Command::new(env!("CARGO_BIN_EXE_chipr-8"))
+            .args(["--headless", "--gdb", "0"])
+            .arg(&rom)
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let mut stderr = BufReader::new(emulator.stderr.take().unwrap());
+        let mut line = String::new();
+        stderr.read_line(&mut line).unwrap();
+        let address = line.trim().strip_prefix("Waiting for GDB on ").unwrap().to_string();
+        // keep reading so the emulator never blocks on a full pipe
+        std::thread::spawn(move || std::io::copy(&mut stderr, &mut std::io::sink()));
+
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        Session { emulator, stream, rom }
+    }
+
+    fn send(&mut self, data: &str) {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", data, checksum).unwrap();
+    }
+
+    /// Reads one packet, skipping acks
+    fn receive(&mut self) -> String {
+        let mut byte = [0];
+        loop {
+            self.stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            self.stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut checksum = [0; 2];
+        self.stream.read_exact(&mut checksum).unwrap();
+        self.stream.write_all(b"+").unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    fn request(&mut self, data: &str) -> String {
+        self.send(data);
+        self.receive()
+    }
+
+    fn register(&mut self, number: usize) -> String {
+        self.request(&format!("p{:x}", number))
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.emulator.kill().ok();
+        self.emulator.wait().ok();
+        fs::remove_file(&self.rom).ok();
+    }
+}
+
+#[test]
+fn reads_registers_and_memory_and_steps() {
+    let mut gdb = Session::start("step", &ROM);
+    assert!(gdb.request("qSupported:swbreak+").contains("qXfer:features:read+"));
+    assert!(gdb.request("qXfer:features:read:target.xml:0,1000").contains(r#"<reg name="pc""#));
+    assert_eq!(gdb.request("?"), "S05");
+
+    let registers = gdb.request("g");
+    assert_eq!(registers.len(), 46);
+    assert_eq!(&registers[32..40], "00000200");
+    assert_eq!(gdb.request("m200,4"), "a3006007");
+
+    assert_eq!(gdb.request("s"), "S05");
+    assert_eq!(gdb.request("s"), "S05");
+    assert_eq!(gdb.register(0), "07");
+    assert_eq!(gdb.register(16), "0300");
+    assert_eq!(gdb.register(17), "0204");
+
+    assert_eq!(gdb.request("P0=41"), "OK");
+    assert_eq!(gdb.request("M300,2:beef"), "OK");
+    assert_eq!(gdb.request("m300,2"), "beef");
+    assert_eq!(gdb.request("s"), "S05");
+    assert_eq!(gdb.register(0), "42");
+    assert_eq!(gdb.request("m1000,1"), "E01");
+}
+
+#[test]
+fn stops_at_breakpoints_and_watchpoints() {
+    let mut gdb = Session::start("break", &ROM);
+    assert_eq!(gdb.request("Z0,208,2"), "OK");
+    assert_eq!(gdb.request("c"), "T05swbreak:;");
+    assert_eq!(gdb.register(17), "0208");
+    assert_eq!(gdb.register(0), "08");
+
+    // resumes past the breakpoint it stopped at
+    assert_eq!(gdb.request("c"), "T05swbreak:;");
+    assert_eq!(gdb.register(0), "09");
+    assert_eq!(gdb.request("z0,208,2"), "OK");
+
+    assert_eq!(gdb.request("Z2,300,1"), "OK");
+    assert_eq!(gdb.request("c"), "T05watch:300;");
+    assert_eq!(gdb.register(17), "0208");
+    assert_eq!(gdb.request("m300,1"), "0a");
+    assert_eq!(gdb.request("z2,300,1"), "OK");
+}
+
+#[test]
+fn interrupts_the_running_machine() {
+    let mut gdb = Session::start("interrupt", &ROM);
+    gdb.send("c");
+    std::thread::sleep(Duration::from_millis(100));
+    gdb.stream.write_all(&[0x03]).unwrap();
+    assert_eq!(gdb.receive(), "S02");
+
+    let pc = u16::from_str_radix(&gdb.register(17), 16).unwrap();
+    assert!((0x204..=0x208).contains(&pc));
+    gdb.send("k");
+    assert!(gdb.emulator.wait().unwrap().success());
+}
+
+#[test]
+fn steps_over_a_key_wait_without_hanging() {
+    // 200: V3 = key, 202: JP 0x202
+    let mut gdb = Session::start("key", &[0xF3, 0x0A, 0x12, 0x02]);
+    assert_eq!(gdb.request("s"), "S05");
+    assert_eq!(gdb.request("s"), "S05");
+    assert_eq!(gdb.register(17), "0200");
+
+    gdb.send("c");
+    std::thread::sleep(Duration::from_millis(100));
+    gdb.stream.write_all(&[0x03]).unwrap();
+    assert_eq!(gdb.receive(), "S02");
+    assert_eq!(gdb.register(17), "0200");
+}